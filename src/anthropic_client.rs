@@ -0,0 +1,668 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use dashmap::DashMap;
+use futures::lock::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::conversation_store::{ConversationStore, InMemoryConversationStore};
+use crate::event_handler::{ErrorCode, ProcessingError};
+use crate::gpt_client::{
+    estimate_tokens, history_version, pop_last_exchange, prune_messages,
+    CompletionStream, DrawOptions, GptError, GtpInteractor, ImageContent,
+    Message, StoredMessage, Temperatures, Tool, ToolCall, ToolCallOrText,
+    ToolResult, Value, DEFAULT_MAX_HISTORY_TOKENS, DEFAULT_MAX_RETRIES,
+    DEFAULT_RETRY_BASE_DELAY,
+};
+use crate::response_cache::ResponseCache;
+use crate::retry::{retry_with_backoff, retryable_status, status_error};
+use crate::usage_stats::UsageStats;
+
+const MESSAGES_URL: &str = "https://api.anthropic.com/v1/messages";
+const API_VERSION: &str = "2023-06-01";
+/// The Messages API has no default for the answer length.
+const MAX_TOKENS: u32 = 4096;
+/// Claude models take temperatures from 0 to 1, not to 2 like OpenAI.
+pub const MAX_TEMPERATURE: f64 = 1.0;
+/// The error of a feature Claude models don't have here, also shown to the
+/// user.
+pub(crate) const UNSUPPORTED: &str = "С моделями Claude это пока не работает";
+
+#[derive(Debug, Serialize)]
+struct AnthropicRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    temperature: f64,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    system: &'a str,
+    messages: &'a [AnthropicMessage<'a>],
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+struct AnthropicMessage<'a> {
+    role: &'static str,
+    content: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicResponse {
+    content: Vec<ContentBlock>,
+    usage: AnthropicUsage,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentBlock {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicUsage {
+    input_tokens: u64,
+    output_tokens: u64,
+}
+
+/// Answers with Claude models through the Anthropic Messages API. Only
+/// text completions are supported, the history is kept in the Lambda
+/// memory.
+#[derive(Debug)]
+pub struct AnthropicClient {
+    token: &'static str,
+    model: &'static str,
+    smart_model: &'static str,
+    http_client: reqwest::Client,
+    messages_url: &'static str,
+    base_rules: Mutex<Arc<String>>,
+    history: InMemoryConversationStore,
+    usage_stats: Arc<UsageStats>,
+    max_history_tokens: usize,
+    max_attempts: u32,
+    retry_base_delay: Duration,
+    history_fill: DashMap<i64, f64>,
+    temperatures: Temperatures,
+    response_cache: Option<ResponseCache>,
+}
+
+impl AnthropicClient {
+    pub fn new(
+        model: &'static str,
+        smart_model: &'static str,
+        token: &'static str,
+        base_rules: String,
+    ) -> Self {
+        AnthropicClient {
+            token,
+            model,
+            smart_model,
+            http_client: reqwest::Client::new(),
+            messages_url: MESSAGES_URL,
+            base_rules: Mutex::new(Arc::new(base_rules)),
+            history: InMemoryConversationStore::default(),
+            usage_stats: Arc::default(),
+            max_history_tokens: DEFAULT_MAX_HISTORY_TOKENS,
+            max_attempts: DEFAULT_MAX_RETRIES + 1,
+            retry_base_delay: DEFAULT_RETRY_BASE_DELAY,
+            history_fill: DashMap::new(),
+            temperatures: Temperatures::default(),
+            response_cache: None,
+        }
+    }
+
+    /// Counts the usage of several clients together.
+    pub fn set_usage_stats(&mut self, usage_stats: Arc<UsageStats>) {
+        self.usage_stats = usage_stats;
+    }
+
+    pub fn set_max_history_tokens(&mut self, max_tokens: usize) {
+        self.max_history_tokens = max_tokens;
+    }
+
+    /// Only the default and smart temperatures apply, images are not
+    /// supported.
+    pub fn set_temperatures(&mut self, temperatures: Temperatures) {
+        self.temperatures = temperatures;
+    }
+
+    pub fn set_response_cache(&mut self, cache: ResponseCache) {
+        self.response_cache = Some(cache);
+    }
+
+    /// Repeats rate limited and failed requests up to `max_retries` times,
+    /// waiting twice as long before each next one.
+    pub fn set_retry_policy(&mut self, max_retries: u32, base_delay: Duration) {
+        self.max_attempts = max_retries + 1;
+        self.retry_base_delay = base_delay;
+    }
+
+    /// Only the answers of the default model without a chat's own system
+    /// prompt are cached, like the plain completions of the OpenAI client.
+    async fn get_history_completion(
+        &self,
+        user_id: i64,
+        model: &'static str,
+        temperature: f64,
+        system_prompt: Option<&str>,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        let mut history = self.history.load(user_id).await?;
+        let fill =
+            estimate_tokens(&history) as f64 / self.max_history_tokens as f64;
+        self.history_fill.insert(user_id, fill);
+        prune_messages(&mut history, self.max_history_tokens);
+
+        let cache = self
+            .response_cache
+            .as_ref()
+            .filter(|_| model == self.model && system_prompt.is_none())
+            .map(|cache| (cache, history_version(&history)));
+        if let Some(response) = cache
+            .and_then(|(cache, version)| cache.get(user_id, version, &prompt))
+        {
+            return self.push_answer(user_id, history, prompt, response).await;
+        }
+
+        let base_rules = self.base_rules.lock().await.clone();
+        let rules = match system_prompt {
+            Some(system_prompt) => system_rules(system_prompt, &base_rules),
+            None => base_rules.to_string(),
+        };
+        let (system, messages) = build_messages(&rules, &history, &prompt);
+        let request = AnthropicRequest {
+            model,
+            max_tokens: MAX_TOKENS,
+            temperature,
+            system: &system,
+            messages: &messages,
+        };
+
+        let response = retry_with_backoff(
+            || self.request_messages(&request),
+            self.max_attempts,
+            self.retry_base_delay,
+        )
+        .await?;
+        self.usage_stats.record_user_tokens(
+            user_id,
+            response.usage.input_tokens,
+            response.usage.output_tokens,
+        );
+
+        let result = response_text(response);
+        if result.trim().is_empty() {
+            bail!(GptError::EmptyResponse);
+        }
+
+        let result = Arc::new(result);
+        if let Some((cache, version)) = cache {
+            cache.insert(user_id, version, &prompt, result.clone());
+        }
+
+        self.push_answer(user_id, history, prompt, result).await
+    }
+
+    async fn push_answer(
+        &self,
+        user_id: i64,
+        mut history: Vec<StoredMessage>,
+        prompt: String,
+        answer: Arc<String>,
+    ) -> Result<Arc<String>> {
+        history.push(Message::User(Value::Plain(prompt.into())).into());
+        history.push(Message::Assistant(Value::Plain(answer.clone())).into());
+        self.history.save(user_id, history).await?;
+
+        Ok(answer)
+    }
+
+    async fn request_messages(
+        &self,
+        request: &AnthropicRequest<'_>,
+    ) -> Result<AnthropicResponse> {
+        self.usage_stats.record_request();
+        let response = self
+            .http_client
+            .post(self.messages_url)
+            .header("x-api-key", self.token)
+            .header("anthropic-version", API_VERSION)
+            .json(request)
+            .send()
+            .await
+            .inspect_err(|_| self.usage_stats.record_error())
+            .context(ErrorCode::GptApiFailure)?;
+
+        let response = retryable_status(response)
+            .await
+            .inspect_err(|_| self.usage_stats.record_error())
+            .context(ErrorCode::GptApiFailure)?;
+
+        if !response.status().is_success() {
+            self.usage_stats.record_error();
+            let error = status_error(response.status(), response.text().await?);
+            return Err(error.context(ErrorCode::GptApiFailure));
+        }
+
+        let response = response.json::<AnthropicResponse>().await?;
+        self.usage_stats.record_completion(
+            response.usage.input_tokens + response.usage.output_tokens,
+        );
+
+        Ok(response)
+    }
+}
+
+/// A retry can't add the feature, so the error is permanent.
+fn unsupported() -> anyhow::Error {
+    ProcessingError::Permanent(UNSUPPORTED.to_string()).into()
+}
+
+/// A chat's own system prompt goes before the base rules, the same order
+/// as in the OpenAI client.
+fn system_rules(system_prompt: &str, base_rules: &str) -> String {
+    if base_rules.is_empty() {
+        system_prompt.to_string()
+    } else {
+        format!("{system_prompt}\n{base_rules}")
+    }
+}
+
+/// The Messages API takes the system prompt apart from the messages, so
+/// the System notes of the history are appended to the rules. It also needs
+/// the messages to start with a question, so an answer whose question was
+/// pruned is skipped.
+fn build_messages<'a>(
+    rules: &str,
+    history: &'a [StoredMessage],
+    prompt: &'a str,
+) -> (String, Vec<AnthropicMessage<'a>>) {
+    let mut system = rules.to_string();
+    let mut messages = Vec::with_capacity(history.len() + 1);
+
+    for stored in history {
+        let content = stored.message.value().text();
+        let role = match stored.message {
+            Message::User(_) => "user",
+            Message::Assistant(_) if messages.is_empty() => continue,
+            Message::Assistant(_) => "assistant",
+            Message::System(_) => {
+                if !system.is_empty() {
+                    system.push_str("\n\n");
+                }
+                system.push_str(content);
+                continue;
+            }
+        };
+        messages.push(AnthropicMessage { role, content });
+    }
+
+    messages.push(AnthropicMessage {
+        role: "user",
+        content: prompt,
+    });
+
+    (system, messages)
+}
+
+fn response_text(response: AnthropicResponse) -> String {
+    response
+        .content
+        .into_iter()
+        .filter(|block| block.kind == "text")
+        .map(|block| block.text)
+        .collect()
+}
+
+impl GtpInteractor for AnthropicClient {
+    async fn get_completion(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        self.get_history_completion(
+            user_id,
+            self.model,
+            self.temperatures.default,
+            None,
+            prompt,
+        )
+        .await
+    }
+
+    async fn get_completion_stream(
+        &self,
+        _user_id: i64,
+        _prompt: String,
+    ) -> Result<CompletionStream> {
+        Err(unsupported())
+    }
+
+    async fn get_completion_with_tools(
+        &self,
+        _user_id: i64,
+        _prompt: String,
+        _tools: Vec<Tool>,
+    ) -> Result<ToolCallOrText> {
+        Err(unsupported())
+    }
+
+    async fn submit_tool_results(
+        &self,
+        _user_id: i64,
+        _prompt: String,
+        _tools: Vec<Tool>,
+        _tool_calls: Vec<ToolCall>,
+        _results: Vec<ToolResult>,
+    ) -> Result<Arc<String>> {
+        Err(unsupported())
+    }
+
+    async fn get_stateless_completion(
+        &self,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        let messages = [AnthropicMessage {
+            role: "user",
+            content: &prompt,
+        }];
+        let request = AnthropicRequest {
+            model: self.model,
+            max_tokens: MAX_TOKENS,
+            temperature: self.temperatures.default,
+            system: "",
+            messages: &messages,
+        };
+
+        let response = retry_with_backoff(
+            || self.request_messages(&request),
+            self.max_attempts,
+            self.retry_base_delay,
+        )
+        .await?;
+
+        Ok(Arc::new(response_text(response)))
+    }
+
+    async fn summarize_history(
+        &self,
+        _messages: &[Message],
+    ) -> Result<Arc<String>> {
+        Err(unsupported())
+    }
+
+    async fn get_smart_completion(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        self.get_history_completion(
+            user_id,
+            self.smart_model,
+            self.temperatures.smart,
+            None,
+            prompt,
+        )
+        .await
+    }
+
+    async fn get_reasoning_completion(
+        &self,
+        _user_id: i64,
+        _prompt: String,
+    ) -> Result<Arc<String>> {
+        Err(unsupported())
+    }
+
+    async fn get_model_completion(
+        &self,
+        _user_id: i64,
+        _model: &'static str,
+        _prompt: String,
+    ) -> Result<Arc<String>> {
+        Err(unsupported())
+    }
+
+    fn models(&self) -> [&'static str; 2] {
+        [self.model, self.smart_model]
+    }
+
+    async fn get_system_prompt_completion(
+        &self,
+        user_id: i64,
+        system_prompt: &str,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        self.get_history_completion(
+            user_id,
+            self.model,
+            self.temperatures.default,
+            Some(system_prompt),
+            prompt,
+        )
+        .await
+    }
+
+    async fn get_code_review_completion(
+        &self,
+        _user_id: i64,
+        _rules: &str,
+        _prompt: String,
+    ) -> Result<Arc<String>> {
+        Err(unsupported())
+    }
+
+    async fn get_image_completion(
+        &self,
+        _user_id: i64,
+        _text: String,
+        _image_url: String,
+    ) -> Result<Arc<String>> {
+        Err(unsupported())
+    }
+
+    async fn get_multi_image_completion(
+        &self,
+        _user_id: i64,
+        _text: String,
+        _image_urls: Vec<String>,
+    ) -> Result<Arc<String>> {
+        Err(unsupported())
+    }
+
+    fn supports_audio_input(&self) -> bool {
+        false
+    }
+
+    async fn get_audio_completion(
+        &self,
+        _user_id: i64,
+        _audio: Vec<u8>,
+        _format: &'static str,
+    ) -> Result<Arc<String>> {
+        Err(unsupported())
+    }
+
+    async fn get_image(
+        &self,
+        _user_id: i64,
+        _prompt: &str,
+        _options: DrawOptions,
+    ) -> Result<ImageContent> {
+        Err(unsupported())
+    }
+
+    async fn get_image_variation(
+        &self,
+        _user_id: i64,
+        _image_bytes: Vec<u8>,
+        _prompt: &str,
+    ) -> Result<Vec<u8>> {
+        Err(unsupported())
+    }
+
+    async fn get_embedding(&self, _text: &str) -> Result<Vec<f32>> {
+        Err(unsupported())
+    }
+
+    async fn get_audio(&self, _prompt: &str) -> Result<Vec<u8>> {
+        Err(unsupported())
+    }
+
+    async fn transcribe_audio(
+        &self,
+        _audio: Vec<u8>,
+        _mime_type: &str,
+    ) -> Result<Arc<String>> {
+        Err(unsupported())
+    }
+
+    async fn migrate_rules(
+        &self,
+        old_rules: &str,
+        new_rules: &str,
+    ) -> Result<usize> {
+        let mut rules = self.base_rules.lock().await;
+
+        let migrated = if rules.as_str() == old_rules {
+            *rules = Arc::new(new_rules.to_string());
+            1
+        } else {
+            0
+        };
+
+        info!(migrated, "Conversations migrated to new rules");
+
+        Ok(migrated)
+    }
+
+    async fn list_available_models(&self) -> Result<Vec<String>> {
+        Ok(self.models().map(str::to_string).to_vec())
+    }
+
+    fn usage_stats(&self) -> Arc<UsageStats> {
+        self.usage_stats.clone()
+    }
+
+    async fn add_system_message(
+        &self,
+        user_id: i64,
+        text: String,
+    ) -> Result<()> {
+        let mut history = self.history.load(user_id).await?;
+        history.push(Message::System(Value::Plain(text.into())).into());
+        self.history.save(user_id, history).await
+    }
+
+    async fn reset_history(&self, user_id: i64) -> Result<()> {
+        self.history.save(user_id, Vec::new()).await
+    }
+
+    async fn remove_last_exchange(&self, user_id: i64) -> Result<()> {
+        let mut history = self.history.load(user_id).await?;
+        if pop_last_exchange(&mut history) {
+            self.history.save(user_id, history).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn get_history(&self, user_id: i64) -> Result<Vec<StoredMessage>> {
+        self.history.load(user_id).await
+    }
+
+    fn invalidate_cache(&self, user_id: i64) {
+        if let Some(cache) = &self.response_cache {
+            cache.invalidate(user_id);
+        }
+    }
+
+    fn history_fill_ratio(&self, user_id: i64) -> f64 {
+        self.history_fill.get(&user_id).map_or(0.0, |fill| *fill)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::anthropic_client::{
+        build_messages, response_text, system_rules, AnthropicMessage,
+        AnthropicResponse,
+    };
+    use crate::gpt_client::{Message, StoredMessage, Value};
+
+    fn stored(message: Message) -> StoredMessage {
+        message.into()
+    }
+
+    #[test]
+    fn test_build_messages() {
+        let history = [
+            stored(Message::Assistant(Value::Plain(Arc::new(
+                "Pruned question".to_string(),
+            )))),
+            stored(Message::User(Value::Plain(Arc::new("Hi".to_string())))),
+            stored(Message::Assistant(Value::Plain(Arc::new(
+                "Hello".to_string(),
+            )))),
+            stored(Message::System(Value::Plain(Arc::new(
+                "Be brief".to_string(),
+            )))),
+        ];
+
+        let (system, messages) = build_messages("Rules", &history, "How?");
+
+        assert_eq!(system, "Rules\n\nBe brief");
+        assert_eq!(
+            messages,
+            [
+                AnthropicMessage {
+                    role: "user",
+                    content: "Hi"
+                },
+                AnthropicMessage {
+                    role: "assistant",
+                    content: "Hello"
+                },
+                AnthropicMessage {
+                    role: "user",
+                    content: "How?"
+                },
+            ]
+        );
+    }
+
+    // Test that a chat's system prompt goes before the base rules
+    #[test]
+    fn test_system_rules() {
+        assert_eq!(system_rules("Be a pirate", "Rules"), "Be a pirate\nRules");
+        assert_eq!(system_rules("Be a pirate", ""), "Be a pirate");
+
+        let (system, _) =
+            build_messages(&system_rules("Be a pirate", "Rules"), &[], "Hi");
+        assert_eq!(system, "Be a pirate\nRules");
+    }
+
+    #[test]
+    fn test_response_text() {
+        let response: AnthropicResponse = serde_json::from_str(
+            r#"{
+                "id": "msg_1",
+                "type": "message",
+                "role": "assistant",
+                "content": [
+                    {"type": "text", "text": "Hello, "},
+                    {"type": "tool_use", "id": "1", "name": "x", "input": {}},
+                    {"type": "text", "text": "world"}
+                ],
+                "usage": {"input_tokens": 10, "output_tokens": 3}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(response.usage.input_tokens, 10);
+        assert_eq!(response_text(response), "Hello, world");
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use derive_more::Constructor;
+#[cfg(test)]
+use mockall::automock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandType {
+    Text,
+    Image,
+    Draw,
+    Admin,
+    Voice,
+    Document,
+}
+
+impl CommandType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandType::Text => "text",
+            CommandType::Image => "image",
+            CommandType::Draw => "draw",
+            CommandType::Admin => "admin",
+            CommandType::Voice => "voice",
+            CommandType::Document => "document",
+        }
+    }
+}
+
+impl FromStr for CommandType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(CommandType::Text),
+            "image" => Ok(CommandType::Image),
+            "draw" => Ok(CommandType::Draw),
+            "admin" => Ok(CommandType::Admin),
+            "voice" => Ok(CommandType::Voice),
+            "document" => Ok(CommandType::Document),
+            _ => Err(anyhow!("Unknown command type: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseStatus {
+    Success,
+    Error,
+}
+
+impl ResponseStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResponseStatus::Success => "success",
+            ResponseStatus::Error => "error",
+        }
+    }
+}
+
+impl<T> From<&Result<T>> for ResponseStatus {
+    fn from(result: &Result<T>) -> Self {
+        if result.is_ok() {
+            ResponseStatus::Success
+        } else {
+            ResponseStatus::Error
+        }
+    }
+}
+
+impl FromStr for ResponseStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "success" => Ok(ResponseStatus::Success),
+            "error" => Ok(ResponseStatus::Error),
+            _ => Err(anyhow!("Unknown response status: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Constructor)]
+pub struct SecurityAuditLog {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub command_type: CommandType,
+    pub timestamp: NaiveDateTime,
+    pub response_status: ResponseStatus,
+}
+
+#[derive(Debug)]
+pub struct DynamoAuditLog {
+    client: aws_sdk_dynamodb::Client,
+    table_name: String,
+    ttl: chrono::Duration,
+}
+
+impl DynamoAuditLog {
+    pub fn new(
+        client: aws_sdk_dynamodb::Client,
+        table_name: String,
+        ttl_days: i64,
+    ) -> Self {
+        DynamoAuditLog {
+            client,
+            table_name,
+            ttl: chrono::Duration::days(ttl_days),
+        }
+    }
+}
+
+impl AuditLogStore for DynamoAuditLog {
+    async fn record(&self, entry: SecurityAuditLog) -> Result<()> {
+        let timestamp = entry.timestamp.and_utc();
+        let expires_at = timestamp + self.ttl;
+
+        self.client
+            .put_item()
+            .table_name(&self.table_name)
+            .item("user_id", AttributeValue::N(entry.user_id.to_string()))
+            .item(
+                "timestamp",
+                AttributeValue::N(timestamp.timestamp_millis().to_string()),
+            )
+            .item("chat_id", AttributeValue::N(entry.chat_id.to_string()))
+            .item(
+                "command_type",
+                AttributeValue::S(entry.command_type.as_str().to_string()),
+            )
+            .item(
+                "response_status",
+                AttributeValue::S(entry.response_status.as_str().to_string()),
+            )
+            .item(
+                "expires_at",
+                AttributeValue::N(expires_at.timestamp().to_string()),
+            )
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn recent(
+        &self,
+        user_id: i64,
+        limit: i32,
+    ) -> Result<Vec<SecurityAuditLog>> {
+        let output = self
+            .client
+            .query()
+            .table_name(&self.table_name)
+            .key_condition_expression("user_id = :user_id")
+            .expression_attribute_values(
+                ":user_id",
+                AttributeValue::N(user_id.to_string()),
+            )
+            .scan_index_forward(false)
+            .limit(limit)
+            .send()
+            .await?;
+
+        output.items().iter().map(parse_item).collect()
+    }
+}
+
+fn parse_item(
+    item: &HashMap<String, AttributeValue>,
+) -> Result<SecurityAuditLog> {
+    let number = |name: &str| -> Result<i64> {
+        let value = item
+            .get(name)
+            .and_then(|value| value.as_n().ok())
+            .ok_or_else(|| anyhow!("Audit log item has no {name}"))?;
+        Ok(value.parse()?)
+    };
+    let string = |name: &str| -> Result<&str> {
+        item.get(name)
+            .and_then(|value| value.as_s().ok())
+            .map(String::as_str)
+            .ok_or_else(|| anyhow!("Audit log item has no {name}"))
+    };
+
+    let timestamp =
+        DateTime::<Utc>::from_timestamp_millis(number("timestamp")?)
+            .ok_or_else(|| anyhow!("Bad audit log timestamp"))?
+            .naive_utc();
+
+    Ok(SecurityAuditLog::new(
+        number("user_id")?,
+        number("chat_id")?,
+        string("command_type")?.parse()?,
+        timestamp,
+        string("response_status")?.parse()?,
+    ))
+}
+
+#[cfg_attr(test, automock)]
+pub trait AuditLogStore {
+    async fn record(&self, entry: SecurityAuditLog) -> Result<()>;
+    async fn recent(
+        &self,
+        user_id: i64,
+        limit: i32,
+    ) -> Result<Vec<SecurityAuditLog>>;
+}
@@ -0,0 +1,65 @@
+use anyhow::Result;
+use aws_sdk_dynamodb::types::AttributeValue;
+
+/// Users blocked with `/block`, so they stay blocked after a cold start.
+#[derive(Debug)]
+pub struct DynamoBlockList {
+    client: aws_sdk_dynamodb::Client,
+    table_name: String,
+}
+
+impl DynamoBlockList {
+    pub fn new(client: aws_sdk_dynamodb::Client, table_name: String) -> Self {
+        DynamoBlockList { client, table_name }
+    }
+
+    pub async fn load(&self) -> Result<Vec<i64>> {
+        let mut user_ids = Vec::new();
+        let mut start_key = None;
+
+        loop {
+            let output = self
+                .client
+                .scan()
+                .table_name(&self.table_name)
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await?;
+
+            for item in output.items() {
+                if let Some(user_id) =
+                    item.get("user_id").and_then(|value| value.as_n().ok())
+                {
+                    user_ids.push(user_id.parse()?);
+                }
+            }
+
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Ok(user_ids);
+            }
+        }
+    }
+
+    pub async fn block(&self, user_id: i64) -> Result<()> {
+        self.client
+            .put_item()
+            .table_name(&self.table_name)
+            .item("user_id", AttributeValue::N(user_id.to_string()))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn unblock(&self, user_id: i64) -> Result<()> {
+        self.client
+            .delete_item()
+            .table_name(&self.table_name)
+            .key("user_id", AttributeValue::N(user_id.to_string()))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+/// Formatting span of escaped MarkdownV2 text that can be left open at the
+/// end of a chunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Span {
+    /// A ``` block with the rest of its opening line, e.g. the language.
+    CodeBlock(String),
+    InlineCode,
+    Bold,
+}
+
+impl Span {
+    fn opening(&self) -> String {
+        match self {
+            Span::CodeBlock(line) => format!("```{line}\n"),
+            Span::InlineCode => "`".to_string(),
+            Span::Bold => "**".to_string(),
+        }
+    }
+
+    fn closing(&self) -> &'static str {
+        match self {
+            Span::CodeBlock(_) => "```",
+            Span::InlineCode => "`",
+            Span::Bold => "**",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SplitPoint {
+    end: usize,
+    spans: Vec<Span>,
+}
+
+/// Splits escaped MarkdownV2 text into chunks of at most `max_size` bytes.
+/// A chunk ends at a blank line or after a code block when there is one,
+/// otherwise at a space or anywhere. The spans left open are closed at the
+/// end of the chunk and reopened in the next one.
+#[derive(Debug, Clone, Copy)]
+pub struct MarkdownV2ChunkSplitter {
+    max_size: usize,
+}
+
+impl MarkdownV2ChunkSplitter {
+    pub fn new(max_size: usize) -> Self {
+        MarkdownV2ChunkSplitter { max_size }
+    }
+
+    pub fn split(&self, text: &str) -> Vec<String> {
+        let mut chunks = Vec::new();
+        let mut spans = Vec::new();
+        let mut rest = text;
+
+        while !rest.is_empty() {
+            let opening: String = spans.iter().map(Span::opening).collect();
+            if opening.len() + rest.len() <= self.max_size {
+                chunks.push(opening + rest);
+                break;
+            }
+
+            let point = self.split_point(rest, &spans, opening.len());
+            let mut chunk = opening;
+            chunk.push_str(&rest[..point.end]);
+            chunk.extend(point.spans.iter().rev().map(Span::closing));
+            chunks.push(chunk);
+
+            rest = &rest[point.end..];
+            spans = point.spans;
+        }
+
+        chunks
+    }
+
+    fn split_point(
+        &self,
+        text: &str,
+        spans: &[Span],
+        opening_len: usize,
+    ) -> SplitPoint {
+        let mut spans = spans.to_vec();
+        let mut boundary = None;
+        let mut space = None;
+        let mut any = None;
+        let mut end = 0;
+
+        while end < text.len() {
+            let (len, closed_block) = next_token(&text[end..], &mut spans);
+            end += len;
+
+            if opening_len + end + closing_len(&spans) > self.max_size {
+                // The chunk has to make progress even if a token is too big.
+                if any.is_none() {
+                    any = Some(SplitPoint {
+                        end,
+                        spans: spans.clone(),
+                    });
+                }
+                break;
+            }
+
+            let chunk = &text[..end];
+            if spans.is_empty() && (closed_block || chunk.ends_with("\n\n")) {
+                boundary = Some(end);
+            } else if chunk.ends_with([' ', '\n']) {
+                space = Some(SplitPoint {
+                    end,
+                    spans: spans.clone(),
+                });
+            }
+            any = Some(SplitPoint {
+                end,
+                spans: spans.clone(),
+            });
+        }
+
+        if let Some(end) = boundary {
+            return SplitPoint {
+                end,
+                spans: Vec::new(),
+            };
+        }
+
+        space.or(any).unwrap_or(SplitPoint {
+            end: text.len(),
+            spans,
+        })
+    }
+}
+
+fn closing_len(spans: &[Span]) -> usize {
+    spans.iter().map(|span| span.closing().len()).sum()
+}
+
+/// Length of the token at the start of `text` and whether it closed a code
+/// block. Escape sequences are a single token, so they are never split.
+fn next_token(text: &str, spans: &mut Vec<Span>) -> (usize, bool) {
+    let char_len = |text: &str| text.chars().next().map_or(0, char::len_utf8);
+
+    if let Some(escaped) = text.strip_prefix('\\') {
+        return (1 + char_len(escaped), false);
+    }
+
+    match spans.last() {
+        Some(Span::CodeBlock(_)) if text.starts_with("```") => {
+            spans.pop();
+            return (3, true);
+        }
+        Some(Span::CodeBlock(_)) => return (char_len(text), false),
+        Some(Span::InlineCode) if text.starts_with('`') => {
+            spans.pop();
+            return (1, false);
+        }
+        Some(Span::InlineCode) => return (char_len(text), false),
+        _ => {}
+    }
+
+    if let Some(block) = text.strip_prefix("```") {
+        // The opening line goes with the opening, it is reopened with it.
+        let line = block.split('\n').next().unwrap_or_default();
+        let newline = usize::from(block.len() > line.len());
+        spans.push(Span::CodeBlock(line.to_string()));
+        return (3 + line.len() + newline, false);
+    }
+
+    if text.starts_with('`') {
+        spans.push(Span::InlineCode);
+        return (1, false);
+    }
+
+    if text.starts_with("**") {
+        if spans.last() == Some(&Span::Bold) {
+            spans.pop();
+        } else {
+            spans.push(Span::Bold);
+        }
+        return (2, false);
+    }
+
+    (char_len(text), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk_splitter::MarkdownV2ChunkSplitter;
+
+    #[test]
+    fn test_split_at_blank_line() {
+        let text = format!("{}\n\n{}", "a ".repeat(10), "b ".repeat(10));
+
+        let chunks = MarkdownV2ChunkSplitter::new(30).split(&text);
+
+        assert_eq!(
+            chunks,
+            [format!("{}\n\n", "a ".repeat(10)), "b ".repeat(10)]
+        );
+    }
+
+    #[test]
+    fn test_split_after_code_block() {
+        let text = "Code:\n```rust\nlet a = 1;\n``` and some more text";
+
+        let chunks = MarkdownV2ChunkSplitter::new(32).split(text);
+
+        assert_eq!(chunks[0], "Code:\n```rust\nlet a = 1;\n```");
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn test_split_reopens_spans() {
+        let text = format!("```rust\n{}```", "let a = 1;\n".repeat(4));
+
+        let chunks = MarkdownV2ChunkSplitter::new(40).split(&text);
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.len() <= 40);
+            assert!(chunk.starts_with("```rust\n"));
+            assert!(chunk.ends_with("```"));
+        }
+
+        let chunks =
+            MarkdownV2ChunkSplitter::new(12).split("**bold `code` text**");
+
+        assert_eq!(chunks, ["**bold **", "**`code` **", "**text**"]);
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicI64, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::{info, warn};
+
+const LATENCY_WINDOW: usize = 10;
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_OPEN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests pass.
+    Closed,
+    /// Too many consecutive failures, requests fail fast.
+    Open,
+    /// The open timeout passed, a single trial request may pass.
+    HalfOpen,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HealthState {
+    pub avg_latency_ms: f64,
+    pub is_degraded: bool,
+}
+
+#[derive(Debug, Default)]
+struct Latencies {
+    samples: VecDeque<f64>,
+    state: HealthState,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    degraded_threshold_ms: f64,
+    latencies: Mutex<Latencies>,
+    failure_threshold: u32,
+    open_timeout_ms: i64,
+    consecutive_failures: AtomicU32,
+    // Unix time in milliseconds.
+    last_failure_at: AtomicI64,
+}
+
+impl CircuitBreaker {
+    pub fn new(degraded_threshold: Duration) -> Self {
+        CircuitBreaker {
+            degraded_threshold_ms: degraded_threshold.as_secs_f64() * 1000.0,
+            latencies: Mutex::default(),
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            open_timeout_ms: DEFAULT_OPEN_TIMEOUT.as_millis() as i64,
+            consecutive_failures: AtomicU32::new(0),
+            last_failure_at: AtomicI64::new(0),
+        }
+    }
+
+    /// Opens the circuit after `threshold` consecutive failures, each within
+    /// `open_timeout` of the previous one, for `open_timeout`.
+    pub fn set_failure_threshold(
+        &mut self,
+        threshold: u32,
+        open_timeout: Duration,
+    ) {
+        self.failure_threshold = threshold;
+        self.open_timeout_ms = open_timeout.as_millis() as i64;
+    }
+
+    /// Returns false while the circuit is open. Only one caller gets the
+    /// trial request of a half-open circuit.
+    pub fn allow_request(&self) -> bool {
+        let now = now_millis();
+        match self.state_at(now) {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                // Moving the last failure reopens the circuit for the rest.
+                let last_failure_at =
+                    self.last_failure_at.load(Ordering::Acquire);
+                self.last_failure_at
+                    .compare_exchange(
+                        last_failure_at,
+                        now,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    )
+                    .is_ok()
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let failures = self.consecutive_failures.swap(0, Ordering::AcqRel);
+        if failures >= self.failure_threshold {
+            info!("GPT API circuit closed");
+        }
+    }
+
+    pub fn record_failure(&self) {
+        self.record_failure_at(now_millis());
+    }
+
+    fn record_failure_at(&self, now: i64) {
+        let last_failure_at = self.last_failure_at.swap(now, Ordering::AcqRel);
+        let failures = if now - last_failure_at > self.open_timeout_ms {
+            self.consecutive_failures.store(1, Ordering::Release);
+            1
+        } else {
+            self.consecutive_failures.fetch_add(1, Ordering::AcqRel) + 1
+        };
+
+        if failures == self.failure_threshold {
+            warn!(failures, "GPT API circuit opened");
+        }
+    }
+
+    fn state_at(&self, now: i64) -> CircuitState {
+        if self.consecutive_failures.load(Ordering::Acquire)
+            < self.failure_threshold
+        {
+            return CircuitState::Closed;
+        }
+
+        let last_failure_at = self.last_failure_at.load(Ordering::Acquire);
+        if now - last_failure_at < self.open_timeout_ms {
+            CircuitState::Open
+        } else {
+            CircuitState::HalfOpen
+        }
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock().unwrap();
+
+        if latencies.samples.len() == LATENCY_WINDOW {
+            latencies.samples.pop_front();
+        }
+        latencies.samples.push_back(latency.as_secs_f64() * 1000.0);
+
+        let avg_latency_ms = latencies.samples.iter().sum::<f64>()
+            / latencies.samples.len() as f64;
+        let is_degraded = avg_latency_ms > self.degraded_threshold_ms;
+
+        if is_degraded != latencies.state.is_degraded {
+            if is_degraded {
+                warn!(avg_latency_ms, "GPT API is degraded");
+            } else {
+                info!(avg_latency_ms, "GPT API recovered");
+            }
+        }
+
+        latencies.state = HealthState {
+            avg_latency_ms,
+            is_degraded,
+        };
+    }
+
+    pub fn health(&self) -> HealthState {
+        self.latencies.lock().unwrap().state
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.health().is_degraded
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::circuit_breaker::{now_millis, CircuitBreaker, CircuitState};
+
+    #[test]
+    fn test_degrades_and_recovers() {
+        let circuit_breaker = CircuitBreaker::new(Duration::from_millis(100));
+
+        circuit_breaker.record_latency(Duration::from_millis(50));
+        assert!(!circuit_breaker.is_degraded());
+
+        for _ in 0..10 {
+            circuit_breaker.record_latency(Duration::from_millis(300));
+        }
+        assert!(circuit_breaker.is_degraded());
+        assert_eq!(circuit_breaker.health().avg_latency_ms, 300.0);
+
+        for _ in 0..10 {
+            circuit_breaker.record_latency(Duration::from_millis(10));
+        }
+        assert!(!circuit_breaker.is_degraded());
+        assert_eq!(circuit_breaker.health().avg_latency_ms, 10.0);
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let mut circuit_breaker = CircuitBreaker::new(Duration::from_secs(1));
+        circuit_breaker.set_failure_threshold(3, Duration::from_secs(30));
+
+        circuit_breaker.record_failure_at(1_000);
+        circuit_breaker.record_failure_at(2_000);
+        assert_eq!(circuit_breaker.state_at(2_000), CircuitState::Closed);
+
+        // A failure long after the previous ones starts a new series.
+        circuit_breaker.record_failure_at(40_000);
+        assert_eq!(circuit_breaker.state_at(40_000), CircuitState::Closed);
+
+        circuit_breaker.record_failure_at(41_000);
+        circuit_breaker.record_failure_at(42_000);
+        assert_eq!(circuit_breaker.state_at(50_000), CircuitState::Open);
+        assert_eq!(circuit_breaker.state_at(72_000), CircuitState::HalfOpen);
+
+        circuit_breaker.record_success();
+        assert_eq!(circuit_breaker.state_at(72_000), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_half_open_allows_single_request() {
+        let mut circuit_breaker = CircuitBreaker::new(Duration::from_secs(1));
+        circuit_breaker.set_failure_threshold(1, Duration::from_millis(50));
+
+        circuit_breaker.record_failure();
+        assert!(!circuit_breaker.allow_request());
+
+        std::thread::sleep(Duration::from_millis(60));
+
+        assert_eq!(
+            circuit_breaker.state_at(now_millis()),
+            CircuitState::HalfOpen
+        );
+        assert!(circuit_breaker.allow_request());
+        assert!(!circuit_breaker.allow_request());
+    }
+}
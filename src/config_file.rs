@@ -0,0 +1,101 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use aws_config::BehaviorVersion;
+use serde_json::{Map, Value};
+
+const S3_SCHEME: &str = "s3://";
+
+/// Loads a JSON object whose keys are env var names into the environment.
+/// Like `dotenv`, variables that are already set take precedence.
+pub fn load_config_file(path: &Path) -> Result<()> {
+    let json = std::fs::read_to_string(path)?;
+    let config: Map<String, Value> = serde_json::from_str(&json)?;
+
+    for (name, value) in config {
+        if std::env::var_os(&name).is_none() {
+            std::env::set_var(&name, env_value(&name, &value)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns `value` itself or, for an `s3://bucket/key` URI, the content of
+/// that object. Lets rules outgrow the 4KB Lambda env var limit.
+pub async fn resolve_s3_value(value: String) -> Result<String> {
+    if value.starts_with(S3_SCHEME) {
+        load_s3_content(&value).await
+    } else {
+        Ok(value)
+    }
+}
+
+pub async fn load_s3_content(uri: &str) -> Result<String> {
+    let (bucket, key) = parse_s3_uri(uri)?;
+    let aws_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let object = aws_sdk_s3::Client::new(&aws_config)
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await?;
+    let bytes = object.body.collect().await?.into_bytes();
+
+    Ok(String::from_utf8(bytes.to_vec())?)
+}
+
+fn parse_s3_uri(uri: &str) -> Result<(&str, &str)> {
+    uri.strip_prefix(S3_SCHEME)
+        .and_then(|path| path.split_once('/'))
+        .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+        .ok_or_else(|| anyhow!("Bad S3 URI {uri}, expected s3://bucket/key"))
+}
+
+fn env_value(name: &str, value: &Value) -> Result<String> {
+    let value = match value {
+        Value::String(text) => text.clone(),
+        Value::Number(number) => number.to_string(),
+        Value::Bool(flag) => flag.to_string(),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| env_value(name, item))
+            .collect::<Result<Vec<_>>>()?
+            .join(","),
+        Value::Object(_) => serde_json::to_string(value)?,
+        Value::Null => bail!("Config value {name} is null"),
+    };
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::config_file::{env_value, parse_s3_uri};
+
+    #[test]
+    fn test_env_value() {
+        assert_eq!(env_value("A", &json!("text")).unwrap(), "text");
+        assert_eq!(env_value("A", &json!(20)).unwrap(), "20");
+        assert_eq!(env_value("A", &json!(true)).unwrap(), "true");
+        assert_eq!(env_value("A", &json!([1, -2, 3])).unwrap(), "1,-2,3");
+        assert_eq!(
+            env_value("A", &json!({"Sam": "Bob"})).unwrap(),
+            r#"{"Sam":"Bob"}"#
+        );
+        assert!(env_value("A", &json!(null)).is_err());
+    }
+
+    #[test]
+    fn test_parse_s3_uri() {
+        assert_eq!(
+            parse_s3_uri("s3://bucket/rules/bot.txt").unwrap(),
+            ("bucket", "rules/bot.txt")
+        );
+        assert!(parse_s3_uri("s3://bucket").is_err());
+        assert!(parse_s3_uri("s3:///key").is_err());
+        assert!(parse_s3_uri("https://bucket/key").is_err());
+    }
+}
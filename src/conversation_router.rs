@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+use crate::message_processor::{fnv_step, FNV_OFFSET_BASIS};
+use crate::tg_client::Message;
+
+/// Conversation ids of threads are below every Telegram user and chat id.
+const THREAD_ID_BASE: i64 = i64::MIN;
+// A reply to an older answer starts a new thread.
+const THREAD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Keeps reply chains in regular groups apart. A reply to an answer of the
+/// bot continues the thread of the user message that answer replied to,
+/// any other message continues the user's own conversation.
+#[derive(Debug, Default)]
+pub struct ConversationRouter {
+    /// The thread root of each answer the bot sent, by chat and answer id.
+    roots: DashMap<(i64, i32), (i32, Instant)>,
+}
+
+impl ConversationRouter {
+    /// The root message of the thread of a message replying to
+    /// `reply_to_message`, if that is the bot's. An answer sent before a
+    /// cold start is its own root.
+    pub fn thread_root(
+        &self,
+        chat_id: i64,
+        reply_to_message: Option<&Message>,
+    ) -> Option<i32> {
+        let reply = reply_to_message.filter(|reply| reply.from.is_bot)?;
+
+        Some(
+            self.roots
+                .get(&(chat_id, reply.message_id))
+                .map_or(reply.message_id, |root| root.0),
+        )
+    }
+
+    /// Remembers that the answer to `message_id` continues its thread, or
+    /// starts one at `message_id` if it had none.
+    pub fn record_answer(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        thread_root: Option<i32>,
+        answer_id: i32,
+    ) {
+        let now = Instant::now();
+        self.roots.retain(|_, (_, sent_at)| {
+            now.duration_since(*sent_at) < THREAD_TTL
+        });
+
+        let root = thread_root.unwrap_or(message_id);
+        self.roots.insert((chat_id, answer_id), (root, now));
+    }
+}
+
+/// The key of the conversation history: the user's own one outside of
+/// threads, else one per `(chat_id, thread_root)` shared by everyone
+/// replying in the thread.
+pub fn conversation_id(
+    user_id: i64,
+    chat_id: i64,
+    thread_root: Option<i32>,
+) -> i64 {
+    let Some(thread_root) = thread_root else {
+        return user_id;
+    };
+
+    let hash = chat_id
+        .to_le_bytes()
+        .into_iter()
+        .chain(thread_root.to_le_bytes())
+        .fold(FNV_OFFSET_BASIS, fnv_step);
+
+    THREAD_ID_BASE + (hash >> 2) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conversation_router::{conversation_id, ConversationRouter};
+    use crate::tg_client::{Message, User};
+
+    fn reply((message_id, is_bot): (i32, bool)) -> Message {
+        Message {
+            message_id,
+            from: User {
+                is_bot,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_thread_root() {
+        let router = ConversationRouter::default();
+        let thread_root = |reply_to: Option<(i32, bool)>| {
+            router.thread_root(-100, reply_to.map(reply).as_ref())
+        };
+
+        assert_eq!(thread_root(None), None);
+        router.record_answer(-100, 10, None, 11);
+
+        assert_eq!(thread_root(Some((11, true))), Some(10));
+        router.record_answer(-100, 12, Some(10), 13);
+
+        assert_eq!(thread_root(Some((13, true))), Some(10));
+        assert_eq!(thread_root(Some((12, false))), None);
+        assert_eq!(thread_root(Some((99, true))), Some(99));
+    }
+
+    #[test]
+    fn test_conversation_id() {
+        assert_eq!(conversation_id(1, -100, None), 1);
+
+        let thread = conversation_id(1, -100, Some(10));
+        assert_eq!(conversation_id(2, -100, Some(10)), thread);
+        assert_ne!(conversation_id(1, -100, Some(11)), thread);
+        assert!(thread < -(1 << 60));
+    }
+}
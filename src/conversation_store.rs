@@ -0,0 +1,246 @@
+use std::future::Future;
+
+use anyhow::{anyhow, bail, Result};
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::types::AttributeValue;
+use dashmap::DashMap;
+#[cfg(test)]
+use mockall::automock;
+
+use crate::gpt_client::{Message, StoredMessage};
+
+/// An item is at most 400KB and holds the public and the private history.
+const MAX_HISTORY_BYTES: usize = 180_000;
+
+/// Keeps the history of every user in DynamoDB so that conversations
+/// survive cold starts. Items are keyed by `user_id`, the history is a JSON
+/// list in `attribute`, so several clients can share a table.
+#[derive(Debug)]
+pub struct DynamoConversationStore {
+    client: aws_sdk_dynamodb::Client,
+    table_name: String,
+    attribute: &'static str,
+    version_attribute: String,
+    /// The version each history had when it was loaded, so a save doesn't
+    /// overwrite what another Lambda saved in between.
+    versions: DashMap<i64, u64>,
+}
+
+impl DynamoConversationStore {
+    pub fn new(
+        client: aws_sdk_dynamodb::Client,
+        table_name: String,
+        attribute: &'static str,
+    ) -> Self {
+        DynamoConversationStore {
+            client,
+            table_name,
+            attribute,
+            version_attribute: format!("{attribute}_version"),
+            versions: DashMap::new(),
+        }
+    }
+}
+
+impl ConversationStore for DynamoConversationStore {
+    async fn load(&self, user_id: i64) -> Result<Vec<StoredMessage>> {
+        let output = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key("user_id", AttributeValue::N(user_id.to_string()))
+            .projection_expression("#messages, #version")
+            .expression_attribute_names("#messages", self.attribute)
+            .expression_attribute_names("#version", &self.version_attribute)
+            .send()
+            .await?;
+
+        let version = output
+            .item()
+            .and_then(|item| item.get(&self.version_attribute))
+            .and_then(|version| version.as_n().ok())
+            .map(|version| version.parse())
+            .transpose()?
+            .unwrap_or_default();
+        self.versions.insert(user_id, version);
+
+        let Some(messages) =
+            output.item().and_then(|item| item.get(self.attribute))
+        else {
+            return Ok(Vec::new());
+        };
+
+        let messages = messages
+            .as_s()
+            .map_err(|_| anyhow!("Conversation history is not a string"))?;
+
+        Ok(serde_json::from_str(messages)?)
+    }
+
+    async fn save(
+        &self,
+        user_id: i64,
+        mut messages: Vec<StoredMessage>,
+    ) -> Result<()> {
+        let json = fit_bytes(&mut messages, MAX_HISTORY_BYTES)?;
+        let request = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key("user_id", AttributeValue::N(user_id.to_string()))
+            .update_expression("SET #messages = :messages ADD #version :one")
+            .expression_attribute_names("#messages", self.attribute)
+            .expression_attribute_names("#version", &self.version_attribute)
+            .expression_attribute_values(":messages", AttributeValue::S(json))
+            .expression_attribute_values(
+                ":one",
+                AttributeValue::N("1".to_string()),
+            );
+        // A save without a load, like a reset, replaces whatever is there.
+        let request = match self.versions.remove(&user_id) {
+            Some((_, 0)) => {
+                request.condition_expression("attribute_not_exists(#version)")
+            }
+            Some((_, version)) => request
+                .condition_expression("#version = :version")
+                .expression_attribute_values(
+                    ":version",
+                    AttributeValue::N(version.to_string()),
+                ),
+            None => request,
+        };
+
+        match request.send().await {
+            Err(SdkError::ServiceError(error))
+                if error.err().is_conditional_check_failed_exception() =>
+            {
+                bail!("History of user {user_id} was changed concurrently")
+            }
+            result => {
+                result?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Drops the oldest non-System messages until the JSON of `messages` fits
+/// `max_bytes`, and returns it.
+fn fit_bytes(
+    messages: &mut Vec<StoredMessage>,
+    max_bytes: usize,
+) -> Result<String> {
+    loop {
+        let json = serde_json::to_string(messages)?;
+        if json.len() <= max_bytes || messages.is_empty() {
+            return Ok(json);
+        }
+
+        let oldest = messages
+            .iter()
+            .position(|stored| !matches!(stored.message, Message::System(_)))
+            .unwrap_or(0);
+        messages.remove(oldest);
+    }
+}
+
+/// Keeps the history in the Lambda instance only, it's lost on cold start.
+#[derive(Debug, Default)]
+pub struct InMemoryConversationStore {
+    conversations: DashMap<i64, Vec<StoredMessage>>,
+}
+
+impl ConversationStore for InMemoryConversationStore {
+    async fn load(&self, user_id: i64) -> Result<Vec<StoredMessage>> {
+        Ok(self
+            .conversations
+            .get(&user_id)
+            .map(|messages| messages.clone())
+            .unwrap_or_default())
+    }
+
+    async fn save(
+        &self,
+        user_id: i64,
+        messages: Vec<StoredMessage>,
+    ) -> Result<()> {
+        self.conversations.insert(user_id, messages);
+
+        Ok(())
+    }
+}
+
+// The futures are `Send`, so a streamed answer can save the history.
+#[cfg_attr(test, automock)]
+pub trait ConversationStore: Send + Sync {
+    fn load(
+        &self,
+        user_id: i64,
+    ) -> impl Future<Output = Result<Vec<StoredMessage>>> + Send;
+    fn save(
+        &self,
+        user_id: i64,
+        messages: Vec<StoredMessage>,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conversation_store::{
+        fit_bytes, ConversationStore, InMemoryConversationStore,
+    };
+    use crate::gpt_client::{Message, StoredMessage, Value};
+
+    #[tokio::test]
+    async fn test_in_memory_conversation_store() {
+        let store = InMemoryConversationStore::default();
+        let stored = |message| StoredMessage {
+            message,
+            timestamp: None,
+        };
+        let messages = vec![
+            stored(Message::User(Value::Plain("Hello".to_string().into()))),
+            stored(Message::Assistant(Value::Plain("Hi".to_string().into()))),
+        ];
+
+        store.save(1, messages).await.unwrap();
+
+        let json = serde_json::to_string(&store.load(1).await.unwrap());
+        assert_eq!(
+            json.unwrap(),
+            r#"[{"role":"user","content":"Hello"},{"role":"assistant","content":"Hi"}]"#
+        );
+        assert!(store.load(2).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fit_bytes() {
+        let stored = |message| StoredMessage {
+            message,
+            timestamp: None,
+        };
+        let mut messages = vec![
+            stored(Message::System(Value::Plain("rules".to_string().into()))),
+            stored(Message::User(Value::Plain("Hello".to_string().into()))),
+            stored(Message::Assistant(Value::Plain("Hi".to_string().into()))),
+        ];
+
+        let json = fit_bytes(&mut messages, 80).unwrap();
+
+        assert_eq!(
+            json,
+            r#"[{"role":"system","content":"rules"},{"role":"assistant","content":"Hi"}]"#
+        );
+        assert_eq!(messages.len(), 2);
+    }
+
+    // The DynamoDB store keeps the history as this JSON.
+    #[test]
+    fn test_message_json_round_trip() {
+        let json = r#"[{"role":"system","content":"rules"},{"role":"user","content":[{"type":"text","text":"What?"},{"type":"image_url","image_url":{"url":"https://a/b.png"}}],"timestamp":"2024-01-02T03:04:05"}]"#;
+
+        let messages: Vec<StoredMessage> = serde_json::from_str(json).unwrap();
+
+        assert_eq!(serde_json::to_string(&messages).unwrap(), json);
+    }
+}
@@ -1,8 +1,158 @@
+use lambda_http::http::StatusCode;
 use lambda_http::Request;
 #[cfg(test)]
 use mockall::automock;
+use thiserror::Error;
+
+use crate::message_processor::RequestError;
+
+/// Kind of a failure, logged for log-based alerting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    MissingMessage,
+    StaleMessage,
+    UnauthorizedChat,
+    UnauthorizedUser,
+    GptApiFailure,
+    TelegramApiFailure,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::MissingMessage => "missing_message",
+            ErrorCode::StaleMessage => "stale_message",
+            ErrorCode::UnauthorizedChat => "unauthorized_chat",
+            ErrorCode::UnauthorizedUser => "unauthorized_user",
+            ErrorCode::GptApiFailure => "gpt_api_failure",
+            ErrorCode::TelegramApiFailure => "telegram_api_failure",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Code of a failure. API failures carry it as the error context.
+pub fn error_code(error: &anyhow::Error) -> Option<ErrorCode> {
+    if let Some(error) = error.downcast_ref::<RequestError>() {
+        return Some(error.code);
+    }
+
+    error.downcast_ref::<ErrorCode>().copied()
+}
+
+/// Whether `error` becomes [`ProcessingError::Retryable`], so Telegram
+/// delivers the update again.
+pub fn is_retryable(error: &anyhow::Error) -> bool {
+    !error.is::<RequestError>()
+        && !matches!(
+            error.downcast_ref::<ProcessingError>(),
+            Some(ProcessingError::Ignorable(_) | ProcessingError::Permanent(_))
+        )
+}
 
 #[cfg_attr(test, automock)]
 pub trait EventHandler {
     async fn process_event(&self, event: &Request) -> anyhow::Result<()>;
+    async fn process_push(&self, event: &Request) -> anyhow::Result<()>;
+}
+
+/// How a failed update should be reported back to Lambda.
+#[derive(Error, Debug)]
+pub enum ProcessingError {
+    /// Expected failure, e.g. an old message or a disallowed chat.
+    #[error("{0}")]
+    Ignorable(String),
+    /// Transient failure, e.g. a GPT or TG API error, worth a retry.
+    #[error(transparent)]
+    Retryable(anyhow::Error),
+    /// Failure that a retry can't fix, e.g. a content policy violation.
+    #[error("{0}")]
+    Permanent(String),
+}
+
+impl ProcessingError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ProcessingError::Ignorable(_) | ProcessingError::Permanent(_) => {
+                StatusCode::OK
+            }
+            ProcessingError::Retryable(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<anyhow::Error> for ProcessingError {
+    fn from(error: anyhow::Error) -> Self {
+        if let Some(error) = error.downcast_ref::<RequestError>() {
+            return ProcessingError::Ignorable(error.to_string());
+        }
+
+        match error.downcast::<ProcessingError>() {
+            Ok(error) => error,
+            Err(error) => ProcessingError::Retryable(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use lambda_http::http::StatusCode;
+
+    use crate::event_handler::{
+        error_code, is_retryable, ErrorCode, ProcessingError,
+    };
+    use crate::message_processor::RequestError;
+
+    #[test]
+    fn test_processing_error_from_anyhow() {
+        let error = ProcessingError::from(anyhow!("GPT is down"));
+        assert!(matches!(error, ProcessingError::Retryable(_)));
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let error = ProcessingError::from(anyhow!(RequestError::new(
+            ErrorCode::UnauthorizedUser,
+            "No"
+        )));
+        assert!(matches!(error, ProcessingError::Ignorable(_)));
+        assert_eq!(error.to_string(), "unauthorized_user: No");
+        assert_eq!(error.status_code(), StatusCode::OK);
+
+        let error = ProcessingError::from(
+            anyhow!(ProcessingError::Permanent("Policy".to_string()))
+                .context("Failed to answer"),
+        );
+        assert!(matches!(error, ProcessingError::Permanent(_)));
+        assert_eq!(error.status_code(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(is_retryable(&anyhow!("GPT is down")));
+        assert!(!is_retryable(&anyhow!(RequestError::new(
+            ErrorCode::StaleMessage,
+            "Too old"
+        ))));
+        assert!(!is_retryable(
+            &anyhow!(ProcessingError::Permanent("Policy".to_string()))
+                .context("Failed to answer")
+        ));
+    }
+
+    #[test]
+    fn test_error_code() {
+        let error =
+            anyhow!(RequestError::new(ErrorCode::StaleMessage, "Too old"));
+        assert_eq!(error_code(&error), Some(ErrorCode::StaleMessage));
+
+        let error = anyhow!("Bad gateway").context(ErrorCode::GptApiFailure);
+        assert_eq!(error_code(&error), Some(ErrorCode::GptApiFailure));
+
+        assert_eq!(error_code(&anyhow!("Unknown")), None);
+    }
 }
@@ -0,0 +1,268 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::anthropic_client::AnthropicClient;
+use crate::conversation_store::ConversationStore;
+use crate::gpt_client::{
+    CompletionStream, DrawOptions, GtpClient, GtpInteractor, ImageContent,
+    Message, StoredMessage, Tool, ToolCall, ToolCallOrText, ToolResult,
+};
+use crate::usage_stats::UsageStats;
+
+/// The API chosen with `GPT_BACKEND`, so the bot keeps a single client type
+/// whichever one answers.
+#[derive(Debug)]
+pub enum GptBackend<Store: ConversationStore> {
+    OpenAi(GtpClient<Store>),
+    Anthropic(AnthropicClient),
+}
+
+macro_rules! dispatch {
+    ($self: ident, |$client: ident| $call: expr) => {
+        match $self {
+            GptBackend::OpenAi($client) => $call,
+            GptBackend::Anthropic($client) => $call,
+        }
+    };
+}
+
+impl<Store: ConversationStore + 'static> GtpInteractor for GptBackend<Store> {
+    async fn get_completion(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        dispatch!(self, |client| client.get_completion(user_id, prompt).await)
+    }
+
+    async fn get_completion_stream(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<CompletionStream> {
+        dispatch!(self, |client| client
+            .get_completion_stream(user_id, prompt)
+            .await)
+    }
+
+    async fn get_completion_with_tools(
+        &self,
+        user_id: i64,
+        prompt: String,
+        tools: Vec<Tool>,
+    ) -> Result<ToolCallOrText> {
+        dispatch!(self, |client| client
+            .get_completion_with_tools(user_id, prompt, tools)
+            .await)
+    }
+
+    async fn submit_tool_results(
+        &self,
+        user_id: i64,
+        prompt: String,
+        tools: Vec<Tool>,
+        tool_calls: Vec<ToolCall>,
+        results: Vec<ToolResult>,
+    ) -> Result<Arc<String>> {
+        dispatch!(self, |client| client
+            .submit_tool_results(user_id, prompt, tools, tool_calls, results)
+            .await)
+    }
+
+    async fn get_stateless_completion(
+        &self,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        dispatch!(self, |client| client.get_stateless_completion(prompt).await)
+    }
+
+    async fn summarize_history(
+        &self,
+        messages: &[Message],
+    ) -> Result<Arc<String>> {
+        dispatch!(self, |client| client.summarize_history(messages).await)
+    }
+
+    async fn get_smart_completion(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        dispatch!(self, |client| client
+            .get_smart_completion(user_id, prompt)
+            .await)
+    }
+
+    async fn get_reasoning_completion(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        dispatch!(self, |client| client
+            .get_reasoning_completion(user_id, prompt)
+            .await)
+    }
+
+    async fn get_model_completion(
+        &self,
+        user_id: i64,
+        model: &'static str,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        dispatch!(self, |client| client
+            .get_model_completion(user_id, model, prompt)
+            .await)
+    }
+
+    fn models(&self) -> [&'static str; 2] {
+        dispatch!(self, |client| client.models())
+    }
+
+    async fn get_system_prompt_completion(
+        &self,
+        user_id: i64,
+        system_prompt: &str,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        dispatch!(self, |client| client
+            .get_system_prompt_completion(user_id, system_prompt, prompt)
+            .await)
+    }
+
+    async fn get_code_review_completion(
+        &self,
+        user_id: i64,
+        rules: &str,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        dispatch!(self, |client| client
+            .get_code_review_completion(user_id, rules, prompt)
+            .await)
+    }
+
+    async fn get_image_completion(
+        &self,
+        user_id: i64,
+        text: String,
+        image_url: String,
+    ) -> Result<Arc<String>> {
+        dispatch!(self, |client| client
+            .get_image_completion(user_id, text, image_url)
+            .await)
+    }
+
+    async fn get_multi_image_completion(
+        &self,
+        user_id: i64,
+        text: String,
+        image_urls: Vec<String>,
+    ) -> Result<Arc<String>> {
+        dispatch!(self, |client| client
+            .get_multi_image_completion(user_id, text, image_urls)
+            .await)
+    }
+
+    fn supports_audio_input(&self) -> bool {
+        dispatch!(self, |client| client.supports_audio_input())
+    }
+
+    async fn get_audio_completion(
+        &self,
+        user_id: i64,
+        audio: Vec<u8>,
+        format: &'static str,
+    ) -> Result<Arc<String>> {
+        dispatch!(self, |client| client
+            .get_audio_completion(user_id, audio, format)
+            .await)
+    }
+
+    async fn get_image(
+        &self,
+        user_id: i64,
+        prompt: &str,
+        options: DrawOptions,
+    ) -> Result<ImageContent> {
+        dispatch!(self, |client| client
+            .get_image(user_id, prompt, options)
+            .await)
+    }
+
+    async fn get_image_variation(
+        &self,
+        user_id: i64,
+        image_bytes: Vec<u8>,
+        prompt: &str,
+    ) -> Result<Vec<u8>> {
+        dispatch!(self, |client| client
+            .get_image_variation(user_id, image_bytes, prompt)
+            .await)
+    }
+
+    async fn get_embedding(&self, text: &str) -> Result<Vec<f32>> {
+        dispatch!(self, |client| client.get_embedding(text).await)
+    }
+
+    async fn get_audio(&self, prompt: &str) -> Result<Vec<u8>> {
+        dispatch!(self, |client| client.get_audio(prompt).await)
+    }
+
+    async fn transcribe_audio(
+        &self,
+        audio: Vec<u8>,
+        mime_type: &str,
+    ) -> Result<Arc<String>> {
+        dispatch!(self, |client| client
+            .transcribe_audio(audio, mime_type)
+            .await)
+    }
+
+    async fn migrate_rules(
+        &self,
+        old_rules: &str,
+        new_rules: &str,
+    ) -> Result<usize> {
+        dispatch!(self, |client| client
+            .migrate_rules(old_rules, new_rules)
+            .await)
+    }
+
+    async fn list_available_models(&self) -> Result<Vec<String>> {
+        dispatch!(self, |client| client.list_available_models().await)
+    }
+
+    fn usage_stats(&self) -> Arc<UsageStats> {
+        dispatch!(self, |client| client.usage_stats())
+    }
+
+    async fn add_system_message(
+        &self,
+        user_id: i64,
+        text: String,
+    ) -> Result<()> {
+        dispatch!(self, |client| client
+            .add_system_message(user_id, text)
+            .await)
+    }
+
+    async fn reset_history(&self, user_id: i64) -> Result<()> {
+        dispatch!(self, |client| client.reset_history(user_id).await)
+    }
+
+    async fn remove_last_exchange(&self, user_id: i64) -> Result<()> {
+        dispatch!(self, |client| client.remove_last_exchange(user_id).await)
+    }
+
+    async fn get_history(&self, user_id: i64) -> Result<Vec<StoredMessage>> {
+        dispatch!(self, |client| client.get_history(user_id).await)
+    }
+
+    fn invalidate_cache(&self, user_id: i64) {
+        dispatch!(self, |client| client.invalidate_cache(user_id))
+    }
+
+    fn history_fill_ratio(&self, user_id: i64) -> f64 {
+        dispatch!(self, |client| client.history_fill_ratio(user_id))
+    }
+}
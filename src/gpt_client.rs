@@ -1,46 +1,278 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use base64::prelude::*;
+use chrono::{NaiveDateTime, Utc};
+use dashmap::DashMap;
 use derive_more::{Constructor, From};
+use eventsource_stream::Eventsource;
 use futures::lock::Mutex;
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryFutureExt};
 #[cfg(test)]
 use mockall::automock;
+use reqwest::{multipart, StatusCode};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+use crate::circuit_breaker::CircuitBreaker;
+use crate::conversation_store::{ConversationStore, InMemoryConversationStore};
+use crate::event_handler::{ErrorCode, ProcessingError};
+use crate::response_cache::ResponseCache;
+use crate::retry::{retry_with_backoff, retryable_status, status_error};
+use crate::semantic_cache::SemanticCache;
+use crate::usage_stats::UsageStats;
 
 #[derive(Debug, Serialize, Constructor)]
-struct Request<'a> {
+struct Request<'a, M = Message> {
     model: &'a str,
-    messages: &'a Vec<Message>,
-    temperature: f64,
+    messages: &'a [M],
+    /// Reasoning models reject any temperature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<&'a [Tool]>,
+}
+
+/// Functions GPT may ask to call instead of answering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    WebSearch,
+}
+
+impl Tool {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tool::WebSearch => "web_search",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Tool> {
+        [Tool::WebSearch]
+            .into_iter()
+            .find(|tool| tool.name() == name)
+    }
+}
+
+impl Serialize for Tool {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        let (description, parameters) = match self {
+            Tool::WebSearch => (
+                "Read a web page to answer with up-to-date information",
+                serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "url": {
+                            "type": "string",
+                            "description": "URL of the page to read",
+                        },
+                    },
+                    "required": ["url"],
+                }),
+            ),
+        };
+
+        serde_json::json!({
+            "type": "function",
+            "function": {
+                "name": self.name(),
+                "description": description,
+                "parameters": parameters,
+            },
+        })
+        .serialize(serializer)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON object of the arguments.
+    pub arguments: String,
+}
+
+impl ToolCall {
+    pub fn tool(&self) -> Option<Tool> {
+        Tool::from_name(&self.function.name)
+    }
+
+    pub fn arguments<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_str(&self.function.arguments)?)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebSearchArguments {
+    pub url: String,
+}
+
+/// Output of a tool call, which is sent back to GPT.
+#[derive(Debug, Clone, PartialEq, Constructor)]
+pub struct ToolResult {
+    tool_call_id: String,
+    content: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToolCallOrText {
+    Text(Arc<String>),
+    ToolCalls(Vec<ToolCall>),
 }
 
-#[derive(Debug, Serialize, Clone)]
+/// Messages of a request answering tool calls. The calls and their results
+/// are never stored in the history.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum ToolMessage<'a> {
+    History(&'a Message),
+    Calls {
+        role: &'static str,
+        tool_calls: &'a [ToolCall],
+    },
+    Result {
+        role: &'static str,
+        tool_call_id: &'a str,
+        content: &'a str,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "role", content = "content", rename_all = "snake_case")]
-enum Message {
+pub enum Message {
     User(Value),
     System(Value),
     Assistant(Value),
 }
 
+/// A history message with the time it was added. Histories saved before
+/// the timestamps were added have none.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredMessage {
+    #[serde(flatten)]
+    pub message: Message,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<NaiveDateTime>,
+}
+
+impl From<Message> for StoredMessage {
+    fn from(message: Message) -> Self {
+        StoredMessage {
+            message,
+            timestamp: Some(Utc::now().naive_utc()),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Constructor, From, Clone)]
-struct Url {
+pub struct Url {
     url: Arc<String>,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AudioInput {
+    data: String,
+    format: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
-enum Content {
+pub enum Content {
     Text { text: Arc<String> },
     ImageUrl { image_url: Url },
+    InputAudio { input_audio: AudioInput },
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
-enum Value {
+pub enum Value {
     Plain(Arc<String>),
     Complex(Vec<Content>),
 }
 
+impl Value {
+    pub(crate) fn text(&self) -> &str {
+        match self {
+            Value::Plain(text) => text,
+            Value::Complex(content) => content
+                .iter()
+                .find_map(|content| match content {
+                    Content::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    // Roughly four characters per token, images are billed flat.
+    fn estimate_tokens(&self) -> usize {
+        match self {
+            Value::Plain(text) => text.chars().count() / 4 + 1,
+            Value::Complex(content) => content
+                .iter()
+                .map(|content| match content {
+                    Content::Text { text } => text.chars().count() / 4 + 1,
+                    Content::ImageUrl { .. } => IMAGE_TOKENS,
+                    Content::InputAudio { input_audio } => {
+                        input_audio.data.len() / 4
+                    }
+                })
+                .sum(),
+        }
+    }
+}
+
+impl Message {
+    pub(crate) fn value(&self) -> &Value {
+        match self {
+            Message::User(value)
+            | Message::System(value)
+            | Message::Assistant(value) => value,
+        }
+    }
+
+    /// The message with its images replaced by a placeholder. Telegram file
+    /// URLs carry the bot token, so they must not be stored or exported.
+    pub fn without_images(&self) -> Message {
+        let value = match self.value() {
+            Value::Complex(content) => Value::Complex(
+                content
+                    .iter()
+                    .map(|content| match content {
+                        Content::ImageUrl { .. } => Content::Text {
+                            text: IMAGE_PLACEHOLDER.to_string().into(),
+                        },
+                        content => content.clone(),
+                    })
+                    .collect(),
+            ),
+            value => value.clone(),
+        };
+
+        match self {
+            Message::User(_) => Message::User(value),
+            Message::System(_) => Message::System(value),
+            Message::Assistant(_) => Message::Assistant(value),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Response {
     id: String,
@@ -61,7 +293,38 @@ struct Choice {
 #[derive(Debug, Serialize, Deserialize)]
 struct ResponseMessage {
     role: String,
-    content: String,
+    // Empty when GPT calls tools.
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChunk {
+    choices: Vec<StreamChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    delta: Delta,
+}
+
+#[derive(Debug, Deserialize)]
+struct Delta {
+    content: Option<String>,
+}
+
+/// Pieces of the answer as they are generated.
+pub type CompletionStream = BoxStream<'static, Result<Arc<String>>>;
+
+#[derive(Debug, Error)]
+pub enum GptError {
+    /// Some safety filters answer with no content at all.
+    #[error("GPT returned an empty answer")]
+    EmptyResponse,
+    /// A DALL-E link expires after an hour.
+    #[error("GPT could not download the image")]
+    ImageUnavailable,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -72,15 +335,103 @@ struct Usage {
 }
 
 #[derive(Debug)]
-pub struct GtpClient {
+pub struct GtpClient<Store: ConversationStore> {
     token: &'static str,
     model: &'static str,
     voice: &'static str,
     smart_model: &'static str,
+    audio_input_model: Option<&'static str>,
+    reasoning_model: Option<&'static str>,
+    max_history_tokens: usize,
+    temperatures: Temperatures,
+    max_attempts: u32,
+    retry_base_delay: Duration,
     http_client: reqwest::Client,
     chat_url: &'static str,
     dalle_url: &'static str,
-    messages: Mutex<Vec<Message>>,
+    models_url: &'static str,
+    base_rules: Mutex<Arc<String>>,
+    history: History<Store>,
+    circuit_breaker: Arc<CircuitBreaker>,
+    usage_stats: Arc<UsageStats>,
+    response_cache: Option<ResponseCache>,
+    semantic_cache: Option<SemanticCache>,
+    /// Summarizes the older half of a history close to the limit instead
+    /// of dropping it.
+    history_compression: bool,
+    history_state: DashMap<i64, HistoryState>,
+}
+
+#[derive(Debug, Default)]
+struct HistoryState {
+    /// Share of `max_history_tokens` the history used before its last
+    /// pruning.
+    fill: f64,
+    /// When summarizing the history last failed, so GPT isn't asked on
+    /// every message.
+    summary_failed_at: Option<Instant>,
+}
+
+/// Where the history lives. Cheap to clone, so a completion stream can save
+/// the answer once it ends.
+#[derive(Debug)]
+struct History<Store> {
+    store: Option<Arc<Store>>,
+    memory: Arc<InMemoryConversationStore>,
+}
+
+impl<Store> Clone for History<Store> {
+    fn clone(&self) -> Self {
+        History {
+            store: self.store.clone(),
+            memory: self.memory.clone(),
+        }
+    }
+}
+
+impl<Store: ConversationStore> History<Store> {
+    async fn load(&self, user_id: i64) -> Result<Vec<StoredMessage>> {
+        match &self.store {
+            Some(store) => store.load(user_id).await,
+            None => self.memory.load(user_id).await,
+        }
+    }
+
+    async fn save(
+        &self,
+        user_id: i64,
+        mut messages: Vec<StoredMessage>,
+    ) -> Result<()> {
+        if estimate_tokens(&messages) > CONTEXT_TOKEN_LIMIT * 4 / 5 {
+            messages = compress_conversation(messages, CONTEXT_TOKEN_LIMIT / 2);
+            info!(len = messages.len(), "Conversation compressed");
+        }
+
+        match &self.store {
+            Some(store) => store.save(user_id, messages).await,
+            None => self.memory.save(user_id, messages).await,
+        }
+    }
+
+    async fn push(
+        &self,
+        user_id: i64,
+        new_messages: impl IntoIterator<Item = Message>,
+    ) -> Result<()> {
+        let mut messages = self.load(user_id).await?;
+        messages.extend(new_messages.into_iter().map(StoredMessage::from));
+        self.save(user_id, messages).await
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<Model>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Model {
+    id: String,
 }
 
 #[derive(Debug, Serialize, Constructor)]
@@ -89,11 +440,302 @@ struct DalleRequest<'a> {
     prompt: &'a str,
     n: i32,
     size: &'static str,
+    quality: ImageQuality,
+    style: ImageStyle,
+}
+
+const CONTEXT_TOKEN_LIMIT: usize = 128_000;
+pub(crate) const DEFAULT_MAX_HISTORY_TOKENS: usize = 3000;
+pub(crate) const DEFAULT_MAX_RETRIES: u32 = 3;
+pub(crate) const DEFAULT_RETRY_BASE_DELAY: Duration =
+    Duration::from_millis(500);
+const IMAGE_TOKENS: usize = 85;
+const IMAGE_PLACEHOLDER: &str = "[изображение]";
+/// The error while the circuit breaker is open, also shown to the user.
+pub(crate) const GPT_UNAVAILABLE: &str =
+    "GPT сейчас недоступен, попробуй позже";
+const SUMMARY_BLOCK: usize = 5;
+const SUMMARY_LINE_CHARS: usize = 100;
+// Messages at the end of the conversation that are never summarized.
+const RECENT_MESSAGES: usize = 4;
+const HISTORY_SUMMARY_PROMPT: &str =
+    "Summarize this conversation in 3 sentences";
+const SUMMARY_RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+
+const IMAGE_SIZES: [&str; 3] = ["1024x1024", "1792x1024", "1024x1792"];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageQuality {
+    #[default]
+    Standard,
+    Hd,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageStyle {
+    #[default]
+    Vivid,
+    Natural,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DrawOptions {
+    pub quality: ImageQuality,
+    pub size: &'static str,
+    pub style: ImageStyle,
+}
+
+impl Default for DrawOptions {
+    fn default() -> Self {
+        DrawOptions {
+            quality: ImageQuality::default(),
+            size: IMAGE_SIZES[0],
+            style: ImageStyle::default(),
+        }
+    }
+}
+
+/// Prompt words that pick the image size when it is not set explicitly,
+/// e.g. `wide` for `1792x1024`. Keywords are whole words in any case, so
+/// `wide` doesn't match `widely`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeKeywords(Vec<(&'static str, Vec<String>)>);
+
+impl Default for SizeKeywords {
+    fn default() -> Self {
+        SizeKeywords(vec![
+            (
+                IMAGE_SIZES[1],
+                ["горизонтальный", "горизонтальная", "горизонтально", "wide"]
+                    .map(String::from)
+                    .to_vec(),
+            ),
+            (
+                IMAGE_SIZES[2],
+                ["портретный", "портретная", "вертикальный", "portrait"]
+                    .map(String::from)
+                    .to_vec(),
+            ),
+        ])
+    }
+}
+
+impl SizeKeywords {
+    /// Parses `{"1792x1024": ["wide"], "1024x1792": ["portrait"]}`.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let sizes: HashMap<String, Vec<String>> = serde_json::from_str(json)?;
+        let mut keywords = sizes
+            .into_iter()
+            .map(|(size, keywords)| {
+                if keywords.iter().any(|word| word.trim().is_empty()) {
+                    bail!("Size {size} has an empty keyword");
+                }
+                let keywords = keywords
+                    .iter()
+                    .map(|word| word.trim().to_lowercase())
+                    .collect();
+                Ok((parse_image_size(&size)?, keywords))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // The order of a JSON object is lost, keep matching deterministic.
+        keywords.sort();
+
+        Ok(SizeKeywords(keywords))
+    }
+
+    /// Finds the first word of `prompt` that names a size, returns the size
+    /// with the prompt without that word. The line breaks of the prompt are
+    /// kept.
+    fn extract(&self, prompt: &str) -> Option<(&'static str, String)> {
+        let mut end = 0;
+
+        prompt.split_whitespace().find_map(|word| {
+            let start = end + prompt[end..].find(word)?;
+            end = start + word.len();
+
+            let word = word
+                .trim_matches(|c: char| !c.is_alphanumeric())
+                .to_lowercase();
+            let &(size, _) = self
+                .0
+                .iter()
+                .find(|(_, keywords)| keywords.contains(&word))?;
+
+            let after = prompt[end..].trim_start_matches([' ', '\t']);
+            let rest = format!("{}{after}", &prompt[..start]);
+            Some((size, rest.trim().to_string()))
+        })
+    }
+}
+
+impl DrawOptions {
+    /// Parses options like `HD 1792x1024` from the start of `prompt` and
+    /// flags like `--style natural` from its end, returns them with the
+    /// rest of the prompt. Without an explicit size it is picked by
+    /// `size_keywords`, and the keyword is removed from the prompt.
+    pub fn parse(
+        prompt: &str,
+        size_keywords: &SizeKeywords,
+    ) -> Result<(Self, String)> {
+        let mut quality = None;
+        let mut size = None;
+        let mut style = None;
+        let mut rest = prompt.trim_start();
+
+        while let Some(word) = rest.split_whitespace().next() {
+            let word_quality = match word.to_lowercase().as_str() {
+                "hd" | "quality:high" | "quality:hd" => Some(ImageQuality::Hd),
+                "sd" | "quality:standard" | "quality:sd" => {
+                    Some(ImageQuality::Standard)
+                }
+                _ => None,
+            };
+
+            if let Some(word_quality) = word_quality {
+                if quality.replace(word_quality).is_some() {
+                    bail!("Quality is set twice");
+                }
+            } else if is_image_size(word) {
+                if size.replace(parse_image_size(word)?).is_some() {
+                    bail!("Size is set twice");
+                }
+            } else if word.to_lowercase().starts_with("quality:") {
+                bail!("Quality {word} is not supported, use HD or SD");
+            } else {
+                break;
+            }
+
+            rest = rest[word.len()..].trim_start();
+        }
+
+        let flags_at = rest
+            .char_indices()
+            .map(|(index, _)| index)
+            .find(|&index| {
+                rest[index..].starts_with("--")
+                    && rest[..index]
+                        .chars()
+                        .last()
+                        .is_none_or(char::is_whitespace)
+            })
+            .unwrap_or(rest.len());
+        let (text, flags) = rest.split_at(flags_at);
+
+        let mut words = flags.split_whitespace();
+        while let Some(flag) = words.next() {
+            let Some(value) = words.next() else {
+                bail!("Flag {flag} has no value");
+            };
+
+            let duplicate = match flag {
+                "--size" => size.replace(parse_image_size(value)?).is_some(),
+                "--quality" => {
+                    let value_quality = match value.to_lowercase().as_str() {
+                        "standard" => ImageQuality::Standard,
+                        "hd" => ImageQuality::Hd,
+                        _ => bail!(
+                            "Quality {value} is not supported, use one of \
+                             standard, hd"
+                        ),
+                    };
+                    quality.replace(value_quality).is_some()
+                }
+                "--style" => {
+                    let value_style = match value.to_lowercase().as_str() {
+                        "vivid" => ImageStyle::Vivid,
+                        "natural" => ImageStyle::Natural,
+                        _ => bail!(
+                            "Style {value} is not supported, use one of \
+                             vivid, natural"
+                        ),
+                    };
+                    style.replace(value_style).is_some()
+                }
+                _ => bail!(
+                    "Flag {flag} is not supported, use one of \
+                     --size, --quality, --style"
+                ),
+            };
+            if duplicate {
+                bail!("Flag {flag} is set twice");
+            }
+        }
+
+        let mut text = text.trim_end().to_string();
+        if size.is_none() {
+            if let Some((keyword_size, rest)) = size_keywords.extract(&text) {
+                size = Some(keyword_size);
+                text = rest;
+            }
+        }
+
+        let default = DrawOptions::default();
+        let options = DrawOptions {
+            quality: quality.unwrap_or(default.quality),
+            size: size.unwrap_or(default.size),
+            style: style.unwrap_or(default.style),
+        };
+
+        Ok((options, text))
+    }
+}
+
+fn parse_image_size(word: &str) -> Result<&'static str> {
+    match IMAGE_SIZES.iter().find(|&&size| size == word) {
+        Some(&size) => Ok(size),
+        None => bail!(
+            "Size {word} is not supported, use one of {}",
+            IMAGE_SIZES.join(", ")
+        ),
+    }
+}
+
+fn is_image_size(word: &str) -> bool {
+    word.split_once('x').is_some_and(|(width, height)| {
+        [width, height].iter().all(|side| {
+            !side.is_empty() && side.chars().all(|c| c.is_ascii_digit())
+        })
+    })
 }
 
 #[derive(Debug, Deserialize, Constructor)]
 struct DalleResponse {
-    data: Vec<Url>,
+    data: Vec<DalleImage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DalleImage {
+    url: Option<String>,
+    b64_json: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageContent {
+    Bytes(Vec<u8>),
+    Url(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct Transcription {
+    text: String,
+}
+
+#[derive(Serialize, Constructor)]
+struct EmbeddingRequest<'a> {
+    model: &'static str,
+    input: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<Embedding>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Embedding {
+    embedding: Vec<f32>,
 }
 
 #[derive(Serialize, Constructor)]
@@ -106,9 +748,32 @@ struct AudioSpeechRequest<'a> {
 enum ModelMode {
     Fast,
     Smart,
+    /// The fast model answering about images.
+    Image,
+    /// The reasoning model, or the smart one if there is none.
+    Reasoning,
+    Custom(&'static str),
 }
 
-impl GtpClient {
+/// Sampling temperatures of the kinds of completions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Temperatures {
+    pub default: f64,
+    pub smart: f64,
+    pub image: f64,
+}
+
+impl Default for Temperatures {
+    fn default() -> Self {
+        Temperatures {
+            default: 1.0,
+            smart: 0.7,
+            image: 0.5,
+        }
+    }
+}
+
+impl<Store: ConversationStore + 'static> GtpClient<Store> {
     pub fn new(
         api_url: &'static str,
         model: &'static str,
@@ -116,171 +781,1957 @@ impl GtpClient {
         voice: &'static str,
         token: &'static str,
         base_rules: String,
+        circuit_breaker: Arc<CircuitBreaker>,
     ) -> Self {
         //let api_url = "https://api.openai.com/v1/chat/completions";
         let http_client = reqwest::Client::new();
 
-        let messages = if base_rules.is_empty() {
-            Vec::new()
-        } else {
-            vec![Message::System(Value::Plain(base_rules.into()))]
-        };
-
         GtpClient {
             token,
             model,
             voice,
             smart_model,
+            audio_input_model: None,
+            reasoning_model: None,
+            max_history_tokens: DEFAULT_MAX_HISTORY_TOKENS,
+            temperatures: Temperatures::default(),
+            max_attempts: DEFAULT_MAX_RETRIES + 1,
+            retry_base_delay: DEFAULT_RETRY_BASE_DELAY,
             http_client,
             chat_url: api_url,
             dalle_url: "https://api.openai.com/v1/images/generations",
-            messages: Mutex::new(messages),
+            models_url: "https://api.openai.com/v1/models",
+            base_rules: Mutex::new(Arc::new(base_rules)),
+            history: History {
+                store: None,
+                memory: Arc::default(),
+            },
+            circuit_breaker,
+            usage_stats: Arc::default(),
+            response_cache: None,
+            semantic_cache: None,
+            history_compression: false,
+            history_state: DashMap::new(),
         }
     }
 
-    async fn get_value_completion(
-        &self,
-        value: Value,
-        mode: ModelMode,
-    ) -> Result<Arc<String>> {
-        let user_message = Message::User(value);
-        let mut messages = {
-            let messages = self.messages.lock().await;
-            messages.clone()
-        };
+    /// Counts the usage of several clients together.
+    pub fn set_usage_stats(&mut self, usage_stats: Arc<UsageStats>) {
+        self.usage_stats = usage_stats;
+    }
 
-        messages.push(user_message.clone());
+    /// Answers repeated `get_completion` prompts from `cache`.
+    pub fn set_response_cache(&mut self, cache: ResponseCache) {
+        self.response_cache = Some(cache);
+    }
 
-        let model = match mode {
-            ModelMode::Fast => self.model,
-            ModelMode::Smart => self.smart_model,
-        };
-        let request_data = Request::new(model, &messages, 1.0);
-        let token = &self.token;
-        let response = self
-            .http_client
-            .post(self.chat_url)
-            .header("Authorization", format!("Bearer {token}"))
-            .json(&request_data)
-            .send()
-            .await?;
+    /// Answers `get_completion` prompts similar to recent ones from `cache`.
+    pub fn set_semantic_cache(&mut self, cache: SemanticCache) {
+        self.semantic_cache = Some(cache);
+    }
 
-        if response.status().is_success() {
-            let mut completion = response.json::<Response>().await?;
-            let choice = completion.choices.swap_remove(0);
-            let result = Arc::new(choice.message.content);
-            let assist_message =
-                Message::Assistant(Value::Plain(result.clone()));
+    pub fn set_history_compression(&mut self, enabled: bool) {
+        self.history_compression = enabled;
+    }
 
-            {
-                let mut messages = self.messages.lock().await;
-                messages.push(user_message);
-                messages.push(assist_message);
-            }
+    /// Persists the history in `store` instead of the Lambda memory.
+    pub fn set_conversation_store(&mut self, store: Store) {
+        self.history.store = Some(Arc::new(store));
+    }
 
-            Ok(result)
-        } else {
-            bail!(response.text().await?)
-        }
+    /// Sends voice messages to `model` as audio instead of dropping them.
+    pub fn set_audio_input_model(&mut self, model: &'static str) {
+        self.audio_input_model = Some(model);
     }
-}
 
-impl GtpInteractor for GtpClient {
-    async fn get_completion(&self, prompt: String) -> Result<Arc<String>> {
-        self.get_value_completion(Value::Plain(prompt.into()), ModelMode::Fast)
-            .await
+    /// Answers "подумай глубоко" requests with `model`, e.g. o1 or o3.
+    pub fn set_reasoning_model(&mut self, model: &'static str) {
+        self.reasoning_model = Some(model);
     }
 
-    async fn get_smart_completion(
-        &self,
-        prompt: String,
-    ) -> Result<Arc<String>> {
-        self.get_value_completion(Value::Plain(prompt.into()), ModelMode::Smart)
-            .await
+    /// Limits the history sent with each request, the oldest messages are
+    /// dropped first.
+    pub fn set_max_history_tokens(&mut self, max_tokens: usize) {
+        self.max_history_tokens = max_tokens;
     }
 
-    async fn get_image_completion(
-        &self,
-        text: String,
-        image_url: String,
-    ) -> Result<Arc<String>> {
-        let value = Value::Complex(vec![
-            Content::Text { text: text.into() },
-            Content::ImageUrl {
-                image_url: Arc::new(image_url).into(),
-            },
-        ]);
-        self.get_value_completion(value, ModelMode::Fast).await
+    pub fn set_temperatures(&mut self, temperatures: Temperatures) {
+        self.temperatures = temperatures;
     }
-    async fn get_image(&self, prompt: &str) -> Result<Arc<String>> {
-        let dalle_request =
-            DalleRequest::new("dall-e-3", prompt, 1, "1024x1024");
 
-        let token = self.token;
-        let response = self
-            .http_client
-            .post(self.dalle_url)
-            .header("Authorization", format!("Bearer {token}"))
-            .json(&dalle_request)
-            .send()
-            .await?;
+    /// Repeats rate limited and failed requests up to `max_retries` times,
+    /// waiting twice as long before each next one.
+    pub fn set_retry_policy(&mut self, max_retries: u32, base_delay: Duration) {
+        self.max_attempts = max_retries + 1;
+        self.retry_base_delay = base_delay;
+    }
 
-        if response.status().is_success() {
-            let mut completion = response.json::<DalleResponse>().await?;
-            let response = completion.data.remove(0);
+    /// Checks that the configured models are `available`. A missing model
+    /// is replaced with the closest available one when `auto_model` is set,
+    /// otherwise startup is aborted.
+    pub fn check_models(
+        &mut self,
+        available: &[String],
+        auto_model: bool,
+    ) -> Result<()> {
+        for model in [&mut self.model, &mut self.smart_model] {
+            if available.iter().any(|id| id == model) {
+                continue;
+            }
 
-            let anwer_message = Message::User(Value::Complex(vec![
-                Content::Text {
-                    text: format!("По запросу '{prompt}' ты нарисовал:").into(),
-                },
-                Content::ImageUrl {
-                    image_url: response.clone(),
-                },
-            ]));
+            error!(model, "Configured model is not available");
 
-            {
-                let mut messages = self.messages.lock().await;
-                messages.push(anwer_message);
+            if !auto_model {
+                bail!("Model {model} is not available");
             }
 
-            Ok(response.url)
-        } else {
-            bail!(response.text().await?)
+            let Some(fallback) = closest_model(model, available) else {
+                bail!("No model like {model} is available");
+            };
+
+            warn!(model, fallback, "Falling back to the closest model");
+            *model = fallback.to_string().leak();
         }
-    }
 
-    async fn get_audio(&self, prompt: &str) -> Result<Vec<u8>> {
-        let request = AudioSpeechRequest::new("tts-1", prompt, self.voice);
+        Ok(())
+    }
 
-        let token = self.token;
-        let response = self
-            .http_client
-            .post("https://api.openai.com/v1/audio/speech")
-            .header("Authorization", format!("Bearer {token}"))
-            .json(&request)
-            .send()
-            .await?;
+    /// The rules go first and are never stored in the history.
+    async fn build_messages(
+        &self,
+        history: &[StoredMessage],
+        rules: Option<&str>,
+        user_message: Message,
+    ) -> Vec<Message> {
+        let rules = match rules {
+            Some(rules) => rules.to_string(),
+            None => self.base_rules.lock().await.to_string(),
+        };
 
-        if response.status().is_success() {
-            let audio = response.bytes().await?;
-            Ok(Vec::from(audio))
-        } else {
-            bail!(response.text().await?)
+        let mut messages = Vec::with_capacity(history.len() + 2);
+        if !rules.is_empty() {
+            messages.push(Message::System(Value::Plain(rules.into())));
         }
+        messages.extend(history.iter().map(|stored| stored.message.clone()));
+        messages.push(user_message);
+        messages
     }
-}
 
-#[cfg_attr(test, automock)]
-pub trait GtpInteractor {
-    async fn get_completion(&self, prompt: String) -> Result<Arc<String>>;
-    async fn get_smart_completion(&self, prompt: String)
-        -> Result<Arc<String>>;
-    async fn get_image_completion(
+    fn prune_history(&self, user_id: i64, history: &mut Vec<StoredMessage>) {
+        let fill =
+            estimate_tokens(history) as f64 / self.max_history_tokens as f64;
+        self.history_state.entry(user_id).or_default().fill = fill;
+        prune_messages(history, self.max_history_tokens);
+    }
+
+    /// Replaces the older half of a history above 80% of the limit with
+    /// its summary. The history is pruned as usual if GPT fails.
+    async fn compress_history(
         &self,
-        text: String,
-        image_url: String,
-    ) -> Result<Arc<String>>;
-    async fn get_image(&self, prompt: &str) -> Result<Arc<String>>;
+        user_id: i64,
+        history: &mut Vec<StoredMessage>,
+    ) {
+        if !self.history_compression
+            || estimate_tokens(history) * 5 <= self.max_history_tokens * 4
+        {
+            return;
+        }
+        let failed_at = self
+            .history_state
+            .get(&user_id)
+            .and_then(|state| state.summary_failed_at);
+        if failed_at
+            .is_some_and(|failed_at| failed_at.elapsed() < SUMMARY_RETRY_DELAY)
+        {
+            return;
+        }
+
+        let split = history.len() / 2;
+        let older: Vec<Message> = history[..split]
+            .iter()
+            .filter(|stored| !matches!(stored.message, Message::System(_)))
+            .map(|stored| stored.message.without_images())
+            .collect();
+        if older.is_empty() {
+            return;
+        }
+
+        match self.summarize_history(&older).await {
+            Ok(summary) => {
+                replace_with_summary(history, split, &summary);
+                self.set_summary_failed_at(user_id, None);
+                info!(len = history.len(), "History summarized");
+            }
+            Err(error) => {
+                warn!(?error, "Failed to summarize history");
+                self.set_summary_failed_at(user_id, Some(Instant::now()));
+            }
+        }
+    }
+
+    fn set_summary_failed_at(&self, user_id: i64, failed_at: Option<Instant>) {
+        self.history_state
+            .entry(user_id)
+            .or_default()
+            .summary_failed_at = failed_at;
+    }
+
+    /// A cached answer goes to the history like a new one, so the
+    /// conversation goes on from it.
+    async fn push_cached_answer(
+        &self,
+        user_id: i64,
+        prompt: String,
+        response: Arc<String>,
+    ) -> Result<Arc<String>> {
+        let messages = [
+            Message::User(Value::Plain(prompt.into())),
+            Message::Assistant(Value::Plain(response.clone())),
+        ];
+        self.history.push(user_id, messages).await?;
+
+        Ok(response)
+    }
+
+    async fn get_value_completion(
+        &self,
+        user_id: i64,
+        value: Value,
+        mode: ModelMode,
+        rules: Option<&str>,
+    ) -> Result<Arc<String>> {
+        let user_message = Message::User(value);
+        let mut history = self.history.load(user_id).await?;
+        self.compress_history(user_id, &mut history).await;
+        self.prune_history(user_id, &mut history);
+        let messages = self
+            .build_messages(&history, rules, user_message.clone())
+            .await;
+
+        let temperatures = self.temperatures;
+        let (model, temperature) = match mode {
+            ModelMode::Smart if self.circuit_breaker.is_degraded() => {
+                warn!("GPT API is degraded, using fast model");
+                (self.model, temperatures.smart)
+            }
+            ModelMode::Fast => (self.model, temperatures.default),
+            ModelMode::Smart => (self.smart_model, temperatures.smart),
+            ModelMode::Image => (self.model, temperatures.image),
+            ModelMode::Reasoning => (
+                self.reasoning_model.unwrap_or(self.smart_model),
+                temperatures.smart,
+            ),
+            ModelMode::Custom(model) => (model, temperatures.default),
+        };
+        let (result, usage) = retry_with_backoff(
+            || self.request_completion(model, &messages, temperature),
+            self.max_attempts,
+            self.retry_base_delay,
+        )
+        .await?;
+        self.usage_stats.record_user_tokens(
+            user_id,
+            usage.prompt_tokens as u64,
+            usage.completion_tokens as u64,
+        );
+        if result.trim().is_empty() {
+            bail!(GptError::EmptyResponse);
+        }
+
+        let result = Arc::new(result);
+        let assist_message = Message::Assistant(Value::Plain(result.clone()));
+
+        history.push(user_message.without_images().into());
+        history.push(assist_message.into());
+        self.history.save(user_id, history).await?;
+
+        Ok(result)
+    }
+
+    async fn request_completion(
+        &self,
+        model: &str,
+        messages: &[Message],
+        temperature: f64,
+    ) -> Result<(String, Usage)> {
+        let temperature = request_temperature(model, temperature);
+        let request_data =
+            Request::new(model, messages, temperature, false, None);
+        let (choice, usage) = self.request_choice(&request_data).await?;
+
+        Ok((choice.message.content.unwrap_or_default(), usage))
+    }
+
+    async fn request_choice<M: Serialize + Sync>(
+        &self,
+        request_data: &Request<'_, M>,
+    ) -> Result<(Choice, Usage)> {
+        let response = self.send_chat_request(request_data).await?;
+
+        if response.status().is_success() {
+            let mut completion = response.json::<Response>().await?;
+            self.usage_stats
+                .record_completion(completion.usage.total_tokens as u64);
+            Ok((completion.choices.swap_remove(0), completion.usage))
+        } else {
+            self.usage_stats.record_error();
+            let error = api_error(response.status(), response.text().await?);
+            Err(error.context(ErrorCode::GptApiFailure))
+        }
+    }
+
+    /// Sends a chat request unless the circuit breaker is open, and tells
+    /// it how the API did.
+    async fn send_chat_request<M: Serialize + Sync>(
+        &self,
+        request_data: &Request<'_, M>,
+    ) -> Result<reqwest::Response> {
+        if !self.circuit_breaker.allow_request() {
+            bail!(ProcessingError::Ignorable(GPT_UNAVAILABLE.to_string()));
+        }
+
+        let token = &self.token;
+        let started_at = Instant::now();
+        self.usage_stats.record_request();
+        let response = self
+            .http_client
+            .post(self.chat_url)
+            .header("Authorization", format!("Bearer {token}"))
+            .json(request_data)
+            .send()
+            .await
+            .inspect_err(|_| {
+                self.usage_stats.record_error();
+                self.circuit_breaker.record_failure();
+            })
+            .context(ErrorCode::GptApiFailure)?;
+
+        self.circuit_breaker.record_latency(started_at.elapsed());
+
+        // Rejected requests don't mean the API is down.
+        let status = response.status();
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            self.circuit_breaker.record_failure();
+        } else {
+            self.circuit_breaker.record_success();
+        }
+
+        retryable_status(response)
+            .await
+            .inspect_err(|_| self.usage_stats.record_error())
+            .context(ErrorCode::GptApiFailure)
+    }
+}
+
+/// Turns server-sent events into answer pieces and saves the whole answer
+/// to the history after the last one.
+fn completion_stream<Store: ConversationStore + 'static>(
+    response: reqwest::Response,
+    history: History<Store>,
+    user_id: i64,
+    user_message: Message,
+) -> CompletionStream {
+    let events = Box::pin(response.bytes_stream().eventsource());
+    let state = Some((events, String::new(), history, user_message));
+
+    stream::unfold(state, move |state| async move {
+        let (mut events, mut answer, history, user_message) = state?;
+
+        loop {
+            let event = match events.next().await {
+                Some(Ok(event)) if event.data != "[DONE]" => event,
+                Some(Err(error)) => return Some((Err(error.into()), None)),
+                _ if answer.trim().is_empty() => {
+                    return Some((Err(GptError::EmptyResponse.into()), None));
+                }
+                _ => {
+                    let assist_message =
+                        Message::Assistant(Value::Plain(answer.into()));
+                    let saved = history
+                        .push(user_id, [user_message, assist_message])
+                        .await;
+                    return saved.err().map(|error| (Err(error), None));
+                }
+            };
+
+            let chunk = match serde_json::from_str::<StreamChunk>(&event.data) {
+                Ok(chunk) => chunk,
+                Err(error) => return Some((Err(error.into()), None)),
+            };
+            let Some(delta) = chunk
+                .choices
+                .into_iter()
+                .next()
+                .and_then(|choice| choice.delta.content)
+                .filter(|delta| !delta.is_empty())
+            else {
+                continue;
+            };
+
+            answer.push_str(&delta);
+            let state = Some((events, answer, history, user_message));
+            return Some((Ok(Arc::new(delta)), state));
+        }
+    })
+    .boxed()
+}
+
+impl<Store: ConversationStore + 'static> GtpInteractor for GtpClient<Store> {
+    async fn get_completion(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        let history = match (&self.response_cache, &self.semantic_cache) {
+            (None, None) => Vec::new(),
+            _ => self.history.load(user_id).await?,
+        };
+        let version = history_version(&history);
+
+        if let Some(cache) = &self.response_cache {
+            if let Some(response) = cache.get(user_id, version, &prompt) {
+                return self
+                    .push_cached_answer(user_id, prompt, response)
+                    .await;
+            }
+        }
+
+        // Only a fresh conversation, a reply to "да" depends on what came
+        // before it.
+        let conversation = fresh_conversation(&history);
+        let embedding = match (&self.semantic_cache, conversation) {
+            (Some(cache), Some(conversation)) => {
+                match self.get_embedding(&prompt).await {
+                    Ok(embedding) => {
+                        if let Some(response) =
+                            cache.get(user_id, conversation, &embedding)
+                        {
+                            return self
+                                .push_cached_answer(user_id, prompt, response)
+                                .await;
+                        }
+                        Some(embedding)
+                    }
+                    // The cache only saves a request, the answer matters more.
+                    Err(error) => {
+                        warn!(?error, "Failed to get prompt embedding");
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let response = self
+            .get_value_completion(
+                user_id,
+                Value::Plain(prompt.clone().into()),
+                ModelMode::Fast,
+                None,
+            )
+            .await?;
+
+        if let Some(cache) = &self.response_cache {
+            cache.insert(user_id, version, &prompt, response.clone());
+        }
+        if let (Some(cache), Some(conversation), Some(embedding)) =
+            (&self.semantic_cache, conversation, embedding)
+        {
+            cache.insert(user_id, conversation, embedding, response.clone());
+        }
+
+        Ok(response)
+    }
+
+    async fn get_completion_stream(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<CompletionStream> {
+        let user_message = Message::User(Value::Plain(prompt.into()));
+        let mut history = self.history.load(user_id).await?;
+        self.prune_history(user_id, &mut history);
+        let messages = self
+            .build_messages(&history, None, user_message.clone())
+            .await;
+
+        let request_data = Request::new(
+            self.model,
+            &messages,
+            request_temperature(self.model, self.temperatures.default),
+            true,
+            None,
+        );
+        let response = self.send_chat_request(&request_data).await?;
+
+        if !response.status().is_success() {
+            self.usage_stats.record_error();
+            let error = api_error(response.status(), response.text().await?);
+            return Err(error.context(ErrorCode::GptApiFailure));
+        }
+
+        // Streamed chunks don't report the used tokens.
+        self.usage_stats.record_completion(0);
+
+        Ok(completion_stream(
+            response,
+            self.history.clone(),
+            user_id,
+            user_message,
+        ))
+    }
+
+    async fn get_completion_with_tools(
+        &self,
+        user_id: i64,
+        prompt: String,
+        tools: Vec<Tool>,
+    ) -> Result<ToolCallOrText> {
+        let user_message = Message::User(Value::Plain(prompt.into()));
+        let mut history = self.history.load(user_id).await?;
+        self.prune_history(user_id, &mut history);
+        let messages = self
+            .build_messages(&history, None, user_message.clone())
+            .await;
+
+        let request_data = Request::new(
+            self.model,
+            &messages,
+            request_temperature(self.model, self.temperatures.default),
+            false,
+            Some(&tools),
+        );
+        let (choice, _) = self.request_choice(&request_data).await?;
+
+        // The prompt is stored with the final answer once the tools ran.
+        if choice.finish_reason == "tool_calls" {
+            info!(count = choice.message.tool_calls.len(), "Tool calls");
+            return Ok(ToolCallOrText::ToolCalls(choice.message.tool_calls));
+        }
+
+        let result = choice.message.content.unwrap_or_default();
+        if result.trim().is_empty() {
+            bail!(GptError::EmptyResponse);
+        }
+
+        let result = Arc::new(result);
+        history.push(user_message.into());
+        history.push(Message::Assistant(Value::Plain(result.clone())).into());
+        self.history.save(user_id, history).await?;
+
+        Ok(ToolCallOrText::Text(result))
+    }
+
+    async fn submit_tool_results(
+        &self,
+        user_id: i64,
+        prompt: String,
+        tools: Vec<Tool>,
+        tool_calls: Vec<ToolCall>,
+        results: Vec<ToolResult>,
+    ) -> Result<Arc<String>> {
+        let user_message = Message::User(Value::Plain(prompt.into()));
+        let mut history = self.history.load(user_id).await?;
+        self.prune_history(user_id, &mut history);
+        let messages = self
+            .build_messages(&history, None, user_message.clone())
+            .await;
+
+        let mut tool_messages: Vec<_> =
+            messages.iter().map(ToolMessage::History).collect();
+        tool_messages.push(ToolMessage::Calls {
+            role: "assistant",
+            tool_calls: &tool_calls,
+        });
+        tool_messages.extend(results.iter().map(|result| {
+            ToolMessage::Result {
+                role: "tool",
+                tool_call_id: &result.tool_call_id,
+                content: &result.content,
+            }
+        }));
+
+        let request_data = Request::new(
+            self.model,
+            &tool_messages,
+            request_temperature(self.model, self.temperatures.default),
+            false,
+            Some(&tools),
+        );
+        let (choice, _) = self.request_choice(&request_data).await?;
+        if choice.finish_reason == "tool_calls" {
+            bail!("GPT asked for tools again");
+        }
+
+        let result = choice.message.content.unwrap_or_default();
+        if result.trim().is_empty() {
+            bail!(GptError::EmptyResponse);
+        }
+
+        let result = Arc::new(result);
+        history.push(user_message.into());
+        history.push(Message::Assistant(Value::Plain(result.clone())).into());
+        self.history.save(user_id, history).await?;
+
+        Ok(result)
+    }
+
+    async fn get_stateless_completion(
+        &self,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        let messages = vec![Message::User(Value::Plain(prompt.into()))];
+        let (result, _) = self
+            .request_completion(
+                self.model,
+                &messages,
+                self.temperatures.default,
+            )
+            .await?;
+
+        Ok(Arc::new(result))
+    }
+
+    async fn summarize_history(
+        &self,
+        messages: &[Message],
+    ) -> Result<Arc<String>> {
+        let mut messages = messages.to_vec();
+        messages.push(Message::User(Value::Plain(
+            HISTORY_SUMMARY_PROMPT.to_string().into(),
+        )));
+        let (summary, _) = retry_with_backoff(
+            || {
+                self.request_completion(
+                    self.model,
+                    &messages,
+                    self.temperatures.default,
+                )
+            },
+            self.max_attempts,
+            self.retry_base_delay,
+        )
+        .await?;
+        if summary.trim().is_empty() {
+            bail!(GptError::EmptyResponse);
+        }
+
+        Ok(Arc::new(summary))
+    }
+
+    async fn get_smart_completion(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        self.get_value_completion(
+            user_id,
+            Value::Plain(prompt.into()),
+            ModelMode::Smart,
+            None,
+        )
+        .await
+    }
+
+    async fn get_reasoning_completion(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        self.get_value_completion(
+            user_id,
+            Value::Plain(prompt.into()),
+            ModelMode::Reasoning,
+            None,
+        )
+        .await
+    }
+
+    async fn get_model_completion(
+        &self,
+        user_id: i64,
+        model: &'static str,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        self.get_value_completion(
+            user_id,
+            Value::Plain(prompt.into()),
+            ModelMode::Custom(model),
+            None,
+        )
+        .await
+    }
+
+    fn models(&self) -> [&'static str; 2] {
+        [self.model, self.smart_model]
+    }
+
+    async fn get_system_prompt_completion(
+        &self,
+        user_id: i64,
+        system_prompt: &str,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        let base_rules = self.base_rules.lock().await.clone();
+        let rules = if base_rules.is_empty() {
+            system_prompt.to_string()
+        } else {
+            format!("{system_prompt}\n{base_rules}")
+        };
+
+        self.get_value_completion(
+            user_id,
+            Value::Plain(prompt.into()),
+            ModelMode::Fast,
+            Some(&rules),
+        )
+        .await
+    }
+
+    async fn get_code_review_completion(
+        &self,
+        user_id: i64,
+        rules: &str,
+        prompt: String,
+    ) -> Result<Arc<String>> {
+        self.get_value_completion(
+            user_id,
+            Value::Plain(prompt.into()),
+            ModelMode::Smart,
+            Some(rules),
+        )
+        .await
+    }
+
+    async fn get_image_completion(
+        &self,
+        user_id: i64,
+        text: String,
+        image_url: String,
+    ) -> Result<Arc<String>> {
+        let value = Value::Complex(vec![
+            Content::Text { text: text.into() },
+            Content::ImageUrl {
+                image_url: Arc::new(image_url).into(),
+            },
+        ]);
+        self.get_value_completion(user_id, value, ModelMode::Image, None)
+            .await
+    }
+
+    async fn get_multi_image_completion(
+        &self,
+        user_id: i64,
+        text: String,
+        image_urls: Vec<String>,
+    ) -> Result<Arc<String>> {
+        let mut contents = Vec::with_capacity(image_urls.len() + 1);
+        contents.push(Content::Text { text: text.into() });
+        contents.extend(image_urls.into_iter().map(|image_url| {
+            Content::ImageUrl {
+                image_url: Arc::new(image_url).into(),
+            }
+        }));
+
+        self.get_value_completion(
+            user_id,
+            Value::Complex(contents),
+            ModelMode::Image,
+            None,
+        )
+        .await
+    }
+
+    fn supports_audio_input(&self) -> bool {
+        self.audio_input_model.is_some()
+    }
+
+    async fn get_audio_completion(
+        &self,
+        user_id: i64,
+        audio: Vec<u8>,
+        format: &'static str,
+    ) -> Result<Arc<String>> {
+        let Some(model) = self.audio_input_model else {
+            bail!("Audio input model is not configured");
+        };
+
+        let audio_message =
+            Message::User(Value::Complex(vec![Content::InputAudio {
+                input_audio: AudioInput {
+                    data: BASE64_STANDARD.encode(audio),
+                    format: format.to_string(),
+                },
+            }]));
+        let mut history = self.history.load(user_id).await?;
+        self.prune_history(user_id, &mut history);
+        let messages = self.build_messages(&history, None, audio_message).await;
+
+        let (result, _) = self
+            .request_completion(model, &messages, self.temperatures.default)
+            .await?;
+        let result = Arc::new(result);
+
+        // Text models reject audio content, so only a marker stays in the
+        // history.
+        self.history
+            .push(
+                user_id,
+                [
+                    Message::User(Value::Plain(
+                        "[voice message]".to_string().into(),
+                    )),
+                    Message::Assistant(Value::Plain(result.clone())),
+                ],
+            )
+            .await?;
+
+        Ok(result)
+    }
+
+    async fn get_image(
+        &self,
+        user_id: i64,
+        prompt: &str,
+        options: DrawOptions,
+    ) -> Result<ImageContent> {
+        let dalle_request = DalleRequest::new(
+            "dall-e-3",
+            prompt,
+            1,
+            options.size,
+            options.quality,
+            options.style,
+        );
+
+        let token = self.token;
+        let response = retry_with_backoff(
+            || {
+                self.usage_stats.record_request();
+                async {
+                    let response = self
+                        .http_client
+                        .post(self.dalle_url)
+                        .header("Authorization", format!("Bearer {token}"))
+                        .json(&dalle_request)
+                        .send()
+                        .await?;
+                    retryable_status(response).await
+                }
+                .inspect_err(|_| self.usage_stats.record_error())
+            },
+            self.max_attempts,
+            self.retry_base_delay,
+        )
+        .await?;
+
+        if response.status().is_success() {
+            self.usage_stats.record_image();
+            let mut completion = response.json::<DalleResponse>().await?;
+            let image = completion.data.remove(0);
+
+            let mut content = vec![Content::Text {
+                text: format!("По запросу '{prompt}' ты нарисовал:").into(),
+            }];
+
+            let image = match (image.url, image.b64_json) {
+                (Some(url), _) => {
+                    content.push(Content::ImageUrl {
+                        image_url: Arc::new(url.clone()).into(),
+                    });
+                    ImageContent::Url(url)
+                }
+                (None, Some(b64_json)) => {
+                    ImageContent::Bytes(BASE64_STANDARD.decode(b64_json)?)
+                }
+                (None, None) => bail!("Image response has no data"),
+            };
+
+            let anwer_message = Message::User(Value::Complex(content));
+            self.history.push(user_id, [anwer_message]).await?;
+
+            Ok(image)
+        } else {
+            self.usage_stats.record_error();
+            bail!(api_error(response.status(), response.text().await?))
+        }
+    }
+
+    async fn get_image_variation(
+        &self,
+        user_id: i64,
+        image_bytes: Vec<u8>,
+        prompt: &str,
+    ) -> Result<Vec<u8>> {
+        let token = self.token;
+        let response = retry_with_backoff(
+            || {
+                self.usage_stats.record_request();
+                // A form can not be cloned, so each attempt builds its own.
+                let form = multipart::Part::bytes(image_bytes.clone())
+                    .file_name("image.jpg")
+                    .mime_str("image/jpeg")
+                    .map(|part| {
+                        multipart::Form::new()
+                            .text("model", "gpt-image-1")
+                            .text("prompt", prompt.to_string())
+                            .part("image", part)
+                    });
+                async {
+                    let response = self
+                        .http_client
+                        .post("https://api.openai.com/v1/images/edits")
+                        .header("Authorization", format!("Bearer {token}"))
+                        .multipart(form?)
+                        .send()
+                        .await?;
+                    retryable_status(response).await
+                }
+                .inspect_err(|_| self.usage_stats.record_error())
+            },
+            self.max_attempts,
+            self.retry_base_delay,
+        )
+        .await?;
+
+        if response.status().is_success() {
+            self.usage_stats.record_image();
+            let mut completion = response.json::<DalleResponse>().await?;
+            let Some(b64_json) = completion.data.remove(0).b64_json else {
+                bail!("Image edit response has no data");
+            };
+
+            let text = format!("По запросу '{prompt}' ты изменил картинку");
+            let answer_message = Message::User(Value::Plain(text.into()));
+            self.history.push(user_id, [answer_message]).await?;
+
+            Ok(BASE64_STANDARD.decode(b64_json)?)
+        } else {
+            self.usage_stats.record_error();
+            bail!(api_error(response.status(), response.text().await?))
+        }
+    }
+
+    async fn get_embedding(&self, text: &str) -> Result<Vec<f32>> {
+        let request = EmbeddingRequest::new("text-embedding-3-small", text);
+
+        let token = self.token;
+        let response = retry_with_backoff(
+            || {
+                self.usage_stats.record_request();
+                async {
+                    let response = self
+                        .http_client
+                        .post("https://api.openai.com/v1/embeddings")
+                        .header("Authorization", format!("Bearer {token}"))
+                        .json(&request)
+                        .send()
+                        .await?;
+                    retryable_status(response).await
+                }
+                .inspect_err(|_| self.usage_stats.record_error())
+            },
+            self.max_attempts,
+            self.retry_base_delay,
+        )
+        .await?;
+
+        if response.status().is_success() {
+            let mut embeddings = response.json::<EmbeddingResponse>().await?;
+            match embeddings.data.pop() {
+                Some(embedding) => Ok(embedding.embedding),
+                None => bail!("Embedding response has no data"),
+            }
+        } else {
+            self.usage_stats.record_error();
+            bail!(api_error(response.status(), response.text().await?))
+        }
+    }
+
+    async fn get_audio(&self, prompt: &str) -> Result<Vec<u8>> {
+        let request = AudioSpeechRequest::new("tts-1", prompt, self.voice);
+
+        let token = self.token;
+        let response = retry_with_backoff(
+            || {
+                self.usage_stats.record_request();
+                async {
+                    let response = self
+                        .http_client
+                        .post("https://api.openai.com/v1/audio/speech")
+                        .header("Authorization", format!("Bearer {token}"))
+                        .json(&request)
+                        .send()
+                        .await?;
+                    retryable_status(response).await
+                }
+                .inspect_err(|_| self.usage_stats.record_error())
+            },
+            self.max_attempts,
+            self.retry_base_delay,
+        )
+        .await?;
+
+        if response.status().is_success() {
+            let audio = response.bytes().await?;
+            Ok(Vec::from(audio))
+        } else {
+            self.usage_stats.record_error();
+            bail!(status_error(response.status(), response.text().await?))
+        }
+    }
+
+    async fn transcribe_audio(
+        &self,
+        audio: Vec<u8>,
+        mime_type: &str,
+    ) -> Result<Arc<String>> {
+        // Whisper tells the format by the file extension.
+        let part = multipart::Part::bytes(audio)
+            .file_name(audio_file_name(mime_type))
+            .mime_str(mime_type)?;
+        let form = multipart::Form::new()
+            .text("model", "whisper-1")
+            .part("file", part);
+
+        let token = self.token;
+        self.usage_stats.record_request();
+        let response = self
+            .http_client
+            .post("https://api.openai.com/v1/audio/transcriptions")
+            .header("Authorization", format!("Bearer {token}"))
+            .multipart(form)
+            .send()
+            .await
+            .inspect_err(|_| self.usage_stats.record_error())?;
+
+        if response.status().is_success() {
+            let transcription = response.json::<Transcription>().await?;
+            Ok(Arc::new(transcription.text))
+        } else {
+            self.usage_stats.record_error();
+            bail!(status_error(response.status(), response.text().await?))
+        }
+    }
+
+    async fn migrate_rules(
+        &self,
+        old_rules: &str,
+        new_rules: &str,
+    ) -> Result<usize> {
+        let mut rules = self.base_rules.lock().await;
+
+        let migrated = if rules.as_str() == old_rules {
+            *rules = Arc::new(new_rules.to_string());
+            1
+        } else {
+            0
+        };
+
+        info!(migrated, "Conversations migrated to new rules");
+
+        Ok(migrated)
+    }
+
+    fn usage_stats(&self) -> Arc<UsageStats> {
+        self.usage_stats.clone()
+    }
+
+    async fn list_available_models(&self) -> Result<Vec<String>> {
+        let token = self.token;
+        let response = self
+            .http_client
+            .get(self.models_url)
+            .header("Authorization", format!("Bearer {token}"))
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            let models = response.json::<ModelList>().await?;
+            Ok(models.data.into_iter().map(|model| model.id).collect())
+        } else {
+            bail!(status_error(response.status(), response.text().await?))
+        }
+    }
+
+    async fn add_system_message(
+        &self,
+        user_id: i64,
+        text: String,
+    ) -> Result<()> {
+        self.history
+            .push(user_id, [Message::System(Value::Plain(text.into()))])
+            .await
+    }
+
+    async fn reset_history(&self, user_id: i64) -> Result<()> {
+        self.history.save(user_id, Vec::new()).await
+    }
+
+    async fn remove_last_exchange(&self, user_id: i64) -> Result<()> {
+        let mut history = self.history.load(user_id).await?;
+        if pop_last_exchange(&mut history) {
+            self.history.save(user_id, history).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn get_history(&self, user_id: i64) -> Result<Vec<StoredMessage>> {
+        self.history.load(user_id).await
+    }
+
+    fn invalidate_cache(&self, user_id: i64) {
+        if let Some(cache) = &self.response_cache {
+            cache.invalidate(user_id);
+        }
+        if let Some(cache) = &self.semantic_cache {
+            cache.invalidate(user_id);
+        }
+    }
+
+    fn history_fill_ratio(&self, user_id: i64) -> f64 {
+        self.history_state
+            .get(&user_id)
+            .map_or(0.0, |state| state.fill)
+    }
+}
+
+#[cfg_attr(test, automock)]
+pub trait GtpInteractor {
+    async fn get_completion(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<Arc<String>>;
+    /// Same as `get_completion`, but yields the answer while it's generated.
+    async fn get_completion_stream(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<CompletionStream>;
+    /// Lets GPT call the tools instead of answering right away.
+    async fn get_completion_with_tools(
+        &self,
+        user_id: i64,
+        prompt: String,
+        tools: Vec<Tool>,
+    ) -> Result<ToolCallOrText>;
+    /// Answers the prompt of `get_completion_with_tools` with the results of
+    /// the calls it returned.
+    async fn submit_tool_results(
+        &self,
+        user_id: i64,
+        prompt: String,
+        tools: Vec<Tool>,
+        tool_calls: Vec<ToolCall>,
+        results: Vec<ToolResult>,
+    ) -> Result<Arc<String>>;
+    /// Completes the prompt without the conversation history.
+    async fn get_stateless_completion(
+        &self,
+        prompt: String,
+    ) -> Result<Arc<String>>;
+    /// A few sentences about `messages`, to stand in for them in the
+    /// history.
+    async fn summarize_history(
+        &self,
+        messages: &[Message],
+    ) -> Result<Arc<String>>;
+    async fn get_smart_completion(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<Arc<String>>;
+    async fn get_reasoning_completion(
+        &self,
+        user_id: i64,
+        prompt: String,
+    ) -> Result<Arc<String>>;
+    async fn get_model_completion(
+        &self,
+        user_id: i64,
+        model: &'static str,
+        prompt: String,
+    ) -> Result<Arc<String>>;
+    /// The fast and the smart models.
+    fn models(&self) -> [&'static str; 2];
+    /// Completion with `system_prompt` put before the base rules.
+    async fn get_system_prompt_completion(
+        &self,
+        user_id: i64,
+        system_prompt: &str,
+        prompt: String,
+    ) -> Result<Arc<String>>;
+    async fn get_code_review_completion(
+        &self,
+        user_id: i64,
+        rules: &str,
+        prompt: String,
+    ) -> Result<Arc<String>>;
+    async fn get_image_completion(
+        &self,
+        user_id: i64,
+        text: String,
+        image_url: String,
+    ) -> Result<Arc<String>>;
+    /// All images go into a single request.
+    async fn get_multi_image_completion(
+        &self,
+        user_id: i64,
+        text: String,
+        image_urls: Vec<String>,
+    ) -> Result<Arc<String>>;
+    fn supports_audio_input(&self) -> bool;
+    async fn get_audio_completion(
+        &self,
+        user_id: i64,
+        audio: Vec<u8>,
+        format: &'static str,
+    ) -> Result<Arc<String>>;
+    async fn get_image(
+        &self,
+        user_id: i64,
+        prompt: &str,
+        options: DrawOptions,
+    ) -> Result<ImageContent>;
+
+    /// Redraws the photo by `prompt`, the result is a PNG.
+    async fn get_image_variation(
+        &self,
+        user_id: i64,
+        image_bytes: Vec<u8>,
+        prompt: &str,
+    ) -> Result<Vec<u8>>;
+
+    async fn get_embedding(&self, text: &str) -> Result<Vec<f32>>;
 
     async fn get_audio(&self, prompt: &str) -> Result<Vec<u8>>;
+
+    /// `mime_type` is the Telegram one, voice notes are OGG/Opus.
+    async fn transcribe_audio(
+        &self,
+        audio: Vec<u8>,
+        mime_type: &str,
+    ) -> Result<Arc<String>>;
+
+    async fn migrate_rules(
+        &self,
+        old_rules: &str,
+        new_rules: &str,
+    ) -> Result<usize>;
+
+    async fn list_available_models(&self) -> Result<Vec<String>>;
+
+    fn usage_stats(&self) -> Arc<UsageStats>;
+
+    async fn add_system_message(
+        &self,
+        user_id: i64,
+        text: String,
+    ) -> Result<()>;
+
+    async fn reset_history(&self, user_id: i64) -> Result<()>;
+
+    /// Drops the last question and answer, so a retry isn't asked twice.
+    async fn remove_last_exchange(&self, user_id: i64) -> Result<()>;
+
+    async fn get_history(&self, user_id: i64) -> Result<Vec<StoredMessage>>;
+
+    /// Drops the cached answers, which came from the old history.
+    fn invalidate_cache(&self, user_id: i64);
+
+    /// Share of the history limit the user's history used before the last
+    /// request, above 1.0 the oldest messages were dropped.
+    fn history_fill_ratio(&self, user_id: i64) -> f64;
+}
+
+/// The o-series reasoning models, e.g. o1-mini or o3.
+fn is_reasoning_model(model: &str) -> bool {
+    let mut chars = model.chars();
+    chars.next() == Some('o')
+        && chars.next().is_some_and(|c| c.is_ascii_digit())
+}
+
+fn request_temperature(model: &str, temperature: f64) -> Option<f64> {
+    Some(temperature).filter(|_| !is_reasoning_model(model))
+}
+
+/// Content policy rejections won't pass on a retry, no more than other
+/// rejected requests, e.g. `context_length_exceeded`.
+fn api_error(status: StatusCode, body: String) -> anyhow::Error {
+    if body.contains("content_policy_violation")
+        || body.contains("content_filter")
+    {
+        ProcessingError::Permanent(body).into()
+    } else if body.contains("invalid_image_url") {
+        GptError::ImageUnavailable.into()
+    } else {
+        status_error(status, body)
+    }
+}
+
+fn audio_file_name(mime_type: &str) -> &'static str {
+    match mime_type {
+        "audio/mpeg" | "audio/mp3" => "voice.mp3",
+        "audio/mp4" | "audio/m4a" | "audio/x-m4a" => "voice.m4a",
+        "audio/wav" | "audio/x-wav" => "voice.wav",
+        _ => "voice.ogg",
+    }
+}
+
+pub(crate) fn estimate_tokens(messages: &[StoredMessage]) -> usize {
+    messages
+        .iter()
+        .map(|stored| stored.message.value().estimate_tokens())
+        .sum()
+}
+
+/// A hash of the messages in `history`, which changes with every answer.
+pub(crate) fn history_version(history: &[StoredMessage]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for stored in history {
+        if let Ok(json) = serde_json::to_string(&stored.message) {
+            json.hash(&mut hasher);
+        }
+    }
+
+    hasher.finish()
+}
+
+/// The version of `history` if it has only System messages, so answers
+/// are only shared by conversations with the same rules.
+fn fresh_conversation(history: &[StoredMessage]) -> Option<u64> {
+    history
+        .iter()
+        .all(|stored| matches!(stored.message, Message::System(_)))
+        .then(|| history_version(history))
+}
+
+/// Removes the last User message and the Assistant answer after it,
+/// returning false if the history doesn't end with such a pair.
+pub(crate) fn pop_last_exchange(history: &mut Vec<StoredMessage>) -> bool {
+    let [.., question, answer] = history.as_slice() else {
+        return false;
+    };
+    if !matches!(
+        (&question.message, &answer.message),
+        (Message::User(_), Message::Assistant(_))
+    ) {
+        return false;
+    }
+
+    history.truncate(history.len() - 2);
+    true
+}
+
+/// Drops the oldest non-System messages until the rest fit `max_tokens`.
+pub(crate) fn prune_messages(
+    messages: &mut Vec<StoredMessage>,
+    max_tokens: usize,
+) {
+    let mut tokens = estimate_tokens(messages);
+
+    while tokens > max_tokens {
+        let Some(index) = messages
+            .iter()
+            .position(|stored| !matches!(stored.message, Message::System(_)))
+        else {
+            break;
+        };
+
+        tokens -= messages.remove(index).message.value().estimate_tokens();
+    }
+}
+
+/// Shrinks `messages` below `target_tokens` without calling GPT: repeated
+/// questions keep only their most detailed answer, then the oldest blocks
+/// of messages are folded into System summaries. The recent messages and
+/// a leading System message are kept as is.
+fn compress_conversation(
+    messages: Vec<StoredMessage>,
+    target_tokens: usize,
+) -> Vec<StoredMessage> {
+    if estimate_tokens(&messages) <= target_tokens {
+        return messages;
+    }
+
+    let mut messages = remove_redundant_exchanges(messages);
+
+    while estimate_tokens(&messages) > target_tokens {
+        let start = usize::from(matches!(
+            messages.first().map(|stored| &stored.message),
+            Some(Message::System(_))
+        ));
+        let end = messages.len().saturating_sub(RECENT_MESSAGES);
+
+        let Some(block_start) = (start..end.saturating_sub(SUMMARY_BLOCK - 1))
+            .find(|&index| {
+                messages[index..index + SUMMARY_BLOCK]
+                    .iter()
+                    .all(|stored| !matches!(stored.message, Message::System(_)))
+            })
+        else {
+            break;
+        };
+
+        let block_end = messages[block_start..end]
+            .iter()
+            .position(|stored| matches!(stored.message, Message::System(_)))
+            .map_or(end, |offset| block_start + offset);
+
+        let summary = summarize(&messages[block_start..block_end]);
+        messages.splice(block_start..block_end, [summary]);
+    }
+
+    messages
+}
+
+fn remove_redundant_exchanges(
+    messages: Vec<StoredMessage>,
+) -> Vec<StoredMessage> {
+    let question = |index: usize| match &messages[index].message {
+        Message::User(value) => Some(normalize(value.text())),
+        _ => None,
+    };
+    let answer_len = |index: usize| match messages.get(index + 1) {
+        Some(StoredMessage {
+            message: Message::Assistant(value),
+            ..
+        }) => value.text().chars().count(),
+        _ => 0,
+    };
+
+    let mut best = std::collections::HashMap::<String, usize>::new();
+    for index in 0..messages.len() {
+        let Some(question) = question(index).filter(|q| !q.is_empty()) else {
+            continue;
+        };
+        best.entry(question)
+            .and_modify(|best| {
+                if answer_len(index) >= answer_len(*best) {
+                    *best = index;
+                }
+            })
+            .or_insert(index);
+    }
+
+    let mut removed = vec![false; messages.len()];
+    for index in 0..messages.len() {
+        let Some(question) = question(index) else {
+            continue;
+        };
+        if best.get(&question).is_some_and(|&best| best != index) {
+            removed[index] = true;
+            if let Some(StoredMessage {
+                message: Message::Assistant(_),
+                ..
+            }) = messages.get(index + 1)
+            {
+                removed[index + 1] = true;
+            }
+        }
+    }
+
+    messages
+        .into_iter()
+        .zip(removed)
+        .filter_map(|(message, removed)| (!removed).then_some(message))
+        .collect()
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Puts a System message with `summary` in place of the first `split`
+/// messages, keeping the System messages among them.
+fn replace_with_summary(
+    history: &mut Vec<StoredMessage>,
+    split: usize,
+    summary: &str,
+) {
+    let (system, summarized): (Vec<_>, Vec<_>) = history
+        .drain(..split)
+        .partition(|stored| matches!(stored.message, Message::System(_)));
+    let summary = StoredMessage {
+        message: Message::System(Value::Plain(
+            format!("Summary of earlier messages: {summary}").into(),
+        )),
+        timestamp: summarized.last().and_then(|stored| stored.timestamp),
+    };
+    history.splice(..0, system.into_iter().chain([summary]));
+}
+
+/// The summary keeps the time of the last summarized message.
+fn summarize(messages: &[StoredMessage]) -> StoredMessage {
+    let mut summary = String::from("Summary of earlier messages:");
+    for StoredMessage { message, .. } in messages {
+        let role = match message {
+            Message::User(_) => "user",
+            Message::Assistant(_) => "assistant",
+            Message::System(_) => "system",
+        };
+        let line = message.value().text().lines().next().unwrap_or_default();
+        let line: String = line.chars().take(SUMMARY_LINE_CHARS).collect();
+        summary.push_str(&format!("\n{role}: {line}"));
+    }
+
+    StoredMessage {
+        message: Message::System(Value::Plain(summary.into())),
+        timestamp: messages.last().and_then(|stored| stored.timestamp),
+    }
+}
+
+/// Picks the available model sharing the most leading `-` separated parts
+/// with `model`, preferring the shortest name, e.g. `gpt-4o` for
+/// `gpt-4o-2099-01-01`. A model sharing nothing is never picked.
+fn closest_model<'a>(model: &str, available: &'a [String]) -> Option<&'a str> {
+    available
+        .iter()
+        .map(|id| {
+            let common = model
+                .split('-')
+                .zip(id.split('-'))
+                .take_while(|(a, b)| a == b)
+                .count();
+            (common, id)
+        })
+        .filter(|&(common, _)| common > 0)
+        .max_by_key(|&(common, id)| (common, std::cmp::Reverse(id.len())))
+        .map(|(_, id)| id.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::circuit_breaker::CircuitBreaker;
+    use crate::conversation_store::InMemoryConversationStore;
+    use crate::event_handler::ProcessingError;
+    use crate::gpt_client::{
+        audio_file_name, closest_model, compress_conversation, estimate_tokens,
+        fresh_conversation, pop_last_exchange, prune_messages,
+        replace_with_summary, request_temperature, Content, DrawOptions,
+        GtpClient, GtpInteractor, ImageQuality, ImageStyle, Message, Request,
+        Response, SizeKeywords, StoredMessage, Tool, ToolMessage, Value,
+        WebSearchArguments, GPT_UNAVAILABLE,
+    };
+
+    fn plain(text: &str) -> Value {
+        Value::Plain(text.to_string().into())
+    }
+
+    fn stored(messages: Vec<Message>) -> Vec<StoredMessage> {
+        messages.into_iter().map(StoredMessage::from).collect()
+    }
+
+    fn texts(messages: &[StoredMessage]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|stored| stored.message.value().text())
+            .collect()
+    }
+
+    // Test that a stream isn't requested while the circuit is open
+    #[tokio::test]
+    async fn test_completion_stream_with_open_circuit() {
+        let mut circuit_breaker = CircuitBreaker::new(Duration::from_secs(1));
+        circuit_breaker.set_failure_threshold(1, Duration::from_secs(60));
+        circuit_breaker.record_failure();
+        let client = GtpClient::<InMemoryConversationStore>::new(
+            "http://127.0.0.1:9/chat",
+            "gpt-4o",
+            "gpt-4o",
+            "onyx",
+            "token",
+            String::new(),
+            Arc::new(circuit_breaker),
+        );
+
+        let Err(error) = client.get_completion_stream(1, "Hi".into()).await
+        else {
+            panic!("The stream was requested");
+        };
+        assert!(matches!(
+            error.downcast_ref::<ProcessingError>(),
+            Some(ProcessingError::Ignorable(text)) if text == GPT_UNAVAILABLE
+        ));
+    }
+
+    #[test]
+    fn test_reasoning_model_request() {
+        assert_eq!(request_temperature("gpt-4o", 0.7), Some(0.7));
+        assert_eq!(request_temperature("o1-mini", 0.7), None);
+        assert_eq!(request_temperature("o3", 0.7), None);
+
+        let messages = [Message::User(plain("Hi"))];
+        let request = Request::new("o3", &messages, None, false, None);
+        let json = serde_json::to_value(&request).unwrap();
+
+        assert!(json.get("temperature").is_none());
+        assert_eq!(json["model"], "o3");
+    }
+
+    #[test]
+    fn test_closest_model() {
+        let available = ["gpt-4o-mini", "gpt-4o", "gpt-3.5-turbo"]
+            .map(str::to_string)
+            .to_vec();
+
+        assert_eq!(
+            closest_model("gpt-4o-2099-01-01", &available),
+            Some("gpt-4o")
+        );
+        assert_eq!(
+            closest_model("gpt-4o-mini-x", &available),
+            Some("gpt-4o-mini")
+        );
+        assert_eq!(
+            closest_model("gpt-3.5-turbo-0301", &available),
+            Some("gpt-3.5-turbo")
+        );
+        assert_eq!(closest_model("gpt-4o", &[]), None);
+        assert_eq!(closest_model("o1-preview", &available), None);
+    }
+
+    #[test]
+    fn test_draw_options_parse() {
+        let keywords = SizeKeywords::default();
+
+        assert_eq!(
+            DrawOptions::parse(" кота", &keywords).unwrap(),
+            (DrawOptions::default(), "кота".to_string())
+        );
+        assert_eq!(
+            DrawOptions::parse(" HD 1792x1024 кота в HD", &keywords).unwrap(),
+            (
+                DrawOptions {
+                    quality: ImageQuality::Hd,
+                    size: "1792x1024",
+                    style: ImageStyle::Vivid,
+                },
+                "кота в HD".to_string()
+            )
+        );
+        assert_eq!(
+            DrawOptions::parse(
+                " кота--мяу --style natural --size 1024x1792",
+                &keywords
+            )
+            .unwrap(),
+            (
+                DrawOptions {
+                    quality: ImageQuality::Standard,
+                    size: "1024x1792",
+                    style: ImageStyle::Natural,
+                },
+                "кота--мяу".to_string()
+            )
+        );
+        assert_eq!(
+            DrawOptions::parse("quality:high кота", &keywords)
+                .unwrap()
+                .0
+                .quality,
+            ImageQuality::Hd
+        );
+        assert!(DrawOptions::parse("512x512 кота", &keywords).is_err());
+        assert!(DrawOptions::parse("HD SD кота", &keywords).is_err());
+        assert!(
+            DrawOptions::parse("1024x1024 1792x1024 кота", &keywords).is_err()
+        );
+        assert!(DrawOptions::parse("quality:low кота", &keywords).is_err());
+        assert!(DrawOptions::parse("кота --style dark", &keywords).is_err());
+        assert!(DrawOptions::parse("кота --quality", &keywords).is_err());
+        assert!(DrawOptions::parse("кота --seed 1", &keywords).is_err());
+        assert!(DrawOptions::parse("HD кота --quality hd", &keywords).is_err());
+    }
+
+    #[test]
+    fn test_draw_options_size_keywords() {
+        let keywords = SizeKeywords::default();
+
+        assert_eq!(
+            DrawOptions::parse("горизонтальный пейзаж с котом", &keywords)
+                .unwrap(),
+            (
+                DrawOptions {
+                    size: "1792x1024",
+                    ..DrawOptions::default()
+                },
+                "пейзаж с котом".to_string()
+            )
+        );
+        assert_eq!(
+            DrawOptions::parse("cat, Portrait", &keywords).unwrap(),
+            (
+                DrawOptions {
+                    size: "1024x1792",
+                    ..DrawOptions::default()
+                },
+                "cat,".to_string()
+            )
+        );
+        assert_eq!(
+            DrawOptions::parse("wide\nкот над горизонтом, widely", &keywords)
+                .unwrap(),
+            (
+                DrawOptions {
+                    size: "1792x1024",
+                    ..DrawOptions::default()
+                },
+                "кот над горизонтом, widely".to_string()
+            )
+        );
+        assert_eq!(
+            DrawOptions::parse("кот\nна закате wide", &keywords)
+                .unwrap()
+                .1,
+            "кот\nна закате"
+        );
+        // An explicit size wins, the keyword stays in the prompt.
+        assert_eq!(
+            DrawOptions::parse("1024x1024 wide cat", &keywords).unwrap(),
+            (DrawOptions::default(), "wide cat".to_string())
+        );
+
+        let keywords =
+            SizeKeywords::from_json(r#"{"1024x1792": ["Tall"]}"#).unwrap();
+        assert_eq!(
+            DrawOptions::parse("tall wide cat", &keywords)
+                .unwrap()
+                .0
+                .size,
+            "1024x1792"
+        );
+        assert!(SizeKeywords::from_json(r#"{"512x512": ["small"]}"#).is_err());
+        assert!(SizeKeywords::from_json(r#"{"1024x1792": [" "]}"#).is_err());
+    }
+
+    #[test]
+    fn test_compress_conversation_removes_repeated_questions() {
+        let messages = stored(vec![
+            Message::System(plain("rules")),
+            Message::User(plain("What is Rust?")),
+            Message::Assistant(plain("A language")),
+            Message::User(plain("what is  rust?")),
+            Message::Assistant(plain("A systems programming language")),
+            Message::User(plain("Thanks")),
+            Message::Assistant(plain("You are welcome")),
+        ]);
+
+        let compressed = compress_conversation(messages, 10);
+
+        assert_eq!(
+            texts(&compressed),
+            [
+                "rules",
+                "what is  rust?",
+                "A systems programming language",
+                "Thanks",
+                "You are welcome"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compress_conversation_summarizes_old_messages() {
+        let mut messages = vec![Message::System(plain("rules"))];
+        for index in 0..10 {
+            let text = format!("{index} {}", "x".repeat(1000));
+            messages.push(match index % 2 {
+                0 => Message::User(plain(&text)),
+                _ => Message::Assistant(plain(&text)),
+            });
+        }
+        let messages = stored(messages);
+        let target = estimate_tokens(&messages) / 2;
+
+        let compressed = compress_conversation(messages.clone(), target);
+
+        assert!(estimate_tokens(&compressed) <= target);
+        assert_eq!(compressed[0].message.value().text(), "rules");
+        assert!(matches!(compressed[1].message, Message::System(_)));
+        assert!(compressed[1]
+            .message
+            .value()
+            .text()
+            .starts_with("Summary of earlier messages:\nuser: 0 x"));
+        assert_eq!(
+            texts(&compressed[compressed.len() - 4..]),
+            texts(&messages[messages.len() - 4..])
+        );
+
+        let small = compress_conversation(messages.clone(), usize::MAX);
+        assert_eq!(small.len(), messages.len());
+    }
+
+    #[test]
+    fn test_prune_messages_drops_oldest() {
+        let long = "a".repeat(400);
+        let mut messages = stored(vec![
+            Message::System(plain(&long)),
+            Message::User(plain("first")),
+            Message::Assistant(plain(&long)),
+            Message::User(plain("last")),
+        ]);
+
+        prune_messages(&mut messages, 110);
+
+        assert_eq!(texts(&messages), vec![long.as_str(), "last"]);
+
+        prune_messages(&mut messages, 10);
+
+        assert_eq!(texts(&messages), vec![long.as_str()]);
+    }
+
+    #[test]
+    fn test_replace_with_summary() {
+        let mut messages = stored(vec![
+            Message::System(plain("rules")),
+            Message::User(plain("first")),
+            Message::Assistant(plain("second")),
+            Message::User(plain("third")),
+            Message::Assistant(plain("fourth")),
+        ]);
+
+        replace_with_summary(&mut messages, 3, "They talked.");
+
+        assert_eq!(
+            texts(&messages),
+            vec![
+                "rules",
+                "Summary of earlier messages: They talked.",
+                "third",
+                "fourth"
+            ]
+        );
+        assert!(matches!(messages[1].message, Message::System(_)));
+    }
+
+    #[test]
+    fn test_audio_file_name() {
+        assert_eq!(audio_file_name("audio/ogg"), "voice.ogg");
+        assert_eq!(audio_file_name("audio/mpeg"), "voice.mp3");
+        assert_eq!(audio_file_name("audio/x-m4a"), "voice.m4a");
+    }
+
+    #[test]
+    fn test_fresh_conversation() {
+        let rules = stored(vec![Message::System(plain("rules"))]);
+        let other_rules = stored(vec![Message::System(plain("other"))]);
+        let mut history = rules.clone();
+        history.extend(stored(vec![Message::User(plain("Hello"))]));
+
+        assert!(fresh_conversation(&rules).is_some());
+        assert_ne!(
+            fresh_conversation(&rules),
+            fresh_conversation(&other_rules)
+        );
+        assert_eq!(fresh_conversation(&history), None);
+    }
+
+    #[test]
+    fn test_pop_last_exchange() {
+        let mut messages = stored(vec![
+            Message::System(plain("rules")),
+            Message::User(plain("first")),
+            Message::Assistant(plain("second")),
+        ]);
+
+        assert!(pop_last_exchange(&mut messages));
+        assert_eq!(texts(&messages), vec!["rules"]);
+        assert!(!pop_last_exchange(&mut messages));
+        assert_eq!(texts(&messages), vec!["rules"]);
+    }
+
+    #[test]
+    fn test_without_images() {
+        let message = Message::User(Value::Complex(vec![
+            Content::Text {
+                text: Arc::new("What is it?".to_string()),
+            },
+            Content::ImageUrl {
+                image_url: Arc::new(
+                    "https://api.telegram.org/file/bot123:token/photo.jpg"
+                        .to_string(),
+                )
+                .into(),
+            },
+        ]));
+
+        let json = serde_json::to_string(&message.without_images()).unwrap();
+
+        assert!(!json.contains("token"));
+        assert!(json.contains("[изображение]"));
+        assert!(json.contains("What is it?"));
+    }
+
+    #[test]
+    fn test_tool_calls_round_trip() {
+        let response: Response = serde_json::from_str(
+            r#"{
+                "id": "1",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "gpt",
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": null,
+                        "tool_calls": [{
+                            "id": "call_1",
+                            "type": "function",
+                            "function": {
+                                "name": "web_search",
+                                "arguments": "{\"url\": \"https://a.b\"}"
+                            }
+                        }]
+                    },
+                    "finish_reason": "tool_calls"
+                }],
+                "usage": {
+                    "prompt_tokens": 1,
+                    "completion_tokens": 1,
+                    "total_tokens": 2
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let tool_calls = &response.choices[0].message.tool_calls;
+        assert_eq!(tool_calls[0].tool(), Some(Tool::WebSearch));
+        let arguments: WebSearchArguments = tool_calls[0].arguments().unwrap();
+        assert_eq!(arguments.url, "https://a.b");
+
+        let user_message = Message::User(plain("Hi"));
+        let messages = [
+            ToolMessage::History(&user_message),
+            ToolMessage::Calls {
+                role: "assistant",
+                tool_calls,
+            },
+            ToolMessage::Result {
+                role: "tool",
+                tool_call_id: "call_1",
+                content: "Page",
+            },
+        ];
+        let tools = [Tool::WebSearch];
+        let request =
+            Request::new("gpt", &messages, Some(1.0), false, Some(&tools));
+        let json = serde_json::to_value(&request).unwrap();
+
+        assert_eq!(json["tools"][0]["function"]["name"], "web_search");
+        assert_eq!(json["messages"][0]["content"], "Hi");
+        assert_eq!(json["messages"][1]["tool_calls"][0]["id"], "call_1");
+        assert_eq!(json["messages"][2]["role"], "tool");
+        assert_eq!(json["messages"][2]["tool_call_id"], "call_1");
+    }
 }
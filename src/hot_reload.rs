@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::config_file::resolve_s3_value;
+
+/// Parameters that can change without a Lambda restart.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigSnapshot {
+    pub dummy_answers: Vec<String>,
+    pub tg_bot_allow_chats: Vec<i64>,
+    pub base_rules: String,
+}
+
+/// Polls SSM parameters under `path` and swaps in a new snapshot when one
+/// of them changes. Parameters are named like the env vars they override:
+/// `DUMMY_ANSWERS`, `TG_ALLOW_CHATS` and `GPT_RULES`.
+pub struct HotReloadConfig {
+    client: aws_sdk_ssm::Client,
+    path: String,
+    interval: Duration,
+    snapshot: Arc<ArcSwap<ConfigSnapshot>>,
+    versions: HashMap<String, i64>,
+}
+
+impl HotReloadConfig {
+    pub fn new(
+        client: aws_sdk_ssm::Client,
+        path: String,
+        interval: Duration,
+        snapshot: Arc<ArcSwap<ConfigSnapshot>>,
+    ) -> Self {
+        HotReloadConfig {
+            client,
+            path,
+            interval,
+            snapshot,
+            versions: HashMap::new(),
+        }
+    }
+
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            loop {
+                interval.tick().await;
+                match self.reload().await {
+                    Ok(true) => info!(path = self.path, "Config reloaded"),
+                    Ok(false) => {}
+                    Err(error) => warn!(?error, "Failed to reload config"),
+                }
+            }
+        })
+    }
+
+    async fn reload(&mut self) -> Result<bool> {
+        let mut snapshot = ConfigSnapshot::clone(&self.snapshot.load());
+        let mut changed = false;
+        let mut next_token = None;
+
+        loop {
+            let output = self
+                .client
+                .get_parameters_by_path()
+                .path(&self.path)
+                .with_decryption(true)
+                .set_next_token(next_token)
+                .send()
+                .await?;
+
+            for parameter in output.parameters() {
+                let (Some(name), Some(value)) =
+                    (parameter.name(), parameter.value())
+                else {
+                    continue;
+                };
+
+                if self.versions.get(name) == Some(&parameter.version()) {
+                    continue;
+                }
+
+                let key = name.rsplit('/').next().unwrap_or(name);
+                // The rules may live in S3 like at startup.
+                let value = match key {
+                    "GPT_RULES" => resolve_s3_value(value.to_string()).await?,
+                    _ => value.to_string(),
+                };
+                changed |= apply_parameter(&mut snapshot, key, &value)?;
+                self.versions.insert(name.to_string(), parameter.version());
+            }
+
+            next_token = output.next_token().map(str::to_string);
+            if next_token.is_none() {
+                break;
+            }
+        }
+
+        if changed {
+            self.snapshot.store(Arc::new(snapshot));
+        }
+
+        Ok(changed)
+    }
+}
+
+/// Returns `false` for parameters that are not hot-reloadable.
+fn apply_parameter(
+    snapshot: &mut ConfigSnapshot,
+    key: &str,
+    value: &str,
+) -> Result<bool> {
+    match key {
+        "DUMMY_ANSWERS" => {
+            snapshot.dummy_answers =
+                value.split(',').map(str::to_string).collect();
+        }
+        "TG_ALLOW_CHATS" => {
+            snapshot.tg_bot_allow_chats = value
+                .split(',')
+                .map(|chat_id| chat_id.trim().parse())
+                .collect::<Result<_, _>>()?;
+        }
+        "GPT_RULES" => snapshot.base_rules = value.to_string(),
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hot_reload::{apply_parameter, ConfigSnapshot};
+
+    #[test]
+    fn test_apply_parameter() {
+        let mut snapshot = ConfigSnapshot::default();
+
+        apply_parameter(&mut snapshot, "DUMMY_ANSWERS", "Да,Нет").unwrap();
+        apply_parameter(&mut snapshot, "TG_ALLOW_CHATS", "1, -2").unwrap();
+        apply_parameter(&mut snapshot, "GPT_RULES", "Be nice").unwrap();
+
+        assert_eq!(
+            snapshot,
+            ConfigSnapshot {
+                dummy_answers: vec!["Да".to_string(), "Нет".to_string()],
+                tg_bot_allow_chats: vec![1, -2],
+                base_rules: "Be nice".to_string(),
+            }
+        );
+        assert!(apply_parameter(&mut snapshot, "TG_ALLOW_CHATS", "x").is_err());
+        assert!(!apply_parameter(&mut snapshot, "OTHER", "x").unwrap());
+    }
+}
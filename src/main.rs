@@ -1,43 +1,182 @@
 #![cfg_attr(not(debug_assertions), deny(warnings))]
 
 use std::backtrace::Backtrace;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use aws_config::BehaviorVersion;
 use dotenvy::dotenv;
 use lambda_http::Body::Empty;
-use lambda_http::{http, run, service_fn, Body, Error, Request, Response};
-use tracing::error;
+use lambda_http::{
+    http, run, service_fn, Body, Error, Request, RequestExt, Response,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
 
-use crate::event_handler::EventHandler;
-use crate::gpt_client::GtpClient;
+use crate::anthropic_client::{AnthropicClient, MAX_TEMPERATURE};
+use crate::audit_log::DynamoAuditLog;
+use crate::blocklist::DynamoBlockList;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config_file::{load_config_file, resolve_s3_value};
+use crate::conversation_store::DynamoConversationStore;
+use crate::event_handler::{error_code, EventHandler, ProcessingError};
+use crate::gpt_backend::GptBackend;
+use crate::gpt_client::{GtpClient, GtpInteractor, SizeKeywords, Temperatures};
+use crate::hot_reload::HotReloadConfig;
 use crate::message_processor::{Config, TgBot};
-use crate::tg_client::{Message, TgClient};
+use crate::preamble::{validate_preamble, PREAMBLE_VARIABLES};
+use crate::premium::DynamoPremiumStore;
+use crate::response_cache::ResponseCache;
+use crate::semantic_cache::SemanticCache;
+use crate::tg_client::{
+    Message, TelegramInteractor, TgClient, ALLOWED_UPDATES,
+};
+use crate::usage_stats::UsageStats;
 
+mod anthropic_client;
+mod audit_log;
+mod blocklist;
+mod chunk_splitter;
+mod circuit_breaker;
+mod config_file;
+mod conversation_router;
+mod conversation_store;
 mod event_handler;
+mod gpt_backend;
 mod gpt_client;
+mod hot_reload;
 mod message_processor;
+mod preamble;
+mod premium;
+mod rate_limiter;
+mod response_cache;
+mod retry;
+mod semantic_cache;
 mod tg_client;
+mod translation;
+mod usage_stats;
+mod user_prefs;
+mod web_page;
+
+const PUSH_PATH: &str = "/push";
+const ADMIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+const WEBHOOK_FLAGS: [&str; 2] = ["--setup-webhook", "--teardown-webhook"];
 
 async fn function_handler(
     event: Request,
     tg_bot: &impl EventHandler,
+    webhook_secret: Option<&str>,
 ) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    if let Err(error) = tg_bot.process_event(&event).await {
-        let body = get_request_body(event.body());
-        let backtrace = Backtrace::force_capture();
-        error!({ ?body, ?backtrace, ?error }, "Error in request handler");
+    // Pushes need the secret too, or anyone could broadcast through the bot.
+    if let Some(secret) = webhook_secret.filter(|_| !cfg!(debug_assertions)) {
+        let token = event
+            .headers()
+            .get(SECRET_TOKEN_HEADER)
+            .and_then(|token| token.to_str().ok());
+        if !is_valid_secret(token, secret) {
+            warn!("Request with invalid webhook secret token");
+            let resp = Response::builder()
+                .status(http::StatusCode::UNAUTHORIZED)
+                .body(Empty)?;
+            return Ok(resp);
+        }
+    }
+
+    if event.method() == http::Method::POST
+        && event.raw_http_path() == PUSH_PATH
+    {
+        // Telegram updates may go unverified, pushes never do.
+        if webhook_secret.is_none() && !cfg!(debug_assertions) {
+            warn!("Push refused, TG_WEBHOOK_SECRET is not set");
+            let resp = Response::builder()
+                .status(http::StatusCode::FORBIDDEN)
+                .body(Empty)?;
+            return Ok(resp);
+        }
+
+        return push_handler(event, tg_bot).await;
+    }
+
+    let status = match tg_bot.process_event(&event).await {
+        Ok(_) => http::StatusCode::OK,
+        Err(error) => {
+            let body = get_request_body(event.body());
+            let code = error_code(&error).map(|code| code.as_str());
+            let error = ProcessingError::from(error);
+            match &error {
+                ProcessingError::Ignorable(_) => {
+                    info!({ ?body, error_code = code, %error }, "Ignored request")
+                }
+                ProcessingError::Permanent(_) => {
+                    warn!(
+                        { ?body, error_code = code, %error },
+                        "Request can't be processed"
+                    )
+                }
+                ProcessingError::Retryable(_) => {
+                    let backtrace = Backtrace::force_capture();
+                    error!(
+                        { ?body, error_code = code, ?backtrace, ?error },
+                        "Error in request handler"
+                    )
+                }
+            }
+            error.status_code()
+        }
     };
 
-    let resp = Response::builder()
-        .status(http::StatusCode::OK)
-        .body(Empty)?;
+    let resp = Response::builder().status(status).body(Empty)?;
 
     Ok(resp)
 }
 
-#[inline]
+async fn push_handler(
+    event: Request,
+    tg_bot: &impl EventHandler,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let status = match tg_bot.process_push(&event).await {
+        Ok(_) => http::StatusCode::OK,
+        Err(error) => {
+            let body = get_request_body(event.body());
+            error!({ ?body, ?error }, "Error in push handler");
+            http::StatusCode::BAD_REQUEST
+        }
+    };
+
+    let resp = Response::builder().status(status).body(Empty)?;
+
+    Ok(resp)
+}
+
+/// Telegram sends the `secret_token` of `setWebhook` as is, so it's
+/// compared in constant time.
+fn is_valid_secret(token: Option<&str>, secret: &str) -> bool {
+    let Some(token) = token else {
+        return false;
+    };
+
+    token.len() == secret.len()
+        && token
+            .bytes()
+            .zip(secret.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn wait_for_shutdown_signal() -> std::io::Result<&'static str> {
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    Ok(tokio::select! {
+        _ = sigint.recv() => "SIGINT",
+        _ = sigterm.recv() => "SIGTERM",
+    })
+}
+
 fn get_request_body(body: &Body) -> &str {
     match body {
         Body::Text(text) => text,
@@ -45,18 +184,97 @@ fn get_request_body(body: &Body) -> &str {
     }
 }
 
+/// OpenAI accepts temperatures from 0 to 2.
+fn temperature_env(name: &str, default: f64) -> Result<f64> {
+    let Ok(value) = std::env::var(name) else {
+        return Ok(default);
+    };
+
+    let temperature: f64 = value
+        .parse()
+        .with_context(|| format!("{name} is not a number: {value}"))?;
+    if !(0.0..=2.0).contains(&temperature) {
+        bail!("{name} must be between 0.0 and 2.0, got {temperature}");
+    }
+
+    Ok(temperature)
+}
+
+/// Aliases of all locales from `BOT_ALIASES_JSON`, e.g.
+/// `{"ru": ["бот"], "en": ["bot", "hey bot"]}`, or else from `BOT_ALIAS`.
+fn bot_aliases() -> Result<Vec<&'static str>> {
+    let mut aliases: Vec<&'static str> = match std::env::var("BOT_ALIASES_JSON")
+    {
+        Ok(json) => {
+            let locales: HashMap<String, Vec<String>> =
+                serde_json::from_str(&json).context("BOT_ALIASES_JSON")?;
+            locales
+                .into_values()
+                .flatten()
+                .map(|alias| &*alias.leak())
+                .collect()
+        }
+        Err(_) => std::env::var("BOT_ALIAS")
+            .context("BOT_ALIAS")?
+            .leak()
+            .split(',')
+            .collect(),
+    };
+
+    // "ботик" has to be tried before "бот" to be stripped completely.
+    aliases.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+    aliases.dedup();
+
+    Ok(aliases)
+}
+
 macro_rules! context_env {
     ($name: literal) => {
         std::env::var($name).context($name)?
     };
 }
 
+async fn setup_webhook(tg_client: &TgClient) -> Result<()> {
+    let url = context_env!("WEBHOOK_URL");
+    let secret = std::env::var("TG_WEBHOOK_SECRET").ok();
+
+    tg_client
+        .setup_webhook(&url, secret.as_deref(), &ALLOWED_UPDATES)
+        .await?;
+    info!(url, "Webhook is set up");
+
+    Ok(())
+}
+
+/// Handles `--setup-webhook` and `--teardown-webhook`, which change the
+/// webhook and exit instead of starting the Lambda runtime. With
+/// `WEBHOOK_URL` set the webhook is also set up on every cold start.
+async fn run_webhook_command(flag: &str) -> Result<()> {
+    let tg_client = TgClient::new(context_env!("TG_TOKEN"));
+
+    match flag {
+        "--setup-webhook" => setup_webhook(&tg_client).await,
+        "--teardown-webhook" => {
+            tg_client.teardown_webhook().await?;
+            info!("Webhook is deleted");
+            Ok(())
+        }
+        _ => bail!("Unknown flag {flag}"),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     if cfg!(debug_assertions) {
         dotenv()?;
     }
 
+    if let Ok(config_file) = std::env::var("CONFIG_FILE") {
+        load_config_file(Path::new(&config_file))?;
+    } else if cfg!(debug_assertions) && Path::new("config.json").exists() {
+        load_config_file(Path::new("config.json"))?;
+    }
+
     tracing_subscriber::fmt()
         .json()
         .with_max_level(tracing::Level::INFO)
@@ -66,16 +284,47 @@ async fn main() -> Result<(), Error> {
         .without_time()
         .init();
 
-    let tg_bot_names = context_env!("BOT_ALIAS").leak().split(',').collect();
+    // Other arguments are not the bot's, e.g. ones the Lambda image adds.
+    if let Some(flag) = std::env::args()
+        .nth(1)
+        .filter(|flag| WEBHOOK_FLAGS.contains(&flag.as_str()))
+    {
+        run_webhook_command(&flag).await?;
+        return Ok(());
+    }
+
+    let tg_bot_names = bot_aliases()?;
     let dummy_answers =
         context_env!("DUMMY_ANSWERS").leak().split(',').collect();
 
     let tg_token = context_env!("TG_TOKEN");
-    let gpt_token = context_env!("GPT_TOKEN").leak();
+    let anthropic_token = match std::env::var("GPT_BACKEND").as_deref() {
+        Ok("openai") | Err(_) => None,
+        Ok("anthropic") => Some(context_env!("ANTHROPIC_API_KEY").leak()),
+        Ok(backend) => Err(anyhow!("Unknown GPT_BACKEND {backend}"))?,
+    };
+    // The OpenAI token is optional when Anthropic answers instead.
+    let gpt_token = match anthropic_token {
+        Some(_) => std::env::var("GPT_TOKEN").unwrap_or_default().leak(),
+        None => context_env!("GPT_TOKEN").leak(),
+    };
     let gpt_model = context_env!("GPT_MODEL").leak();
     let gpt_smart_model = context_env!("GPT_SMART_MODEL").leak();
-    let base_rules = context_env!("GPT_RULES");
+    let base_rules = resolve_s3_value(context_env!("GPT_RULES")).await?;
+    let private_base_rules = resolve_s3_value(
+        std::env::var("PRIVATE_GPT_RULES").unwrap_or_default(),
+    )
+    .await?;
     let gtp_preamble = context_env!("GPT_PREAMBLE");
+    if let Err(err) = validate_preamble(&gtp_preamble) {
+        panic!(
+            "Invalid GPT_PREAMBLE template {gtp_preamble:?}: {err}. \
+            Allowed placeholders are {}",
+            PREAMBLE_VARIABLES
+                .map(|name| format!("{{{name}}}"))
+                .join(", ")
+        );
+    }
     let heartbeat_interval_seconds =
         std::env::var("HEARTBEAT_INTERVAL_SECONDS");
     let voice = std::env::var("VOICE").unwrap_or("onyx".to_string()).leak();
@@ -85,27 +334,180 @@ async fn main() -> Result<(), Error> {
         tg_bot_allow_chats.push(chat_id.parse::<i64>()?);
     }
 
+    let mut admin_user_ids = Vec::new();
+
+    if let Ok(user_ids) = std::env::var("ADMIN_USER_IDS") {
+        for user_id in user_ids.split(',') {
+            admin_user_ids.push(user_id.parse::<i64>()?);
+        }
+    }
+
+    let mut admin_chat_ids = Vec::new();
+
+    if let Ok(chat_ids) = std::env::var("ADMIN_CHAT_IDS") {
+        for chat_id in chat_ids.split(',') {
+            admin_chat_ids.push(chat_id.parse::<i64>()?);
+        }
+    }
+
+    let mut blocked_user_ids = HashSet::new();
+
+    if let Ok(user_ids) = std::env::var("TG_BLOCK_USERS") {
+        for user_id in user_ids.split(',') {
+            blocked_user_ids.insert(user_id.parse::<i64>()?);
+        }
+    }
+
+    let mut blocked_chat_ids = HashSet::new();
+
+    if let Ok(chat_ids) = std::env::var("TG_BLOCK_CHATS") {
+        for chat_id in chat_ids.split(',') {
+            blocked_chat_ids.insert(chat_id.parse::<i64>()?);
+        }
+    }
+
     let api_url = std::env::var("GPT_CHAT_URL")
         .map(|s| s.leak() as &'static str)
         .unwrap_or_else(|_| "https://api.openai.com/v1/chat/completions");
 
+    let degraded_threshold_ms = std::env::var("GPT_DEGRADED_THRESHOLD_MS")
+        .map(|ms| ms.parse())
+        .unwrap_or(Ok(10000))?;
+    let mut circuit_breaker =
+        CircuitBreaker::new(Duration::from_millis(degraded_threshold_ms));
+    if let Ok(threshold) = std::env::var("CIRCUIT_BREAKER_THRESHOLD") {
+        let timeout_secs = std::env::var("CIRCUIT_BREAKER_TIMEOUT_SECS")
+            .map(|secs| secs.parse())
+            .unwrap_or(Ok(30))?;
+        circuit_breaker.set_failure_threshold(
+            threshold.parse()?,
+            Duration::from_secs(timeout_secs),
+        );
+    }
+    let circuit_breaker = Arc::new(circuit_breaker);
+
     let tg_client = TgClient::new(tg_token);
-    let gtp_client = GtpClient::new(
+    // Setting the same webhook again is harmless, so each cold start does.
+    if std::env::var("WEBHOOK_URL").is_ok() {
+        if let Err(error) = setup_webhook(&tg_client).await {
+            error!(?error, "Failed to set up webhook");
+        }
+    }
+
+    let mut gtp_client = GtpClient::new(
         api_url,
         gpt_model,
         gpt_smart_model,
         voice,
         gpt_token,
-        base_rules,
+        base_rules.clone(),
+        circuit_breaker.clone(),
     );
-    let private_gtp_client = GtpClient::new(
+    let rules = (base_rules.clone(), private_base_rules.clone());
+    let mut private_gtp_client = GtpClient::new(
         api_url,
         gpt_model,
         gpt_smart_model,
         voice,
         gpt_token,
-        String::default(),
+        private_base_rules,
+        circuit_breaker,
     );
+    let usage_stats = Arc::new(UsageStats::default());
+    gtp_client.set_usage_stats(usage_stats.clone());
+    private_gtp_client.set_usage_stats(usage_stats.clone());
+    if let Ok(audio_model) = std::env::var("GPT_AUDIO_MODEL") {
+        let audio_model = audio_model.leak();
+        gtp_client.set_audio_input_model(audio_model);
+        private_gtp_client.set_audio_input_model(audio_model);
+    }
+    if let Ok(reasoning_model) = std::env::var("GPT_REASONING_MODEL") {
+        let reasoning_model = reasoning_model.leak();
+        gtp_client.set_reasoning_model(reasoning_model);
+        private_gtp_client.set_reasoning_model(reasoning_model);
+    }
+    let max_retries = std::env::var("GPT_MAX_RETRIES")
+        .map(|retries| retries.parse())
+        .unwrap_or(Ok(3))?;
+    let retry_base_delay_ms = std::env::var("GPT_RETRY_BASE_DELAY_MS")
+        .map(|ms| ms.parse())
+        .unwrap_or(Ok(500))?;
+    let retry_base_delay = Duration::from_millis(retry_base_delay_ms);
+    gtp_client.set_retry_policy(max_retries, retry_base_delay);
+    private_gtp_client.set_retry_policy(max_retries, retry_base_delay);
+
+    let max_history_tokens = std::env::var("GPT_MAX_HISTORY_TOKENS")
+        .ok()
+        .map(|max_tokens| max_tokens.parse())
+        .transpose()?;
+    if let Some(max_history_tokens) = max_history_tokens {
+        gtp_client.set_max_history_tokens(max_history_tokens);
+        private_gtp_client.set_max_history_tokens(max_history_tokens);
+    }
+    let cache_ttl = std::env::var("CACHE_TTL_SECONDS")
+        .ok()
+        .map(|cache_ttl| cache_ttl.parse().map(Duration::from_secs))
+        .transpose()?;
+    if let Some(cache_ttl) = cache_ttl {
+        // Private answers must not show up in groups, so no shared cache.
+        gtp_client.set_response_cache(ResponseCache::new(cache_ttl));
+        private_gtp_client.set_response_cache(ResponseCache::new(cache_ttl));
+    }
+    let semantic_cache =
+        std::env::var("SEMANTIC_CACHE").is_ok_and(|enable| enable == "true");
+    if semantic_cache {
+        let threshold = std::env::var("EMBEDDING_SIMILARITY_THRESHOLD")
+            .map(|threshold| threshold.parse())
+            .unwrap_or(Ok(0.95))?;
+        if !(threshold > 0.0 && threshold <= 1.0) {
+            Err(anyhow!("EMBEDDING_SIMILARITY_THRESHOLD must be in (0, 1]"))?;
+        }
+        gtp_client.set_semantic_cache(SemanticCache::new(threshold));
+        private_gtp_client.set_semantic_cache(SemanticCache::new(threshold));
+    }
+    let history_compression = std::env::var("ENABLE_HISTORY_COMPRESSION")
+        .is_ok_and(|enable| enable == "true");
+    if history_compression {
+        gtp_client.set_history_compression(true);
+        private_gtp_client.set_history_compression(true);
+    }
+    let defaults = Temperatures::default();
+    let temperatures = Temperatures {
+        default: temperature_env("GPT_TEMPERATURE", defaults.default)?,
+        smart: temperature_env("GPT_SMART_TEMPERATURE", defaults.smart)?,
+        image: temperature_env(
+            "GPT_IMAGE_COMPLETION_TEMPERATURE",
+            defaults.image,
+        )?,
+    };
+    gtp_client.set_temperatures(temperatures);
+    private_gtp_client.set_temperatures(temperatures);
+    if anthropic_token.is_some() {
+        if temperatures.default.max(temperatures.smart) > MAX_TEMPERATURE {
+            Err(anyhow!("Claude models take temperatures from 0.0 to 1.0"))?;
+        }
+        // Both need OpenAI requests, embeddings and summaries.
+        if semantic_cache || history_compression {
+            warn!(
+                semantic_cache,
+                history_compression,
+                "GPT_BACKEND=anthropic ignores SEMANTIC_CACHE and \
+                ENABLE_HISTORY_COMPRESSION"
+            );
+        }
+    }
+    if anthropic_token.is_none()
+        && std::env::var("VALIDATE_MODELS")
+            .is_ok_and(|validate| validate == "true")
+    {
+        let auto_model = std::env::var("GPT_AUTO_MODEL")
+            .is_ok_and(|auto_model| auto_model == "true");
+        // Both clients use the same API token, so one list does for both.
+        let available = gtp_client.list_available_models().await?;
+        gtp_client.check_models(&available, auto_model)?;
+        private_gtp_client.check_models(&available, auto_model)?;
+    }
+
     let names_map = context_env!("NAMES_MAP");
     let names_map = serde_json::from_str(&names_map)?;
 
@@ -122,14 +524,288 @@ async fn main() -> Result<(), Error> {
             Duration::from_secs(heartbeat_interval_seconds.parse()?);
     }
 
-    let tg_bot = TgBot::new(
+    if let Ok(dedup_window_seconds) = std::env::var("DEDUP_WINDOW_SECONDS") {
+        config.dedup_window =
+            Duration::from_secs(dedup_window_seconds.parse()?);
+    }
+
+    if let Ok(log_timezone) = std::env::var("LOG_TIMEZONE") {
+        config.log_timezone = log_timezone
+            .parse()
+            .map_err(|error| anyhow!("Bad LOG_TIMEZONE: {error}"))?;
+    }
+
+    if let Ok(max_message_age_minutes) =
+        std::env::var("MAX_MESSAGE_AGE_MINUTES")
+    {
+        let max_message_age_minutes: u64 = max_message_age_minutes.parse()?;
+        config.max_message_age =
+            Duration::from_secs(max_message_age_minutes * 60);
+    }
+
+    if let Ok(rate_limit_count) = std::env::var("RATE_LIMIT_COUNT") {
+        config.rate_limit_count = Some(rate_limit_count.parse()?);
+    }
+
+    if let Ok(rate_limit_window_seconds) =
+        std::env::var("RATE_LIMIT_WINDOW_SECONDS")
+    {
+        config.rate_limit_window =
+            Duration::from_secs(rate_limit_window_seconds.parse()?);
+    }
+
+    if let Ok(buffer_ttl_ms) = std::env::var("MEDIA_GROUP_BUFFER_TTL_MS") {
+        config.media_group_delay =
+            Duration::from_millis(buffer_ttl_ms.parse()?);
+    }
+
+    let warn_threshold_ratio = std::env::var("HISTORY_WARN_THRESHOLD_RATIO")
+        .map(|ratio| ratio.parse())
+        .unwrap_or(Ok(0.8))?;
+    if !(warn_threshold_ratio > 0.0 && warn_threshold_ratio < 1.0) {
+        Err(anyhow!("HISTORY_WARN_THRESHOLD_RATIO must be in (0, 1)"))?;
+    }
+    config.warn_threshold_ratio = Some(warn_threshold_ratio);
+
+    if let Ok(cost) = std::env::var("GPT_INPUT_COST_PER_1K") {
+        config.input_cost_per_1k = cost.parse()?;
+    }
+
+    if let Ok(cost) = std::env::var("GPT_OUTPUT_COST_PER_1K") {
+        config.output_cost_per_1k = cost.parse()?;
+    }
+
+    if let Ok(image_rate_limit_count) = std::env::var("IMAGE_RATE_LIMIT_COUNT")
+    {
+        config.image_rate_limit_count = image_rate_limit_count.parse()?;
+    }
+
+    if let Ok(image_rate_limit_window_seconds) =
+        std::env::var("IMAGE_RATE_LIMIT_WINDOW_SECONDS")
+    {
+        config.image_rate_limit_window =
+            Duration::from_secs(image_rate_limit_window_seconds.parse()?);
+    }
+
+    config.admin_user_ids = admin_user_ids;
+    config.admin_chat_ids = admin_chat_ids;
+    config.blocked_user_ids = blocked_user_ids;
+    config.blocked_chat_ids = blocked_chat_ids;
+    config.function_version = std::env::var("AWS_LAMBDA_FUNCTION_VERSION")
+        .unwrap_or("$LATEST".to_string());
+    config.base_rules = base_rules;
+
+    config.code_review_rules = std::env::var("GPT_CODE_REVIEW_RULES").ok();
+    config.enhance_image_prompt = std::env::var("ENHANCE_IMAGE_PROMPT")
+        .is_ok_and(|enable| enable == "true");
+    if let Ok(json) = std::env::var("IMAGE_SIZE_KEYWORDS") {
+        config.image_size_keywords =
+            SizeKeywords::from_json(&json).context("IMAGE_SIZE_KEYWORDS")?;
+    }
+    if let Ok(json) = std::env::var("PREAMBLE_TRANSLATIONS") {
+        let translations: HashMap<String, String> =
+            serde_json::from_str(&json).context("PREAMBLE_TRANSLATIONS")?;
+        for (lang, preamble) in &translations {
+            validate_preamble(preamble)
+                .context(format!("PREAMBLE_TRANSLATIONS {lang}"))?;
+        }
+        config.preamble_translations = translations;
+    }
+    config.auto_translate_input = std::env::var("AUTO_TRANSLATE_INPUT").ok();
+    config.auto_translate_output = std::env::var("AUTO_TRANSLATE_OUTPUT")
+        .is_ok_and(|enable| enable == "true");
+    config.forget_keyword = std::env::var("FORGET_KEYWORD").ok();
+    config.enable_document_analysis = std::env::var("ENABLE_DOCUMENT_ANALYSIS")
+        .is_ok_and(|enable| enable == "true");
+    config.chat_actions =
+        std::env::var("CHAT_ACTIONS").is_ok_and(|enable| enable == "true");
+    config.reply_context = std::env::var("ENABLE_REPLY_CONTEXT")
+        .is_ok_and(|enable| enable == "true");
+    config.respond_with_voice = std::env::var("RESPOND_WITH_VOICE")
+        .is_ok_and(|enable| enable == "true");
+    config.url_summary = std::env::var("ENABLE_URL_SUMMARY")
+        .is_ok_and(|enable| enable == "true");
+    config.auto_leave_unauthorized = std::env::var("AUTO_LEAVE_UNAUTHORIZED")
+        .is_ok_and(|enable| enable == "true");
+    config.web_search =
+        std::env::var("ENABLE_WEB_SEARCH").is_ok_and(|enable| enable == "true");
+    config.channel_posts = std::env::var("ENABLE_CHANNEL_POSTS")
+        .is_ok_and(|enable| enable == "true");
+    config.quick_actions =
+        std::env::var("QUICK_ACTIONS").is_ok_and(|enable| enable == "true");
+    config.stream_responses =
+        std::env::var("GPT_STREAM").is_ok_and(|enable| enable == "true");
+    config.new_member_greeting = std::env::var("NEW_MEMBER_GREETING").ok();
+    config.left_member_goodbye = std::env::var("LEFT_MEMBER_GOODBYE").ok();
+    config.bot_username = std::env::var("TG_BOT_USERNAME")
+        .ok()
+        .map(|username| username.trim_start_matches('@').to_string());
+    config.thread_conversations = std::env::var("THREAD_CONVERSATIONS")
+        .is_ok_and(|enable| enable == "true");
+    config.welcome_message = std::env::var("START_MESSAGE")
+        .or_else(|_| std::env::var("WELCOME_MESSAGE"))
+        .unwrap_or_default();
+    config.start_capabilities = std::env::var("START_CAPABILITIES")
+        .is_ok_and(|enable| enable == "true");
+
+    if std::env::var("ENABLE_REACTIONS").is_ok_and(|enable| enable == "true") {
+        config.reaction_emojis = std::env::var("REACTION_EMOJIS")
+            .unwrap_or("👍,🤔,🔥".to_string())
+            .split(',')
+            .map(str::to_string)
+            .collect();
+    }
+
+    if let Ok(repost_channel_id) = std::env::var("REPOST_CHANNEL_ID") {
+        config.repost_channel_id = Some(repost_channel_id.parse()?);
+    }
+
+    let audit_log_table = std::env::var("AUDIT_LOG_TABLE").ok();
+    let premium_table = std::env::var("PREMIUM_TABLE").ok();
+    let conversation_table = std::env::var("CONVERSATION_TABLE").ok();
+    let blocklist_table = std::env::var("BLOCKLIST_TABLE").ok();
+
+    let config_ssm_path = std::env::var("CONFIG_SSM_PATH").ok();
+
+    let uses_dynamo = audit_log_table.is_some()
+        || premium_table.is_some()
+        || conversation_table.is_some()
+        || blocklist_table.is_some();
+    let aws_config = if uses_dynamo || config_ssm_path.is_some() {
+        Some(aws_config::load_defaults(BehaviorVersion::latest()).await)
+    } else {
+        None
+    };
+    let dynamo_client = aws_config
+        .as_ref()
+        .filter(|_| uses_dynamo)
+        .map(aws_sdk_dynamodb::Client::new);
+
+    if let (Some(table_name), Some(dynamo_client)) =
+        (conversation_table, &dynamo_client)
+    {
+        gtp_client.set_conversation_store(DynamoConversationStore::new(
+            dynamo_client.clone(),
+            table_name.clone(),
+            "messages",
+        ));
+        private_gtp_client.set_conversation_store(
+            DynamoConversationStore::new(
+                dynamo_client.clone(),
+                table_name,
+                "private_messages",
+            ),
+        );
+    }
+
+    let audit_log = match (audit_log_table, &dynamo_client) {
+        (Some(table_name), Some(dynamo_client)) => {
+            let ttl_days = std::env::var("AUDIT_LOG_TTL_DAYS")
+                .map(|days| days.parse())
+                .unwrap_or(Ok(90))?;
+            Some(DynamoAuditLog::new(
+                dynamo_client.clone(),
+                table_name,
+                ttl_days,
+            ))
+        }
+        _ => None,
+    };
+
+    // The smart model is only paid for when sessions can be stored.
+    let premium_store = match (premium_table, &dynamo_client) {
+        (Some(table_name), Some(dynamo_client)) => {
+            config.smart_price_stars =
+                Some(context_env!("SMART_PRICE_STARS").parse()?);
+            Some(DynamoPremiumStore::new(dynamo_client.clone(), table_name))
+        }
+        _ => None,
+    };
+
+    let block_list = match (blocklist_table, &dynamo_client) {
+        (Some(table_name), Some(dynamo_client)) => {
+            // The bot loads it on the first update and then every minute.
+            Some(DynamoBlockList::new(dynamo_client.clone(), table_name))
+        }
+        _ => None,
+    };
+
+    let notify_on_shutdown = !config.admin_chat_ids.is_empty();
+
+    let (gtp_client, private_gtp_client) = match anthropic_token {
+        Some(token) => {
+            let anthropic_client = |rules| {
+                let mut client = AnthropicClient::new(
+                    gpt_model,
+                    gpt_smart_model,
+                    token,
+                    rules,
+                );
+                client.set_usage_stats(usage_stats.clone());
+                client.set_retry_policy(max_retries, retry_base_delay);
+                client.set_temperatures(temperatures);
+                if let Some(cache_ttl) = cache_ttl {
+                    client.set_response_cache(ResponseCache::new(cache_ttl));
+                }
+                if let Some(max_history_tokens) = max_history_tokens {
+                    client.set_max_history_tokens(max_history_tokens);
+                }
+                client
+            };
+            (
+                GptBackend::Anthropic(anthropic_client(rules.0)),
+                GptBackend::Anthropic(anthropic_client(rules.1)),
+            )
+        }
+        None => (
+            GptBackend::OpenAi(gtp_client),
+            GptBackend::OpenAi(private_gtp_client),
+        ),
+    };
+
+    let mut tg_bot = TgBot::new(
         gtp_client,
         private_gtp_client,
         tg_client,
+        audit_log,
+        premium_store,
         config,
         rand::thread_rng,
     );
 
+    if let Some(block_list) = block_list {
+        tg_bot.set_block_list(block_list);
+    }
+
+    if let (Some(path), Some(aws_config)) = (config_ssm_path, &aws_config) {
+        let interval_secs = std::env::var("CONFIG_RELOAD_INTERVAL_SECS")
+            .map(|secs| secs.parse())
+            .unwrap_or(Ok(60))?;
+        HotReloadConfig::new(
+            aws_sdk_ssm::Client::new(aws_config),
+            path,
+            Duration::from_secs(interval_secs),
+            tg_bot.config_snapshot(),
+        )
+        .spawn();
+    }
+
+    {
+        let tg_bot = tg_bot.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ADMIN_REFRESH_INTERVAL);
+            loop {
+                interval.tick().await;
+                let admins = tg_bot.load_chat_administrators().await;
+                info!(admins, "Chat administrators loaded");
+            }
+        });
+    }
+
+    if let Err(error) = tg_bot.register_commands().await {
+        error!(?error, "Failed to register bot commands");
+    }
+
     if cfg!(debug_assertions) {
         let message_path = Path::new(env!("CARGO_MANIFEST_DIR"));
 
@@ -140,7 +816,49 @@ async fn main() -> Result<(), Error> {
 
         tg_bot.process_message(message).await?;
     } else {
-        run(service_fn(|event| function_handler(event, &tg_bot))).await?;
+        let webhook_secret = std::env::var("TG_WEBHOOK_SECRET").ok();
+        if webhook_secret.is_none() {
+            warn!(
+                "TG_WEBHOOK_SECRET is not set, requests are not verified \
+                and /push is disabled"
+            );
+        }
+
+        let handler = run(service_fn(|event| {
+            function_handler(event, &tg_bot, webhook_secret.as_deref())
+        }));
+
+        if notify_on_shutdown {
+            // Lambda only sends SIGTERM before shutdown to runtimes that
+            // have an extension registered, so register one with no events.
+            let extension = lambda_extension::Extension::new()
+                .with_events(&[])
+                .with_extension_name("gpt-tg-bot-shutdown")
+                .register()
+                .await?;
+            tokio::spawn(async move {
+                if let Err(error) = extension.run().await {
+                    error!(?error, "Shutdown extension failed");
+                }
+            });
+
+            // SIGTERM comes once the environment is idle, so no request is
+            // cut off when main returns.
+            tokio::select! {
+                result = handler => result?,
+                reason = wait_for_shutdown_signal() => {
+                    let reason = reason?;
+                    if let Err(error) = tg_bot.notify_shutdown(reason).await {
+                        error!(
+                            ?error,
+                            "Failed to notify admins about shutdown"
+                        );
+                    }
+                }
+            }
+        } else {
+            handler.await?;
+        }
     }
 
     Ok(())
@@ -1,51 +1,509 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io::Write;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::bail;
+use arc_swap::ArcSwap;
+use base64::prelude::*;
 use chrono::Utc;
+use chrono_tz::Tz;
+use dashmap::{DashMap, DashSet};
 use derive_more::Constructor;
 use derive_new::new;
-use dyn_fmt::AsStrFormatExt;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::lock::Mutex;
+use futures::StreamExt;
 use lambda_http::{Request, RequestPayloadExt};
 use rand::seq::SliceRandom;
 use rand::Rng;
+use serde::Deserialize;
 use thiserror::Error;
 use tokio::sync::oneshot;
 use tokio::time::Instant;
-use tracing::{error, info, span, warn, Instrument, Span};
+use tracing::{debug, error, info, instrument, span, warn, Instrument, Span};
 
-use crate::event_handler::EventHandler;
-use crate::gpt_client::GtpInteractor;
+use crate::anthropic_client::UNSUPPORTED;
+use crate::audit_log::{
+    AuditLogStore, CommandType, ResponseStatus, SecurityAuditLog,
+};
+use crate::blocklist::DynamoBlockList;
+use crate::conversation_router::{conversation_id, ConversationRouter};
+use crate::event_handler::{
+    error_code, is_retryable, ErrorCode, EventHandler, ProcessingError,
+};
+use crate::gpt_client::{
+    DrawOptions, GptError, GtpInteractor, ImageContent, Message as GptMessage,
+    SizeKeywords, StoredMessage, Tool, ToolCall, ToolCallOrText, ToolResult,
+    WebSearchArguments, GPT_UNAVAILABLE,
+};
+use crate::hot_reload::ConfigSnapshot;
+use crate::preamble::format_preamble;
+use crate::premium::PremiumStore;
+use crate::rate_limiter::RateLimiter;
 use crate::tg_client::{
-    Chat, Message, TelegramInteractor, Update, PRIVATE_CHAT,
+    BotCommand, CallbackQuery, Chat, ChatAction, ChatBoostUpdated,
+    ChatMemberUpdated, Document, InlineKeyboardButton, InlineQueryResult,
+    InputTextMessageContent, KeyboardButton, Message, ParseMode, PhotoSize,
+    Poll, PreCheckoutQuery, ReplyMarkup, SentMessage, Sticker,
+    SuccessfulPayment, TelegramInteractor, Update, User, Voice, WebAppData,
+    PRIVATE_CHAT,
+};
+use crate::translation::{
+    detect_language, needs_translation, TranslationClient,
 };
+use crate::user_prefs::{Tone, UserPrefs, TONES};
 
 const DRAW_COMMAND: &str = "нарисуй";
+/// Caption of a photo to redraw it by the rest of the caption.
+const EDIT_COMMAND: &str = "измени";
+const EDIT_PROMPT_MISSING: &str = "Напиши после «измени», что поменять на фото";
+/// Message starts that pin the answer.
+const PIN_EMOJI: &str = "📌";
+const PIN_COMMAND: &str = "закрепи";
+/// Command words that ask for a quiz, the first one is shown in /help.
+const QUIZ_TRIGGERS: [&str; 2] = ["викторина", "викторину"];
+const POLL_TRIGGER: &str = "опрос";
+// Telegram limits of a poll.
+const POLL_QUESTION_LIMIT: usize = 300;
+const POLL_OPTION_LIMIT: usize = 100;
+const CODE_REVIEW_TRIGGERS: [&str; 3] =
+    ["review", "code review", "проверь код"];
+const AUDIT_COMMAND: &str = "/audit";
+const AUDIT_LOG_LIMIT: i32 = 20;
+const SET_RULES_COMMAND: &str = "/setrules";
+const REPOST_COMMAND: &str = "/repost";
+const SET_AVATAR_COMMAND: &str = "/setavatar";
+const TONE_COMMAND: &str = "/tone";
+const MODEL_COMMAND: &str = "/model";
+const START_COMMAND: &str = "/start";
+const HELP_COMMAND: &str = "/help";
+const EXPORT_COMMAND: &str = "/export";
+const EXPORT_PRIVATE_ONLY: &str =
+    "Историю можно выгрузить только в личных сообщениях";
+const DESCRIBE_COMMAND: &str = "/describe";
+const RETRY_COMMAND: &str = "/retry";
+const WHOAMI_COMMAND: &str = "/whoami";
+const STATS_COMMAND: &str = "/stats";
+const USAGE_COMMAND: &str = "/usage";
+const SET_PROMPT_COMMAND: &str = "/setprompt";
+const CLEAR_PROMPT_COMMAND: &str = "/clearprompt";
+const ROLL_COMMAND: &str = "/roll";
+const BLOCK_COMMAND: &str = "/block";
+const UNBLOCK_COMMAND: &str = "/unblock";
+const DICE_EMOJI: &str = "🎲";
+const SMART_TRIGGER: &str = "подумай";
+const REASONING_TRIGGER: &str = "подумай глубоко";
+const FORGET_TRIGGER: &str = "забудь";
+const RATE_LIMIT_MESSAGE: &str = "Подожди немного";
+const DESCRIBE_PROMPT: &str = "Опиши, что нарисовано на этом изображении";
+const EMPTY_RESPONSE_MESSAGE: &str = "Я не могу ответить на этот запрос";
+const STREAM_INTERRUPTED: &str = "Ответ прервался, попробуй ещё раз";
+const NO_PREVIOUS_PROMPT: &str = "Нет предыдущего запроса";
+const IMAGE_UNAVAILABLE: &str = "Картинка уже недоступна, нарисуй новую";
+const HISTORY_WARNING: &str =
+    "История разговора почти заполнена, старые сообщения будут удалены";
+const ACCESS_REQUEST_MESSAGE: &str =
+    "Привет! Доступ к боту пока закрыт, я отправил запрос администратору";
+const QUICK_ACTION_PREFIX: &str = "quick:";
+const CONTINUE_ACTION: &str = "continue";
+const SHORTER_ACTION: &str = "shorter";
+const RESET_ACTION: &str = "reset";
+const DOCUMENT_QUESTION: &str = "Что в этом файле?";
+const DOCUMENT_UNREADABLE: &str = "Не удалось прочитать файл";
+const PHOTO_QUESTION: &str = "Что на картинке?";
+const URL_SUMMARY_PREFIX: &str = "Summarize this:";
+// Keeps a huge file from taking the whole context.
+const DOCUMENT_TEXT_LIMIT: usize = 50_000;
+const PAGE_TEXT_LIMIT: usize = 20_000;
+const EXPORT_GZIP_THRESHOLD: usize = 50 * 1024;
+// Telegram redelivers an update when the Lambda times out or fails.
+const UPDATE_DEDUP_TTL: Duration = Duration::from_secs(10 * 60);
+// How long a `/block` on another instance may take to apply here.
+const BLOCK_LIST_TTL: Duration = Duration::from_secs(60);
+const CHAT_ACTION_INTERVAL: Duration = Duration::from_secs(5);
+// Telegram allows about one edit of a message per second.
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_secs(1);
+// Streamed answers are edited only while they fit into a single message.
+const STREAM_EDIT_LIMIT: usize = 3000;
+const STARS_CURRENCY: &str = "XTR";
+/// The bot sells a single product, the title is also the invoice payload.
+const PREMIUM_TITLE: &str = "Premium";
+const INVOICE_OUTDATED: &str = "Счёт устарел, запроси новый";
+const PREMIUM_SESSION_HOURS: i64 = 24;
+const PREMIUM_BOOST_DAYS: i64 = 7;
+pub(crate) const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+const ADMIN_COMMANDS: [&str; 11] = [
+    AUDIT_COMMAND,
+    SET_RULES_COMMAND,
+    REPOST_COMMAND,
+    SET_AVATAR_COMMAND,
+    WHOAMI_COMMAND,
+    STATS_COMMAND,
+    USAGE_COMMAND,
+    SET_PROMPT_COMMAND,
+    CLEAR_PROMPT_COMMAND,
+    BLOCK_COMMAND,
+    UNBLOCK_COMMAND,
+];
+
+/// Commands only the bot owners may use. Chat admins manage their own chat,
+/// these reach all chats, show the usage or change the bot's personality.
+const GLOBAL_ADMIN_COMMANDS: [&str; 9] = [
+    AUDIT_COMMAND,
+    SET_RULES_COMMAND,
+    REPOST_COMMAND,
+    STATS_COMMAND,
+    USAGE_COMMAND,
+    SET_PROMPT_COMMAND,
+    CLEAR_PROMPT_COMMAND,
+    BLOCK_COMMAND,
+    UNBLOCK_COMMAND,
+];
 
 #[derive(new)]
 pub struct Config {
     name_map: HashMap<String, String>,
     preamble: String,
+    // Startup values, the live ones are read from the config snapshot.
     dummy_answers: Vec<&'static str>,
     tg_bot_allow_chats: Vec<i64>,
     tg_bot_names: Vec<&'static str>,
+    /// The bot's Telegram username, so `/help@other_bot` in a group is
+    /// left to that bot. Commands for any bot are taken without it.
+    #[new(default)]
+    pub bot_username: Option<String>,
     #[new(value = "std::time::Duration::from_secs(20)")]
     pub message_delay: Duration,
+    #[new(default)]
+    pub admin_user_ids: Vec<i64>,
+    #[new(default)]
+    pub admin_chat_ids: Vec<i64>,
+    /// Startup value, `/block` and `/unblock` change the live set.
+    #[new(default)]
+    pub blocked_user_ids: HashSet<i64>,
+    #[new(default)]
+    pub blocked_chat_ids: HashSet<i64>,
+    #[new(default)]
+    pub function_version: String,
+    #[new(default)]
+    pub base_rules: String,
+    #[new(default)]
+    pub repost_channel_id: Option<i64>,
+    #[new(default)]
+    pub code_review_rules: Option<String>,
+    #[new(default)]
+    pub reaction_emojis: Vec<String>,
+    #[new(default)]
+    pub welcome_message: String,
+    /// Group greeting for new members, `{name}` is the member's name.
+    #[new(default)]
+    pub new_member_greeting: Option<String>,
+    #[new(default)]
+    pub left_member_goodbye: Option<String>,
+    #[new(value = "std::time::Duration::from_secs(60)")]
+    pub dedup_window: Duration,
+    #[new(default)]
+    pub smart_price_stars: Option<i32>,
+    /// USD per 1000 prompt tokens, for the `/usage` estimate.
+    #[new(default)]
+    pub input_cost_per_1k: f64,
+    #[new(default)]
+    pub output_cost_per_1k: f64,
+    #[new(value = "chrono_tz::UTC")]
+    pub log_timezone: Tz,
+    #[new(default)]
+    pub enhance_image_prompt: bool,
+    #[new(default)]
+    pub image_size_keywords: SizeKeywords,
+    /// Preambles for groups writing in other languages, keyed by ISO 639-1
+    /// code. The default preamble is Russian.
+    #[new(default)]
+    pub preamble_translations: HashMap<String, String>,
+    #[new(default)]
+    pub auto_translate_input: Option<String>,
+    #[new(default)]
+    pub auto_translate_output: bool,
+    #[new(default)]
+    pub forget_keyword: Option<String>,
+    #[new(default)]
+    pub stream_responses: bool,
+    #[new(default)]
+    pub enable_document_analysis: bool,
+    #[new(default)]
+    pub quick_actions: bool,
+    #[new(default)]
+    pub chat_actions: bool,
+    #[new(default)]
+    pub reply_context: bool,
+    #[new(default)]
+    pub respond_with_voice: bool,
+    #[new(default)]
+    pub url_summary: bool,
+    #[new(default)]
+    pub auto_leave_unauthorized: bool,
+    #[new(default)]
+    pub web_search: bool,
+    #[new(default)]
+    pub channel_posts: bool,
+    /// Gives each reply chain to the bot in a group a conversation of its
+    /// own.
+    #[new(default)]
+    pub thread_conversations: bool,
+    #[new(default)]
+    pub start_capabilities: bool,
+    #[new(default)]
+    pub rate_limit_count: Option<usize>,
+    #[new(value = "std::time::Duration::from_secs(60)")]
+    pub rate_limit_window: Duration,
+    /// Images cost much more than text, so they have a limit of their own.
+    #[new(value = "3")]
+    pub image_rate_limit_count: usize,
+    #[new(value = "std::time::Duration::from_secs(10 * 60)")]
+    pub image_rate_limit_window: Duration,
+    #[new(value = "std::time::Duration::from_secs(10 * 60)")]
+    pub max_message_age: Duration,
+    /// Share of the history limit after which users are warned that old
+    /// messages will be dropped.
+    #[new(default)]
+    pub warn_threshold_ratio: Option<f64>,
+    /// How long to wait for the rest of an album. Telegram can take more
+    /// than a second to deliver all of its photos. Albums are collected in
+    /// memory, so only photos that reach the same instance while it waits
+    /// are answered together. A Lambda environment takes one request at a
+    /// time, there each photo usually waits and is answered on its own.
+    #[new(value = "std::time::Duration::from_secs(2)")]
+    pub media_group_delay: Duration,
 }
 
-#[derive(Constructor)]
-pub struct TgBot<TgClient: TelegramInteractor, GtpClient: GtpInteractor, R: Rng>
-{
-    gtp_client: GtpClient,
-    private_gtp_client: GtpClient,
-    tg_client: TgClient,
-    config: Config,
+/// Photos of an album, which Telegram sends as separate messages.
+#[derive(Debug, Default)]
+struct MediaGroup {
+    caption: Option<String>,
+    photos: Vec<PhotoSize>,
+}
+
+/// The last prompt of a user in a chat, `/retry` asks it again in the
+/// same thread.
+#[derive(Debug, Clone)]
+struct LastPrompt {
+    thread_root: Option<i32>,
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct WebAppRequest {
+    query_id: Option<String>,
+    #[serde(flatten)]
+    command: WebAppCommand,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum WebAppCommand {
+    Ask { text: String },
+    Draw { prompt: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct PushRequest {
+    chat_id: i64,
+    text: String,
+}
+
+/// The user asking and the history the question goes to, their own one
+/// unless the message is part of a thread.
+#[derive(Debug, Clone, Copy)]
+struct Asker<'a> {
+    user: &'a User,
+    thread_root: Option<i32>,
+    conversation_id: i64,
+}
+
+impl<'a> Asker<'a> {
+    fn own(user: &'a User) -> Self {
+        Self::in_thread(user, 0, None)
+    }
+
+    fn in_thread(
+        user: &'a User,
+        chat_id: i64,
+        thread_root: Option<i32>,
+    ) -> Self {
+        Self {
+            user,
+            thread_root,
+            conversation_id: conversation_id(user.id, chat_id, thread_root),
+        }
+    }
+}
+
+/// A poll as GPT writes it, `answer` is the index of the correct option.
+#[derive(Debug, PartialEq, Deserialize)]
+struct PollData {
+    question: String,
+    options: Vec<String>,
+    #[serde(default)]
+    answer: Option<u8>,
+}
+
+pub struct TgBot<
+    TgClient: TelegramInteractor,
+    GtpClient: GtpInteractor,
+    AuditLog: AuditLogStore,
+    Premium: PremiumStore,
+    R: Rng,
+> {
+    gtp_client: Arc<GtpClient>,
+    private_gtp_client: Arc<GtpClient>,
+    tg_client: Arc<TgClient>,
+    audit_log: Option<Arc<AuditLog>>,
+    premium_store: Option<Arc<Premium>>,
+    config: Arc<Config>,
+    snapshot: Arc<ArcSwap<ConfigSnapshot>>,
+    /// The snapshot the conversations got their base rules from.
+    rules_snapshot: Arc<Mutex<Arc<ConfigSnapshot>>>,
+    user_prefs: Arc<DashMap<i64, UserPrefs>>,
+    recent_messages: Arc<DashMap<(i64, i64, u64), Instant>>,
+    recent_updates: Arc<DashMap<i64, Instant>>,
+    chat_admins: Arc<DashMap<(i64, i64), AdminStatus>>,
+    per_chat_model: Arc<DashMap<i64, &'static str>>,
+    /// Model names admins picked with `/model`, leaked once each.
+    model_names: Arc<DashSet<&'static str>>,
+    chat_system_prompts: Arc<DashMap<i64, String>>,
+    media_groups: Arc<DashMap<String, MediaGroup>>,
+    conversation_router: Arc<ConversationRouter>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    image_rate_limiter: Arc<RateLimiter>,
+    blocked_users: Arc<DashSet<i64>>,
+    history_warned_users: Arc<DashSet<i64>>,
+    /// Strangers the admin was already told about.
+    access_requested_users: Arc<DashSet<i64>>,
+    /// The last image drawn for each user, for `/describe`.
+    drawn_images: Arc<DashMap<i64, ImageContent>>,
+    /// The last prompt of each user in each chat, for `/retry`.
+    last_prompts: Arc<DashMap<(i64, i64), LastPrompt>>,
+    block_list: Option<Arc<DynamoBlockList>>,
+    block_list_loaded_at: Arc<Mutex<Option<Instant>>>,
+    started_at: Instant,
     rng: fn() -> R,
 }
 
-impl<TgClient: TelegramInteractor, GtpClient: GtpInteractor, R: Rng>
-    TgBot<TgClient, GtpClient, R>
+impl<
+        TgClient: TelegramInteractor,
+        GtpClient: GtpInteractor,
+        AuditLog: AuditLogStore,
+        Premium: PremiumStore,
+        R: Rng,
+    > Clone for TgBot<TgClient, GtpClient, AuditLog, Premium, R>
+{
+    fn clone(&self) -> Self {
+        TgBot {
+            gtp_client: self.gtp_client.clone(),
+            private_gtp_client: self.private_gtp_client.clone(),
+            tg_client: self.tg_client.clone(),
+            audit_log: self.audit_log.clone(),
+            premium_store: self.premium_store.clone(),
+            config: self.config.clone(),
+            snapshot: self.snapshot.clone(),
+            rules_snapshot: self.rules_snapshot.clone(),
+            user_prefs: self.user_prefs.clone(),
+            recent_messages: self.recent_messages.clone(),
+            recent_updates: self.recent_updates.clone(),
+            chat_admins: self.chat_admins.clone(),
+            per_chat_model: self.per_chat_model.clone(),
+            model_names: self.model_names.clone(),
+            chat_system_prompts: self.chat_system_prompts.clone(),
+            media_groups: self.media_groups.clone(),
+            conversation_router: self.conversation_router.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            image_rate_limiter: self.image_rate_limiter.clone(),
+            blocked_users: self.blocked_users.clone(),
+            history_warned_users: self.history_warned_users.clone(),
+            access_requested_users: self.access_requested_users.clone(),
+            drawn_images: self.drawn_images.clone(),
+            last_prompts: self.last_prompts.clone(),
+            block_list: self.block_list.clone(),
+            block_list_loaded_at: self.block_list_loaded_at.clone(),
+            started_at: self.started_at,
+            rng: self.rng,
+        }
+    }
+}
+
+impl<
+        TgClient: TelegramInteractor,
+        GtpClient: GtpInteractor,
+        AuditLog: AuditLogStore,
+        Premium: PremiumStore,
+        R: Rng,
+    > TgBot<TgClient, GtpClient, AuditLog, Premium, R>
 {
+    pub fn new(
+        gtp_client: GtpClient,
+        private_gtp_client: GtpClient,
+        tg_client: TgClient,
+        audit_log: Option<AuditLog>,
+        premium_store: Option<Premium>,
+        config: Config,
+        rng: fn() -> R,
+    ) -> Self {
+        let snapshot = Arc::new(ConfigSnapshot {
+            dummy_answers: config
+                .dummy_answers
+                .iter()
+                .map(|answer| answer.to_string())
+                .collect(),
+            tg_bot_allow_chats: config.tg_bot_allow_chats.clone(),
+            base_rules: config.base_rules.clone(),
+        });
+
+        TgBot {
+            gtp_client: Arc::new(gtp_client),
+            private_gtp_client: Arc::new(private_gtp_client),
+            tg_client: Arc::new(tg_client),
+            audit_log: audit_log.map(Arc::new),
+            premium_store: premium_store.map(Arc::new),
+            rules_snapshot: Arc::new(Mutex::new(snapshot.clone())),
+            snapshot: Arc::new(ArcSwap::new(snapshot)),
+            rate_limiter: config.rate_limit_count.map(|count| {
+                Arc::new(RateLimiter::new(count, config.rate_limit_window))
+            }),
+            image_rate_limiter: Arc::new(RateLimiter::new(
+                config.image_rate_limit_count,
+                config.image_rate_limit_window,
+            )),
+            blocked_users: Arc::new(
+                config.blocked_user_ids.iter().copied().collect(),
+            ),
+            history_warned_users: Arc::default(),
+            access_requested_users: Arc::default(),
+            drawn_images: Arc::default(),
+            last_prompts: Arc::default(),
+            block_list: None,
+            block_list_loaded_at: Arc::default(),
+            config: Arc::new(config),
+            user_prefs: Arc::default(),
+            recent_messages: Arc::default(),
+            recent_updates: Arc::default(),
+            chat_admins: Arc::default(),
+            per_chat_model: Arc::default(),
+            model_names: Arc::default(),
+            chat_system_prompts: Arc::default(),
+            media_groups: Arc::default(),
+            conversation_router: Arc::default(),
+            started_at: Instant::now(),
+            rng,
+        }
+    }
+
+    pub fn set_block_list(&mut self, block_list: DynamoBlockList) {
+        self.block_list = Some(Arc::new(block_list));
+    }
+
     pub async fn process_message(
         &self,
         message: Message,
@@ -58,6 +516,10 @@ impl<TgClient: TelegramInteractor, GtpClient: GtpInteractor, R: Rng>
         let wait_loop = self.wait_loop(chat_id, duration, tx);
 
         let process_task = async {
+            if let Err(error) = self.sync_base_rules().await {
+                warn!(?error, "Failed to apply reloaded base rules");
+            }
+
             let result = self.process_message_internal(message).await;
 
             rx.close();
@@ -70,6 +532,71 @@ impl<TgClient: TelegramInteractor, GtpClient: GtpInteractor, R: Rng>
         result
     }
 
+    /// Shows `action` in the chat until `task` is done.
+    async fn with_chat_action<T>(
+        &self,
+        chat_id: i64,
+        action: ChatAction,
+        task: impl Future<Output = T>,
+    ) -> T {
+        if !self.config.chat_actions {
+            return task.await;
+        }
+
+        if let Err(error) =
+            self.tg_client.send_chat_action(chat_id, action).await
+        {
+            warn!(?error, "Failed to send chat action");
+            return task.await;
+        }
+
+        let (tx, mut rx) = oneshot::channel::<()>();
+
+        let action_loop = self.chat_action_loop(chat_id, action, tx);
+
+        let process_task = async {
+            let result = task.await;
+
+            rx.close();
+
+            result
+        };
+
+        let (_, result) = tokio::join!(action_loop, process_task);
+
+        result
+    }
+
+    async fn chat_action_loop(
+        &self,
+        chat_id: i64,
+        action: ChatAction,
+        mut tx: oneshot::Sender<()>,
+    ) {
+        // Telegram shows an action for 5 seconds at most.
+        let start = Instant::now() + CHAT_ACTION_INTERVAL;
+        let mut interval =
+            tokio::time::interval_at(start, CHAT_ACTION_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = tx.closed() => {
+                    break;
+                },
+                _ = interval.tick() => {
+                    let result = self.tg_client
+                        .send_chat_action(chat_id, action)
+                        .await;
+
+                    if let Err(error) = result {
+                        warn!(?error, "Failed to send chat action");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
     async fn wait_loop(
         &self,
         chat_id: i64,
@@ -89,7 +616,7 @@ impl<TgClient: TelegramInteractor, GtpClient: GtpInteractor, R: Rng>
                 _ = &mut timeout => {
 
                     let _ = self.tg_client
-                    .send_message(chat_id, "Я не знаю что на это ответить", None)
+                    .send_message(chat_id, "Я не знаю что на это ответить", None, None)
                     .await;
 
                     break;
@@ -100,7 +627,7 @@ impl<TgClient: TelegramInteractor, GtpClient: GtpInteractor, R: Rng>
                 _ = interval.tick() => {
 
                     let result = self.tg_client
-                    .send_message(chat_id, "Погоди, надо еще подумать", None)
+                    .send_message(chat_id, "Погоди, надо еще подумать", None, None)
                     .await;
 
                     match result {
@@ -116,16 +643,148 @@ impl<TgClient: TelegramInteractor, GtpClient: GtpInteractor, R: Rng>
         }
     }
 
+    pub async fn push_message(
+        &self,
+        chat_id: i64,
+        text: &str,
+    ) -> anyhow::Result<()> {
+        self.tg_client
+            .send_message(chat_id, text, None, None)
+            .await
+            .map(|_| ())
+    }
+
     async fn process_message_internal(
         &self,
         message: Message,
     ) -> anyhow::Result<()> {
+        if let Some(payment) = message.successful_payment {
+            return self
+                .process_successful_payment(
+                    &message.from,
+                    &message.chat,
+                    &payment,
+                )
+                .await;
+        }
+
+        if message.new_chat_members.is_some()
+            || message.left_chat_member.is_some()
+        {
+            return self.process_chat_members(&message).await;
+        }
+
         if message.photo.is_some() {
             return self.process_photo(message).await;
         }
 
+        if let Some(voice) = &message.voice {
+            return self.process_voice(&message, voice).await;
+        }
+
+        if let Some(document) = &message.document {
+            return self.process_document(&message, document).await;
+        }
+
+        if let Some(poll) = &message.poll {
+            return self.process_poll(&message, poll).await;
+        }
+
+        if let Some(sticker) = &message.sticker {
+            return self.process_sticker(&message, sticker).await;
+        }
+
+        if let Some(web_app_data) = message.web_app_data {
+            return self
+                .process_web_app_data(
+                    &message.from,
+                    &message.chat,
+                    message.message_id,
+                    &web_app_data,
+                )
+                .await;
+        }
+
         if let Some(text) = message.text {
-            if text.contains("https://") {
+            if self.is_admin_command(&text) {
+                let result = self
+                    .process_admin_command(
+                        &message.from,
+                        &message.chat,
+                        message.reply_to_message.as_deref(),
+                        &text,
+                    )
+                    .await;
+                self.audit(
+                    message.from.id,
+                    message.chat.id,
+                    CommandType::Admin,
+                    &result,
+                )
+                .await;
+
+                return result;
+            }
+
+            if let Some(tone) = self.command_args(&text, TONE_COMMAND) {
+                return self
+                    .process_tone_command(&message.from, &message.chat, tone)
+                    .await;
+            }
+
+            if let Some(model) = self.command_args(&text, MODEL_COMMAND) {
+                return self
+                    .process_model_command(&message.from, &message.chat, model)
+                    .await;
+            }
+
+            if self.command_args(&text, ROLL_COMMAND).is_some() {
+                return self
+                    .process_roll_command(&message.from, &message.chat)
+                    .await;
+            }
+
+            if self.command_args(&text, HELP_COMMAND).is_some() {
+                return self.process_help_command(&message.chat).await;
+            }
+
+            if self.command_args(&text, EXPORT_COMMAND).is_some() {
+                return self
+                    .process_export_command(&message.from, &message.chat)
+                    .await;
+            }
+
+            if self.command_args(&text, DESCRIBE_COMMAND).is_some() {
+                return self
+                    .process_describe_command(
+                        &message.from,
+                        &message.chat,
+                        message.message_id,
+                    )
+                    .await;
+            }
+
+            if self.command_args(&text, RETRY_COMMAND).is_some() {
+                return self
+                    .process_retry_command(
+                        &message.from,
+                        &message.chat,
+                        message.message_id,
+                    )
+                    .await;
+            }
+
+            if self.command_args(&text, START_COMMAND).is_some() {
+                return self
+                    .process_start_command(
+                        &message.from,
+                        &message.chat,
+                        message.message_id,
+                    )
+                    .await;
+            }
+
+            if text.contains("https://") && !self.config.url_summary {
                 self.dummy_reaction(message.chat.id).await?;
 
                 return Ok(());
@@ -142,577 +801,7879 @@ impl<TgClient: TelegramInteractor, GtpClient: GtpInteractor, R: Rng>
                 message.reply_to_message.as_deref(),
                 &message.chat,
                 used_name,
-                &self.config.tg_bot_allow_chats,
+                &self.snapshot.load().tg_bot_allow_chats,
             ) {
+                if self.is_duplicate(message.from.id, message.chat.id, &text) {
+                    info!("Skipping duplicate message");
+                    return Ok(());
+                }
+
                 let text = used_name
                     .map(|name| text.replace(name, ""))
                     .unwrap_or(text);
+                let (pin_answer, text) = match strip_pin_request(&text) {
+                    Some(rest) => (true, rest.to_string()),
+                    None => (false, text),
+                };
+
+                let thread_root = self.thread_root(
+                    &message.chat,
+                    message.reply_to_message.as_deref(),
+                );
+                let asker = Asker::in_thread(
+                    &message.from,
+                    message.chat.id,
+                    thread_root,
+                );
+
+                if self.is_forget_request(&text) {
+                    return self
+                        .process_forget_request(
+                            asker.conversation_id,
+                            &message.chat,
+                            Some(message.message_id),
+                        )
+                        .await;
+                }
 
-                let mut first_name = message.from.first_name;
+                if self.is_rate_limited(&message.from, &message.chat).await? {
+                    return Ok(());
+                }
 
-                for (name, replacement) in &self.config.name_map {
-                    first_name = first_name.replace(name, replacement);
+                // Only a link sent to the bot is downloaded.
+                if text.contains("https://") {
+                    return self
+                        .process_url(
+                            &message.from,
+                            &message.chat,
+                            &text,
+                            message.message_id,
+                        )
+                        .await;
                 }
 
+                let user_id = message.from.id;
+                let command_type = if text.to_lowercase().contains(DRAW_COMMAND)
+                {
+                    CommandType::Draw
+                } else {
+                    CommandType::Text
+                };
+
+                let first_name = self.display_name(&message.from);
+                let reply_context =
+                    self.reply_context(message.reply_to_message.as_deref());
+
                 let span = span!(
                     tracing::Level::INFO,
                     "response",
                     user_name = first_name
                 );
 
-                let _enter = span.enter();
+                async {
+                    let result = self
+                        .process_and_answer(
+                            &message.chat,
+                            asker,
+                            &text,
+                            reply_context,
+                            false,
+                            Some(message.message_id),
+                        )
+                        .await;
 
-                let result = self
-                    .process_and_answer(&message.chat, &text, &first_name)
-                    .await;
+                    self.record_thread_answer(
+                        &message.chat,
+                        message.message_id,
+                        thread_root,
+                        &result,
+                    );
 
-                if let Err(error) = result {
-                    if message.chat.is_private() {
-                        let error_message = format!("```\n{}\n```", &error);
-                        self.tg_client
-                            .send_message(
-                                message.chat.id,
-                                &error_message,
-                                "MarkdownV2".into(),
-                            )
-                            .await?;
-                        return Err(error);
+                    self.audit(user_id, message.chat.id, command_type, &result)
+                        .await;
+
+                    if let (true, Ok(Some(answer))) = (pin_answer, &result) {
+                        self.pin_answer(message.chat.id, answer.message_id)
+                            .await;
+                    }
+
+                    if result.is_ok() {
+                        self.react(message.chat.id, message.message_id).await;
+                    }
+
+                    if let Err(error) = result {
+                        if message.chat.is_private() {
+                            let error_message = format!("```\n{}\n```", &error);
+                            self.tg_client
+                                .send_message(
+                                    message.chat.id,
+                                    &error_message,
+                                    Some(ParseMode::MarkdownV2),
+                                    None,
+                                )
+                                .await?;
+                            return Err(error);
+                        }
                     }
-                }
 
-                info!("Complete");
+                    info!("Complete");
 
-                drop(_enter)
+                    Ok::<_, anyhow::Error>(())
+                }
+                .instrument(span)
+                .await?;
             }
         }
 
         Ok(())
     }
 
+    pub async fn notify_shutdown(&self, reason: &str) -> anyhow::Result<()> {
+        let text = format!(
+            "Бот выключается. Версия: {}. Причина: {reason}",
+            self.config.function_version
+        );
+
+        let mut result = Ok(());
+        for &chat_id in &self.config.admin_chat_ids {
+            let sent = self
+                .tg_client
+                .send_message(chat_id, &text, Some(ParseMode::MarkdownV2), None)
+                .await;
+            if let Err(error) = sent {
+                warn!(?error, chat_id, "Failed to send shutdown notification");
+                result = Err(error);
+            }
+        }
+
+        result
+    }
+
+    /// Triggers come from the user's own `text`, `reply_context` only goes
+    /// to GPT.
     async fn process_and_answer(
         &self,
         chat: &Chat,
+        asker: Asker<'_>,
         text: &str,
-        first_name: &str,
-    ) -> anyhow::Result<()> {
+        reply_context: Option<&str>,
+        voice_answer: bool,
+        reply_to_id: Option<i32>,
+    ) -> anyhow::Result<Option<SentMessage>> {
         if let Some(index) = text.to_lowercase().find(DRAW_COMMAND) {
-            self.process_image_request(text, &index, chat).await?;
+            self.process_image_request(
+                asker.user.id,
+                text,
+                &index,
+                chat,
+                reply_to_id,
+            )
+            .await?;
 
-            return Ok(());
+            return Ok(None);
         }
 
-        self.process_text_message(text, first_name, chat).await?;
+        if let Some((is_quiz, topic)) = poll_kind(text) {
+            self.process_poll_request(chat, topic, is_quiz, reply_to_id)
+                .await?;
 
-        Ok(())
+            return Ok(None);
+        }
+
+        self.process_text_message(
+            text,
+            reply_context,
+            asker,
+            chat,
+            voice_answer,
+            reply_to_id,
+        )
+        .await
     }
 
-    async fn process_text_message(
+    fn is_threaded(&self, chat: &Chat) -> bool {
+        self.config.thread_conversations && !chat.is_private()
+    }
+
+    fn record_thread_answer(
         &self,
-        text: &str,
-        first_name: &str,
         chat: &Chat,
-    ) -> anyhow::Result<()> {
-        let text = if chat.is_private() {
-            text.to_owned()
-        } else {
-            let mut prepend = self.config.preamble.format(&[first_name]);
-            prepend.push_str(text);
-            prepend
-        };
+        message_id: i32,
+        thread_root: Option<i32>,
+        result: &anyhow::Result<Option<SentMessage>>,
+    ) {
+        if let (true, Ok(Some(answer))) = (self.is_threaded(chat), result) {
+            self.conversation_router.record_answer(
+                chat.id,
+                message_id,
+                thread_root,
+                answer.message_id,
+            );
+        }
+    }
 
-        info!("Ask GPT");
+    fn thread_root(
+        &self,
+        chat: &Chat,
+        reply_to_message: Option<&Message>,
+    ) -> Option<i32> {
+        self.is_threaded(chat)
+            .then(|| {
+                self.conversation_router
+                    .thread_root(chat.id, reply_to_message)
+            })
+            .flatten()
+    }
 
-        let result = if chat.is_private()
-            && contains_case_insensitive(&text, "подумай")
+    /// Pinning is a nicety, e.g. the bot may lack the rights in a group.
+    async fn pin_answer(&self, chat_id: i64, message_id: i32) {
+        info!(message_id, "Pinning answer");
+        if let Err(error) =
+            self.tg_client.pin_message(chat_id, message_id).await
         {
-            info!("Smart completion");
-            self.gtp_client(chat)
-                .get_smart_completion(text)
-                .instrument(Span::current())
-                .await?
+            warn!(?error, chat_id, "Failed to pin answer");
+        }
+    }
+
+    /// Asks GPT to write a quiz or a poll about `text` and sends it.
+    async fn process_poll_request(
+        &self,
+        chat: &Chat,
+        text: &str,
+        is_quiz: bool,
+        reply_to_id: Option<i32>,
+    ) -> anyhow::Result<()> {
+        let (kind, answer) = if is_quiz {
+            ("quiz", "the zero-based index of the correct option")
         } else {
-            self.gtp_client(chat)
-                .get_completion(text)
-                .instrument(Span::current())
-                .await?
+            ("poll", "null")
         };
+        let prompt = format!(
+            "Write a {kind} for the request '{}'. Reply only with JSON like \
+             {{\"question\": \"...\", \"options\": [\"...\"], \"answer\": N}} \
+             with 2 to 10 short options, where answer is {answer}",
+            text.trim()
+        );
+        info!(is_quiz, "Poll request");
 
-        info!("Sending answer to TG");
+        // The JSON template must not end up in the conversation history.
+        let result = self
+            .gtp_client(chat)
+            .get_stateless_completion(prompt)
+            .await
+            .and_then(|response| parse_poll_response(&response))
+            .and_then(|poll| match poll.answer {
+                None if is_quiz => bail!("Quiz has no answer"),
+                _ => Ok(poll),
+            });
 
-        if !chat.is_private() {
-            if result.contains("из чата")
-                || result.contains("уйти")
-                || result.contains("выйти")
-                || result.contains("выйду")
-            {
-                let num = self.get_random_number();
-                if num < 20 {
-                    self.tg_client.leave_chat(chat.id).await?;
-                    return Ok(());
-                }
+        let result = match result {
+            Ok(poll) => {
+                let options: Vec<&str> =
+                    poll.options.iter().map(String::as_str).collect();
+                let correct_option_id = poll.answer.filter(|_| is_quiz);
+                self.tg_client
+                    .send_poll(
+                        chat.id,
+                        &poll.question,
+                        &options,
+                        is_quiz,
+                        correct_option_id,
+                    )
+                    .await
             }
+            Err(error) => Err(error),
+        };
 
-            let num = self.get_random_number();
-            if num > 100 {
-                let audio = self.gtp_client(chat).get_audio(&result).await?;
-
-                let res = self.tg_client.send_voice(chat.id, audio).await;
-
-                if let Err(err) = res {
-                    warn!(?err);
-                } else {
-                    return Ok(());
-                }
-            }
+        // The user is told here, so the error is not sent again.
+        if let Err(error) = result {
+            warn!(?error, "Failed to make a poll");
+            self.tg_client
+                .send_message(
+                    chat.id,
+                    "Не получилось составить опрос",
+                    None,
+                    reply_to_id,
+                )
+                .await?;
         }
 
-        self.tg_client
-            .send_message(chat.id, &result, "MarkdownV2".into())
-            .await?;
-
         Ok(())
     }
 
-    async fn process_image_request(
+    async fn process_text_message(
         &self,
         text: &str,
-        index: &usize,
+        reply_context: Option<&str>,
+        asker: Asker<'_>,
         chat: &Chat,
-    ) -> anyhow::Result<()> {
-        let text = &text[index + DRAW_COMMAND.len()..];
-
-        info!("Image request");
+        voice_answer: bool,
+        reply_to_id: Option<i32>,
+    ) -> anyhow::Result<Option<SentMessage>> {
+        self.last_prompts.insert(
+            (chat.id, asker.user.id),
+            LastPrompt {
+                thread_root: asker.thread_root,
+                text: text.to_string(),
+            },
+        );
 
-        let url = self.gtp_client(chat).get_image(text).await;
+        let task = self.process_text_message_internal(
+            text,
+            reply_context,
+            asker,
+            chat,
+            voice_answer,
+            reply_to_id,
+        );
+        let result = self.with_chat_action(chat.id, ChatAction::Typing, task);
 
-        match url {
-            Ok(url) => {
-                self.tg_client.send_image(chat.id, &url).await?;
-            }
-            Err(error) => {
+        match result.await {
+            Err(error)
+                if matches!(
+                    error.downcast_ref::<GptError>(),
+                    Some(GptError::EmptyResponse)
+                ) =>
+            {
+                warn!(?error, "Answering with the empty response message");
                 self.tg_client
                     .send_message(
                         chat.id,
-                        "Сейчас я такое не могу нарисовать",
-                        None,
+                        EMPTY_RESPONSE_MESSAGE,
+                        Some(ParseMode::MarkdownV2),
+                        reply_to_id,
                     )
-                    .await?;
-                return Err(error);
+                    .await
+                    .map(|_| None)
             }
+            // Not worth a retry, but the user should know why.
+            Err(error) => match gpt_notice(&error) {
+                Some(notice) => {
+                    warn!(?error, "Answering with a notice");
+                    self.tg_client
+                        .send_message(chat.id, notice, None, reply_to_id)
+                        .await
+                        .map(|_| None)
+                }
+                None => Err(error),
+            },
+            result => result,
         }
-        Ok(())
     }
 
-    fn gtp_client(&self, chat: &Chat) -> &GtpClient {
-        if chat.is_private() {
-            &self.private_gtp_client
-        } else {
-            &self.gtp_client
+    /// Returns the answer if it was sent as a text message.
+    async fn process_text_message_internal(
+        &self,
+        text: &str,
+        reply_context: Option<&str>,
+        asker: Asker<'_>,
+        chat: &Chat,
+        voice_answer: bool,
+        reply_to_id: Option<i32>,
+    ) -> anyhow::Result<Option<SentMessage>> {
+        let Asker {
+            user,
+            conversation_id,
+            ..
+        } = asker;
+        let user_id = user.id;
+        if conversation_id != user_id {
+            self.gtp_client(chat)
+                .usage_stats()
+                .set_conversation_user(conversation_id, user_id);
         }
-    }
+        let tone = self.user_prefs.get(&user_id).and_then(|prefs| prefs.tone);
+        let preamble = self.preamble(text);
 
-    async fn process_photo(&self, message: Message) -> anyhow::Result<()> {
-        let text = message.caption.unwrap_or("Что на картинке?".to_string());
+        let translated = self.translate_input(chat, text).await?;
+        let own_text = translated.as_deref().unwrap_or(text);
+        let text = match reply_context {
+            Some(context) => format!("Контекст: {context}\n{own_text}"),
+            None => own_text.to_owned(),
+        };
 
-        let used_name = self
-            .config
-            .tg_bot_names
-            .iter()
-            .copied()
-            .find(|&name| text.starts_with(name));
+        let text = if chat.is_private() {
+            match tone {
+                Some(tone) => tone.instruction() + &text,
+                None => text,
+            }
+        } else {
+            let first_name = self.display_name(user);
+            let date = Utc::now().date_naive().to_string();
+            let context = HashMap::from([
+                ("first_name", first_name.as_str()),
+                ("last_name", user.last_name.as_deref().unwrap_or_default()),
+                ("username", user.username.as_deref().unwrap_or_default()),
+                ("chat_title", chat.title.as_deref().unwrap_or_default()),
+                ("date", date.as_str()),
+            ]);
+            let mut prepend = format_preamble(preamble, &context)?;
+            if let Some(tone) = tone {
+                prepend.push_str(&tone.instruction());
+            }
+            prepend.push_str(&text);
+            prepend
+        };
 
-        if should_answer(
-            message.reply_to_message.as_deref(),
-            &message.chat,
-            used_name,
-            &self.config.tg_bot_allow_chats,
-        ) {
-            let Some(photo) = message.photo.and_then(|photos| {
-                photos.into_iter().max_by_key(|x| x.file_size)
-            }) else {
-                return Ok(());
-            };
+        info!("Ask GPT");
 
-            info!("Photo request");
-            let photo_url = self.tg_client.get_file_url(&photo.file_id).await?;
+        let code_review_rules = self
+            .config
+            .code_review_rules
+            .as_deref()
+            .filter(|_| is_code_review_request(own_text));
 
-            let result = self
-                .gtp_client(&message.chat)
-                .get_image_completion(text, photo_url)
+        let result = if let Some(rules) = code_review_rules {
+            info!("Code review completion");
+            self.gtp_client(chat)
+                .get_code_review_completion(conversation_id, rules, text)
                 .instrument(Span::current())
-                .await;
+                .await?
+        } else if contains_case_insensitive(own_text, SMART_TRIGGER)
+            && (chat.is_private() || self.config.smart_price_stars.is_some())
+        {
+            if !chat.is_private() && !self.has_premium_session(user_id).await? {
+                info!("Smart completion requires payment");
+                self.send_smart_invoice(chat.id).await?;
+                return Ok(None);
+            }
+
+            if contains_case_insensitive(own_text, REASONING_TRIGGER) {
+                info!("Reasoning completion");
+                self.gtp_client(chat)
+                    .get_reasoning_completion(conversation_id, text)
+                    .instrument(Span::current())
+                    .await?
+            } else {
+                info!("Smart completion");
+                self.gtp_client(chat)
+                    .get_smart_completion(conversation_id, text)
+                    .instrument(Span::current())
+                    .await?
+            }
+        } else if let Some(model) = self.chat_model(chat) {
+            self.gtp_client(chat)
+                .get_model_completion(conversation_id, model, text)
+                .instrument(Span::current())
+                .await?
+        } else if let Some(system_prompt) = self.chat_system_prompt(chat) {
+            self.gtp_client(chat)
+                .get_system_prompt_completion(
+                    conversation_id,
+                    &system_prompt,
+                    text,
+                )
+                .instrument(Span::current())
+                .await?
+        } else if self.config.web_search {
+            self.completion_with_tools(conversation_id, text, chat)
+                .await?
+        } else if self.config.stream_responses
+            && chat.is_private()
+            && translated.is_none()
+            && !voice_answer
+        {
+            self.stream_answer(conversation_id, text, chat, reply_to_id)
+                .await?;
+            return Ok(None);
+        } else {
+            self.gtp_client(chat)
+                .get_completion(conversation_id, text)
+                .instrument(Span::current())
+                .await?
+        };
 
-            info!("Sending answer to TG");
+        let result = match (&translated, user.language_code.as_deref()) {
+            (Some(_), Some(language_code))
+                if self.config.auto_translate_output =>
+            {
+                let translated_result = self
+                    .gtp_client(chat)
+                    .translate(&result, language_code)
+                    .await?;
+                debug!(
+                    original = result.as_str(),
+                    translated = translated_result,
+                    "Translated output"
+                );
+                Arc::new(translated_result)
+            }
+            _ => result,
+        };
 
-            match result {
-                Ok(result) => {
-                    self.tg_client
-                        .send_message(
-                            message.chat.id,
-                            result.as_str(),
-                            "MarkdownV2".into(),
-                        )
-                        .instrument(Span::current())
-                        .await?;
+        info!("Sending answer to TG");
+
+        if !chat.is_private() {
+            if result.contains("из чата")
+                || result.contains("уйти")
+                || result.contains("выйти")
+                || result.contains("выйду")
+            {
+                let num = self.get_random_number();
+                if num < 20 {
+                    self.tg_client.leave_chat(chat.id).await?;
+                    return Ok(None);
                 }
-                Err(error) => {
-                    self.tg_client
-                        .send_message(
-                            message.chat.id,
-                            "Прости, я задумался. Можешь повторить?",
-                            "MarkdownV2".into(),
-                        )
-                        .instrument(Span::current())
-                        .await?;
+            }
+
+            let num = self.get_random_number();
+            if num > 100 {
+                let audio = self.gtp_client(chat).get_audio(&result).await?;
+
+                let res = self
+                    .tg_client
+                    .send_voice(chat.id, audio, reply_to_id)
+                    .await;
 
-                    bail!(error)
+                if let Err(err) = res {
+                    warn!(?err);
+                } else {
+                    return Ok(None);
                 }
-            };
+            }
+        }
 
-            info!("Complete");
+        if voice_answer
+            && self.send_voice_answer(chat, &result, reply_to_id).await
+        {
+            return Ok(None);
         }
 
-        Ok(())
-    }
+        let answer = if self.config.quick_actions {
+            let message_id = self
+                .tg_client
+                .send_message_with_reply_markup(
+                    chat.id,
+                    &result,
+                    Some(ParseMode::MarkdownV2),
+                    Some(quick_actions_markup()),
+                    reply_to_id,
+                )
+                .await?;
+            SentMessage { message_id }
+        } else {
+            self.tg_client
+                .send_message(
+                    chat.id,
+                    &result,
+                    Some(ParseMode::MarkdownV2),
+                    reply_to_id,
+                )
+                .await?
+        };
 
-    fn get_random_number(&self) -> i32 {
-        let mut rng = (self.rng)();
-        rng.gen_range(0..100)
-    }
+        self.warn_history_fill(conversation_id, chat).await?;
 
-    fn get_random_answer(&self) -> Option<&str> {
-        let mut rng = (self.rng)();
-        let num = rng.gen_range(0..100);
-        if num < 30 {
-            self.config.dummy_answers.choose(&mut rng).copied()
-        } else {
-            None
-        }
+        Ok(Some(answer))
     }
 
-    async fn dummy_reaction(&self, chat_id: i64) -> anyhow::Result<()> {
-        let Some(answer) = self.get_random_answer() else {
+    /// Warns a user once per session that the oldest messages of the
+    /// history are about to be dropped.
+    async fn warn_history_fill(
+        &self,
+        user_id: i64,
+        chat: &Chat,
+    ) -> anyhow::Result<()> {
+        let Some(ratio) = self.config.warn_threshold_ratio else {
             return Ok(());
         };
 
+        if self.gtp_client(chat).history_fill_ratio(user_id) < ratio
+            || !self.history_warned_users.insert(user_id)
+        {
+            return Ok(());
+        }
+
+        info!(user_id, "History is almost full");
         self.tg_client
-            .send_message(chat_id, answer, "MarkdownV2".into())
+            .send_message(
+                chat.id,
+                HISTORY_WARNING,
+                Some(ParseMode::MarkdownV2),
+                None,
+            )
             .await?;
 
         Ok(())
     }
-}
 
-impl<TgClient: TelegramInteractor, GtpClient: GtpInteractor, R: Rng>
-    EventHandler for TgBot<TgClient, GtpClient, R>
-{
-    async fn process_event(&self, event: &Request) -> anyhow::Result<()> {
-        let update: Option<Update> = event.payload()?;
-
-        match update.and_then(|x| x.message) {
-            None => bail!(RequestError::new("Message field is missing")),
-            Some(message) => {
-                let utc = Utc::now().naive_utc();
-                if message.date < (utc - chrono::Duration::minutes(10)) {
-                    warn!(date = ?message.date, "Too old message");
-                    return Ok(());
+    /// Runs the tools GPT asks for and returns the answer based on their
+    /// results.
+    async fn completion_with_tools(
+        &self,
+        user_id: i64,
+        text: String,
+        chat: &Chat,
+    ) -> anyhow::Result<Arc<String>> {
+        let gtp_client = self.gtp_client(chat);
+        let tools = vec![Tool::WebSearch];
+
+        let tool_calls = match gtp_client
+            .get_completion_with_tools(user_id, text.clone(), tools.clone())
+            .instrument(Span::current())
+            .await?
+        {
+            ToolCallOrText::Text(result) => return Ok(result),
+            ToolCallOrText::ToolCalls(tool_calls) => tool_calls,
+        };
+
+        let mut results = Vec::with_capacity(tool_calls.len());
+        for tool_call in &tool_calls {
+            let content = self.call_tool(tool_call).await;
+            results.push(ToolResult::new(tool_call.id.clone(), content));
+        }
+
+        gtp_client
+            .submit_tool_results(user_id, text, tools, tool_calls, results)
+            .instrument(Span::current())
+            .await
+    }
+
+    /// Failures are reported to GPT, which can answer without the tool.
+    async fn call_tool(&self, tool_call: &ToolCall) -> String {
+        match tool_call.tool() {
+            Some(Tool::WebSearch) => {
+                let page = match tool_call.arguments::<WebSearchArguments>() {
+                    Ok(arguments) => {
+                        info!(url = arguments.url, "Web search tool");
+                        self.tg_client.download_page(&arguments.url).await
+                    }
+                    Err(error) => Err(error),
+                };
+
+                match page {
+                    Ok(Some(page)) => {
+                        page.chars().take(PAGE_TEXT_LIMIT).collect()
+                    }
+                    Ok(None) => {
+                        "The page is disallowed by robots.txt".to_string()
+                    }
+                    // The error could tell GPT about the network inside.
+                    Err(error) => {
+                        warn!(?error, "Web search tool failed");
+                        "Failed to read the page".to_string()
+                    }
+                }
+            }
+            None => {
+                warn!(name = tool_call.function.name, "Unknown tool");
+                format!("Unknown tool {}", tool_call.function.name)
+            }
+        }
+    }
+
+    /// Reads the answer out loud, returning false if it has to be sent as
+    /// text instead.
+    async fn send_voice_answer(
+        &self,
+        chat: &Chat,
+        text: &str,
+        reply_to_id: Option<i32>,
+    ) -> bool {
+        let sent = async {
+            let audio = self.gtp_client(chat).get_audio(text).await?;
+            self.tg_client.send_voice(chat.id, audio, reply_to_id).await
+        }
+        .await;
+
+        if let Err(error) = sent {
+            warn!(?error, "Failed to send voice answer, falling back to text");
+            return false;
+        }
+
+        true
+    }
+
+    /// Sends the first piece of the answer right away and edits the message
+    /// as the rest arrives.
+    async fn stream_answer(
+        &self,
+        user_id: i64,
+        text: String,
+        chat: &Chat,
+        reply_to_id: Option<i32>,
+    ) -> anyhow::Result<()> {
+        info!("Streaming completion");
+
+        let mut stream = self
+            .gtp_client(chat)
+            .get_completion_stream(user_id, text)
+            .instrument(Span::current())
+            .await?;
+
+        let mut answer = String::new();
+        let mut message_id = None;
+        let mut sent_len = 0;
+        let mut edited_at = Instant::now();
+
+        while let Some(delta) = stream.next().await {
+            let delta = match (delta, message_id) {
+                (Ok(delta), _) => delta,
+                // Nothing was sent yet, so Telegram may deliver it again.
+                (Err(error), None) => return Err(error),
+                // A retry would send the answer twice, so end this one.
+                (Err(error), Some(id)) => {
+                    warn!(?error, "Answer stream failed");
+                    answer.push_str("\n\n");
+                    answer.push_str(STREAM_INTERRUPTED);
+                    return self
+                        .tg_client
+                        .edit_message(
+                            chat.id,
+                            id,
+                            &answer,
+                            Some(ParseMode::MarkdownV2),
+                            None,
+                        )
+                        .await;
+                }
+            };
+            answer.push_str(&delta);
+
+            match message_id {
+                // Telegram rejects a message with only whitespace.
+                None if answer.trim().is_empty() => {}
+                None => {
+                    let sent = self
+                        .tg_client
+                        .send_message(
+                            chat.id,
+                            &answer,
+                            Some(ParseMode::MarkdownV2),
+                            reply_to_id,
+                        )
+                        .await?;
+                    message_id = Some(sent.message_id);
+                    sent_len = answer.len();
+                    edited_at = Instant::now();
+                }
+                Some(id)
+                    if edited_at.elapsed() >= STREAM_EDIT_INTERVAL
+                        && answer.len() < STREAM_EDIT_LIMIT =>
+                {
+                    // Unfinished markup may be rejected, the final edit
+                    // fixes it.
+                    let res = self
+                        .tg_client
+                        .edit_message(
+                            chat.id,
+                            id,
+                            &answer,
+                            Some(ParseMode::MarkdownV2),
+                            None,
+                        )
+                        .await;
+                    edited_at = Instant::now();
+                    match res {
+                        Ok(()) => sent_len = answer.len(),
+                        Err(err) => warn!(?err, "Failed to update answer"),
+                    }
                 }
+                Some(_) => {}
+            }
+        }
 
-                self.process_message(message).await?;
+        let reply_markup = self.config.quick_actions.then(quick_actions_markup);
+        match message_id {
+            Some(id) if sent_len != answer.len() || reply_markup.is_some() => {
+                self.tg_client
+                    .edit_message(
+                        chat.id,
+                        id,
+                        &answer,
+                        Some(ParseMode::MarkdownV2),
+                        reply_markup,
+                    )
+                    .await?
             }
-        };
+            Some(_) => {}
+            None => bail!(GptError::EmptyResponse),
+        }
 
         Ok(())
     }
-}
 
-fn should_answer(
-    reply_to_message: Option<&Message>,
-    chat: &Chat,
-    used_name: Option<&str>,
-    tg_bot_allow_chats: &[i64],
-) -> bool {
-    (tg_bot_allow_chats.contains(&chat.id))
-        && (chat.chat_type == PRIVATE_CHAT
-            || used_name.is_some()
-            || reply_to_message.is_some_and(|reply| reply.from.is_bot))
-}
+    /// The preamble translated to the language of `text`, if there is one.
+    fn preamble(&self, text: &str) -> &str {
+        let translations = &self.config.preamble_translations;
+        if translations.is_empty() {
+            return &self.config.preamble;
+        }
 
-fn contains_case_insensitive(haystack: &str, needle: &str) -> bool {
-    if needle.is_empty() {
-        return true;
+        let Some(lang) = detect_language(text) else {
+            return &self.config.preamble;
+        };
+        debug!(lang, "Message language detected");
+        translations
+            .get(lang)
+            .filter(|_| lang != "ru")
+            .unwrap_or(&self.config.preamble)
     }
 
-    let haystack_chars = haystack.chars();
-    let needle_chars: Vec<char> = needle.chars().collect();
-
-    let m = needle_chars.len();
+    async fn translate_input(
+        &self,
+        chat: &Chat,
+        text: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let Some(target_lang) = &self.config.auto_translate_input else {
+            return Ok(None);
+        };
 
-    let mut pi = vec![0; m];
-    let mut k = 0;
-    for q in 1..m {
-        while k > 0 && !eq_case_insensitive(needle_chars[k], needle_chars[q]) {
-            k = pi[k - 1];
+        if !needs_translation(text, target_lang) {
+            return Ok(None);
         }
-        if eq_case_insensitive(needle_chars[k], needle_chars[q]) {
-            k += 1;
+
+        let translated =
+            self.gtp_client(chat).translate(text, target_lang).await?;
+        debug!(original = text, translated, "Translated input");
+
+        Ok(Some(translated))
+    }
+
+    /// Tells the user when to come back if they have drawn enough images.
+    async fn is_image_rate_limited(
+        &self,
+        user_id: i64,
+        chat: &Chat,
+        reply_to_id: Option<i32>,
+    ) -> anyhow::Result<bool> {
+        if self.config.admin_user_ids.contains(&user_id)
+            || self.image_rate_limiter.try_acquire(user_id)
+        {
+            return Ok(false);
         }
-        pi[q] = k;
+
+        let retry_after = self.image_rate_limiter.retry_after(user_id);
+        info!(user_id, ?retry_after, "Image rate limit exceeded");
+        let message = format!(
+            "Картинок пока хватит, следующую можно через {}",
+            format_duration(retry_after)
+        );
+        self.tg_client
+            .send_message(chat.id, &message, None, reply_to_id)
+            .await?;
+
+        Ok(true)
+    }
+
+    async fn process_image_request(
+        &self,
+        user_id: i64,
+        text: &str,
+        index: &usize,
+        chat: &Chat,
+        reply_to_id: Option<i32>,
+    ) -> anyhow::Result<()> {
+        let task = self.process_image_request_internal(
+            user_id,
+            text,
+            index,
+            chat,
+            reply_to_id,
+        );
+        self.with_chat_action(chat.id, ChatAction::UploadPhoto, task)
+            .await
     }
 
-    let mut q = 0;
-    for ch in haystack_chars {
-        while q > 0 && !eq_case_insensitive(needle_chars[q], ch) {
-            q = pi[q - 1];
-        }
-        if eq_case_insensitive(needle_chars[q], ch) {
-            q += 1;
-        }
-        if q == m {
-            return true;
+    async fn process_image_request_internal(
+        &self,
+        user_id: i64,
+        text: &str,
+        index: &usize,
+        chat: &Chat,
+        reply_to_id: Option<i32>,
+    ) -> anyhow::Result<()> {
+        let text = &text[index + DRAW_COMMAND.len()..];
+
+        let parsed = DrawOptions::parse(text, &self.config.image_size_keywords);
+        let (options, text) = match parsed {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                let message = format!("Не могу так нарисовать: {error}");
+                self.tg_client
+                    .send_message(
+                        chat.id,
+                        &message,
+                        Some(ParseMode::MarkdownV2),
+                        reply_to_id,
+                    )
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        let text = text.as_str();
+        info!(?options, "Image request");
+
+        if self
+            .is_image_rate_limited(user_id, chat, reply_to_id)
+            .await?
+        {
+            return Ok(());
+        }
+
+        let enhanced_prompt = if self.config.enhance_image_prompt {
+            let prompt = format!(
+                "Rewrite the following image generation prompt to be more \
+                 detailed and effective for DALL-E: '{}'",
+                text.trim()
+            );
+            // The rewrite is not part of the conversation.
+            let enhanced_prompt = self
+                .gtp_client(chat)
+                .get_stateless_completion(prompt)
+                .await?;
+            info!(
+                original_prompt = text,
+                enhanced_prompt = enhanced_prompt.as_str(),
+                "Enhanced image prompt"
+            );
+            Some(enhanced_prompt)
+        } else {
+            None
+        };
+        let text = enhanced_prompt.as_deref().map_or(text, String::as_str);
+
+        let image = self
+            .gtp_client(chat)
+            .get_image(user_id, text, options)
+            .await;
+
+        match image {
+            Ok(image) => {
+                self.tg_client
+                    .send_image(chat.id, image.clone(), reply_to_id)
+                    .await?;
+                self.drawn_images.insert(user_id, image);
+            }
+            Err(error) => {
+                self.tg_client
+                    .send_message(
+                        chat.id,
+                        "Сейчас я такое не могу нарисовать",
+                        None,
+                        reply_to_id,
+                    )
+                    .await?;
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    async fn process_retry_command(
+        &self,
+        user: &User,
+        chat: &Chat,
+        message_id: i32,
+    ) -> anyhow::Result<()> {
+        if !self.snapshot.load().tg_bot_allow_chats.contains(&chat.id) {
+            return Ok(());
+        }
+
+        let Some(LastPrompt {
+            thread_root,
+            text: prompt,
+        }) = self
+            .last_prompts
+            .get(&(chat.id, user.id))
+            .map(|prompt| prompt.clone())
+        else {
+            return self
+                .tg_client
+                .send_message(
+                    chat.id,
+                    NO_PREVIOUS_PROMPT,
+                    None,
+                    Some(message_id),
+                )
+                .await
+                .map(|_| ());
+        };
+
+        if self.is_rate_limited(user, chat).await? {
+            return Ok(());
+        }
+
+        info!(user_id = user.id, "Retry the last prompt");
+        let asker = Asker::in_thread(user, chat.id, thread_root);
+        // A cached answer would be the same one the user did not like.
+        let gtp_client = self.gtp_client(chat);
+        gtp_client.invalidate_cache(asker.conversation_id);
+        gtp_client
+            .remove_last_exchange(asker.conversation_id)
+            .await?;
+
+        let result = self
+            .process_and_answer(
+                chat,
+                asker,
+                &prompt,
+                None,
+                false,
+                Some(message_id),
+            )
+            .await;
+        self.record_thread_answer(chat, message_id, thread_root, &result);
+
+        result.map(|_| ())
+    }
+
+    async fn process_describe_command(
+        &self,
+        user: &User,
+        chat: &Chat,
+        message_id: i32,
+    ) -> anyhow::Result<()> {
+        if !self.snapshot.load().tg_bot_allow_chats.contains(&chat.id) {
+            return Ok(());
+        }
+
+        // GPT downloads a DALL-E link itself while it is valid. The history
+        // keeps a placeholder instead of a data URL.
+        let image_url =
+            self.drawn_images.get(&user.id).map(|image| match &*image {
+                ImageContent::Url(url) => url.clone(),
+                ImageContent::Bytes(bytes) => format!(
+                    "data:image/png;base64,{}",
+                    BASE64_STANDARD.encode(bytes)
+                ),
+            });
+        let Some(image_url) = image_url else {
+            return self
+                .tg_client
+                .send_message(
+                    chat.id,
+                    "Я ещё ничего тебе не нарисовал",
+                    None,
+                    Some(message_id),
+                )
+                .await
+                .map(|_| ());
+        };
+
+        info!(user_id = user.id, "Describe the drawn image");
+        let result = self
+            .gtp_client(chat)
+            .get_image_completion(
+                user.id,
+                DESCRIBE_PROMPT.to_string(),
+                image_url,
+            )
+            .await;
+        let description = match result {
+            Err(error)
+                if matches!(
+                    error.downcast_ref::<GptError>(),
+                    Some(GptError::ImageUnavailable)
+                ) =>
+            {
+                warn!(?error, "The drawn image is no longer available");
+                self.drawn_images.remove(&user.id);
+                return self
+                    .tg_client
+                    .send_message(
+                        chat.id,
+                        IMAGE_UNAVAILABLE,
+                        None,
+                        Some(message_id),
+                    )
+                    .await
+                    .map(|_| ());
+            }
+            result => result?,
+        };
+
+        self.tg_client
+            .send_message(
+                chat.id,
+                &description,
+                Some(ParseMode::MarkdownV2),
+                Some(message_id),
+            )
+            .await
+            .map(|_| ())
+    }
+
+    fn gtp_client(&self, chat: &Chat) -> &GtpClient {
+        if chat.is_private() {
+            &self.private_gtp_client
+        } else {
+            &self.gtp_client
+        }
+    }
+
+    /// Asks the user to wait when they are over the rate limit.
+    async fn is_rate_limited(
+        &self,
+        user: &User,
+        chat: &Chat,
+    ) -> anyhow::Result<bool> {
+        let Some(rate_limiter) = &self.rate_limiter else {
+            return Ok(false);
+        };
+
+        if rate_limiter.try_acquire(user.id) {
+            return Ok(false);
+        }
+
+        info!(user_id = user.id, "Rate limit exceeded");
+        self.tg_client
+            .send_message(chat.id, RATE_LIMIT_MESSAGE, None, None)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// The model chosen with `/model`, if any.
+    fn chat_model(&self, chat: &Chat) -> Option<&'static str> {
+        self.per_chat_model.get(&chat.id).map(|model| *model)
+    }
+
+    /// The prompt set with `/setprompt`, if any.
+    fn chat_system_prompt(&self, chat: &Chat) -> Option<String> {
+        self.chat_system_prompts
+            .get(&chat.id)
+            .map(|prompt| prompt.clone())
+    }
+
+    async fn process_photo(&self, message: Message) -> anyhow::Result<()> {
+        // Only the first photo of an album has the caption, the rest belong
+        // to the album it started.
+        if let Some(group_id) = message
+            .media_group_id
+            .clone()
+            .filter(|id| self.media_groups.contains_key(id))
+        {
+            return self.process_media_group(message, group_id).await;
+        }
+
+        let text = message
+            .caption
+            .clone()
+            .unwrap_or(PHOTO_QUESTION.to_string());
+
+        let used_name = self
+            .config
+            .tg_bot_names
+            .iter()
+            .copied()
+            .find(|&name| text.starts_with(name));
+
+        if should_answer(
+            message.reply_to_message.as_deref(),
+            &message.chat,
+            used_name,
+            &self.snapshot.load().tg_bot_allow_chats,
+        ) {
+            if let Some(group_id) = message.media_group_id.clone() {
+                return self.process_media_group(message, group_id).await;
+            }
+
+            let Some(photo) = message
+                .photo
+                .as_ref()
+                .and_then(|photos| photos.iter().max_by_key(|x| x.file_size))
+            else {
+                return Ok(());
+            };
+
+            if self.is_rate_limited(&message.from, &message.chat).await? {
+                return Ok(());
+            }
+
+            let command = used_name
+                .map_or(text.as_str(), |name| &text[name.len()..])
+                .trim_start_matches([',', ' ']);
+            if let Some(prompt) = strip_command_word(command, EDIT_COMMAND) {
+                let prompt = prompt.trim();
+                if prompt.is_empty() {
+                    return self
+                        .tg_client
+                        .send_message(
+                            message.chat.id,
+                            EDIT_PROMPT_MISSING,
+                            None,
+                            Some(message.message_id),
+                        )
+                        .await
+                        .map(|_| ());
+                }
+                let task = self.process_image_edit(&message, photo, prompt);
+                return self
+                    .with_chat_action(
+                        message.chat.id,
+                        ChatAction::UploadPhoto,
+                        task,
+                    )
+                    .await;
+            }
+
+            info!("Photo request");
+            let photo_url = self.tg_client.get_file_url(&photo.file_id).await?;
+
+            let result = self
+                .gtp_client(&message.chat)
+                .get_image_completion(message.from.id, text, photo_url)
+                .instrument(Span::current())
+                .await;
+
+            self.answer_photo(&message, result).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn process_image_edit(
+        &self,
+        message: &Message,
+        photo: &PhotoSize,
+        prompt: &str,
+    ) -> anyhow::Result<()> {
+        let user_id = message.from.id;
+        if self
+            .is_image_rate_limited(user_id, &message.chat, None)
+            .await?
+        {
+            return Ok(());
+        }
+
+        info!("Image edit request");
+        let photo_url = self.tg_client.get_file_url(&photo.file_id).await?;
+        let photo = self.tg_client.download_file(&photo_url).await?;
+
+        let image = self
+            .gtp_client(&message.chat)
+            .get_image_variation(user_id, photo, prompt)
+            .await;
+
+        match image {
+            Ok(image) => {
+                let image = ImageContent::Bytes(image);
+                self.tg_client
+                    .send_image(
+                        message.chat.id,
+                        image.clone(),
+                        Some(message.message_id),
+                    )
+                    .await?;
+                self.drawn_images.insert(user_id, image);
+                Ok(())
+            }
+            Err(error) => {
+                self.tg_client
+                    .send_message(
+                        message.chat.id,
+                        "Сейчас я такое не могу нарисовать",
+                        None,
+                        Some(message.message_id),
+                    )
+                    .await?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Collects the photos of an album and answers once no more arrive
+    /// within the delay. Only the first photo waits, the later ones are
+    /// added to its album and answered with it.
+    async fn process_media_group(
+        &self,
+        message: Message,
+        group_id: String,
+    ) -> anyhow::Result<()> {
+        let Some(photo) = message.photo.as_ref().and_then(|photos| {
+            photos.iter().max_by_key(|photo| photo.file_size)
+        }) else {
+            return Ok(());
+        };
+
+        let is_first = {
+            let mut group =
+                self.media_groups.entry(group_id.clone()).or_default();
+            if group.caption.is_none() {
+                group.caption.clone_from(&message.caption);
+            }
+            group.photos.push(photo.clone());
+            group.photos.len() == 1
+        };
+        if !is_first {
+            return Ok(());
+        }
+
+        // Waits again as long as photos keep arriving.
+        let mut count = 0;
+        loop {
+            let current = self
+                .media_groups
+                .get(&group_id)
+                .map_or(0, |group| group.photos.len());
+            if current == count {
+                break;
+            }
+            count = current;
+            tokio::time::sleep(self.config.media_group_delay).await;
+        }
+
+        let Some((_, group)) = self.media_groups.remove(&group_id) else {
+            return Ok(());
+        };
+
+        if self.is_rate_limited(&message.from, &message.chat).await? {
+            return Ok(());
+        }
+
+        info!(count, "Media group request");
+        let mut photo_urls = Vec::with_capacity(group.photos.len());
+        for photo in &group.photos {
+            photo_urls.push(self.tg_client.get_file_url(&photo.file_id).await?);
+        }
+
+        let text = group.caption.unwrap_or(PHOTO_QUESTION.to_string());
+        let result = self
+            .gtp_client(&message.chat)
+            .get_multi_image_completion(message.from.id, text, photo_urls)
+            .instrument(Span::current())
+            .await;
+
+        self.answer_photo(&message, result).await
+    }
+
+    async fn answer_photo(
+        &self,
+        message: &Message,
+        result: anyhow::Result<Arc<String>>,
+    ) -> anyhow::Result<()> {
+        self.audit(
+            message.from.id,
+            message.chat.id,
+            CommandType::Image,
+            &result,
+        )
+        .await;
+
+        info!("Sending answer to TG");
+
+        match result {
+            Ok(result) => {
+                self.tg_client
+                    .send_message(
+                        message.chat.id,
+                        result.as_str(),
+                        Some(ParseMode::MarkdownV2),
+                        Some(message.message_id),
+                    )
+                    .instrument(Span::current())
+                    .await?;
+
+                self.react(message.chat.id, message.message_id).await;
+            }
+            Err(error) => {
+                self.tg_client
+                    .send_message(
+                        message.chat.id,
+                        "Прости, я задумался. Можешь повторить?",
+                        Some(ParseMode::MarkdownV2),
+                        Some(message.message_id),
+                    )
+                    .instrument(Span::current())
+                    .await?;
+
+                bail!(error)
+            }
+        };
+
+        info!("Complete");
+
+        Ok(())
+    }
+
+    async fn process_document(
+        &self,
+        message: &Message,
+        document: &Document,
+    ) -> anyhow::Result<()> {
+        if !self.config.enable_document_analysis {
+            return Ok(());
+        }
+
+        let caption = message.caption.as_deref().unwrap_or(DOCUMENT_QUESTION);
+        let used_name = self
+            .config
+            .tg_bot_names
+            .iter()
+            .copied()
+            .find(|&name| caption.starts_with(name));
+
+        if !should_answer(
+            message.reply_to_message.as_deref(),
+            &message.chat,
+            used_name,
+            &self.snapshot.load().tg_bot_allow_chats,
+        ) {
+            return Ok(());
+        }
+
+        let mime_type = document.mime_type.as_deref().unwrap_or_default();
+        if !matches!(mime_type, "text/plain" | "application/pdf") {
+            self.tg_client
+                .send_message(
+                    message.chat.id,
+                    "Формат файла не поддерживается",
+                    None,
+                    None,
+                )
+                .await?;
+            return Ok(());
+        }
+
+        if self.is_rate_limited(&message.from, &message.chat).await? {
+            return Ok(());
+        }
+
+        info!(mime_type, "Document request");
+        let file_url = self.tg_client.get_file_url(&document.file_id).await?;
+        let bytes = self.tg_client.download_file(&file_url).await?;
+
+        let content = if mime_type == "application/pdf" {
+            // The parser is CPU-bound and panics on some broken files.
+            let text = tokio::task::spawn_blocking(move || {
+                pdf_extract::extract_text_from_mem(&bytes)
+            })
+            .await;
+            match text {
+                Ok(Ok(text)) => text,
+                Ok(Err(err)) => {
+                    warn!(?err, "Failed to read PDF");
+                    return self.send_unreadable_document(message).await;
+                }
+                Err(err) => {
+                    warn!(?err, "PDF parser panicked");
+                    return self.send_unreadable_document(message).await;
+                }
+            }
+        } else {
+            String::from_utf8_lossy(&bytes).into_owned()
+        };
+        let content: String =
+            content.trim().chars().take(DOCUMENT_TEXT_LIMIT).collect();
+
+        let question = used_name
+            .map(|name| caption.replace(name, ""))
+            .unwrap_or(caption.to_string());
+        let prompt = format!("{content}\n\n{}", question.trim());
+
+        // The file text must not trigger drawing, so it skips
+        // `process_and_answer`.
+        let result = self
+            .process_text_message(
+                &prompt,
+                None,
+                Asker::own(&message.from),
+                &message.chat,
+                false,
+                Some(message.message_id),
+            )
+            .await;
+
+        self.audit(
+            message.from.id,
+            message.chat.id,
+            CommandType::Document,
+            &result,
+        )
+        .await;
+
+        if result.is_ok() {
+            self.react(message.chat.id, message.message_id).await;
+        }
+
+        result.map(|_| ())
+    }
+
+    async fn send_unreadable_document(
+        &self,
+        message: &Message,
+    ) -> anyhow::Result<()> {
+        self.tg_client
+            .send_message(
+                message.chat.id,
+                DOCUMENT_UNREADABLE,
+                None,
+                Some(message.message_id),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn process_voice(
+        &self,
+        message: &Message,
+        voice: &Voice,
+    ) -> anyhow::Result<()> {
+        let task = self.process_voice_internal(message, voice);
+        self.with_chat_action(message.chat.id, ChatAction::RecordVoice, task)
+            .await
+    }
+
+    async fn process_voice_internal(
+        &self,
+        message: &Message,
+        voice: &Voice,
+    ) -> anyhow::Result<()> {
+        if !should_answer(
+            message.reply_to_message.as_deref(),
+            &message.chat,
+            None,
+            &self.snapshot.load().tg_bot_allow_chats,
+        ) {
+            return Ok(());
+        }
+
+        if self.is_rate_limited(&message.from, &message.chat).await? {
+            return Ok(());
+        }
+
+        info!(duration = voice.duration, "Voice request");
+        let voice_url = self.tg_client.get_file_url(&voice.file_id).await?;
+        let audio = self.tg_client.download_file(&voice_url).await?;
+
+        let gtp_client = self.gtp_client(&message.chat);
+        // Voice notes are OGG/Opus, which `input_audio` doesn't take, so
+        // they go through Whisper.
+        let format = audio_format(voice.mime_type.as_deref())
+            .filter(|_| gtp_client.supports_audio_input());
+        if let Some(format) = format {
+            return self.process_audio_input(message, audio, format).await;
+        }
+
+        let mime_type = voice.mime_type.as_deref().unwrap_or("audio/ogg");
+        let transcript = gtp_client.transcribe_audio(audio, mime_type).await?;
+        info!(transcript = transcript.as_str(), "Voice transcribed");
+
+        let result = self
+            .process_and_answer(
+                &message.chat,
+                Asker::own(&message.from),
+                &transcript,
+                None,
+                self.config.respond_with_voice,
+                Some(message.message_id),
+            )
+            .await;
+
+        self.audit(
+            message.from.id,
+            message.chat.id,
+            CommandType::Voice,
+            &result,
+        )
+        .await;
+
+        if result.is_ok() {
+            self.react(message.chat.id, message.message_id).await;
+        }
+
+        result.map(|_| ())
+    }
+
+    async fn process_audio_input(
+        &self,
+        message: &Message,
+        audio: Vec<u8>,
+        format: &'static str,
+    ) -> anyhow::Result<()> {
+        let result = self
+            .gtp_client(&message.chat)
+            .get_audio_completion(message.from.id, audio, format)
+            .await;
+
+        self.audit(
+            message.from.id,
+            message.chat.id,
+            CommandType::Voice,
+            &result,
+        )
+        .await;
+
+        self.tg_client
+            .send_message(
+                message.chat.id,
+                result?.as_str(),
+                Some(ParseMode::MarkdownV2),
+                Some(message.message_id),
+            )
+            .await?;
+        self.react(message.chat.id, message.message_id).await;
+
+        Ok(())
+    }
+
+    fn display_name(&self, user: &User) -> String {
+        let mut first_name = user.first_name.clone();
+
+        for (name, replacement) in &self.config.name_map {
+            first_name = first_name.replace(name, replacement);
+        }
+
+        first_name
+    }
+
+    async fn process_poll(
+        &self,
+        message: &Message,
+        poll: &Poll,
+    ) -> anyhow::Result<()> {
+        if !should_answer(
+            message.reply_to_message.as_deref(),
+            &message.chat,
+            None,
+            &self.snapshot.load().tg_bot_allow_chats,
+        ) {
+            return Ok(());
+        }
+
+        let options = poll
+            .options
+            .iter()
+            .map(|option| format!("{} ({})", option.text, option.voter_count))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let prompt = format!(
+            "Analyze this poll: '{}'. Options: {options}. \
+             Total voters: {}{}. What does this tell us?",
+            poll.question,
+            poll.total_voter_count,
+            if poll.is_closed { ", closed" } else { "" },
+        );
+
+        info!(poll_id = poll.id, "Poll analysis");
+
+        let result = self
+            .gtp_client(&message.chat)
+            .get_stateless_completion(prompt)
+            .await?;
+
+        self.tg_client
+            .send_message(
+                message.chat.id,
+                &result,
+                Some(ParseMode::MarkdownV2),
+                Some(message.message_id),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Greets the people who joined the group and says goodbye to the one
+    /// who left. Bots, including this one, are skipped.
+    async fn process_chat_members(
+        &self,
+        message: &Message,
+    ) -> anyhow::Result<()> {
+        if !self
+            .snapshot
+            .load()
+            .tg_bot_allow_chats
+            .contains(&message.chat.id)
+        {
+            return Ok(());
+        }
+
+        let greeting = self.config.new_member_greeting.as_deref();
+        let goodbye = self.config.left_member_goodbye.as_deref();
+        let joined = message.new_chat_members.iter().flatten();
+        let members = joined
+            .map(|user| (user, greeting))
+            .chain(message.left_chat_member.iter().map(|user| (user, goodbye)));
+
+        for (user, template) in members {
+            let Some(template) = template.filter(|_| !user.is_bot) else {
+                continue;
+            };
+
+            info!(user_id = user.id, chat_id = message.chat.id, "Chat member");
+            let text = template.replace("{name}", &self.display_name(user));
+            self.tg_client
+                .send_message(
+                    message.chat.id,
+                    &text,
+                    Some(ParseMode::MarkdownV2),
+                    None,
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn process_sticker(
+        &self,
+        message: &Message,
+        sticker: &Sticker,
+    ) -> anyhow::Result<()> {
+        // A sticker has no text to name the bot in, only a reply can.
+        if !should_answer(
+            message.reply_to_message.as_deref(),
+            &message.chat,
+            None,
+            &self.snapshot.load().tg_bot_allow_chats,
+        ) {
+            return Ok(());
+        }
+
+        let prompt = match (&sticker.emoji, &sticker.set_name) {
+            (Some(emoji), _) => {
+                format!(
+                    "The user sent you a {emoji} emoji, react appropriately"
+                )
+            }
+            (None, Some(set_name)) => format!(
+                "The user sent you a sticker from the '{set_name}' sticker \
+                 set, react appropriately"
+            ),
+            (None, None) => return Ok(()),
+        };
+
+        if self.is_rate_limited(&message.from, &message.chat).await? {
+            return Ok(());
+        }
+
+        info!(file_id = sticker.file_id, "Sticker");
+
+        self.process_text_message(
+            &prompt,
+            None,
+            Asker::own(&message.from),
+            &message.chat,
+            false,
+            Some(message.message_id),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn process_tone_command(
+        &self,
+        user: &User,
+        chat: &Chat,
+        args: &str,
+    ) -> anyhow::Result<()> {
+        if !self.snapshot.load().tg_bot_allow_chats.contains(&chat.id) {
+            return Ok(());
+        }
+
+        let args = args.trim();
+
+        let text = if args.is_empty() {
+            let tone =
+                self.user_prefs.get(&user.id).and_then(|prefs| prefs.tone);
+            let text = match tone {
+                Some(tone) => format!("Текущий тон: {}", tone.as_str()),
+                None => "Выбери тон ответов".to_string(),
+            };
+
+            let buttons = TONES
+                .split(", ")
+                .map(|tone| {
+                    InlineKeyboardButton::new(
+                        tone.to_string(),
+                        format!("{TONE_COMMAND} {tone}"),
+                    )
+                })
+                .collect();
+            let reply_markup = ReplyMarkup::InlineKeyboardMarkup {
+                inline_keyboard: vec![buttons],
+            };
+
+            self.tg_client
+                .send_message_with_reply_markup(
+                    chat.id,
+                    &text,
+                    Some(ParseMode::MarkdownV2),
+                    Some(reply_markup),
+                    None,
+                )
+                .await?;
+
+            return Ok(());
+        } else {
+            match args.parse::<Tone>() {
+                Ok(tone) => {
+                    self.user_prefs.entry(user.id).or_default().tone =
+                        Some(tone);
+                    format!("Тон ответов: {}", tone.as_str())
+                }
+                Err(_) => format!("Доступные тона: {TONES}"),
+            }
+        };
+
+        self.tg_client
+            .send_message(chat.id, &text, Some(ParseMode::MarkdownV2), None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn process_model_command(
+        &self,
+        user: &User,
+        chat: &Chat,
+        args: &str,
+    ) -> anyhow::Result<()> {
+        if !chat.is_private()
+            || !self.snapshot.load().tg_bot_allow_chats.contains(&chat.id)
+        {
+            return Ok(());
+        }
+
+        let args = args.trim();
+        let [model, smart_model] = self.gtp_client(chat).models();
+
+        let text = if args.is_empty() {
+            let active = self.chat_model(chat).unwrap_or(model);
+            format!("Текущая модель: {active}")
+        } else if let Some(selected) =
+            [model, smart_model].into_iter().find(|&name| name == args)
+        {
+            self.per_chat_model.insert(chat.id, selected);
+            format!("Модель: {selected}")
+        } else if self.is_admin(user, chat) {
+            let available =
+                self.gtp_client(chat).list_available_models().await?;
+            if available.iter().any(|name| name == args) {
+                let selected = self.model_name(args);
+                self.per_chat_model.insert(chat.id, selected);
+                format!("Модель: {selected}")
+            } else {
+                format!("Модель {args} недоступна")
+            }
+        } else {
+            format!("Доступные модели: {model}, {smart_model}")
+        };
+
+        self.tg_client
+            .send_message(chat.id, &text, Some(ParseMode::MarkdownV2), None)
+            .await?;
+
+        Ok(())
+    }
+
+    fn model_name(&self, name: &str) -> &'static str {
+        if let Some(name) = self.model_names.get(name) {
+            return *name;
+        }
+
+        let name: &'static str = name.to_string().leak();
+        self.model_names.insert(name);
+        name
+    }
+
+    async fn process_roll_command(
+        &self,
+        user: &User,
+        chat: &Chat,
+    ) -> anyhow::Result<()> {
+        if !self.snapshot.load().tg_bot_allow_chats.contains(&chat.id) {
+            return Ok(());
+        }
+
+        let dice = self.tg_client.send_dice(chat.id, DICE_EMOJI).await?;
+
+        info!(value = dice.value, "Dice rolled");
+
+        // Lets following answers use the roll, e.g. in text adventure games.
+        self.gtp_client(chat)
+            .add_system_message(
+                user.id,
+                format!("The dice roll resulted in {}", dice.value),
+            )
+            .await
+    }
+
+    async fn process_help_command(&self, chat: &Chat) -> anyhow::Result<()> {
+        if !self.snapshot.load().tg_bot_allow_chats.contains(&chat.id) {
+            return Ok(());
+        }
+
+        let commands = self.help_commands(chat.is_private());
+        let text = commands
+            .iter()
+            .map(|(command, description)| format!("{command} - {description}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let keyboard = slash_commands(&commands)
+            .map(|(command, _)| vec![KeyboardButton::new(command.to_string())])
+            .collect();
+        let reply_markup = ReplyMarkup::ReplyKeyboardMarkup {
+            keyboard,
+            one_time_keyboard: true,
+        };
+
+        self.tg_client
+            .send_message_with_reply_markup(
+                chat.id,
+                &text,
+                Some(ParseMode::MarkdownV2),
+                Some(reply_markup),
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Commands and triggers of the features enabled in the config.
+    fn help_commands(
+        &self,
+        private: bool,
+    ) -> Vec<(&'static str, &'static str)> {
+        let mut commands = vec![
+            (START_COMMAND, "начать новый разговор"),
+            (TONE_COMMAND, "выбрать тон ответов"),
+        ];
+        if private {
+            commands.push((MODEL_COMMAND, "выбрать модель"));
+        }
+        commands.push((ROLL_COMMAND, "бросить кубик"));
+        commands.push((HELP_COMMAND, "показать это меню"));
+        commands.push((EXPORT_COMMAND, "выгрузить историю разговора"));
+        commands.push((DESCRIBE_COMMAND, "описать последнюю картинку"));
+        commands.push((RETRY_COMMAND, "ответить на последний запрос заново"));
+
+        commands.push((DRAW_COMMAND, "нарисовать картинку по описанию"));
+        commands.push((EDIT_COMMAND, "изменить фото по подписи"));
+        commands.push((QUIZ_TRIGGERS[0], "составить викторину или опрос"));
+        if private || self.config.smart_price_stars.is_some() {
+            commands.push((SMART_TRIGGER, "ответить умной моделью"));
+            commands
+                .push((REASONING_TRIGGER, "ответить моделью с рассуждениями"));
+        }
+        commands.push((FORGET_TRIGGER, "забыть разговор"));
+        commands.push(("голосовое", "ответить на голосовое сообщение"));
+        if self.config.enable_document_analysis {
+            commands.push(("файл", "ответить на вопрос по PDF или тексту"));
+        }
+
+        commands
+    }
+
+    async fn process_callback_query(
+        &self,
+        query: CallbackQuery,
+    ) -> anyhow::Result<()> {
+        self.tg_client.answer_callback_query(&query.id).await?;
+
+        let (Some(message), Some(data)) = (query.message, query.data) else {
+            return Ok(());
+        };
+
+        if let Some(tone) = data.strip_prefix(TONE_COMMAND) {
+            self.process_tone_command(&query.from, &message.chat, tone)
+                .await
+        } else if let Some(action) = data.strip_prefix(QUICK_ACTION_PREFIX) {
+            self.process_quick_action(&query.from, &message, action)
+                .await
+        } else {
+            warn!(data, "Unknown callback query");
+            Ok(())
+        }
+    }
+
+    /// `message` is the answer with the buttons, the action continues its
+    /// thread.
+    async fn process_quick_action(
+        &self,
+        user: &User,
+        message: &Message,
+        action: &str,
+    ) -> anyhow::Result<()> {
+        let chat = &message.chat;
+        // The buttons stay on old answers, so the same gates as for text.
+        if !self.snapshot.load().tg_bot_allow_chats.contains(&chat.id)
+            || self.config.blocked_chat_ids.contains(&chat.id)
+        {
+            return Ok(());
+        }
+
+        let thread_root = self.thread_root(chat, Some(message));
+        let asker = Asker::in_thread(user, chat.id, thread_root);
+        let prompt = match action {
+            CONTINUE_ACTION => "Продолжи",
+            SHORTER_ACTION => "Перескажи свой последний ответ короче",
+            RESET_ACTION => {
+                return self
+                    .process_forget_request(asker.conversation_id, chat, None)
+                    .await
+            }
+            _ => {
+                warn!(action, "Unknown quick action");
+                return Ok(());
+            }
+        };
+
+        if self.is_rate_limited(user, chat).await? {
+            return Ok(());
+        }
+
+        info!(action, "Quick action");
+        let result = self
+            .process_text_message(prompt, None, asker, chat, false, None)
+            .await;
+        self.record_thread_answer(
+            chat,
+            message.message_id,
+            thread_root,
+            &result,
+        );
+
+        result.map(|_| ())
+    }
+
+    /// Text of the replied-to user message, which GPT has not seen unless
+    /// it was one of the bot's own answers.
+    fn reply_context<'a>(
+        &self,
+        reply_to_message: Option<&'a Message>,
+    ) -> Option<&'a str> {
+        if !self.config.reply_context {
+            return None;
+        }
+
+        reply_to_message
+            .filter(|reply| !reply.from.is_bot)
+            .and_then(|reply| reply.text.as_deref())
+    }
+
+    /// Only a message starting with the keyword, "не забудь" is a request
+    /// to remember.
+    fn is_forget_request(&self, text: &str) -> bool {
+        strip_command_word(text, FORGET_TRIGGER).is_some()
+            || self
+                .config
+                .forget_keyword
+                .as_deref()
+                .is_some_and(|keyword| {
+                    strip_command_word(text, &keyword.to_lowercase()).is_some()
+                })
+    }
+
+    /// Clears the history and whatever was derived from it.
+    async fn reset_conversation(
+        &self,
+        user_id: i64,
+        chat: &Chat,
+    ) -> anyhow::Result<()> {
+        let gtp_client = self.gtp_client(chat);
+        gtp_client.reset_history(user_id).await?;
+        gtp_client.invalidate_cache(user_id);
+        self.history_warned_users.remove(&user_id);
+
+        Ok(())
+    }
+
+    async fn process_forget_request(
+        &self,
+        conversation_id: i64,
+        chat: &Chat,
+        reply_to_id: Option<i32>,
+    ) -> anyhow::Result<()> {
+        self.reset_conversation(conversation_id, chat).await?;
+        info!("Conversation reset");
+
+        self.tg_client
+            .send_message(
+                chat.id,
+                "Хорошо, начнём с чистого листа",
+                None,
+                reply_to_id,
+            )
+            .await
+            .map(|_| ())
+    }
+
+    /// Sends the private history as a JSON file. Groups are refused, the
+    /// file would show the user's conversation to everyone. The System
+    /// messages hold the rules and summaries, so they are left out.
+    async fn process_export_command(
+        &self,
+        user: &User,
+        chat: &Chat,
+    ) -> anyhow::Result<()> {
+        if !self.snapshot.load().tg_bot_allow_chats.contains(&chat.id) {
+            return Ok(());
+        }
+
+        if !chat.is_private() {
+            return self
+                .tg_client
+                .send_message(chat.id, EXPORT_PRIVATE_ONLY, None, None)
+                .await
+                .map(|_| ());
+        }
+
+        // Histories saved before the images were replaced still have the
+        // token-bearing file URLs.
+        let history: Vec<_> = self
+            .gtp_client(chat)
+            .get_history(user.id)
+            .await?
+            .into_iter()
+            .filter(|stored| !matches!(stored.message, GptMessage::System(_)))
+            .map(|stored| StoredMessage {
+                message: stored.message.without_images(),
+                ..stored
+            })
+            .collect();
+
+        if history.is_empty() {
+            return self
+                .tg_client
+                .send_message(chat.id, "История разговора пуста", None, None)
+                .await
+                .map(|_| ());
+        }
+
+        let json = serde_json::to_vec_pretty(&history)?;
+        let (filename, data) = if json.len() > EXPORT_GZIP_THRESHOLD {
+            let mut encoder =
+                GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&json)?;
+            ("history.json.gz", encoder.finish()?)
+        } else {
+            ("history.json", json)
+        };
+        info!(
+            messages = history.len(),
+            size = data.len(),
+            "History exported"
+        );
+
+        self.tg_client.send_document(chat.id, filename, data).await
+    }
+
+    async fn process_start_command(
+        &self,
+        user: &User,
+        chat: &Chat,
+        message_id: i32,
+    ) -> anyhow::Result<()> {
+        if !self.snapshot.load().tg_bot_allow_chats.contains(&chat.id) {
+            return self.process_access_request(user, chat, message_id).await;
+        }
+
+        // A group conversation goes on for the others.
+        if chat.is_private() {
+            self.reset_conversation(user.id, chat).await?;
+        }
+
+        let gtp_client = self.gtp_client(chat);
+        let text = if self.config.welcome_message.is_empty() {
+            let prompt = format!(
+                "Поприветствуй пользователя {} и коротко расскажи, чем ты можешь помочь",
+                user.first_name
+            );
+            // Neither the prompt nor the greeting belongs in a history,
+            // which goes on in a group.
+            gtp_client.get_stateless_completion(prompt).await?
+        } else {
+            Arc::new(self.config.welcome_message.clone())
+        };
+
+        self.tg_client
+            .send_message(
+                chat.id,
+                &text,
+                Some(ParseMode::MarkdownV2),
+                Some(message_id),
+            )
+            .await?;
+
+        if self.config.start_capabilities {
+            self.process_help_command(chat).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Strangers who start the bot are greeted and the first bot admin is
+    /// asked to let them in.
+    async fn process_access_request(
+        &self,
+        user: &User,
+        chat: &Chat,
+        message_id: i32,
+    ) -> anyhow::Result<()> {
+        if !chat.is_private() {
+            return Ok(());
+        }
+
+        info!(user_id = user.id, "Access request");
+        let greeting = if self.config.welcome_message.is_empty() {
+            ACCESS_REQUEST_MESSAGE
+        } else {
+            &self.config.welcome_message
+        };
+        self.tg_client
+            .send_message(
+                chat.id,
+                greeting,
+                Some(ParseMode::MarkdownV2),
+                Some(message_id),
+            )
+            .await?;
+
+        let Some(&admin_id) = self.config.admin_user_ids.first() else {
+            return Ok(());
+        };
+        // Repeated /start must not spam the admin.
+        if !self.access_requested_users.insert(user.id) {
+            return Ok(());
+        }
+        let username = user
+            .username
+            .as_deref()
+            .map(|username| format!(" @{username}"))
+            .unwrap_or_default();
+        let text = format!(
+            "Запрос доступа: {}{username}, user {} chat {}",
+            user.first_name, user.id, chat.id
+        );
+        // The text is escaped, so ids and names show as they are.
+        self.tg_client
+            .send_message(admin_id, &text, Some(ParseMode::MarkdownV2), None)
+            .await
+            .map(|_| ())
+    }
+
+    async fn has_premium_session(&self, user_id: i64) -> anyhow::Result<bool> {
+        match &self.premium_store {
+            Some(premium_store) => premium_store.is_premium_user(user_id).await,
+            None => Ok(true),
+        }
+    }
+
+    async fn send_smart_invoice(&self, chat_id: i64) -> anyhow::Result<()> {
+        let Some(price) = self.config.smart_price_stars else {
+            return Ok(());
+        };
+
+        self.tg_client
+            .create_invoice(
+                chat_id,
+                PREMIUM_TITLE,
+                "Доступ к умной модели на 24 часа",
+                "",
+                STARS_CURRENCY,
+                price,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Only the invoice the bot sends now can be paid, e.g. not one sent
+    /// before the price changed.
+    async fn process_pre_checkout_query(
+        &self,
+        query: &PreCheckoutQuery,
+    ) -> anyhow::Result<()> {
+        let valid = query.invoice_payload == PREMIUM_TITLE
+            && query.currency == STARS_CURRENCY
+            && self.config.smart_price_stars == Some(query.total_amount);
+        if !valid {
+            warn!(
+                user_id = query.from.id,
+                payload = query.invoice_payload,
+                amount = query.total_amount,
+                "Declining an unknown invoice"
+            );
+        }
+
+        let error_message = (!valid).then_some(INVOICE_OUTDATED);
+        self.tg_client
+            .answer_pre_checkout_query(&query.id, error_message)
+            .await
+    }
+
+    async fn process_successful_payment(
+        &self,
+        user: &User,
+        chat: &Chat,
+        payment: &SuccessfulPayment,
+    ) -> anyhow::Result<()> {
+        info!(
+            user_id = user.id,
+            charge_id = payment.telegram_payment_charge_id,
+            "Premium session purchased"
+        );
+
+        let Some(premium_store) = &self.premium_store else {
+            bail!("Premium store is not configured");
+        };
+
+        let until = Utc::now().naive_utc()
+            + chrono::Duration::hours(PREMIUM_SESSION_HOURS);
+        premium_store.grant(user.id, until).await?;
+
+        self.tg_client
+            .send_message(
+                chat.id,
+                "Спасибо! Умная модель доступна 24 часа",
+                Some(ParseMode::MarkdownV2),
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn process_chat_boost(
+        &self,
+        chat_boost: &ChatBoostUpdated,
+    ) -> anyhow::Result<()> {
+        let Some(user) = chat_boost.boost.source.user() else {
+            return Ok(());
+        };
+
+        if !self
+            .snapshot
+            .load()
+            .tg_bot_allow_chats
+            .contains(&chat_boost.chat.id)
+        {
+            return Ok(());
+        }
+
+        let Some(premium_store) = &self.premium_store else {
+            return Ok(());
+        };
+
+        info!(
+            user_id = user.id,
+            chat_id = chat_boost.chat.id,
+            "Chat boosted"
+        );
+
+        let until =
+            Utc::now().naive_utc() + chrono::Duration::days(PREMIUM_BOOST_DAYS);
+        premium_store.grant(user.id, until).await?;
+
+        // Only works if the booster has started a private chat with the bot.
+        let result = self
+            .tg_client
+            .send_message(
+                user.id,
+                "Спасибо за буст! Умная модель доступна 7 дней",
+                Some(ParseMode::MarkdownV2),
+                None,
+            )
+            .await;
+        if let Err(error) = result {
+            warn!(?error, "Failed to thank the booster");
+        }
+
+        Ok(())
+    }
+
+    async fn process_web_app_data(
+        &self,
+        user: &User,
+        chat: &Chat,
+        message_id: i32,
+        web_app_data: &WebAppData,
+    ) -> anyhow::Result<()> {
+        if !self.snapshot.load().tg_bot_allow_chats.contains(&chat.id) {
+            return Ok(());
+        }
+
+        let request: WebAppRequest = serde_json::from_str(&web_app_data.data)?;
+
+        info!(button = web_app_data.button_text, "Web app request");
+
+        match request.command {
+            WebAppCommand::Ask { text } => {
+                let result = self
+                    .gtp_client(chat)
+                    .get_completion(user.id, text)
+                    .instrument(Span::current())
+                    .await?;
+
+                match request.query_id {
+                    Some(query_id) => {
+                        let answer = InlineQueryResult::Article {
+                            id: query_id.clone(),
+                            title: web_app_data.button_text.clone(),
+                            input_message_content: InputTextMessageContent::new(
+                                result.to_string(),
+                            ),
+                        };
+                        self.tg_client
+                            .answer_web_app_query(&query_id, answer)
+                            .await?;
+                    }
+                    None => {
+                        self.tg_client
+                            .send_message(
+                                chat.id,
+                                &result,
+                                Some(ParseMode::MarkdownV2),
+                                Some(message_id),
+                            )
+                            .await?;
+                    }
+                }
+            }
+            WebAppCommand::Draw { prompt } => {
+                let image = self
+                    .gtp_client(chat)
+                    .get_image(user.id, &prompt, DrawOptions::default())
+                    .await?;
+                self.tg_client
+                    .send_image(chat.id, image, Some(message_id))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_random_number(&self) -> i32 {
+        let mut rng = (self.rng)();
+        rng.gen_range(0..100)
+    }
+
+    fn get_random_answer(&self) -> Option<String> {
+        let mut rng = (self.rng)();
+        let num = rng.gen_range(0..100);
+        if num < 30 {
+            self.snapshot.load().dummy_answers.choose(&mut rng).cloned()
+        } else {
+            None
+        }
+    }
+
+    async fn process_admin_command(
+        &self,
+        user: &User,
+        chat: &Chat,
+        reply_to_message: Option<&Message>,
+        text: &str,
+    ) -> anyhow::Result<()> {
+        if !self.is_admin(user, chat) {
+            bail!(RequestError::new(
+                ErrorCode::UnauthorizedUser,
+                "User is not an admin"
+            ));
+        }
+
+        if GLOBAL_ADMIN_COMMANDS
+            .iter()
+            .any(|&command| self.command_args(text, command).is_some())
+            && !self.config.admin_user_ids.contains(&user.id)
+        {
+            bail!(RequestError::new(
+                ErrorCode::UnauthorizedUser,
+                "User is not a bot admin"
+            ));
+        }
+
+        let command_args = |command| self.command_args(text, command);
+
+        if let Some(args) = command_args(AUDIT_COMMAND) {
+            self.process_audit_command(chat, args).await
+        } else if let Some(rules) = command_args(SET_RULES_COMMAND) {
+            self.process_set_rules_command(chat, rules.trim()).await
+        } else if command_args(REPOST_COMMAND).is_some() {
+            self.process_repost_command(chat, reply_to_message).await
+        } else if let Some(prompt) = command_args(SET_AVATAR_COMMAND) {
+            self.process_set_avatar_command(user, chat, prompt.trim())
+                .await
+        } else if command_args(WHOAMI_COMMAND).is_some() {
+            self.process_whoami_command(user, chat).await
+        } else if command_args(STATS_COMMAND).is_some() {
+            self.process_stats_command(chat).await
+        } else if command_args(USAGE_COMMAND).is_some() {
+            self.process_usage_command(chat).await
+        } else if let Some(prompt) = command_args(SET_PROMPT_COMMAND) {
+            self.process_set_prompt_command(chat, prompt.trim()).await
+        } else if command_args(CLEAR_PROMPT_COMMAND).is_some() {
+            self.process_clear_prompt_command(chat).await
+        } else if let Some(user_id) = command_args(BLOCK_COMMAND) {
+            self.process_block_command(chat, user_id.trim(), true).await
+        } else if let Some(user_id) = command_args(UNBLOCK_COMMAND) {
+            self.process_block_command(chat, user_id.trim(), false)
+                .await
+        } else {
+            Ok(())
+        }
+    }
+
+    fn command_args<'a>(
+        &self,
+        text: &'a str,
+        command: &str,
+    ) -> Option<&'a str> {
+        command_args(text, command, self.config.bot_username.as_deref())
+    }
+
+    fn is_admin_command(&self, text: &str) -> bool {
+        ADMIN_COMMANDS
+            .iter()
+            .any(|&command| self.command_args(text, command).is_some())
+    }
+
+    fn is_admin(&self, user: &User, chat: &Chat) -> bool {
+        self.config.admin_user_ids.contains(&user.id)
+            || self.chat_admins.contains_key(&(chat.id, user.id))
+    }
+
+    /// Replaces the tracked admins of every allowed group with the ones
+    /// Telegram reports and returns the number of loaded admins.
+    pub async fn load_chat_administrators(&self) -> usize {
+        let chat_ids = self.snapshot.load().tg_bot_allow_chats.clone();
+        let mut loaded = 0;
+
+        // Private chats have positive ids and no administrators.
+        for chat_id in chat_ids.into_iter().filter(|&chat_id| chat_id < 0) {
+            let admins =
+                match self.tg_client.get_chat_administrators(chat_id).await {
+                    Ok(admins) => admins,
+                    Err(error) => {
+                        warn!(chat_id, ?error, "Failed to load chat admins");
+                        continue;
+                    }
+                };
+
+            self.chat_admins
+                .retain(|&(admin_chat_id, _), _| admin_chat_id != chat_id);
+            for admin in admins {
+                if let Some(status) = AdminStatus::from_status(&admin.status) {
+                    self.chat_admins.insert((chat_id, admin.user.id), status);
+                    loaded += 1;
+                }
+            }
+        }
+
+        loaded
+    }
+
+    fn process_chat_member(&self, update: &ChatMemberUpdated) {
+        let key = (update.chat.id, update.new_chat_member.user.id);
+        let status = AdminStatus::from_status(&update.new_chat_member.status);
+
+        info!(
+            chat_id = key.0,
+            user_id = key.1,
+            old_status = update.old_chat_member.status,
+            new_status = update.new_chat_member.status,
+            "Chat member updated"
+        );
+
+        match status {
+            Some(status) => {
+                self.chat_admins.insert(key, status);
+            }
+            None => {
+                self.chat_admins.remove(&key);
+            }
+        }
+    }
+
+    async fn process_stats_command(&self, chat: &Chat) -> anyhow::Result<()> {
+        // Both clients share the counters.
+        let text = self
+            .gtp_client
+            .usage_stats()
+            .report(self.started_at.elapsed());
+
+        self.tg_client
+            .send_message(chat.id, &text, Some(ParseMode::MarkdownV2), None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn process_usage_command(&self, chat: &Chat) -> anyhow::Result<()> {
+        let text = self.gtp_client.usage_stats().users().report(
+            self.config.input_cost_per_1k,
+            self.config.output_cost_per_1k,
+        );
+
+        self.tg_client
+            .send_message(chat.id, &text, Some(ParseMode::MarkdownV2), None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn process_set_prompt_command(
+        &self,
+        chat: &Chat,
+        prompt: &str,
+    ) -> anyhow::Result<()> {
+        if prompt.is_empty() {
+            self.tg_client
+                .send_message(
+                    chat.id,
+                    "Использование: /setprompt <промпт>",
+                    Some(ParseMode::MarkdownV2),
+                    None,
+                )
+                .await?;
+            return Ok(());
+        }
+
+        info!(chat_id = chat.id, "Chat system prompt set");
+        self.chat_system_prompts.insert(chat.id, prompt.to_string());
+
+        self.tg_client
+            .send_message(
+                chat.id,
+                "Промпт чата обновлён",
+                Some(ParseMode::MarkdownV2),
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn process_block_command(
+        &self,
+        chat: &Chat,
+        user_id: &str,
+        block: bool,
+    ) -> anyhow::Result<()> {
+        let Ok(user_id) = user_id.parse::<i64>() else {
+            let command = if block {
+                BLOCK_COMMAND
+            } else {
+                UNBLOCK_COMMAND
+            };
+            self.tg_client
+                .send_message(
+                    chat.id,
+                    &format!("Использование: {command} <user_id>"),
+                    Some(ParseMode::MarkdownV2),
+                    None,
+                )
+                .await?;
+            return Ok(());
+        };
+
+        let text = if block {
+            info!(user_id, "User blocked");
+            self.blocked_users.insert(user_id);
+            if let Some(block_list) = &self.block_list {
+                block_list.block(user_id).await?;
+            }
+            format!("Пользователь {user_id} заблокирован")
+        } else {
+            info!(user_id, "User unblocked");
+            self.blocked_users.remove(&user_id);
+            if let Some(block_list) = &self.block_list {
+                block_list.unblock(user_id).await?;
+            }
+            format!("Пользователь {user_id} разблокирован")
+        };
+
+        self.tg_client
+            .send_message(chat.id, &text, Some(ParseMode::MarkdownV2), None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn is_blocked(&self, message: &Message) -> bool {
+        self.is_blocked_user(message.from.id).await
+            || self.config.blocked_chat_ids.contains(&message.chat.id)
+    }
+
+    async fn is_blocked_user(&self, user_id: i64) -> bool {
+        self.refresh_block_list().await;
+        self.blocked_users.contains(&user_id)
+    }
+
+    /// Reloads the users blocked on other instances once in a while.
+    async fn refresh_block_list(&self) {
+        let Some(block_list) = &self.block_list else {
+            return;
+        };
+
+        {
+            let mut loaded_at = self.block_list_loaded_at.lock().await;
+            if loaded_at.is_some_and(|at| at.elapsed() < BLOCK_LIST_TTL) {
+                return;
+            }
+            *loaded_at = Some(Instant::now());
+        }
+
+        match block_list.load().await {
+            Ok(user_ids) => {
+                let user_ids: HashSet<i64> = user_ids.into_iter().collect();
+                self.blocked_users.retain(|user_id| {
+                    user_ids.contains(user_id)
+                        || self.config.blocked_user_ids.contains(user_id)
+                });
+                for user_id in user_ids {
+                    self.blocked_users.insert(user_id);
+                }
+            }
+            Err(error) => warn!(?error, "Failed to load the block list"),
+        }
+    }
+
+    async fn process_clear_prompt_command(
+        &self,
+        chat: &Chat,
+    ) -> anyhow::Result<()> {
+        info!(chat_id = chat.id, "Chat system prompt cleared");
+        self.chat_system_prompts.remove(&chat.id);
+
+        self.tg_client
+            .send_message(
+                chat.id,
+                "Промпт чата сброшен",
+                Some(ParseMode::MarkdownV2),
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn process_whoami_command(
+        &self,
+        user: &User,
+        chat: &Chat,
+    ) -> anyhow::Result<()> {
+        let mut text = format!("user {} chat {}", user.id, chat.id);
+
+        match self.registered_commands().await {
+            Ok(registered) => {
+                let expected: Vec<_> =
+                    slash_commands(&self.help_commands(true))
+                        .map(|(command, _)| command)
+                        .collect();
+                let (missing, unexpected) =
+                    command_drift(&registered, &expected);
+
+                text.push_str(&format!(
+                    "\nКоманды в Telegram: {}\nОжидаемые команды: {}",
+                    registered.join(", "),
+                    expected.join(", ")
+                ));
+                if !missing.is_empty() {
+                    text.push_str(&format!(
+                        "\nНе зарегистрированы: {}",
+                        missing.join(", ")
+                    ));
+                }
+                if !unexpected.is_empty() {
+                    text.push_str(&format!(
+                        "\nЛишние: {}",
+                        unexpected.join(", ")
+                    ));
+                }
+            }
+            Err(error) => {
+                text.push_str(&format!("\nКоманды недоступны: {error}"));
+            }
+        }
+
+        self.tg_client
+            .send_message(chat.id, &text, Some(ParseMode::MarkdownV2), None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn registered_commands(&self) -> anyhow::Result<Vec<String>> {
+        let commands = self.tg_client.get_my_commands().await?;
+
+        Ok(commands
+            .into_iter()
+            .map(|command| format!("/{}", command.command))
+            .collect())
+    }
+
+    /// Registers the commands of `/help` in Telegram for the autocomplete
+    /// menu. The menu is the same in every chat, so it has the private chat
+    /// commands too.
+    pub async fn register_commands(&self) -> anyhow::Result<()> {
+        let commands: Vec<_> = slash_commands(&self.help_commands(true))
+            .map(|(command, description)| BotCommand {
+                command: command.trim_start_matches('/').to_string(),
+                description: description.to_string(),
+            })
+            .collect();
+
+        self.tg_client.set_my_commands(&commands).await
+    }
+
+    async fn process_set_rules_command(
+        &self,
+        chat: &Chat,
+        new_rules: &str,
+    ) -> anyhow::Result<()> {
+        if new_rules.is_empty() {
+            self.tg_client
+                .send_message(
+                    chat.id,
+                    "Использование: /setrules <правила>",
+                    Some(ParseMode::MarkdownV2),
+                    None,
+                )
+                .await?;
+            return Ok(());
+        }
+
+        self.snapshot.rcu(|snapshot| ConfigSnapshot {
+            base_rules: new_rules.to_string(),
+            ..ConfigSnapshot::clone(snapshot)
+        });
+
+        self.sync_base_rules().await?;
+
+        self.tg_client
+            .send_message(
+                chat.id,
+                "Правила обновлены",
+                Some(ParseMode::MarkdownV2),
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Migrates the conversations of both clients when the base rules in
+    /// the config snapshot differ from the previous snapshot's.
+    async fn sync_base_rules(&self) -> anyhow::Result<()> {
+        let snapshot = self.snapshot.load_full();
+        let mut previous = self.rules_snapshot.lock().await;
+
+        if previous.base_rules == snapshot.base_rules {
+            return Ok(());
+        }
+
+        let (old_rules, new_rules) =
+            (&previous.base_rules, &snapshot.base_rules);
+        self.gtp_client.migrate_rules(old_rules, new_rules).await?;
+        self.private_gtp_client
+            .migrate_rules(old_rules, new_rules)
+            .await?;
+
+        *previous = snapshot;
+
+        Ok(())
+    }
+
+    pub fn config_snapshot(&self) -> Arc<ArcSwap<ConfigSnapshot>> {
+        self.snapshot.clone()
+    }
+
+    async fn process_set_avatar_command(
+        &self,
+        user: &User,
+        chat: &Chat,
+        prompt: &str,
+    ) -> anyhow::Result<()> {
+        if chat.is_private() || prompt.is_empty() {
+            self.tg_client
+                .send_message(
+                    chat.id,
+                    "Использование в группе: /setavatar <описание>",
+                    Some(ParseMode::MarkdownV2),
+                    None,
+                )
+                .await?;
+            return Ok(());
+        }
+
+        info!("Avatar request");
+
+        let photo = match self
+            .gtp_client
+            .get_image(user.id, prompt, DrawOptions::default())
+            .await?
+        {
+            ImageContent::Bytes(bytes) => bytes,
+            ImageContent::Url(url) => {
+                self.tg_client.download_file(&url).await?
+            }
+        };
+
+        if let Err(error) = self.tg_client.set_chat_photo(chat.id, photo).await
+        {
+            if error.to_string().contains("not enough rights") {
+                self.tg_client
+                    .send_message(
+                        chat.id,
+                        "Мне нужно право на изменение информации группы",
+                        None,
+                        None,
+                    )
+                    .await?;
+                return Ok(());
+            }
+
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    async fn process_repost_command(
+        &self,
+        chat: &Chat,
+        reply_to_message: Option<&Message>,
+    ) -> anyhow::Result<()> {
+        let Some(channel_id) = self.config.repost_channel_id else {
+            self.tg_client
+                .send_message(
+                    chat.id,
+                    "Канал для репостов не настроен",
+                    None,
+                    None,
+                )
+                .await?;
+            return Ok(());
+        };
+
+        let Some(reply_to_message) = reply_to_message else {
+            self.tg_client
+                .send_message(
+                    chat.id,
+                    "Ответь командой /repost на сообщение для репоста",
+                    None,
+                    None,
+                )
+                .await?;
+            return Ok(());
+        };
+
+        let message_id = self
+            .tg_client
+            .copy_message(channel_id, chat.id, reply_to_message.message_id)
+            .await?;
+
+        info!(channel_id, message_id, "Message reposted");
+
+        Ok(())
+    }
+
+    async fn process_audit_command(
+        &self,
+        chat: &Chat,
+        args: &str,
+    ) -> anyhow::Result<()> {
+        let Some(audit_log) = &self.audit_log else {
+            self.tg_client
+                .send_message(chat.id, "Аудит выключен", None, None)
+                .await?;
+            return Ok(());
+        };
+
+        let Ok(target_user_id) = args.trim().parse::<i64>() else {
+            self.tg_client
+                .send_message(
+                    chat.id,
+                    "Использование: /audit <user_id>",
+                    Some(ParseMode::MarkdownV2),
+                    None,
+                )
+                .await?;
+            return Ok(());
+        };
+
+        let entries = audit_log.recent(target_user_id, AUDIT_LOG_LIMIT).await?;
+
+        let text = if entries.is_empty() {
+            format!("Нет записей для пользователя {target_user_id}")
+        } else {
+            let mut text = format!("Активность пользователя {target_user_id}:");
+            for entry in entries {
+                text.push_str(&format!(
+                    "\n{} chat {} {} {}",
+                    entry.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                    entry.chat_id,
+                    entry.command_type.as_str(),
+                    entry.response_status.as_str(),
+                ));
+            }
+            text
+        };
+
+        self.tg_client
+            .send_message(chat.id, &text, Some(ParseMode::MarkdownV2), None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn audit<T>(
+        &self,
+        user_id: i64,
+        chat_id: i64,
+        command_type: CommandType,
+        result: &anyhow::Result<T>,
+    ) {
+        let Some(audit_log) = &self.audit_log else {
+            return;
+        };
+
+        let entry = SecurityAuditLog::new(
+            user_id,
+            chat_id,
+            command_type,
+            Utc::now().naive_utc(),
+            ResponseStatus::from(result),
+        );
+
+        if let Err(error) = audit_log.record(entry).await {
+            error!(?error, "Failed to write audit log");
+        }
+    }
+
+    fn is_duplicate(&self, user_id: i64, chat_id: i64, text: &str) -> bool {
+        let Some(hash) = content_hash(text) else {
+            return false;
+        };
+
+        let now = Instant::now();
+        let window = self.config.dedup_window;
+        self.recent_messages
+            .retain(|_, &mut seen_at| now.duration_since(seen_at) < window);
+
+        self.recent_messages
+            .insert((user_id, chat_id, hash), now)
+            .is_some()
+    }
+
+    fn is_duplicate_update(&self, update_id: i64) -> bool {
+        let now = Instant::now();
+        self.recent_updates.retain(|_, &mut seen_at| {
+            now.duration_since(seen_at) < UPDATE_DEDUP_TTL
+        });
+
+        self.recent_updates.insert(update_id, now).is_some()
+    }
+
+    async fn react(&self, chat_id: i64, message_id: i32) {
+        let emoji = {
+            let mut rng = (self.rng)();
+            self.config.reaction_emojis.choose(&mut rng).cloned()
+        };
+
+        let Some(emoji) = emoji else {
+            return;
+        };
+
+        let result = self
+            .tg_client
+            .set_reaction(chat_id, message_id, &emoji)
+            .await;
+
+        if let Err(error) = result {
+            warn!(?error, "Failed to set reaction");
+        }
+    }
+
+    /// Summarizes the first linked page, falling back to a dummy answer when
+    /// the page can't be read.
+    async fn process_url(
+        &self,
+        user: &User,
+        chat: &Chat,
+        text: &str,
+        message_id: i32,
+    ) -> anyhow::Result<()> {
+        if !self.snapshot.load().tg_bot_allow_chats.contains(&chat.id) {
+            return self.dummy_reaction(chat.id).await;
+        }
+
+        let Some(url) = text
+            .split_whitespace()
+            .find(|word| word.starts_with("https://"))
+        else {
+            return self.dummy_reaction(chat.id).await;
+        };
+
+        let page = match self.tg_client.download_page(url).await {
+            Ok(Some(page)) if !page.is_empty() => page,
+            Ok(_) => {
+                info!(url, "Page is not allowed or empty");
+                return self.dummy_reaction(chat.id).await;
+            }
+            Err(error) => {
+                warn!(url, ?error, "Failed to download page");
+                return self.dummy_reaction(chat.id).await;
+            }
+        };
+
+        info!(user_id = user.id, url, "Summarizing page");
+        let page: String = page.chars().take(PAGE_TEXT_LIMIT).collect();
+        // The page stays out of the history, it may carry instructions.
+        let summary = self
+            .gtp_client(chat)
+            .get_stateless_completion(format!("{URL_SUMMARY_PREFIX}\n{page}"))
+            .await?;
+
+        self.tg_client
+            .send_message(
+                chat.id,
+                &summary,
+                Some(ParseMode::MarkdownV2),
+                Some(message_id),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn process_update(&self, event: &Request) -> anyhow::Result<()> {
+        let Some(update) = event
+            .payload::<Update>()
+            .map_err(|error| ProcessingError::Permanent(error.to_string()))?
+        else {
+            bail!(RequestError::new(
+                ErrorCode::MissingMessage,
+                "Message field is missing"
+            ));
+        };
+
+        let update_id = update.update_id;
+        Span::current().record("update_id", update_id);
+        if self.is_duplicate_update(update_id) {
+            debug!(update_id, "Skipping duplicate update");
+            return Ok(());
+        }
+
+        let result = self.dispatch_update(update).await;
+        if result.as_ref().is_err_and(is_retryable) {
+            // Telegram delivers it again, which must not look like a
+            // duplicate.
+            self.recent_updates.remove(&update_id);
+        }
+
+        result
+    }
+
+    async fn dispatch_update(&self, update: Update) -> anyhow::Result<()> {
+        let user_id = update
+            .pre_checkout_query
+            .as_ref()
+            .map(|query| query.from.id)
+            .or(update.callback_query.as_ref().map(|query| query.from.id))
+            .or(update
+                .poll_answer
+                .as_ref()
+                .and_then(|answer| answer.user.as_ref())
+                .map(|user| user.id));
+        if let Some(user_id) = user_id {
+            if self.is_blocked_user(user_id).await {
+                debug!(user_id, "Skipping blocked update");
+                return Ok(());
+            }
+        }
+
+        if let Some(query) = update.pre_checkout_query {
+            return self.process_pre_checkout_query(&query).await;
+        }
+
+        if let Some(query) = update.callback_query {
+            return self.process_callback_query(query).await;
+        }
+
+        if let Some(chat_boost) = update.chat_boost {
+            return self.process_chat_boost(&chat_boost).await;
+        }
+
+        if let Some(member) = update.chat_member.or(update.my_chat_member) {
+            self.process_chat_member(&member);
+            return Ok(());
+        }
+
+        if let Some(answer) = update.poll_answer {
+            info!(
+                poll_id = answer.poll_id,
+                user_id = answer.user.map(|user| user.id),
+                option_ids = ?answer.option_ids,
+                "Poll answer"
+            );
+            return Ok(());
+        }
+
+        let message = match (
+            update.message,
+            update.edited_message,
+            update.channel_post,
+        ) {
+            (Some(message), _, _) => message,
+            (None, Some(message), _) => {
+                warn!(
+                    message_id = message.message_id,
+                    chat_id = message.chat.id,
+                    "Message was edited, answering the new text"
+                );
+                message
+            }
+            (None, None, Some(mut message)) if self.config.channel_posts => {
+                info!(chat_id = message.chat.id, "Channel post");
+                // Posts have no author, the channel keeps one history.
+                message.from.id = message.chat.id;
+                message
+            }
+            _ => bail!(RequestError::new(
+                ErrorCode::MissingMessage,
+                "Message field is missing"
+            )),
+        };
+
+        if self.is_blocked(&message).await {
+            debug!(
+                user_id = message.from.id,
+                chat_id = message.chat.id,
+                "Skipping blocked message"
+            );
+            return Ok(());
+        }
+
+        let utc = Utc::now().naive_utc();
+        let max_age = chrono::Duration::from_std(self.config.max_message_age)?;
+        // An edit is as fresh as the edit itself.
+        let date = message.edit_date.unwrap_or(message.date);
+        if date < (utc - max_age) {
+            let date = date.and_utc().with_timezone(&self.config.log_timezone);
+            info!(%date, "Too old message");
+            bail!(RequestError::new(
+                ErrorCode::StaleMessage,
+                "Too old message"
+            ));
+        }
+
+        let chat_id = message.chat.id;
+        if self.config.auto_leave_unauthorized
+            && !message.chat.is_private()
+            && !self.snapshot.load().tg_bot_allow_chats.contains(&chat_id)
+        {
+            warn!(chat_id, "Leaving unauthorized chat");
+            return self.tg_client.leave_chat(chat_id).await;
+        }
+
+        self.process_message(message).await?;
+
+        Ok(())
+    }
+
+    async fn dummy_reaction(&self, chat_id: i64) -> anyhow::Result<()> {
+        let Some(answer) = self.get_random_answer() else {
+            return Ok(());
+        };
+
+        self.tg_client
+            .send_message(chat_id, &answer, Some(ParseMode::MarkdownV2), None)
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl<
+        TgClient: TelegramInteractor,
+        GtpClient: GtpInteractor,
+        AuditLog: AuditLogStore,
+        Premium: PremiumStore,
+        R: Rng,
+    > EventHandler for TgBot<TgClient, GtpClient, AuditLog, Premium, R>
+{
+    #[instrument(skip_all, fields(update_id, error_code))]
+    async fn process_event(&self, event: &Request) -> anyhow::Result<()> {
+        let result = self.process_update(event).await;
+
+        if let Some(code) = result.as_ref().err().and_then(error_code) {
+            Span::current().record("error_code", code.as_str());
+        }
+
+        result
+    }
+
+    async fn process_push(&self, event: &Request) -> anyhow::Result<()> {
+        let Some(request) = event.payload::<PushRequest>()? else {
+            bail!(RequestError::new(
+                ErrorCode::MissingMessage,
+                "Push body is missing"
+            ));
+        };
+
+        if !self
+            .snapshot
+            .load()
+            .tg_bot_allow_chats
+            .contains(&request.chat_id)
+        {
+            bail!(RequestError::new(
+                ErrorCode::UnauthorizedChat,
+                "Push to not allowed chat"
+            ));
+        }
+
+        self.push_message(request.chat_id, &request.text).await
+    }
+}
+
+/// Audio formats accepted as GPT `input_audio`, OGG/Opus is not one.
+fn audio_format(mime_type: Option<&str>) -> Option<&'static str> {
+    match mime_type? {
+        "audio/mpeg" | "audio/mp3" => Some("mp3"),
+        "audio/wav" | "audio/x-wav" => Some("wav"),
+        _ => None,
+    }
+}
+
+fn quick_actions_markup() -> ReplyMarkup {
+    let buttons = [
+        ("Продолжи", CONTINUE_ACTION),
+        ("Кратко", SHORTER_ACTION),
+        ("Сброс", RESET_ACTION),
+    ]
+    .map(|(text, action)| {
+        InlineKeyboardButton::new(
+            text.to_string(),
+            format!("{QUICK_ACTION_PREFIX}{action}"),
+        )
+    });
+
+    ReplyMarkup::InlineKeyboardMarkup {
+        inline_keyboard: vec![buttons.to_vec()],
+    }
+}
+
+/// The slash commands of `help_commands`, the rest are text triggers.
+fn slash_commands<'a>(
+    help_commands: &'a [(&'static str, &'static str)],
+) -> impl Iterator<Item = (&'static str, &'static str)> + 'a {
+    help_commands
+        .iter()
+        .copied()
+        .filter(|(command, _)| command.starts_with('/'))
+}
+
+/// Returns the `expected` commands missing from `registered` and the
+/// registered commands the bot does not handle.
+fn command_drift<'a>(
+    registered: &'a [String],
+    expected: &[&'static str],
+) -> (Vec<&'static str>, Vec<&'a str>) {
+    let missing = expected
+        .iter()
+        .copied()
+        .filter(|&command| !registered.iter().any(|x| x == command))
+        .collect();
+    let unexpected = registered
+        .iter()
+        .map(String::as_str)
+        .filter(|command| !expected.contains(command))
+        .collect();
+
+    (missing, unexpected)
+}
+
+fn should_answer(
+    reply_to_message: Option<&Message>,
+    chat: &Chat,
+    used_name: Option<&str>,
+    tg_bot_allow_chats: &[i64],
+) -> bool {
+    (tg_bot_allow_chats.contains(&chat.id))
+        && (chat.chat_type == PRIVATE_CHAT
+            || used_name.is_some()
+            || reply_to_message.is_some_and(|reply| reply.from.is_bot))
+}
+
+/// FNV-1a hash of the lowercased text with whitespace runs collapsed, so
+/// copy-pasted messages that differ only in spacing hash the same.
+fn content_hash(text: &str) -> Option<u64> {
+    let mut hash = FNV_OFFSET_BASIS;
+    let mut is_empty = true;
+
+    for word in text.split_whitespace() {
+        if !is_empty {
+            hash = fnv_step(hash, b' ');
+        }
+        is_empty = false;
+
+        for c in word.chars().flat_map(char::to_lowercase) {
+            let mut buf = [0; 4];
+            for &byte in c.encode_utf8(&mut buf).as_bytes() {
+                hash = fnv_step(hash, byte);
+            }
+        }
+    }
+
+    (!is_empty).then_some(hash)
+}
+
+pub(crate) fn fnv_step(hash: u64, byte: u8) -> u64 {
+    (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+}
+
+/// The arguments of `text` if it is `command`, maybe addressed to the bot,
+/// e.g. `/start@bot ref42` but not `/starting`. A command addressed to
+/// another bot than `bot_username` is not for this one.
+fn command_args<'a>(
+    text: &'a str,
+    command: &str,
+    bot_username: Option<&str>,
+) -> Option<&'a str> {
+    let rest = text.strip_prefix(command)?;
+
+    let args = match rest.strip_prefix('@') {
+        Some(rest) => {
+            let (name, args) =
+                rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            if bot_username
+                .is_some_and(|username| !name.eq_ignore_ascii_case(username))
+            {
+                return None;
+            }
+            args
+        }
+        None if rest.is_empty() || rest.starts_with(char::is_whitespace) => {
+            rest
+        }
+        None => return None,
+    };
+
+    Some(args.trim_start())
+}
+
+/// The text for the user of a GPT error they can do nothing about.
+fn gpt_notice(error: &anyhow::Error) -> Option<&'static str> {
+    match error.downcast_ref::<ProcessingError>()? {
+        ProcessingError::Ignorable(text) if text == GPT_UNAVAILABLE => {
+            Some(GPT_UNAVAILABLE)
+        }
+        ProcessingError::Permanent(text) if text == UNSUPPORTED => {
+            Some(UNSUPPORTED)
+        }
+        _ => None,
+    }
+}
+
+/// The rest of `text` if it asks to pin the answer.
+fn strip_pin_request(text: &str) -> Option<&str> {
+    text.trim_start_matches([',', ' '])
+        .strip_prefix(PIN_EMOJI)
+        .map(|rest| rest.trim_start_matches([',', ' ']))
+        .or_else(|| strip_command_word(text, PIN_COMMAND))
+}
+
+/// The rest of `text` if it starts with the lowercase `word` as a whole
+/// word, in any case.
+fn strip_command_word<'a>(text: &'a str, word: &str) -> Option<&'a str> {
+    let text = text.trim_start_matches([',', ' ']);
+    let prefix = text.get(..word.len())?;
+    let rest = &text[word.len()..];
+
+    let whole_word = !rest.starts_with(char::is_alphanumeric);
+    (prefix.to_lowercase() == word && whole_word)
+        .then(|| rest.trim_start_matches([',', ' ']))
+}
+
+/// Whether `text` asks for a quiz, a poll or neither, and the topic of it.
+fn poll_kind(text: &str) -> Option<(bool, &str)> {
+    QUIZ_TRIGGERS
+        .iter()
+        .find_map(|trigger| strip_command_word(text, trigger))
+        .map(|topic| (true, topic))
+        .or_else(|| {
+            strip_command_word(text, POLL_TRIGGER).map(|topic| (false, topic))
+        })
+}
+
+/// Parses the poll GPT wrote, which may be wrapped in a code block.
+fn parse_poll_response(json: &str) -> anyhow::Result<PollData> {
+    let json = json.trim();
+    let json = json
+        .strip_prefix("```json")
+        .or_else(|| json.strip_prefix("```"))
+        .and_then(|json| json.strip_suffix("```"))
+        .unwrap_or(json);
+    let poll: PollData = serde_json::from_str(json)?;
+
+    if poll.question.trim().is_empty() {
+        bail!("Poll has no question");
+    }
+    if poll.question.chars().count() > POLL_QUESTION_LIMIT {
+        bail!("Poll question is longer than {POLL_QUESTION_LIMIT} chars");
+    }
+    if poll.options.iter().any(|option| {
+        option.trim().is_empty() || option.chars().count() > POLL_OPTION_LIMIT
+    }) {
+        bail!("Poll option is empty or longer than {POLL_OPTION_LIMIT} chars");
+    }
+    if !(2..=10).contains(&poll.options.len()) {
+        bail!("Poll has {} options, expected 2 to 10", poll.options.len());
+    }
+    if poll
+        .answer
+        .is_some_and(|answer| usize::from(answer) >= poll.options.len())
+    {
+        bail!("Poll answer is not one of the options");
+    }
+
+    Ok(poll)
+}
+
+fn is_code_review_request(text: &str) -> bool {
+    text.contains("```")
+        && CODE_REVIEW_TRIGGERS
+            .iter()
+            .any(|trigger| contains_case_insensitive(text, trigger))
+}
+
+fn contains_case_insensitive(haystack: &str, needle: &str) -> bool {
+    if needle.is_empty() {
+        return true;
+    }
+
+    let haystack_chars = haystack.chars();
+    let needle_chars: Vec<char> = needle.chars().collect();
+
+    let m = needle_chars.len();
+
+    let mut pi = vec![0; m];
+    let mut k = 0;
+    for q in 1..m {
+        while k > 0 && !eq_case_insensitive(needle_chars[k], needle_chars[q]) {
+            k = pi[k - 1];
+        }
+        if eq_case_insensitive(needle_chars[k], needle_chars[q]) {
+            k += 1;
+        }
+        pi[q] = k;
+    }
+
+    let mut q = 0;
+    for ch in haystack_chars {
+        while q > 0 && !eq_case_insensitive(needle_chars[q], ch) {
+            q = pi[q - 1];
+        }
+        if eq_case_insensitive(needle_chars[q], ch) {
+            q += 1;
+        }
+        if q == m {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// E.g. "1 ч 5 мин" or "30 с", rounded up to a second.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+    let parts = [
+        (seconds / 3600, "ч"),
+        (seconds / 60 % 60, "мин"),
+        (seconds % 60, "с"),
+    ];
+
+    let text: Vec<_> = parts
+        .iter()
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{value} {unit}"))
+        .collect();
+    if text.is_empty() {
+        return "0 с".to_string();
+    }
+
+    text.join(" ")
+}
+
+fn eq_case_insensitive(a: char, b: char) -> bool {
+    let mut a_lower = a.to_lowercase();
+    let mut b_lower = b.to_lowercase();
+    loop {
+        match (a_lower.next(), b_lower.next()) {
+            (Some(a_c), Some(b_c)) if a_c == b_c => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AdminStatus {
+    Creator,
+    Administrator,
+}
+
+impl AdminStatus {
+    fn from_status(status: &str) -> Option<Self> {
+        match status {
+            "creator" => Some(AdminStatus::Creator),
+            "administrator" => Some(AdminStatus::Administrator),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Error, Debug, Constructor)]
+#[error("{code}: {msg}")]
+pub struct RequestError {
+    pub code: ErrorCode,
+    pub msg: &'static str,
+}
+
+//unit tests
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use chrono::{NaiveDate, Utc};
+    use futures::stream::{self, StreamExt};
+    use lambda_http::{http, Body, Request};
+    use mockall::predicate::{always, eq};
+    use mockall::Sequence;
+    use rand::rngs::mock::StepRng;
+    use rand::rngs::ThreadRng;
+
+    use crate::anthropic_client::UNSUPPORTED;
+    use crate::audit_log::{
+        CommandType, DynamoAuditLog, MockAuditLogStore, ResponseStatus,
+        SecurityAuditLog,
+    };
+    use crate::conversation_router::conversation_id;
+    use crate::conversation_store::DynamoConversationStore;
+    use crate::event_handler::{
+        error_code, ErrorCode, EventHandler, ProcessingError,
+    };
+    use crate::gpt_client::{
+        DrawOptions, GptError, GtpClient, ImageContent, ImageQuality,
+        Message as GptMessage, MockGtpInteractor, StoredMessage, Tool,
+        ToolCall, ToolCallOrText, ToolResult, Value, GPT_UNAVAILABLE,
+    };
+    use crate::hot_reload::ConfigSnapshot;
+    use crate::message_processor::{
+        command_args, command_drift, contains_case_insensitive, content_hash,
+        eq_case_insensitive, format_duration, is_code_review_request,
+        parse_poll_response, poll_kind, strip_command_word, strip_pin_request,
+        AdminStatus, PollData, DESCRIBE_PROMPT, DOCUMENT_UNREADABLE,
+        EDIT_PROMPT_MISSING, EMPTY_RESPONSE_MESSAGE, EXPORT_PRIVATE_ONLY,
+        HISTORY_WARNING, IMAGE_UNAVAILABLE, INVOICE_OUTDATED,
+        NO_PREVIOUS_PROMPT, POLL_OPTION_LIMIT, POLL_QUESTION_LIMIT,
+        STREAM_INTERRUPTED,
+    };
+    use crate::premium::{DynamoPremiumStore, MockPremiumStore};
+    use crate::retry::status_error;
+    use crate::tg_client::{
+        BotCommand, CallbackQuery, Chat, ChatAction, ChatMember, Dice,
+        Document, InlineQueryResult, InputTextMessageContent, Message,
+        MockTelegramInteractor, ParseMode, PhotoSize, Poll, PollOption,
+        ReplyMarkup, SentMessage, Sticker, SuccessfulPayment, TgClient, User,
+        Voice, WebAppData, PRIVATE_CHAT,
+    };
+    use crate::usage_stats::UsageStats;
+    use crate::user_prefs::Tone;
+
+    use super::{should_answer, Config, TgBot};
+
+    #[test]
+    fn test_contains_case_insensitive() {
+        assert!(contains_case_insensitive("Hello", "hello"));
+        assert!(contains_case_insensitive("Придумай", "придумай"));
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::ZERO), "0 с");
+        assert_eq!(format_duration(Duration::from_millis(29_100)), "30 с");
+        assert_eq!(format_duration(Duration::from_secs(9 * 60)), "9 мин");
+        assert_eq!(format_duration(Duration::from_secs(3905)), "1 ч 5 мин 5 с");
+    }
+
+    /// O(n*m) reference for `contains_case_insensitive`.
+    fn naive_contains_case_insensitive(haystack: &str, needle: &str) -> bool {
+        let haystack: Vec<char> = haystack.chars().collect();
+        let needle: Vec<char> = needle.chars().collect();
+
+        needle.is_empty()
+            || haystack.windows(needle.len()).any(|window| {
+                window
+                    .iter()
+                    .zip(&needle)
+                    .all(|(&a, &b)| eq_case_insensitive(a, b))
+            })
+    }
+
+    fn assert_contains(haystack: &str, needle: &str, expected: bool) {
+        assert_eq!(
+            contains_case_insensitive(haystack, needle),
+            expected,
+            "{needle:?} in {haystack:?}"
+        );
+        assert_eq!(
+            naive_contains_case_insensitive(haystack, needle),
+            expected,
+            "reference: {needle:?} in {haystack:?}"
+        );
+    }
+
+    #[test]
+    fn test_contains_case_insensitive_edge_cases() {
+        assert_contains("", "", true);
+        assert_contains("Hello", "", true);
+        assert_contains("", "a", false);
+        assert_contains("abc", "abcd", false);
+        assert_contains("ПоДуМаЙ", "подумай", true);
+
+        assert_contains("Hello world", "HELLO", true);
+        assert_contains("Hello world", "O W", true);
+        assert_contains("Hello world", "WORLD", true);
+        assert_contains("Hello world", "worlds", false);
+        assert_contains("abcABCabc", "cab", true);
+    }
+
+    #[test]
+    fn test_contains_case_insensitive_multi_char_folding() {
+        // Characters are folded one at a time, so a char that lowercases to
+        // several chars only matches a char with the same folding.
+        assert_contains("STRASSE", "straße", false);
+        assert_contains("Straße", "STRAßE", true);
+        assert_contains("İstanbul", "istanbul", false);
+        assert_contains("İstanbul", "İSTANBUL", true);
+        assert_contains("Istanbul", "ıstanbul", false);
+    }
+
+    #[test]
+    fn test_contains_case_insensitive_astral_chars() {
+        assert_contains("🙂 Hello 🙃", "HELLO 🙃", true);
+        assert_contains("🙂🙂🙃", "🙂🙃", true);
+        assert_contains("\u{10400}\u{10401}", "\u{10428}\u{10429}", true);
+        assert_contains("𝔸𝔹", "𝔹𝔸", false);
+    }
+
+    #[test]
+    fn test_contains_case_insensitive_overlapping_partial_matches() {
+        assert_contains("aaab", "AAB", true);
+        assert_contains("ababac", "ABAC", true);
+        assert_contains("abababx", "ababx", true);
+        assert_contains("ababab", "ababb", false);
+        assert_contains("aabaabaaab", "AABAAAB", true);
+        assert_contains("banana", "ANA", true);
+    }
+
+    #[test]
+    fn test_contains_case_insensitive_matches_reference() {
+        fn strings(alphabet: &[char], max_len: usize) -> Vec<String> {
+            let mut result = vec![String::new()];
+            let mut last = vec![String::new()];
+            for _ in 0..max_len {
+                last = last
+                    .iter()
+                    .flat_map(|prefix| {
+                        alphabet.iter().map(move |&ch| format!("{prefix}{ch}"))
+                    })
+                    .collect();
+                result.extend(last.iter().cloned());
+            }
+            result
+        }
+
+        let alphabet = ['a', 'A', 'b', 'ß'];
+        let haystacks = strings(&alphabet, 5);
+        let needles = strings(&alphabet, 3);
+
+        for haystack in &haystacks {
+            for needle in &needles {
+                assert_eq!(
+                    contains_case_insensitive(haystack, needle),
+                    naive_contains_case_insensitive(haystack, needle),
+                    "{needle:?} in {haystack:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_code_review_request() {
+        assert!(is_code_review_request("Проверь код ```fn main() {}```"));
+        assert!(is_code_review_request("Code review please ```x = 1```"));
+        assert!(!is_code_review_request("Проверь код fn main() {}"));
+        assert!(!is_code_review_request("What is this? ```x = 1```"));
+    }
+
+    #[test]
+    fn test_strip_pin_request() {
+        assert_eq!(strip_pin_request("📌 Список дел"), Some("Список дел"));
+        assert_eq!(strip_pin_request(", Закрепи, правила"), Some("правила"));
+        assert_eq!(strip_pin_request("Что значит закрепи?"), None);
+        assert_eq!(strip_pin_request("Закрепить можно?"), None);
+        assert_eq!(strip_pin_request("Привет"), None);
+    }
+
+    #[test]
+    fn test_strip_command_word() {
+        assert_eq!(strip_command_word("Забудь всё", "забудь"), Some("всё"));
+        assert_eq!(strip_command_word(", забудь", "забудь"), Some(""));
+        assert_eq!(strip_command_word("Не забудь хлеб", "забудь"), None);
+        assert_eq!(strip_command_word("Забудьте", "забудь"), None);
+    }
+
+    #[test]
+    fn test_command_args() {
+        assert_eq!(command_args("/start", "/start", None), Some(""));
+        assert_eq!(command_args("/start ref42", "/start", None), Some("ref42"));
+        assert_eq!(command_args("/start@gpt_bot", "/start", None), Some(""));
+        assert_eq!(
+            command_args("/model@Gpt_Bot gpt-4o", "/model", Some("gpt_bot")),
+            Some("gpt-4o")
+        );
+        assert_eq!(
+            command_args("/help@other_bot", "/help", Some("gpt_bot")),
+            None
+        );
+        assert_eq!(command_args("/starting", "/start", None), None);
+        assert_eq!(command_args("/retry_later", "/retry", None), None);
+        assert_eq!(command_args("start", "/start", None), None);
+    }
+
+    #[test]
+    fn test_poll_kind() {
+        assert_eq!(
+            poll_kind("Викторину про космос"),
+            Some((true, "про космос"))
+        );
+        assert_eq!(
+            poll_kind(", Опрос: чай или кофе?"),
+            Some((false, ": чай или кофе?"))
+        );
+        assert_eq!(poll_kind("Сделай викторину про космос"), None);
+        assert_eq!(poll_kind("У меня вопрос про опросы"), None);
+        assert_eq!(poll_kind("Опросы бывают разные"), None);
+    }
+
+    #[test]
+    fn test_parse_poll_response() {
+        let json =
+            r#"{"question": "2 + 2?", "options": ["3", "4"], "answer": 1}"#;
+        let poll = PollData {
+            question: "2 + 2?".to_string(),
+            options: vec!["3".to_string(), "4".to_string()],
+            answer: Some(1),
+        };
+
+        assert_eq!(parse_poll_response(json).unwrap(), poll);
+        assert_eq!(
+            parse_poll_response(&format!("```json\n{json}\n```")).unwrap(),
+            poll
+        );
+        assert_eq!(
+            parse_poll_response(
+                r#"{"question": "Чай?", "options": ["Да", "Нет"]}"#
+            )
+            .unwrap()
+            .answer,
+            None
+        );
+        assert!(parse_poll_response("Вот опрос").is_err());
+        assert!(parse_poll_response(
+            r#"{"question": "2 + 2?", "options": ["4"], "answer": 0}"#
+        )
+        .is_err());
+        assert!(parse_poll_response(
+            r#"{"question": "2 + 2?", "options": ["3", "4"], "answer": 2}"#
+        )
+        .is_err());
+        assert!(parse_poll_response(
+            r#"{"question": " ", "options": ["3", "4"], "answer": 0}"#
+        )
+        .is_err());
+        let question = "?".repeat(POLL_QUESTION_LIMIT + 1);
+        assert!(parse_poll_response(&format!(
+            r#"{{"question": "{question}", "options": ["3", "4"]}}"#
+        ))
+        .is_err());
+        let option = "4".repeat(POLL_OPTION_LIMIT + 1);
+        assert!(parse_poll_response(&format!(
+            r#"{{"question": "2 + 2?", "options": ["3", "{option}"]}}"#
+        ))
+        .is_err());
+    }
+
+    // test for should_answer function
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(content_hash("Hello  World\n"), content_hash("hello world"));
+        assert_ne!(content_hash("hello world"), content_hash("helloworld"));
+        assert_eq!(content_hash(" \t "), None);
+    }
+
+    #[test]
+    fn test_command_drift() {
+        let registered = ["/start", "/draw"].map(str::to_string);
+        let expected = ["/start", "/tone", "/roll", "/help"];
+        let (missing, unexpected) = command_drift(&registered, &expected);
+        assert_eq!(missing, vec!["/tone", "/roll", "/help"]);
+        assert_eq!(unexpected, vec!["/draw"]);
+    }
+
+    #[test]
+    fn test_should_answer() {
+        let reply_to_message = build_private_message();
+        let chat = Chat {
+            id: 123,
+            first_name: None,
+            last_name: None,
+            username: None,
+            chat_type: "private".to_string(),
+            title: None,
+        };
+        let used_name = Some("Hello");
+        let tg_bot_allow_chats = vec![123];
+        assert!(should_answer(
+            reply_to_message.as_deref(),
+            &chat,
+            used_name,
+            &tg_bot_allow_chats
+        ));
+    }
+
+    // test for should_answer function for negative case
+    #[test]
+    fn test_should_answer_negative() {
+        let reply_to_message = build_private_message();
+        let chat = Chat {
+            id: 123,
+            first_name: None,
+            last_name: None,
+            username: None,
+            chat_type: "private".to_string(),
+            title: None,
+        };
+        let used_name = Some("Hello");
+        let tg_bot_allow_chats = vec![124];
+        assert!(!should_answer(
+            reply_to_message.as_deref(),
+            &chat,
+            used_name,
+            &tg_bot_allow_chats
+        ));
+    }
+
+    // Compile-time check that the bot can be shared between tokio tasks
+    #[test]
+    fn test_tg_bot_is_send_and_sync() {
+        fn assert_send<T: Send>(_: T) {}
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        type Bot = TgBot<
+            TgClient,
+            GtpClient<DynamoConversationStore>,
+            DynamoAuditLog,
+            DynamoPremiumStore,
+            ThreadRng,
+        >;
+
+        assert_send_sync::<Bot>();
+        let _ = |bot: &Bot, event: &Request| {
+            assert_send(bot.process_event(event));
+        };
+        let _ = |bot: &Bot, message: Message| {
+            assert_send(bot.process_message(message));
+        };
+    }
+
+    //test for process_message function
+    #[tokio::test]
+    async fn test_process_message() {
+        let message = build_public_message().unwrap();
+        let mut tg_client = MockTelegramInteractor::new();
+        let private_gtp_client = MockGtpInteractor::new();
+        let mut public_gtp_client = MockGtpInteractor::new();
+
+        public_gtp_client
+            .expect_get_completion()
+            .times(1)
+            .with(always(), eq("Call me Bob.  Hello".to_string()))
+            .returning(|_, _| Ok("How are you?".to_string().into()));
+
+        tg_client
+            .expect_send_message()
+            .times(1)
+            .with(
+                eq(0),
+                eq("How are you?"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(0)),
+            )
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = TgBot::new(
+            public_gtp_client,
+            private_gtp_client,
+            tg_client,
+            None::<MockAuditLogStore>,
+            None::<MockPremiumStore>,
+            build_test_config(),
+            || StepRng::new(0, 0),
+        );
+        let result = bot.process_message(*message).await;
+        assert!(result.is_ok());
+    }
+
+    fn build_test_config() -> Config {
+        Config::new(
+            HashMap::from_iter(vec![("Sam".to_string(), "Bob".to_string())]),
+            "Call me {}. ".to_string(),
+            vec![
+                "Dummy answer",
+                "Another dummy answer",
+                "Yet another dummy answer",
+            ],
+            vec![0],
+            vec!["simple bot"],
+        )
+    }
+
+    // Test when the message contains a photo
+    #[tokio::test]
+    async fn test_process_message_with_photo() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+        let public_gtp_client = MockGtpInteractor::new();
+
+        tg_client
+            .expect_get_file_url()
+            .times(1)
+            .with(eq("file_id"))
+            .returning(|_| Ok("url".to_string()));
+
+        gtp_client
+            .expect_get_image_completion()
+            .times(1)
+            .with(
+                always(),
+                eq("Что на картинке?".to_string()),
+                eq("url".to_string()),
+            )
+            .returning(|_, _, _| Ok("Red image".to_string().into()));
+
+        tg_client
+            .expect_send_message()
+            .times(1)
+            .with(
+                eq(123),
+                eq("Red image"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, public_gtp_client);
+        let message = create_private_message(
+            None,
+            Some(vec![PhotoSize {
+                file_id: "file_id".to_string(),
+                file_size: 1,
+            }]),
+        );
+        let result = bot.process_message(message).await;
+        assert!(result.is_ok());
+    }
+
+    // Test that a photo captioned with the edit command is redrawn
+    #[tokio::test]
+    async fn test_process_message_with_photo_edit() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        tg_client
+            .expect_get_file_url()
+            .with(eq("file_id"))
+            .times(1)
+            .returning(|_| Ok("url".to_string()));
+        tg_client
+            .expect_download_file()
+            .with(eq("url"))
+            .times(1)
+            .returning(|_| Ok(vec![1, 2, 3]));
+
+        gtp_client
+            .expect_get_image_variation()
+            .with(eq(1), eq(vec![1, 2, 3]), eq("фон на море"))
+            .times(1)
+            .returning(|_, _, _| Ok(vec![4, 5, 6]));
+        gtp_client.expect_get_image_completion().never();
+
+        tg_client
+            .expect_send_image()
+            .with(eq(123), eq(ImageContent::Bytes(vec![4, 5, 6])), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        let mut message = create_private_message(
+            None,
+            Some(vec![PhotoSize {
+                file_id: "file_id".to_string(),
+                file_size: 1,
+            }]),
+        );
+        message.caption = Some("Измени фон на море".to_string());
+
+        assert!(bot.process_message(message).await.is_ok());
+        assert_eq!(
+            *bot.drawn_images.get(&1).unwrap(),
+            ImageContent::Bytes(vec![4, 5, 6])
+        );
+    }
+
+    // Test that only the whole edit command with a prompt redraws a photo
+    #[tokio::test]
+    async fn test_process_message_with_photo_edit_word() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client.expect_get_image_variation().never();
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq(EDIT_PROMPT_MISSING), eq(None), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        tg_client
+            .expect_get_file_url()
+            .times(1)
+            .returning(|_| Ok("url".to_string()));
+        gtp_client
+            .expect_get_image_completion()
+            .with(
+                eq(1),
+                eq("Изменилось ли что-то?".to_string()),
+                eq("url".to_string()),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok("Нет".to_string().into()));
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("Нет"), always(), always())
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        for caption in ["Измени", "Изменилось ли что-то?"]
+        {
+            let mut message = create_private_message(
+                None,
+                Some(vec![PhotoSize {
+                    file_id: "file_id".to_string(),
+                    file_size: 1,
+                }]),
+            );
+            message.caption = Some(caption.to_string());
+
+            assert!(bot.process_message(message).await.is_ok());
+        }
+    }
+
+    // Test that the photos of an album are sent in a single request
+    #[tokio::test]
+    async fn test_process_message_with_media_group() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        tg_client
+            .expect_get_file_url()
+            .times(2)
+            .returning(|file_id| Ok(format!("url_{file_id}")));
+        gtp_client
+            .expect_get_multi_image_completion()
+            .with(
+                always(),
+                eq("Compare".to_string()),
+                eq(vec!["url_a".to_string(), "url_b".to_string()]),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok("Same".to_string().into()));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Same"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().media_group_delay =
+            Duration::from_millis(50);
+
+        let messages = ["a", "b"].map(|file_id| {
+            let mut message = create_private_message(
+                None,
+                Some(vec![PhotoSize {
+                    file_id: file_id.to_string(),
+                    file_size: 1,
+                }]),
+            );
+            message.media_group_id = Some("album".to_string());
+            message
+        });
+        let [mut first, second] = messages;
+        first.caption = Some("Compare".to_string());
+
+        let (first, second) = tokio::join!(
+            bot.process_message(first),
+            bot.process_message(second)
+        );
+        assert!(first.is_ok());
+        assert!(second.is_ok());
+    }
+
+    // Test when the message contains a text with a URL
+    #[tokio::test]
+    async fn test_process_message_with_url() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let gtp_client = MockGtpInteractor::new();
+        let public_gtp_client = MockGtpInteractor::new();
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Another dummy answer"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, public_gtp_client);
+        let message = create_public_message(
+            Some("https://example.com".to_string()),
+            None,
+        );
+        let result = bot.process_message(message).await;
+        assert!(result.is_ok());
+    }
+
+    // Test that the linked page is summarized when enabled
+    #[tokio::test]
+    async fn test_process_message_with_url_summary() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        tg_client
+            .expect_download_page()
+            .with(eq("https://example.com"))
+            .times(1)
+            .returning(|_| Ok(Some("Page text".to_string())));
+        gtp_client
+            .expect_get_stateless_completion()
+            .with(eq("Summarize this:\nPage text".to_string()))
+            .times(1)
+            .returning(|_| Ok("Summary".to_string().into()));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Summary"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().url_summary = true;
+
+        let message = create_private_message(
+            Some("Look at https://example.com".to_string()),
+            None,
+        );
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a link in a group is not read unless the bot is addressed
+    #[tokio::test]
+    async fn test_process_message_with_url_summary_in_group() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        tg_client.expect_download_page().never();
+        tg_client.expect_send_message().never();
+        gtp_client.expect_get_stateless_completion().never();
+
+        let mut bot =
+            create_bot(tg_client, MockGtpInteractor::new(), gtp_client);
+        Arc::get_mut(&mut bot.config).unwrap().url_summary = true;
+
+        let message = create_public_message(
+            Some("Look at https://example.com".to_string()),
+            None,
+        );
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that the web search tool reads the page GPT asks for
+    #[tokio::test]
+    async fn test_process_message_with_web_search_tool() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        let tool_call: ToolCall = serde_json::from_str(
+            r#"{
+                "id": "call_1",
+                "type": "function",
+                "function": {
+                    "name": "web_search",
+                    "arguments": "{\"url\": \"https://example.com\"}"
+                }
+            }"#,
+        )
+        .unwrap();
+        let tool_calls = vec![tool_call];
+
+        let returned_calls = tool_calls.clone();
+        gtp_client
+            .expect_get_completion_with_tools()
+            .with(eq(1), eq("News?".to_string()), eq(vec![Tool::WebSearch]))
+            .times(1)
+            .returning(move |_, _, _| {
+                Ok(ToolCallOrText::ToolCalls(returned_calls.clone()))
+            });
+        tg_client
+            .expect_download_page()
+            .with(eq("https://example.com"))
+            .times(1)
+            .returning(|_| Ok(Some("Page text".to_string())));
+        gtp_client
+            .expect_submit_tool_results()
+            .with(
+                eq(1),
+                eq("News?".to_string()),
+                eq(vec![Tool::WebSearch]),
+                eq(tool_calls),
+                eq(vec![ToolResult::new(
+                    "call_1".to_string(),
+                    "Page text".to_string(),
+                )]),
+            )
+            .times(1)
+            .returning(|_, _, _, _, _| Ok("Nothing new".to_string().into()));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Nothing new"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().web_search = true;
+
+        let message = create_private_message(Some("News?".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test when the message contains a text with a bot name
+    #[tokio::test]
+    async fn test_process_message_with_bot_name() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let gtp_client = MockGtpInteractor::new();
+        let mut public_gtp_client = MockGtpInteractor::new();
+
+        public_gtp_client
+            .expect_get_completion()
+            .with(always(), eq("preamble Hello".to_string()))
+            .times(1)
+            .returning(|_, _| Ok("Hello Sir".to_string().into()));
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Hello Sir"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, public_gtp_client);
+        let message =
+            create_public_message(Some("bot_name Hello".to_string()), None);
+        let result = bot.process_message(message).await;
+        assert!(result.is_ok());
+    }
+
+    // Test when the message contains a text with a draw command
+    #[tokio::test]
+    async fn test_process_message_with_draw_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+        let public_gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_image()
+            .with(always(), eq("cat"), eq(DrawOptions::default()))
+            .times(1)
+            .returning(|_, _, _| Ok(ImageContent::Url("url".to_string())));
+
+        tg_client
+            .expect_send_image()
+            .with(
+                eq(123),
+                eq(ImageContent::Url("url".to_string())),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let bot = create_bot(tg_client, gtp_client, public_gtp_client);
+        let message =
+            create_private_message(Some("нарисуй cat".to_string()), None);
+        let result = bot.process_message(message).await;
+        assert!(result.is_ok());
+    }
+
+    // Test that /describe asks GPT about the last drawn image
+    #[tokio::test]
+    async fn test_process_describe_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_image()
+            .times(1)
+            .returning(|_, _, _| Ok(ImageContent::Bytes(vec![1, 2, 3])));
+        gtp_client
+            .expect_get_image_completion()
+            .with(
+                eq(1),
+                eq(DESCRIBE_PROMPT.to_string()),
+                eq("data:image/png;base64,AQID".to_string()),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok("A cat".to_string().into()));
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Я ещё ничего тебе не нарисовал"),
+                always(),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+        tg_client
+            .expect_send_image()
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("A cat"), always(), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+
+        for text in ["/describe", "нарисуй cat", "/describe"] {
+            let message = create_private_message(Some(text.to_string()), None);
+            assert!(bot.process_message(message).await.is_ok());
+        }
+    }
+
+    // Test that an expired image is forgotten with a message to the user
+    #[tokio::test]
+    async fn test_process_describe_command_expired_image() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client.expect_get_image().times(1).returning(|_, _, _| {
+            Ok(ImageContent::Url("https://dalle/cat.png".to_string()))
+        });
+        gtp_client
+            .expect_get_image_completion()
+            .times(1)
+            .returning(|_, _, _| Err(GptError::ImageUnavailable.into()));
+
+        tg_client
+            .expect_send_image()
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq(IMAGE_UNAVAILABLE), always(), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Я ещё ничего тебе не нарисовал"),
+                always(),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+
+        for text in ["нарисуй cat", "/describe", "/describe"] {
+            let message = create_private_message(Some(text.to_string()), None);
+            assert!(bot.process_message(message).await.is_ok());
+        }
+    }
+
+    // Test that draw options are parsed from the draw command
+    #[tokio::test]
+    async fn test_process_message_with_draw_options() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_image()
+            .with(
+                always(),
+                eq("cat"),
+                eq(DrawOptions {
+                    quality: ImageQuality::Hd,
+                    size: "1792x1024",
+                    ..DrawOptions::default()
+                }),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok(ImageContent::Url("url".to_string())));
+
+        tg_client
+            .expect_send_image()
+            .with(
+                eq(123),
+                eq(ImageContent::Url("url".to_string())),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        let message = create_private_message(
+            Some("нарисуй HD 1792x1024 cat".to_string()),
+            None,
+        );
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that unsupported draw options are reported to the user
+    #[tokio::test]
+    async fn test_process_message_with_invalid_draw_options() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client.expect_get_image().never();
+
+        tg_client
+            .expect_send_message()
+            .withf(|chat_id, text, parse_mode, _| {
+                *chat_id == 123
+                    && text.contains("512x512")
+                    && *parse_mode == Some(ParseMode::MarkdownV2)
+            })
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        let message = create_private_message(
+            Some("нарисуй 512x512 cat".to_string()),
+            None,
+        );
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that the draw prompt is rewritten by GPT before drawing
+    #[tokio::test]
+    async fn test_process_draw_command_with_enhanced_prompt() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_stateless_completion()
+            .with(eq("Rewrite the following image generation prompt to be \
+                 more detailed and effective for DALL-E: 'cat'"
+                .to_string()))
+            .times(1)
+            .returning(|_| Ok("A fluffy cat in the sun".to_string().into()));
+
+        gtp_client
+            .expect_get_image()
+            .with(
+                always(),
+                eq("A fluffy cat in the sun"),
+                eq(DrawOptions::default()),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok(ImageContent::Url("url".to_string())));
+
+        tg_client
+            .expect_send_image()
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().enhance_image_prompt = true;
+
+        let message =
+            create_private_message(Some("нарисуй cat".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test when an admin requests the audit log of a user
+    #[tokio::test]
+    async fn test_process_audit_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut audit_log = MockAuditLogStore::new();
+
+        audit_log
+            .expect_recent()
+            .with(eq(42), eq(20))
+            .times(1)
+            .returning(|_, _| {
+                Ok(vec![SecurityAuditLog::new(
+                    42,
+                    123,
+                    CommandType::Draw,
+                    NaiveDate::from_ymd_opt(2024, 1, 2)
+                        .unwrap()
+                        .and_hms_opt(3, 4, 5)
+                        .unwrap(),
+                    ResponseStatus::Success,
+                )])
+            });
+
+        audit_log
+            .expect_record()
+            .withf(|entry| {
+                entry.user_id == 1
+                    && entry.command_type == CommandType::Admin
+                    && entry.response_status == ResponseStatus::Success
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Активность пользователя 42:\n2024-01-02 03:04:05 chat 123 draw success"),
+                eq(Some(ParseMode::MarkdownV2)), eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut config = build_test_config();
+        config.admin_user_ids = vec![1];
+
+        let bot = TgBot::new(
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+            tg_client,
+            Some(audit_log),
+            None::<MockPremiumStore>,
+            config,
+            || StepRng::new(0, 0),
+        );
+        let message =
+            create_private_message(Some("/audit 42".to_string()), None);
+        let result = bot.process_message(message).await;
+        assert!(result.is_ok());
+    }
+
+    // Test when an admin replaces the base rules
+    #[tokio::test]
+    async fn test_process_set_rules_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+        let mut private_gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_migrate_rules()
+            .with(eq("old rules"), eq("new rules"))
+            .times(1)
+            .returning(|_, _| Ok(1));
+        private_gtp_client
+            .expect_migrate_rules()
+            .with(eq("old rules"), eq("new rules"))
+            .times(1)
+            .returning(|_, _| Ok(0));
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Правила обновлены"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut config = build_test_config();
+        config.admin_user_ids = vec![1];
+        config.base_rules = "old rules".to_string();
+
+        let bot = TgBot::new(
+            gtp_client,
+            private_gtp_client,
+            tg_client,
+            None::<MockAuditLogStore>,
+            None::<MockPremiumStore>,
+            config,
+            || StepRng::new(0, 0),
+        );
+        let message = create_private_message(
+            Some("/setrules new rules".to_string()),
+            None,
+        );
+        let result = bot.process_message(message).await;
+        assert!(result.is_ok());
+        assert_eq!(bot.rules_snapshot.lock().await.base_rules, "new rules");
+    }
+
+    // Test that a chat admin can't run the commands for all chats
+    #[tokio::test]
+    async fn test_chat_admin_global_commands() {
+        let mut tg_client = MockTelegramInteractor::new();
+        tg_client.expect_send_message().never();
+
+        let bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+        bot.chat_admins.insert((123, 1), AdminStatus::Administrator);
+
+        for text in [
+            "/setrules new rules",
+            "/audit 42",
+            "/repost",
+            "/stats",
+            "/setprompt Be rude",
+            "/block 7",
+        ] {
+            let message = create_private_message(Some(text.to_string()), None);
+            let error = bot.process_message(message).await.unwrap_err();
+            assert_eq!(error_code(&error), Some(ErrorCode::UnauthorizedUser));
+        }
+        assert_eq!(bot.rules_snapshot.lock().await.base_rules, "");
+    }
+
+    // Test when the bot has no right to change the group avatar
+    #[tokio::test]
+    async fn test_process_set_avatar_without_rights() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_image()
+            .with(always(), eq("cat"), always())
+            .times(1)
+            .returning(|_, _, _| Ok(ImageContent::Url("url".to_string())));
+
+        tg_client
+            .expect_download_file()
+            .with(eq("url"))
+            .times(1)
+            .returning(|_| Ok(vec![1, 2, 3]));
+
+        tg_client
+            .expect_set_chat_photo()
+            .with(eq(123), eq(vec![1, 2, 3]))
+            .times(1)
+            .returning(|_, _| {
+                Err(anyhow!(
+                    "Bad Request: not enough rights to change chat info"
+                ))
+            });
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Мне нужно право на изменение информации группы"),
+                eq(None),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut config = build_test_config();
+        config.admin_user_ids = vec![1];
+
+        let bot = TgBot::new(
+            gtp_client,
+            MockGtpInteractor::new(),
+            tg_client,
+            None::<MockAuditLogStore>,
+            None::<MockPremiumStore>,
+            config,
+            || StepRng::new(0, 0),
+        );
+        let message =
+            create_public_message(Some("/setavatar cat".to_string()), None);
+        let result = bot.process_message(message).await;
+        assert!(result.is_ok());
+    }
+
+    // Test when a web app asks a question through an inline query
+    #[tokio::test]
+    async fn test_process_web_app_data() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .with(always(), eq("Hello".to_string()))
+            .times(1)
+            .returning(|_, _| Ok("Hello Sir".to_string().into()));
+
+        tg_client
+            .expect_answer_web_app_query()
+            .with(
+                eq("query"),
+                eq(InlineQueryResult::Article {
+                    id: "query".to_string(),
+                    title: "Ask".to_string(),
+                    input_message_content: InputTextMessageContent::new(
+                        "Hello Sir".to_string(),
+                    ),
+                }),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        let mut message = create_private_message(None, None);
+        message.web_app_data = Some(WebAppData {
+            data: r#"{"query_id":"query","action":"ask","text":"Hello"}"#
+                .to_string(),
+            button_text: "Ask".to_string(),
+        });
+        let result = bot.process_message(message).await;
+        assert!(result.is_ok());
+    }
+
+    // Test that the user's tone preference is added to the prompt
+    #[tokio::test]
+    async fn test_process_tone_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let gtp_client = MockGtpInteractor::new();
+        let mut public_gtp_client = MockGtpInteractor::new();
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Тон ответов: casual"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        public_gtp_client
+            .expect_get_completion()
+            .with(
+                always(),
+                eq("preambleRespond in a casual tone.  Hello".to_string()),
+            )
+            .times(1)
+            .returning(|_, _| Ok("Hey".to_string().into()));
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Hey"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, public_gtp_client);
+
+        let message =
+            create_public_message(Some("/tone casual".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+
+        let message =
+            create_public_message(Some("bot_name Hello".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that /start resets the conversation and sends the welcome message
+    #[tokio::test]
+    async fn test_process_start_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client.expect_invalidate_cache().return_const(());
+        gtp_client
+            .expect_reset_history()
+            .times(1)
+            .returning(|_| Ok(()));
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Welcome!"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().welcome_message =
+            "Welcome!".to_string();
+
+        let message = create_private_message(Some("/start".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that /start in a group keeps the conversation
+    #[tokio::test]
+    async fn test_process_start_command_in_group() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client.expect_reset_history().never();
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("Welcome!"), always(), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, MockGtpInteractor::new(), gtp_client);
+        Arc::get_mut(&mut bot.config).unwrap().welcome_message =
+            "Welcome!".to_string();
+
+        for text in ["/start@bot_name", "/starting"] {
+            let message = create_public_message(Some(text.to_string()), None);
+            assert!(bot.process_message(message).await.is_ok());
+        }
+    }
+
+    // Test that /start from a stranger notifies the first bot admin once
+    #[tokio::test]
+    async fn test_process_start_command_access_request() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(999),
+                eq("Привет! Доступ к боту пока закрыт, я отправил запрос администратору"),
+                always(), always(),
+            )
+            .times(2)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(42),
+                eq("Запрос доступа: Sam, user 1 chat 999"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+        Arc::get_mut(&mut bot.config).unwrap().admin_user_ids = vec![42, 43];
+
+        for message_id in [1, 2] {
+            let mut message =
+                create_private_message(Some("/start".to_string()), None);
+            message.message_id = message_id;
+            message.chat.id = 999;
+            message.from.first_name = "Sam".to_string();
+            assert!(bot.process_message(message).await.is_ok());
+        }
+    }
+
+    // Test that /start falls back to a generated welcome message
+    #[tokio::test]
+    async fn test_process_start_command_generated() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client.expect_invalidate_cache().return_const(());
+        gtp_client
+            .expect_reset_history()
+            .times(1)
+            .returning(|_| Ok(()));
+
+        gtp_client.expect_get_completion().never();
+        gtp_client
+            .expect_get_stateless_completion()
+            .withf(|prompt| prompt.contains("Yury"))
+            .times(1)
+            .returning(|_| Ok("Hi, Yury!".to_string().into()));
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Hi, Yury!"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+
+        let message = create_private_message(Some("/start".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a near-duplicate message is skipped
+    #[tokio::test]
+    async fn test_process_duplicate_message() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut public_gtp_client = MockGtpInteractor::new();
+
+        public_gtp_client
+            .expect_get_completion()
+            .times(1)
+            .returning(|_, _| Ok("Hey".to_string().into()));
+
+        tg_client
+            .expect_send_message()
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot =
+            create_bot(tg_client, MockGtpInteractor::new(), public_gtp_client);
+
+        let message =
+            create_public_message(Some("bot_name Hello".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+
+        let message =
+            create_public_message(Some("bot_name  hello ".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that the smart model sends an invoice without a premium session
+    #[tokio::test]
+    async fn test_process_smart_request_without_premium() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut premium_store = MockPremiumStore::new();
+
+        premium_store
+            .expect_is_premium_user()
+            .with(eq(1))
+            .times(1)
+            .returning(|_| Ok(false));
+
+        tg_client
+            .expect_create_invoice()
+            .with(eq(123), eq("Premium"), always(), eq(""), eq("XTR"), eq(50))
+            .times(1)
+            .returning(|_, _, _, _, _, _| Ok(10));
+
+        let mut config = build_test_config();
+        config.tg_bot_allow_chats = vec![123];
+        config.smart_price_stars = Some(50);
+
+        let bot = TgBot::new(
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+            tg_client,
+            None::<MockAuditLogStore>,
+            Some(premium_store),
+            config,
+            || StepRng::new(0, 0),
+        );
+
+        let message = create_public_message(
+            Some("simple bot подумай о жизни".to_string()),
+            None,
+        );
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a successful payment grants a premium session
+    #[tokio::test]
+    async fn test_process_successful_payment() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut premium_store = MockPremiumStore::new();
+
+        premium_store
+            .expect_grant()
+            .withf(|&user_id, until| {
+                user_id == 1 && *until > Utc::now().naive_utc()
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        tg_client
+            .expect_send_message()
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = TgBot::new(
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+            tg_client,
+            None::<MockAuditLogStore>,
+            Some(premium_store),
+            build_test_config(),
+            || StepRng::new(0, 0),
+        );
+
+        let mut message = create_public_message(None, None);
+        message.successful_payment = Some(SuccessfulPayment {
+            currency: "XTR".to_string(),
+            total_amount: 50,
+            invoice_payload: "Premium".to_string(),
+            telegram_payment_charge_id: "charge".to_string(),
+        });
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that an answer with quick actions is a reply to the message
+    #[tokio::test]
+    async fn test_process_message_with_quick_actions() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .times(1)
+            .returning(|_, _| Ok("Hi".to_string().into()));
+
+        tg_client
+            .expect_send_message_with_reply_markup()
+            .withf(|&chat_id, text, _, reply_markup, &reply_to_id| {
+                chat_id == 123
+                    && text == "Hi"
+                    && reply_markup.is_some()
+                    && reply_to_id == Some(1)
+            })
+            .times(1)
+            .returning(|_, _, _, _, _| Ok(7));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().quick_actions = true;
+
+        let message = create_private_message(Some("Hello".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that /help shows the command keyboard
+    #[tokio::test]
+    async fn test_process_help_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client
+            .expect_send_message_with_reply_markup()
+            .withf(|&chat_id, _, _, reply_markup, _| {
+                chat_id == 123
+                    && matches!(
+                        reply_markup,
+                        Some(ReplyMarkup::ReplyKeyboardMarkup {
+                            keyboard,
+                            one_time_keyboard: true,
+                        }) if keyboard.len() == 8
+                    )
+            })
+            .times(1)
+            .returning(|_, _, _, _, _| Ok(1));
+
+        let bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+
+        let message = create_private_message(Some("/help".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that /help in a group answers only the command for this bot
+    #[tokio::test]
+    async fn test_process_help_command_for_other_bot() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client
+            .expect_send_message_with_reply_markup()
+            .withf(|&chat_id, _, _, _, _| chat_id == 123)
+            .times(1)
+            .returning(|_, _, _, _, _| Ok(1));
+
+        let mut bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+        Arc::get_mut(&mut bot.config).unwrap().bot_username =
+            Some("gpt_bot".to_string());
+
+        for text in ["/help@other_bot", "/helpme", "/help@gpt_bot"] {
+            let message = create_public_message(Some(text.to_string()), None);
+            assert!(bot.process_message(message).await.is_ok());
+        }
+    }
+
+    // Test that /help lists the enabled features
+    #[tokio::test]
+    async fn test_process_help_command_lists_features() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client
+            .expect_send_message_with_reply_markup()
+            .withf(|_, text, _, _, _| {
+                text.contains("/model - выбрать модель")
+                    && text.contains("подумай - ответить умной моделью")
+                    && text.contains("файл - ")
+            })
+            .times(1)
+            .returning(|_, _, _, _, _| Ok(1));
+
+        let mut bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+        Arc::get_mut(&mut bot.config)
+            .unwrap()
+            .enable_document_analysis = true;
+
+        let message = create_private_message(Some("/help".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that every slash command of /help is registered in Telegram
+    #[tokio::test]
+    async fn test_register_commands() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client
+            .expect_set_my_commands()
+            .withf(|commands| {
+                commands.iter().map(|command| command.command.as_str()).eq([
+                    "start", "tone", "model", "roll", "help", "export",
+                    "describe", "retry",
+                ]) && commands.iter().any(|command| {
+                    command.command == "export"
+                        && command.description == "выгрузить историю разговора"
+                })
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+
+        assert!(bot.register_commands().await.is_ok());
+    }
+
+    // Test that a tone button press sets the tone
+    #[tokio::test]
+    async fn test_process_tone_callback_query() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client
+            .expect_answer_callback_query()
+            .with(eq("42"))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Тон ответов: formal"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+
+        let request = build_json_request(
+            "/",
+            r#"{
+                "update_id": 1,
+                "callback_query": {
+                    "id": "42",
+                    "from": {"id": 1, "is_bot": false, "first_name": "Yury"},
+                    "message": {
+                        "message_id": 5,
+                        "from": {"id": 2, "is_bot": true, "first_name": "Bot"},
+                        "chat": {"id": 123, "type": "private"},
+                        "date": 0
+                    },
+                    "data": "/tone formal"
+                }
+            }"#,
+        );
+        assert!(bot.process_event(&request).await.is_ok());
+        assert_eq!(
+            bot.user_prefs.get(&1).and_then(|prefs| prefs.tone),
+            Some(Tone::Formal)
+        );
+    }
+
+    // Test that only a checkout of the current invoice is approved
+    #[tokio::test]
+    async fn test_process_pre_checkout_query() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client
+            .expect_answer_pre_checkout_query()
+            .withf(|id, error_message| id == "1" && error_message.is_none())
+            .times(1)
+            .returning(|_, _| Ok(()));
+        tg_client
+            .expect_answer_pre_checkout_query()
+            .withf(|id, error_message| {
+                id == "2" && *error_message == Some(INVOICE_OUTDATED)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let mut bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+        Arc::get_mut(&mut bot.config).unwrap().smart_price_stars = Some(50);
+
+        for (update_id, amount) in [(1, 50), (2, 10)] {
+            let request = build_json_request(
+                "/",
+                &format!(
+                    r#"{{
+                        "update_id": {update_id},
+                        "pre_checkout_query": {{
+                            "id": "{update_id}",
+                            "from": {{
+                                "id": 1,
+                                "is_bot": false,
+                                "first_name": "Yury"
+                            }},
+                            "currency": "XTR",
+                            "total_amount": {amount},
+                            "invoice_payload": "Premium"
+                        }}
+                    }}"#
+                ),
+            );
+            assert!(bot.process_event(&request).await.is_ok());
+        }
+    }
+
+    // Test that quick action buttons are attached and handled
+    #[tokio::test]
+    async fn test_process_quick_actions() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .with(
+                eq(1),
+                eq("Перескажи свой последний ответ короче".to_string()),
+            )
+            .times(1)
+            .returning(|_, _| Ok("Short".to_string().into()));
+        gtp_client.expect_invalidate_cache().return_const(());
+        gtp_client
+            .expect_reset_history()
+            .with(eq(1))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        tg_client
+            .expect_answer_callback_query()
+            .with(eq("42"))
+            .times(2)
+            .returning(|_| Ok(()));
+        tg_client
+            .expect_send_message_with_reply_markup()
+            .withf(|chat_id, text, _, reply_markup, reply_to_id| {
+                *chat_id == 123
+                    && text == "Short"
+                    && reply_to_id.is_none()
+                    && matches!(
+                        reply_markup,
+                        Some(ReplyMarkup::InlineKeyboardMarkup {
+                            inline_keyboard
+                        }) if inline_keyboard[0].len() == 3
+                    )
+            })
+            .times(1)
+            .returning(|_, _, _, _, _| Ok(7));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Хорошо, начнём с чистого листа"),
+                eq(None),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().quick_actions = true;
+
+        for (update_id, action) in [(1, "shorter"), (2, "reset")] {
+            let request = build_json_request(
+                "/",
+                &format!(
+                    r#"{{
+                        "update_id": {update_id},
+                        "callback_query": {{
+                            "id": "42",
+                            "from": {{
+                                "id": 1,
+                                "is_bot": false,
+                                "first_name": "Yury"
+                            }},
+                            "message": {{
+                                "message_id": 5,
+                                "from": {{
+                                    "id": 2,
+                                    "is_bot": true,
+                                    "first_name": "Bot"
+                                }},
+                                "chat": {{"id": 123, "type": "private"}},
+                                "date": 0
+                            }},
+                            "data": "quick:{action}"
+                        }}
+                    }}"#
+                ),
+            );
+            assert!(bot.process_event(&request).await.is_ok());
+        }
+    }
+
+    // Test that quick actions in a chat that is not allowed are ignored
+    #[tokio::test]
+    async fn test_process_quick_action_in_unauthorized_chat() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client.expect_get_completion().never();
+        gtp_client.expect_reset_history().never();
+        tg_client
+            .expect_answer_callback_query()
+            .with(eq("42"))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let bot = create_bot(tg_client, MockGtpInteractor::new(), gtp_client);
+
+        let request = build_json_request(
+            "/",
+            r#"{
+                "update_id": 1,
+                "callback_query": {
+                    "id": "42",
+                    "from": {"id": 1, "is_bot": false, "first_name": "Yury"},
+                    "message": {
+                        "message_id": 5,
+                        "from": {"id": 2, "is_bot": true, "first_name": "Bot"},
+                        "chat": {"id": -100, "type": "group"},
+                        "date": 0
+                    },
+                    "data": "quick:continue"
+                }
+            }"#,
+        );
+        assert!(bot.process_event(&request).await.is_ok());
+    }
+
+    // Test that the answer is pinned when the message asks for it
+    #[tokio::test]
+    async fn test_process_message_pin_answer() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .with(eq(1), eq("Список дел".to_string()))
+            .times(1)
+            .returning(|_, _| Ok("1. Купить хлеб".to_string().into()));
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("1. Купить хлеб"), always(), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage { message_id: 42 }));
+        tg_client
+            .expect_pin_message()
+            .with(eq(123), eq(42))
+            .times(1)
+            .returning(|_, _| Err(anyhow!("not enough rights")));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        let message =
+            create_private_message(Some("📌 Список дел".to_string()), None);
+
+        // A failed pin does not fail the answer.
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that /retry answers the last prompt again without the cache
+    #[tokio::test]
+    async fn test_process_retry_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq(NO_PREVIOUS_PROMPT), always(), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+        gtp_client
+            .expect_get_completion()
+            .with(eq(1), eq("Hello".to_string()))
+            .times(2)
+            .returning(|_, _| Ok("Hi".to_string().into()));
+        gtp_client
+            .expect_invalidate_cache()
+            .with(eq(1))
+            .times(1)
+            .return_const(());
+        gtp_client
+            .expect_remove_last_exchange()
+            .with(eq(1))
+            .times(1)
+            .returning(|_| Ok(()));
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("Hi"), always(), eq(Some(1)))
+            .times(2)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+
+        for text in ["/retry", "Hello", "/retry"] {
+            let message = create_private_message(Some(text.to_string()), None);
+            assert!(bot.process_message(message).await.is_ok());
+        }
+    }
+
+    // Test that /retry and quick actions stay in the thread of the answer
+    #[tokio::test]
+    async fn test_process_retry_and_quick_action_in_thread() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut public_gtp_client = MockGtpInteractor::new();
+
+        let thread_id = conversation_id(1, 123, Some(5));
+        public_gtp_client
+            .expect_usage_stats()
+            .return_const(Arc::new(UsageStats::default()));
+        public_gtp_client
+            .expect_get_completion()
+            .with(eq(thread_id), always())
+            .times(2)
+            .returning(|_, _| Ok("Sure".to_string().into()));
+        public_gtp_client
+            .expect_invalidate_cache()
+            .with(eq(thread_id))
+            .return_const(());
+        public_gtp_client
+            .expect_remove_last_exchange()
+            .with(eq(thread_id))
+            .times(1)
+            .returning(|_| Ok(()));
+        public_gtp_client
+            .expect_reset_history()
+            .with(eq(thread_id))
+            .times(1)
+            .returning(|_| Ok(()));
+        tg_client
+            .expect_send_message()
+            .times(3)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+        tg_client
+            .expect_answer_callback_query()
+            .returning(|_| Ok(()));
+
+        let mut bot =
+            create_bot(tg_client, MockGtpInteractor::new(), public_gtp_client);
+        Arc::get_mut(&mut bot.config).unwrap().thread_conversations = true;
+
+        let bot_answer = || Message {
+            message_id: 5,
+            from: User {
+                is_bot: true,
+                ..Default::default()
+            },
+            chat: create_public_message(None, None).chat,
+            ..Default::default()
+        };
+        let mut message =
+            create_public_message(Some("And then?".to_string()), None);
+        message.reply_to_message = Some(Box::new(bot_answer()));
+        assert!(bot.process_message(message).await.is_ok());
+
+        let message = create_public_message(Some("/retry".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+
+        let query = CallbackQuery {
+            id: "42".to_string(),
+            from: create_public_message(None, None).from,
+            message: Some(bot_answer()),
+            data: Some("quick:reset".to_string()),
+        };
+        assert!(bot.process_callback_query(query).await.is_ok());
+    }
+
+    // Test that an edited message is answered like a new one
+    #[tokio::test]
+    async fn test_process_edited_message() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .with(eq(1), eq("Hello again".to_string()))
+            .times(1)
+            .returning(|_, _| Ok("Hi".to_string().into()));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Hi"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(5)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+
+        let request = build_json_request(
+            "/",
+            &format!(
+                r#"{{
+                    "update_id": 1,
+                    "edited_message": {{
+                        "message_id": 5,
+                        "from": {{"id": 1, "is_bot": false, "first_name": "Yury"}},
+                        "chat": {{"id": 123, "type": "private"}},
+                        "date": 0,
+                        "edit_date": {},
+                        "text": "Hello again"
+                    }}
+                }}"#,
+                Utc::now().timestamp()
+            ),
+        );
+        assert!(bot.process_event(&request).await.is_ok());
+    }
+
+    // Test that a redelivered update is processed once
+    #[tokio::test]
+    async fn test_process_duplicate_update() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client
+            .expect_answer_callback_query()
+            .with(eq("42"))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+
+        for _ in 0..2 {
+            let request = build_json_request(
+                "/",
+                r#"{
+                    "update_id": 7,
+                    "callback_query": {
+                        "id": "42",
+                        "from": {"id": 1, "is_bot": false, "first_name": "Yury"}
+                    }
+                }"#,
+            );
+            assert!(bot.process_event(&request).await.is_ok());
+        }
+    }
+
+    // Test that an update that failed with a retryable error is processed
+    // again when Telegram redelivers it
+    #[tokio::test]
+    async fn test_process_redelivered_failed_update() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut sequence = Sequence::new();
+
+        tg_client
+            .expect_answer_callback_query()
+            .with(eq("42"))
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_| Err(anyhow!("Telegram is down")));
+        tg_client
+            .expect_answer_callback_query()
+            .with(eq("42"))
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_| Ok(()));
+
+        let bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+
+        let request = || {
+            build_json_request(
+                "/",
+                r#"{
+                    "update_id": 7,
+                    "callback_query": {
+                        "id": "42",
+                        "from": {"id": 1, "is_bot": false, "first_name": "Yury"}
+                    }
+                }"#,
+            )
+        };
+        assert!(bot.process_event(&request()).await.is_err());
+        assert!(bot.process_event(&request()).await.is_ok());
+    }
+
+    // Test that an update Telegram rejected with a 400 is answered with 200
+    // and not processed again
+    #[tokio::test]
+    async fn test_process_update_rejected_by_telegram() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .times(1)
+            .returning(|_, _| Ok("Hi".to_string().into()));
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("Hi"), always(), always())
+            .times(1)
+            .returning(|_, _, _, _| {
+                Err(status_error(
+                    http::StatusCode::BAD_REQUEST,
+                    "Bad Request: can't parse entities".to_string(),
+                ))
+            });
+        tg_client
+            .expect_send_message()
+            .withf(|_, text, _, _| text.contains("can't parse entities"))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+
+        let date = Utc::now().timestamp();
+        let request = || {
+            build_json_request(
+                "/",
+                &format!(
+                    r#"{{
+                        "update_id": 7,
+                        "message": {{
+                            "message_id": 5,
+                            "from": {{"id": 1, "is_bot": false, "first_name": "Sam"}},
+                            "chat": {{"id": 123, "type": "private"}},
+                            "date": {date},
+                            "text": "Hello"
+                        }}
+                    }}"#
+                ),
+            )
+        };
+        let error = bot.process_event(&request()).await.unwrap_err();
+        assert_eq!(
+            ProcessingError::from(error).status_code(),
+            http::StatusCode::OK
+        );
+        assert!(bot.process_event(&request()).await.is_ok());
+    }
+
+    // Test that the bot leaves a group that is not allowed
+    #[tokio::test]
+    async fn test_process_unauthorized_chat() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client
+            .expect_leave_chat()
+            .with(eq(-100))
+            .times(1)
+            .returning(|_| Ok(()));
+        tg_client.expect_send_message().never();
+
+        let mut bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+        Arc::get_mut(&mut bot.config)
+            .unwrap()
+            .auto_leave_unauthorized = true;
+
+        let date = Utc::now().timestamp();
+        let request = build_json_request(
+            "/",
+            &format!(
+                r#"{{
+                    "update_id": 1,
+                    "message": {{
+                        "message_id": 5,
+                        "from": {{"id": 1, "is_bot": false, "first_name": "Sam"}},
+                        "chat": {{"id": -100, "type": "group"}},
+                        "date": {date},
+                        "text": "bot_name Hello"
+                    }}
+                }}"#
+            ),
+        );
+        assert!(bot.process_event(&request).await.is_ok());
+    }
+
+    // Test that a channel post is answered with the channel as the user
+    #[tokio::test]
+    async fn test_process_channel_post() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .with(eq(123), eq("preamble Hello".to_string()))
+            .times(1)
+            .returning(|_, _| Ok("Hi".to_string().into()));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Hi"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(5)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, MockGtpInteractor::new(), gtp_client);
+        Arc::get_mut(&mut bot.config).unwrap().channel_posts = true;
+
+        let date = Utc::now().timestamp();
+        let request = build_json_request(
+            "/",
+            &format!(
+                r#"{{
+                    "update_id": 1,
+                    "channel_post": {{
+                        "message_id": 5,
+                        "chat": {{"id": 123, "type": "channel"}},
+                        "date": {date},
+                        "text": "bot_name Hello"
+                    }}
+                }}"#
+            ),
+        );
+        assert!(bot.process_event(&request).await.is_ok());
+    }
+
+    // Test that the named preamble placeholders are filled from the message
+    #[tokio::test]
+    async fn test_process_message_preamble_variables() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .with(eq(1), eq("Sam Smith (@sam) in Team:  Hello".to_string()))
+            .times(1)
+            .returning(|_, _| Ok("Hi".to_string().into()));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Hi"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(5)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, MockGtpInteractor::new(), gtp_client);
+        Arc::get_mut(&mut bot.config).unwrap().preamble =
+            "{} {last_name} (@{username}) in {chat_title}: ".to_string();
+
+        let date = Utc::now().timestamp();
+        let request = build_json_request(
+            "/",
+            &format!(
+                r#"{{
+                    "update_id": 1,
+                    "message": {{
+                        "message_id": 5,
+                        "from": {{
+                            "id": 1,
+                            "is_bot": false,
+                            "first_name": "Sam",
+                            "last_name": "Smith",
+                            "username": "sam"
+                        }},
+                        "chat": {{"id": 123, "type": "group", "title": "Team"}},
+                        "date": {date},
+                        "text": "bot_name Hello"
+                    }}
+                }}"#
+            ),
+        );
+        assert!(bot.process_event(&request).await.is_ok());
+    }
+
+    // Test that a group message in another language gets the translated
+    // preamble and a Russian one keeps the default
+    #[tokio::test]
+    async fn test_process_message_translated_preamble() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        for prompt in [
+            "Sam wrote:  What is the weather like today?",
+            "Сэм пишет:  Какая сегодня погода?",
+        ] {
+            gtp_client
+                .expect_get_completion()
+                .with(eq(1), eq(prompt.to_string()))
+                .times(1)
+                .returning(|_, _| Ok("Hi".to_string().into()));
+        }
+        tg_client
+            .expect_send_message()
+            .times(2)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, MockGtpInteractor::new(), gtp_client);
+        let config = Arc::get_mut(&mut bot.config).unwrap();
+        config.preamble = "Сэм пишет: ".to_string();
+        config.preamble_translations =
+            HashMap::from([("en".to_string(), "Sam wrote: ".to_string())]);
+
+        let date = Utc::now().timestamp();
+        for (update_id, text) in [
+            (1, "What is the weather like today?"),
+            (2, "Какая сегодня погода?"),
+        ] {
+            let request = build_json_request(
+                "/",
+                &format!(
+                    r#"{{
+                        "update_id": {update_id},
+                        "message": {{
+                            "message_id": {update_id},
+                            "from": {{
+                                "id": 1,
+                                "is_bot": false,
+                                "first_name": "Sam"
+                            }},
+                            "chat": {{"id": 123, "type": "group"}},
+                            "date": {date},
+                            "text": "bot_name {text}"
+                        }}
+                    }}"#
+                ),
+            );
+            assert!(bot.process_event(&request).await.is_ok());
+        }
+    }
+
+    // Test that messages older than the configured age are skipped
+    #[tokio::test]
+    async fn test_process_too_old_message() {
+        let mut config = build_test_config();
+        config.max_message_age = Duration::from_secs(60);
+
+        let bot = TgBot::new(
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+            MockTelegramInteractor::new(),
+            None::<MockAuditLogStore>,
+            None::<MockPremiumStore>,
+            config,
+            || StepRng::new(0, 0),
+        );
+
+        let date = (Utc::now() - chrono::Duration::minutes(2)).timestamp();
+        let request = build_json_request(
+            "/",
+            &format!(
+                r#"{{
+                    "update_id": 1,
+                    "message": {{
+                        "message_id": 5,
+                        "from": {{"id": 0, "is_bot": false, "first_name": "Sam"}},
+                        "chat": {{"id": 0, "type": "private"}},
+                        "date": {date},
+                        "text": "Hello"
+                    }}
+                }}"#
+            ),
+        );
+        let error = bot.process_event(&request).await.unwrap_err();
+        assert_eq!(error_code(&error), Some(ErrorCode::StaleMessage));
+        assert!(matches!(
+            ProcessingError::from(error),
+            ProcessingError::Ignorable(_)
+        ));
+    }
+
+    // Test that /whoami reports the commands missing from Telegram
+    #[tokio::test]
+    async fn test_process_whoami_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client.expect_get_my_commands().times(1).returning(|| {
+            Ok(vec![BotCommand {
+                command: "start".to_string(),
+                description: "Start".to_string(),
+            }])
+        });
+
+        tg_client
+            .expect_send_message()
+            .withf(|&chat_id, text, _, _| {
+                chat_id == 123
+                    && text.starts_with("user 1 chat 123")
+                    && text.contains(
+                        "Не зарегистрированы: /tone, /model, /roll, /help, \
+                         /export, /describe, /retry",
+                    )
+            })
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+        Arc::get_mut(&mut bot.config).unwrap().admin_user_ids = vec![1];
+
+        let message = create_private_message(Some("/whoami".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a member promoted in a group can run admin commands there
+    #[tokio::test]
+    async fn test_promoted_chat_member_is_admin() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client.expect_get_my_commands().returning(|| Ok(vec![]));
+        tg_client
+            .expect_send_message()
+            .withf(|&chat_id, text, _, _| {
+                chat_id == 123 && text.starts_with("user 1 chat 123")
+            })
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+
+        let member_update = |update_id: i64, status: &str| {
+            build_json_request(
+                "/",
+                &format!(
+                    r#"{{
+                        "update_id": {update_id},
+                        "chat_member": {{
+                            "chat": {{"id": 123, "type": "group"}},
+                            "from": {{"id": 2, "is_bot": false, "first_name": "Owner"}},
+                            "old_chat_member": {{
+                                "status": "member",
+                                "user": {{"id": 1, "is_bot": false, "first_name": "Sam"}}
+                            }},
+                            "new_chat_member": {{
+                                "status": "{status}",
+                                "user": {{"id": 1, "is_bot": false, "first_name": "Sam"}}
+                            }}
+                        }}
+                    }}"#
+                ),
+            )
+        };
+
+        assert!(bot
+            .process_event(&member_update(1, "administrator"))
+            .await
+            .is_ok());
+        let message = create_public_message(Some("/whoami".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+
+        assert!(bot.process_event(&member_update(2, "member")).await.is_ok());
+        let message = create_public_message(Some("/whoami".to_string()), None);
+        assert!(bot.process_message(message).await.is_err());
+    }
+
+    // Test that chat administrators are loaded for allowed groups only
+    #[tokio::test]
+    async fn test_load_chat_administrators() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client
+            .expect_get_chat_administrators()
+            .with(eq(-100))
+            .times(1)
+            .returning(|_| {
+                Ok(vec![ChatMember {
+                    status: "creator".to_string(),
+                    user: User {
+                        id: 7,
+                        ..Default::default()
+                    },
+                }])
+            });
+
+        let mut config = build_test_config();
+        config.tg_bot_allow_chats = vec![123, -100];
+        let bot = TgBot::new(
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+            tg_client,
+            None::<MockAuditLogStore>,
+            None::<MockPremiumStore>,
+            config,
+            || StepRng::new(0, 0),
+        );
+        bot.chat_admins
+            .insert((-100, 8), AdminStatus::Administrator);
+
+        assert_eq!(bot.load_chat_administrators().await, 1);
+        assert!(bot.chat_admins.contains_key(&(-100, 7)));
+        assert!(!bot.chat_admins.contains_key(&(-100, 8)));
+    }
+
+    // Test that every admin chat is notified about shutdown
+    #[tokio::test]
+    async fn test_notify_shutdown() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client
+            .expect_send_message()
+            .withf(|&chat_id, text, _, _| {
+                [10, 20].contains(&chat_id)
+                    && text == "Бот выключается. Версия: 7. Причина: SIGTERM"
+            })
+            .times(2)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+        let config = Arc::get_mut(&mut bot.config).unwrap();
+        config.admin_chat_ids = vec![10, 20];
+        config.function_version = "7".to_string();
+
+        assert!(bot.notify_shutdown("SIGTERM").await.is_ok());
+    }
+
+    // Test that a poll is passed to GPT for analysis
+    #[tokio::test]
+    async fn test_process_poll() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_stateless_completion()
+            .with(eq("Analyze this poll: 'Tea or coffee?'. \
+                 Options: Tea (3), Coffee (5). Total voters: 8, closed. \
+                 What does this tell us?"
+                .to_string()))
+            .times(1)
+            .returning(|_| Ok("Coffee wins".to_string().into()));
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Coffee wins"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+
+        let mut message = create_private_message(None, None);
+        message.poll = Some(Poll {
+            id: "poll".to_string(),
+            question: "Tea or coffee?".to_string(),
+            options: vec![
+                PollOption {
+                    text: "Tea".to_string(),
+                    voter_count: 3,
+                },
+                PollOption {
+                    text: "Coffee".to_string(),
+                    voter_count: 5,
+                },
+            ],
+            total_voter_count: 8,
+            is_closed: true,
+        });
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that an empty GPT answer is replaced with the fallback message
+    #[tokio::test]
+    async fn test_process_message_empty_response() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .times(1)
+            .returning(|_, _| Err(GptError::EmptyResponse.into()));
+
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq(EMPTY_RESPONSE_MESSAGE), always(), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+
+        let message = create_private_message(Some("Hello".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that the user is asked to wait while the circuit is open
+    #[tokio::test]
+    async fn test_process_message_gpt_unavailable() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .times(1)
+            .returning(|_, _| {
+                Err(ProcessingError::Ignorable(GPT_UNAVAILABLE.to_string())
+                    .into())
+            });
+
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq(GPT_UNAVAILABLE), always(), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+
+        let message = create_private_message(Some("Hello".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a feature the backend lacks is reported in a group too
+    #[tokio::test]
+    async fn test_process_message_unsupported_feature() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .times(1)
+            .returning(|_, _| {
+                Err(ProcessingError::Permanent(UNSUPPORTED.to_string()).into())
+            });
+
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq(UNSUPPORTED), always(), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, MockGtpInteractor::new(), gtp_client);
+
+        let message =
+            create_public_message(Some("bot_name Hello".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that an almost full history is reported once per conversation
+    #[tokio::test]
+    async fn test_process_message_history_warning() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .times(3)
+            .returning(|_, _| Ok("Hi".to_string().into()));
+        gtp_client
+            .expect_history_fill_ratio()
+            .with(eq(1))
+            .times(3)
+            .return_const(0.9);
+        gtp_client
+            .expect_reset_history()
+            .times(1)
+            .returning(|_| Ok(()));
+        gtp_client.expect_invalidate_cache().return_const(());
+
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("Hi"), always(), eq(Some(1)))
+            .times(3)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq(HISTORY_WARNING), always(), eq(None))
+            .times(2)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Хорошо, начнём с чистого листа"),
+                always(),
+                always(),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().warn_threshold_ratio = Some(0.8);
+
+        // The warning comes again once the history is reset.
+        for text in ["Hello", "Hello again", "Забудь", "Hello once more"]
+        {
+            let message = create_private_message(Some(text.to_string()), None);
+            assert!(bot.process_message(message).await.is_ok());
+        }
+    }
+
+    // Test that a sticker is answered by its emoji or its set name
+    #[tokio::test]
+    async fn test_process_sticker() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .with(
+                eq(1),
+                eq("The user sent you a 😂 emoji, react appropriately"
+                    .to_string()),
+            )
+            .times(1)
+            .returning(|_, _| Ok("Haha".to_string().into()));
+        gtp_client
+            .expect_get_completion()
+            .with(
+                eq(1),
+                eq("The user sent you a sticker from the 'Cats' sticker \
+                 set, react appropriately"
+                    .to_string()),
+            )
+            .times(1)
+            .returning(|_, _| Ok("Meow".to_string().into()));
+
+        for answer in ["Haha", "Meow"] {
+            tg_client
+                .expect_send_message()
+                .with(eq(123), eq(answer), always(), eq(Some(1)))
+                .times(1)
+                .returning(|_, _, _, _| Ok(SentMessage::default()));
+        }
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+
+        for (emoji, set_name) in
+            [(Some("😂"), Some("Cats")), (None, Some("Cats"))]
+        {
+            let mut message = create_private_message(None, None);
+            message.sticker = Some(Sticker {
+                file_id: "sticker".to_string(),
+                emoji: emoji.map(str::to_string),
+                set_name: set_name.map(str::to_string),
+            });
+            assert!(bot.process_message(message).await.is_ok());
+        }
+
+        // In a group a sticker is only answered as a reply to the bot.
+        let mut message = create_private_message(None, None);
+        message.chat.chat_type = "group".to_string();
+        message.sticker = Some(Sticker {
+            file_id: "sticker".to_string(),
+            emoji: Some("😂".to_string()),
+            set_name: None,
+        });
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that users joining or leaving the chat are greeted, bots are not
+    #[tokio::test]
+    async fn test_process_chat_members() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        for text in ["Привет, Sam", "Пока, Yury"] {
+            tg_client
+                .expect_send_message()
+                .with(eq(123), eq(text), always(), eq(None))
+                .times(1)
+                .returning(|_, _, _, _| Ok(SentMessage::default()));
+        }
+
+        let mut bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+        let config = Arc::get_mut(&mut bot.config).unwrap();
+        config.new_member_greeting = Some("Привет, {name}".to_string());
+        config.left_member_goodbye = Some("Пока, {name}".to_string());
+
+        let mut message = create_private_message(None, None);
+        message.chat.chat_type = "group".to_string();
+        message.new_chat_members = Some(vec![
+            User {
+                id: 2,
+                first_name: "Sam".to_string(),
+                ..Default::default()
+            },
+            User {
+                id: 3,
+                is_bot: true,
+                first_name: "Other bot".to_string(),
+                ..Default::default()
+            },
+        ]);
+        message.left_chat_member = Some(User {
+            id: 1,
+            first_name: "Yury".to_string(),
+            ..Default::default()
+        });
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a quiz request is answered with the poll GPT wrote
+    #[tokio::test]
+    async fn test_process_quiz_request() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_stateless_completion()
+            .withf(|prompt| {
+                prompt.contains("quiz") && prompt.contains("'про планеты'")
+            })
+            .times(1)
+            .returning(|_| {
+                Ok(r#"```json
+{"question": "Самая большая планета?", "options": ["Марс", "Юпитер"], "answer": 1}
+```"#
+                    .to_string()
+                    .into())
+            });
+        tg_client
+            .expect_send_poll()
+            .withf(|chat_id, question, options, is_quiz, correct_option_id| {
+                *chat_id == 123
+                    && question == "Самая большая планета?"
+                    && *options == ["Марс", "Юпитер"]
+                    && *is_quiz
+                    && *correct_option_id == Some(1)
+            })
+            .times(1)
+            .returning(|_, _, _, _, _| Ok(()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        let message = create_private_message(
+            Some("Викторина про планеты".to_string()),
+            None,
+        );
+
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a poll Telegram rejects is reported to the user
+    #[tokio::test]
+    async fn test_process_poll_request_send_failure() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_stateless_completion()
+            .times(1)
+            .returning(|_| {
+                Ok(r#"{"question": "Чай?", "options": ["Да", "Нет"]}"#
+                    .to_string()
+                    .into())
+            });
+        tg_client
+            .expect_send_poll()
+            .times(1)
+            .returning(|_, _, _, _, _| Err(anyhow!("Bad Request")));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Не получилось составить опрос"),
+                eq(None),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        let message =
+            create_private_message(Some("Опрос про чай".to_string()), None);
+
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a reloaded config snapshot applies to the next message
+    #[tokio::test]
+    async fn test_process_message_with_reloaded_config() {
+        let mut gtp_client = MockGtpInteractor::new();
+        let mut private_gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_migrate_rules()
+            .with(eq(""), eq("reloaded rules"))
+            .times(1)
+            .returning(|_, _| Ok(1));
+        private_gtp_client
+            .expect_migrate_rules()
+            .with(eq(""), eq("reloaded rules"))
+            .times(1)
+            .returning(|_, _| Ok(1));
+
+        let bot = create_bot(
+            MockTelegramInteractor::new(),
+            private_gtp_client,
+            gtp_client,
+        );
+        bot.config_snapshot().store(Arc::new(ConfigSnapshot {
+            dummy_answers: Vec::new(),
+            tg_bot_allow_chats: Vec::new(),
+            base_rules: "reloaded rules".to_string(),
+        }));
+
+        let message = create_private_message(Some("Hello".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+        assert_eq!(
+            bot.rules_snapshot.lock().await.base_rules,
+            "reloaded rules"
+        );
+    }
+
+    // Test that a chat boost grants the booster premium access
+    #[tokio::test]
+    async fn test_process_chat_boost() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut premium_store = MockPremiumStore::new();
+
+        premium_store
+            .expect_grant()
+            .withf(|&user_id, until| {
+                user_id == 7
+                    && *until
+                        > Utc::now().naive_utc() + chrono::Duration::days(6)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        tg_client
+            .expect_send_message()
+            .with(eq(7), always(), always(), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = TgBot::new(
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+            tg_client,
+            None::<MockAuditLogStore>,
+            Some(premium_store),
+            build_test_config(),
+            || StepRng::new(0, 0),
+        );
+
+        let request = build_json_request(
+            "/",
+            r#"{
+                "update_id": 1,
+                "chat_boost": {
+                    "chat": {"id": 0, "type": "supergroup"},
+                    "boost": {
+                        "boost_id": "boost",
+                        "add_date": 0,
+                        "expiration_date": 0,
+                        "source": {
+                            "source": "premium",
+                            "user": {"id": 7, "is_bot": false, "first_name": "Ann"}
+                        }
+                    }
+                }
+            }"#,
+        );
+        assert!(bot.process_event(&request).await.is_ok());
+    }
+
+    // Test that a rolled dice value is added to the conversation
+    #[tokio::test]
+    async fn test_process_roll_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        tg_client
+            .expect_send_dice()
+            .with(eq(123), eq("🎲"))
+            .times(1)
+            .returning(|_, _| {
+                Ok(Dice {
+                    emoji: "🎲".to_string(),
+                    value: 4,
+                })
+            });
+
+        gtp_client
+            .expect_add_system_message()
+            .with(always(), eq("The dice roll resulted in 4".to_string()))
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+
+        let message = create_private_message(Some("/roll".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that non-English input and the answer are translated
+    #[tokio::test]
+    async fn test_process_message_with_translation() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_stateless_completion()
+            .withf(|prompt| {
+                prompt
+                    .ends_with("'en'. Reply with the translation only: Привет")
+            })
+            .times(1)
+            .returning(|_| Ok("Hello".to_string().into()));
+
+        gtp_client
+            .expect_get_completion()
+            .with(always(), eq("Hello".to_string()))
+            .times(1)
+            .returning(|_, _| Ok("Hi there".to_string().into()));
+
+        gtp_client
+            .expect_get_stateless_completion()
+            .withf(|prompt| {
+                prompt.ends_with(
+                    "'ru'. Reply with the translation only: Hi there",
+                )
+            })
+            .times(1)
+            .returning(|_| Ok("Приветствую".to_string().into()));
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Приветствую"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        let config = Arc::get_mut(&mut bot.config).unwrap();
+        config.auto_translate_input = Some("en".to_string());
+        config.auto_translate_output = true;
+
+        let mut message =
+            create_private_message(Some("Привет".to_string()), None);
+        message.from.language_code = Some("ru".to_string());
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a voice message is sent to GPT as audio
+    #[tokio::test]
+    async fn test_process_voice_message() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client.expect_supports_audio_input().return_const(true);
+        gtp_client
+            .expect_get_audio_completion()
+            .with(always(), eq(vec![1, 2, 3]), eq("mp3"))
+            .times(1)
+            .returning(|_, _, _| Ok("Hi".to_string().into()));
+
+        tg_client
+            .expect_get_file_url()
+            .with(eq("voice_id"))
+            .times(1)
+            .returning(|_| Ok("url".to_string()));
+        tg_client
+            .expect_download_file()
+            .with(eq("url"))
+            .times(1)
+            .returning(|_| Ok(vec![1, 2, 3]));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Hi"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        let mut message = create_private_message(None, None);
+        message.voice = Some(Voice {
+            file_id: "voice_id".to_string(),
+            duration: 3,
+            mime_type: Some("audio/mpeg".to_string()),
+        });
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that an OGG voice note is transcribed even with an audio model
+    #[tokio::test]
+    async fn test_process_ogg_voice_message_with_audio_input() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client.expect_supports_audio_input().return_const(true);
+        gtp_client.expect_get_audio_completion().never();
+        gtp_client
+            .expect_transcribe_audio()
+            .with(eq(vec![1, 2, 3]), eq("audio/ogg"))
+            .times(1)
+            .returning(|_, _| Ok("Hello".to_string().into()));
+        gtp_client
+            .expect_get_completion()
+            .with(eq(1), eq("Hello".to_string()))
+            .times(1)
+            .returning(|_, _| Ok("Hi".to_string().into()));
+
+        tg_client
+            .expect_get_file_url()
+            .times(1)
+            .returning(|_| Ok("url".to_string()));
+        tg_client
+            .expect_download_file()
+            .times(1)
+            .returning(|_| Ok(vec![1, 2, 3]));
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("Hi"), always(), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        let mut message = create_private_message(None, None);
+        message.voice = Some(Voice {
+            file_id: "voice_id".to_string(),
+            duration: 3,
+            mime_type: Some("audio/ogg".to_string()),
+        });
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that the forget keyword resets the history of the user
+    #[tokio::test]
+    async fn test_process_message_with_forget_keyword() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_invalidate_cache()
+            .with(eq(1))
+            .times(2)
+            .return_const(());
+        gtp_client
+            .expect_reset_history()
+            .with(eq(1))
+            .times(2)
+            .returning(|_| Ok(()));
+        gtp_client.expect_get_completion().never();
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Хорошо, начнём с чистого листа"),
+                eq(None),
+                eq(Some(1)),
+            )
+            .times(2)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().forget_keyword =
+            Some("forget".to_string());
+
+        let message =
+            create_private_message(Some("Забудь всё".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+
+        let message =
+            create_private_message(Some("FORGET it, please".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a voice message is transcribed and answered as text
+    #[tokio::test]
+    async fn test_process_transcribed_voice_message() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client.expect_supports_audio_input().return_const(false);
+        gtp_client
+            .expect_transcribe_audio()
+            .with(eq(vec![1, 2, 3]), eq("audio/ogg"))
+            .times(1)
+            .returning(|_, _| Ok("Hello".to_string().into()));
+        gtp_client
+            .expect_get_completion()
+            .with(eq(1), eq("Hello".to_string()))
+            .times(1)
+            .returning(|_, _| Ok("Hi".to_string().into()));
+
+        tg_client
+            .expect_get_file_url()
+            .with(eq("voice_id"))
+            .times(1)
+            .returning(|_| Ok("url".to_string()));
+        tg_client
+            .expect_download_file()
+            .with(eq("url"))
+            .times(1)
+            .returning(|_| Ok(vec![1, 2, 3]));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Hi"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        let mut message = create_private_message(None, None);
+        message.voice = Some(Voice {
+            file_id: "voice_id".to_string(),
+            duration: 3,
+            mime_type: Some("audio/ogg".to_string()),
+        });
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a transcribed voice message is answered with voice
+    #[tokio::test]
+    async fn test_process_voice_message_with_voice_answer() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client.expect_supports_audio_input().return_const(false);
+        gtp_client
+            .expect_transcribe_audio()
+            .times(1)
+            .returning(|_, _| Ok("Hello".to_string().into()));
+        gtp_client
+            .expect_get_completion()
+            .times(1)
+            .returning(|_, _| Ok("Hi".to_string().into()));
+        gtp_client
+            .expect_get_audio()
+            .with(eq("Hi"))
+            .times(1)
+            .returning(|_| Ok(vec![4, 5, 6]));
+
+        tg_client
+            .expect_get_file_url()
+            .times(1)
+            .returning(|_| Ok("url".to_string()));
+        tg_client
+            .expect_download_file()
+            .times(1)
+            .returning(|_| Ok(vec![1, 2, 3]));
+        tg_client
+            .expect_send_voice()
+            .with(eq(123), eq(vec![4, 5, 6]), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        tg_client.expect_send_message().never();
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().respond_with_voice = true;
+
+        let mut message = create_private_message(None, None);
+        message.voice = Some(Voice {
+            file_id: "voice_id".to_string(),
+            duration: 3,
+            mime_type: Some("audio/ogg".to_string()),
+        });
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a streamed answer is sent once and then edited
+    #[tokio::test]
+    async fn test_process_streamed_answer() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion_stream()
+            .with(eq(1), eq("Hello".to_string()))
+            .times(1)
+            .returning(|_, _| {
+                let chunks = ["Hi", ", ", "there"]
+                    .map(|chunk| Ok(Arc::new(chunk.to_string())));
+                Ok(stream::iter(chunks).boxed())
+            });
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Hi"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage { message_id: 42 }));
+        tg_client
+            .expect_edit_message()
+            .withf(|chat_id, message_id, text, _, reply_markup| {
+                *chat_id == 123
+                    && *message_id == 42
+                    && text == "Hi, there"
+                    && reply_markup.is_some()
+            })
+            .times(1)
+            .returning(|_, _, _, _, _| Ok(()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        let config = Arc::get_mut(&mut bot.config).unwrap();
+        config.stream_responses = true;
+        config.quick_actions = true;
+
+        let message = create_private_message(Some("Hello".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a blank stream is answered with the empty response message
+    #[tokio::test]
+    async fn test_process_blank_streamed_answer() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion_stream()
+            .times(1)
+            .returning(|_, _| {
+                let chunks = [Ok(Arc::new("\n".to_string()))];
+                Ok(stream::iter(chunks).boxed())
+            });
+
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq(EMPTY_RESPONSE_MESSAGE), always(), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().stream_responses = true;
+
+        let message = create_private_message(Some("Hello".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a stream failing after the first piece ends with a note
+    #[tokio::test]
+    async fn test_process_interrupted_streamed_answer() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion_stream()
+            .times(1)
+            .returning(|_, _| {
+                let chunks = [
+                    Ok(Arc::new("Hi".to_string())),
+                    Err(anyhow!("Connection reset")),
+                ];
+                Ok(stream::iter(chunks).boxed())
+            });
+
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("Hi"), always(), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage { message_id: 42 }));
+        tg_client
+            .expect_edit_message()
+            .with(
+                eq(123),
+                eq(42),
+                eq(format!("Hi\n\n{STREAM_INTERRUPTED}")),
+                always(),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _, _| Ok(()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().stream_responses = true;
+
+        let message = create_private_message(Some("Hello".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that /model switches the model of the chat
+    #[tokio::test]
+    async fn test_process_model_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client.expect_models().return_const(["fast", "smart"]);
+        gtp_client
+            .expect_get_model_completion()
+            .with(eq(1), eq("smart"), eq("Hello".to_string()))
+            .times(1)
+            .returning(|_, _, _| Ok("Hi".to_string().into()));
+
+        for (text, reply_to_id) in [
+            ("Текущая модель: fast", None),
+            ("Доступные модели: fast, smart", None),
+            ("Модель: smart", None),
+            ("Текущая модель: smart", None),
+            ("Hi", Some(1)),
+        ] {
+            tg_client
+                .expect_send_message()
+                .with(
+                    eq(123),
+                    eq(text),
+                    eq(Some(ParseMode::MarkdownV2)),
+                    eq(reply_to_id),
+                )
+                .times(1)
+                .returning(|_, _, _, _| Ok(SentMessage::default()));
+        }
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+
+        for text in [
+            "/model",
+            "/model gpt-9",
+            "/model@gpt_bot smart",
+            "/model",
+            "Hello",
+        ] {
+            let message = create_private_message(Some(text.to_string()), None);
+            assert!(bot.process_message(message).await.is_ok());
+        }
+    }
+
+    // Test that an admin may pick only a model the API has
+    #[tokio::test]
+    async fn test_process_model_command_by_admin() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client.expect_models().return_const(["fast", "smart"]);
+        gtp_client
+            .expect_list_available_models()
+            .times(3)
+            .returning(|| Ok(vec!["gpt-9".to_string()]));
+
+        for (text, times) in
+            [("Модель: gpt-9", 2), ("Модель gpt-x недоступна", 1)]
+        {
+            tg_client
+                .expect_send_message()
+                .with(eq(123), eq(text), always(), eq(None))
+                .times(times)
+                .returning(|_, _, _, _| Ok(SentMessage::default()));
+        }
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().admin_user_ids = vec![1];
+
+        for text in ["/model gpt-9", "/model gpt-9", "/model gpt-x"] {
+            let message = create_private_message(Some(text.to_string()), None);
+            assert!(bot.process_message(message).await.is_ok());
+        }
+        assert_eq!(bot.model_names.len(), 1);
+    }
+
+    // Test that a user over the rate limit is asked to wait
+    #[tokio::test]
+    async fn test_process_rate_limited_message() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_completion()
+            .times(1)
+            .returning(|_, _| Ok("Hi".to_string().into()));
+
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Hi"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("Подожди немного"), eq(None), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut config = build_test_config();
+        config.tg_bot_allow_chats = vec![123];
+        config.rate_limit_count = Some(1);
+        let bot = TgBot::new(
+            MockGtpInteractor::new(),
+            gtp_client,
+            tg_client,
+            None::<MockAuditLogStore>,
+            None::<MockPremiumStore>,
+            config,
+            || StepRng::new(0, 0),
+        );
+
+        for text in ["Hello", "Hello again"] {
+            let message = create_private_message(Some(text.to_string()), None);
+            assert!(bot.process_message(message).await.is_ok());
+        }
+    }
+
+    // Test that drawing over the image limit reports the cooldown
+    #[tokio::test]
+    async fn test_process_image_rate_limited_message() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_image()
+            .times(1)
+            .returning(|_, _, _| Ok(ImageContent::Url("url".to_string())));
+        tg_client
+            .expect_send_image()
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        tg_client
+            .expect_send_message()
+            .withf(|&chat_id, text, _, &reply_to_id| {
+                chat_id == 123
+                    && text.starts_with("Картинок пока хватит")
+                    && text.ends_with("через 10 мин")
+                    && reply_to_id == Some(1)
+            })
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut config = build_test_config();
+        config.tg_bot_allow_chats = vec![123];
+        config.image_rate_limit_count = 1;
+        let bot = TgBot::new(
+            MockGtpInteractor::new(),
+            gtp_client,
+            tg_client,
+            None::<MockAuditLogStore>,
+            None::<MockPremiumStore>,
+            config,
+            || StepRng::new(0, 0),
+        );
+
+        for text in ["нарисуй cat", "нарисуй dog"] {
+            let message = create_private_message(Some(text.to_string()), None);
+            assert!(bot.process_message(message).await.is_ok());
+        }
+    }
+
+    // Test that the text of a document is sent to GPT with the caption
+    #[tokio::test]
+    async fn test_process_document() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        tg_client
+            .expect_get_file_url()
+            .with(eq("doc_id"))
+            .times(1)
+            .returning(|_| Ok("url".to_string()));
+        tg_client
+            .expect_download_file()
+            .with(eq("url"))
+            .times(1)
+            .returning(|_| Ok(b"Rust is fast\n".to_vec()));
+        gtp_client
+            .expect_get_completion()
+            .with(eq(1), eq("Rust is fast\n\nIs it true?".to_string()))
+            .times(1)
+            .returning(|_, _| Ok("Yes".to_string().into()));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Yes"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Формат файла не поддерживается"),
+                eq(None),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config)
+            .unwrap()
+            .enable_document_analysis = true;
+
+        for mime_type in ["text/plain", "image/png"] {
+            let mut message = create_private_message(None, None);
+            message.caption = Some("Is it true?".to_string());
+            message.document = Some(Document {
+                file_id: "doc_id".to_string(),
+                file_name: None,
+                mime_type: Some(mime_type.to_string()),
+            });
+            assert!(bot.process_message(message).await.is_ok());
+        }
+    }
+
+    // Test that a broken PDF is answered with a message, not an error
+    #[tokio::test]
+    async fn test_process_broken_pdf_document() {
+        let mut tg_client = MockTelegramInteractor::new();
+
+        tg_client
+            .expect_get_file_url()
+            .times(1)
+            .returning(|_| Ok("url".to_string()));
+        tg_client
+            .expect_download_file()
+            .times(1)
+            .returning(|_| Ok(b"not a pdf".to_vec()));
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq(DOCUMENT_UNREADABLE), eq(None), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+        Arc::get_mut(&mut bot.config)
+            .unwrap()
+            .enable_document_analysis = true;
+
+        let mut message = create_private_message(None, None);
+        message.document = Some(Document {
+            file_id: "doc_id".to_string(),
+            file_name: None,
+            mime_type: Some("application/pdf".to_string()),
+        });
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that /stats sends the usage counters to a bot admin only
+    #[tokio::test]
+    async fn test_process_stats_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut public_gtp_client = MockGtpInteractor::new();
+
+        let usage_stats = Arc::new(UsageStats::default());
+        usage_stats.record_request();
+        usage_stats.record_completion(10);
+        public_gtp_client
+            .expect_usage_stats()
+            .times(1)
+            .return_const(usage_stats);
+
+        tg_client
+            .expect_send_message()
+            .withf(|chat_id, text, _, _| {
+                *chat_id == 123
+                    && text.contains("Запросы: 1")
+                    && text.contains("Токены: 10")
+            })
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, MockGtpInteractor::new(), public_gtp_client);
+        Arc::get_mut(&mut bot.config).unwrap().admin_user_ids = vec![1];
+
+        let message = create_private_message(Some("/stats".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+
+        bot.chat_admins.insert((123, 2), AdminStatus::Administrator);
+        let mut message =
+            create_private_message(Some("/stats".to_string()), None);
+        message.from.id = 2;
+        assert!(bot.process_message(message).await.is_err());
+    }
+
+    // Test that the usage report estimates the cost of each user
+    #[tokio::test]
+    async fn test_process_usage_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut public_gtp_client = MockGtpInteractor::new();
+
+        let usage_stats = Arc::new(UsageStats::default());
+        usage_stats.record_user_tokens(7, 2000, 1000);
+        public_gtp_client
+            .expect_usage_stats()
+            .times(1)
+            .return_const(usage_stats);
+
+        tg_client
+            .expect_send_message()
+            .withf(|chat_id, text, _, _| {
+                *chat_id == 123
+                    && text.contains("7: 2000 + 1000, $0.0400")
+                    && text.contains("Всего: 2000 + 1000, $0.0400")
+            })
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, MockGtpInteractor::new(), public_gtp_client);
+        let config = Arc::get_mut(&mut bot.config).unwrap();
+        config.admin_user_ids = vec![1];
+        config.input_cost_per_1k = 0.01;
+        config.output_cost_per_1k = 0.02;
+
+        let message = create_private_message(Some("/usage".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that the chat prompt is used until it is cleared
+    #[tokio::test]
+    async fn test_process_set_prompt_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
+
+        gtp_client
+            .expect_get_system_prompt_completion()
+            .withf(|_, system_prompt, prompt| {
+                system_prompt == "Be a pirate" && prompt == "Hello"
+            })
+            .times(1)
+            .returning(|_, _, _| Ok("Arr".to_string().into()));
+        gtp_client
+            .expect_get_completion()
+            .times(1)
+            .returning(|_, _| Ok("Hi".to_string().into()));
+
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("Промпт чата обновлён"), always(), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("Arr"), always(), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("Промпт чата сброшен"), always(), eq(None))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+        tg_client
+            .expect_send_message()
+            .with(eq(123), eq("Hi"), always(), eq(Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().admin_user_ids = vec![1];
+
+        for text in ["/setprompt Be a pirate", "Hello", "/clearprompt", "Hi"] {
+            let message = create_private_message(Some(text.to_string()), None);
+            assert!(bot.process_message(message).await.is_ok());
         }
     }
 
-    false
-}
+    // Test that updates of blocked users and chats are skipped silently
+    #[tokio::test]
+    async fn test_process_blocked_message() {
+        let mut tg_client = MockTelegramInteractor::new();
+        tg_client.expect_answer_callback_query().never();
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Пользователь 7 заблокирован"),
+                always(),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
 
-fn eq_case_insensitive(a: char, b: char) -> bool {
-    let mut a_lower = a.to_lowercase();
-    let mut b_lower = b.to_lowercase();
-    loop {
-        match (a_lower.next(), b_lower.next()) {
-            (Some(a_c), Some(b_c)) if a_c == b_c => continue,
-            (None, None) => return true,
-            _ => return false,
+        let mut bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+        let config = Arc::get_mut(&mut bot.config).unwrap();
+        config.admin_user_ids = vec![1];
+        config.blocked_chat_ids = HashSet::from([-100]);
+
+        let message =
+            create_private_message(Some("/block 7".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+
+        let date = Utc::now().timestamp();
+        for (update_id, user_id, chat_id) in [(1, 7, 123), (2, 1, -100)] {
+            let request = build_json_request(
+                "/",
+                &format!(
+                    r#"{{
+                        "update_id": {update_id},
+                        "message": {{
+                            "message_id": 5,
+                            "from": {{
+                                "id": {user_id},
+                                "is_bot": false,
+                                "first_name": "Sam"
+                            }},
+                            "chat": {{"id": {chat_id}, "type": "group"}},
+                            "date": {date},
+                            "text": "bot_name Hello"
+                        }}
+                    }}"#
+                ),
+            );
+            assert!(bot.process_event(&request).await.is_ok());
         }
+
+        let request = build_json_request(
+            "/",
+            r#"{
+                "update_id": 3,
+                "callback_query": {
+                    "id": "42",
+                    "from": {"id": 7, "is_bot": false, "first_name": "Sam"},
+                    "data": "quick:continue"
+                }
+            }"#,
+        );
+        assert!(bot.process_event(&request).await.is_ok());
     }
-}
 
-#[derive(Error, Debug, Constructor)]
-#[error("{msg:?}")]
-pub struct RequestError {
-    pub msg: &'static str,
-}
+    // Test that the typing action is shown while GPT answers
+    #[tokio::test]
+    async fn test_process_message_with_chat_action() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
 
-//unit tests
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
+        tg_client
+            .expect_send_chat_action()
+            .with(eq(123), eq(ChatAction::Typing))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        gtp_client
+            .expect_get_completion()
+            .times(1)
+            .returning(|_, _| Ok("Hi".to_string().into()));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Hi"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
 
-    use chrono::Utc;
-    use mockall::predicate::eq;
-    use rand::rngs::mock::StepRng;
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().chat_actions = true;
 
-    use crate::gpt_client::MockGtpInteractor;
-    use crate::message_processor::contains_case_insensitive;
-    use crate::tg_client::{
-        Chat, Message, MockTelegramInteractor, PhotoSize, User, PRIVATE_CHAT,
-    };
+        let message = create_private_message(Some("Hello".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
+    }
 
-    use super::{should_answer, Config, TgBot};
+    // Test that /export sends the history without the System messages
+    #[tokio::test]
+    async fn test_process_export_command() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
 
-    #[test]
-    fn test_contains_case_insensitive() {
-        assert!(contains_case_insensitive("Hello", "hello"));
-        assert!(contains_case_insensitive("Придумай", "придумай"));
-    }
+        gtp_client
+            .expect_get_history()
+            .with(eq(1))
+            .times(1)
+            .returning(|_| {
+                let plain = |text: &str| Value::Plain(text.to_string().into());
+                let photo: GptMessage = serde_json::from_str(
+                    r#"{"role": "user", "content": [{
+                        "type": "image_url",
+                        "image_url": {"url": "https://api.telegram.org/file/bot1:secret/a.jpg"}
+                    }]}"#,
+                )
+                .unwrap();
+                Ok(vec![
+                    GptMessage::System(plain("rules")).into(),
+                    GptMessage::User(plain("Hello")).into(),
+                    GptMessage::Assistant(plain("Hi")).into(),
+                    photo.into(),
+                ])
+            });
+        tg_client
+            .expect_send_document()
+            .withf(|chat_id, filename, data| {
+                let history: Vec<StoredMessage> =
+                    serde_json::from_slice(data).unwrap();
+                *chat_id == 123
+                    && filename == "history.json"
+                    && history.len() == 3
+                    && history.iter().all(|stored| stored.timestamp.is_some())
+                    && !String::from_utf8_lossy(data).contains("secret")
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
 
-    // test for should_answer function
-    #[test]
-    fn test_should_answer() {
-        let reply_to_message = build_private_message();
-        let chat = Chat {
-            id: 123,
-            first_name: None,
-            last_name: None,
-            username: None,
-            chat_type: "private".to_string(),
-        };
-        let used_name = Some("Hello");
-        let tg_bot_allow_chats = vec![123];
-        assert!(should_answer(
-            reply_to_message.as_deref(),
-            &chat,
-            used_name,
-            &tg_bot_allow_chats
-        ));
-    }
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
 
-    // test for should_answer function for negative case
-    #[test]
-    fn test_should_answer_negative() {
-        let reply_to_message = build_private_message();
-        let chat = Chat {
-            id: 123,
-            first_name: None,
-            last_name: None,
-            username: None,
-            chat_type: "private".to_string(),
-        };
-        let used_name = Some("Hello");
-        let tg_bot_allow_chats = vec![124];
-        assert!(!should_answer(
-            reply_to_message.as_deref(),
-            &chat,
-            used_name,
-            &tg_bot_allow_chats
-        ));
+        let message = create_private_message(Some("/export".to_string()), None);
+        assert!(bot.process_message(message).await.is_ok());
     }
 
-    //test for process_message function
+    // Test that /export in a group does not post the history
     #[tokio::test]
-    async fn test_process_message() {
-        let message = build_public_message().unwrap();
+    async fn test_process_export_command_in_group() {
         let mut tg_client = MockTelegramInteractor::new();
-        let private_gtp_client = MockGtpInteractor::new();
-        let mut public_gtp_client = MockGtpInteractor::new();
-
-        public_gtp_client
-            .expect_get_completion()
-            .times(1)
-            .with(eq("Call me Bob.  Hello".to_string()))
-            .returning(|_| Ok("How are you?".to_string().into()));
 
         tg_client
             .expect_send_message()
+            .with(eq(123), eq(EXPORT_PRIVATE_ONLY), eq(None), eq(None))
             .times(1)
-            .with(eq(0), eq("How are you?"), eq(Some("MarkdownV2")))
-            .returning(|_, _, _| Ok(()));
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+        tg_client.expect_send_document().never();
 
-        let bot = TgBot::new(
-            public_gtp_client,
-            private_gtp_client,
+        let bot = create_bot(
             tg_client,
-            build_test_config(),
-            || StepRng::new(0, 0),
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
         );
-        let result = bot.process_message(*message).await;
-        assert!(result.is_ok());
-    }
 
-    fn build_test_config() -> Config {
-        Config::new(
-            HashMap::from_iter(vec![("Sam".to_string(), "Bob".to_string())]),
-            "Call me {}. ".to_string(),
-            vec![
-                "Dummy answer",
-                "Another dummy answer",
-                "Yet another dummy answer",
-            ],
-            vec![0],
-            vec!["simple bot"],
-        )
+        let mut message =
+            create_private_message(Some("/export".to_string()), None);
+        message.chat.chat_type = "group".to_string();
+        assert!(bot.process_message(message).await.is_ok());
     }
 
-    // Test when the message contains a photo
+    // Test that "подумай глубоко" is answered by the reasoning model
     #[tokio::test]
-    async fn test_process_message_with_photo() {
+    async fn test_process_reasoning_request() {
         let mut tg_client = MockTelegramInteractor::new();
         let mut gtp_client = MockGtpInteractor::new();
-        let public_gtp_client = MockGtpInteractor::new();
 
+        gtp_client
+            .expect_get_reasoning_completion()
+            .with(eq(1), eq("Подумай глубоко о жизни".to_string()))
+            .times(1)
+            .returning(|_, _| Ok("42".to_string().into()));
         tg_client
-            .expect_get_file_url()
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("42"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
             .times(1)
-            .with(eq("file_id"))
-            .returning(|_| Ok("url".to_string()));
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let bot = create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+
+        let message = create_private_message(
+            Some("Подумай глубоко о жизни".to_string()),
+            None,
+        );
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that the replied-to user message is added to the prompt as context
+    #[tokio::test]
+    async fn test_process_message_with_reply_context() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
 
         gtp_client
-            .expect_get_image_completion()
+            .expect_get_completion()
+            .withf(|_, prompt| prompt == "Контекст: Hello\nWhat is it?")
+            .times(1)
+            .returning(|_, _| Ok("Greeting".to_string().into()));
+        tg_client
+            .expect_send_message()
+            .with(
+                eq(123),
+                eq("Greeting"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
             .times(1)
-            .with(eq("Что на картинке?".to_string()), eq("url".to_string()))
-            .returning(|_, _| Ok("Red image".to_string().into()));
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().reply_context = true;
+
+        let mut message =
+            create_private_message(Some("What is it?".to_string()), None);
+        message.reply_to_message = build_private_message();
+        assert!(bot.process_message(message).await.is_ok());
+    }
+
+    // Test that a trigger in the replied-to message is not acted on
+    #[tokio::test]
+    async fn test_process_message_with_reply_context_trigger() {
+        let mut tg_client = MockTelegramInteractor::new();
+        let mut gtp_client = MockGtpInteractor::new();
 
+        gtp_client.expect_get_image().never();
+        gtp_client
+            .expect_get_completion()
+            .withf(|_, prompt| prompt == "Контекст: нарисуй кота\nЗачем?")
+            .times(1)
+            .returning(|_, _| Ok("Because".to_string().into()));
         tg_client
             .expect_send_message()
+            .with(eq(123), eq("Because"), always(), eq(Some(1)))
             .times(1)
-            .with(eq(123), eq("Red image"), eq(Some("MarkdownV2")))
-            .returning(|_, _, _| Ok(()));
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
 
-        let bot = create_bot(tg_client, gtp_client, public_gtp_client);
-        let message = create_private_message(
-            None,
-            Some(vec![PhotoSize {
-                file_id: "file_id".to_string(),
-                file_size: 1,
-            }]),
-        );
-        let result = bot.process_message(message).await;
-        assert!(result.is_ok());
+        let mut bot =
+            create_bot(tg_client, gtp_client, MockGtpInteractor::new());
+        Arc::get_mut(&mut bot.config).unwrap().reply_context = true;
+
+        let mut message =
+            create_private_message(Some("Зачем?".to_string()), None);
+        let mut reply = build_private_message().unwrap();
+        reply.text = Some("нарисуй кота".to_string());
+        message.reply_to_message = Some(reply);
+        assert!(bot.process_message(message).await.is_ok());
     }
 
-    // Test when the message contains a text with a URL
+    // Test that a reply to the bot in a group continues the thread's history
     #[tokio::test]
-    async fn test_process_message_with_url() {
+    async fn test_process_message_in_thread_conversation() {
         let mut tg_client = MockTelegramInteractor::new();
-        let gtp_client = MockGtpInteractor::new();
-        let public_gtp_client = MockGtpInteractor::new();
+        let mut public_gtp_client = MockGtpInteractor::new();
 
+        let thread_id = conversation_id(1, 123, Some(5));
+        let usage_stats = Arc::new(UsageStats::default());
+        public_gtp_client
+            .expect_usage_stats()
+            .return_const(usage_stats);
+        public_gtp_client
+            .expect_get_completion()
+            .withf(move |conversation_id, _| *conversation_id == thread_id)
+            .times(1)
+            .returning(|_, _| Ok("Sure".to_string().into()));
         tg_client
             .expect_send_message()
-            .with(eq(123), eq("Another dummy answer"), eq(Some("MarkdownV2")))
+            .with(eq(123), eq("Sure"), always(), eq(Some(1)))
             .times(1)
-            .returning(|_, _, _| Ok(()));
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
 
-        let bot = create_bot(tg_client, gtp_client, public_gtp_client);
-        let message = create_public_message(
-            Some("https://example.com".to_string()),
-            None,
-        );
-        let result = bot.process_message(message).await;
-        assert!(result.is_ok());
+        let mut bot =
+            create_bot(tg_client, MockGtpInteractor::new(), public_gtp_client);
+        Arc::get_mut(&mut bot.config).unwrap().thread_conversations = true;
+
+        let mut message =
+            create_public_message(Some("And then?".to_string()), None);
+        message.reply_to_message = Some(Box::new(Message {
+            message_id: 5,
+            from: User {
+                is_bot: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        assert!(bot.process_message(message).await.is_ok());
     }
 
-    // Test when the message contains a text with a bot name
+    // Test that a reaction is added to the answered message
     #[tokio::test]
-    async fn test_process_message_with_bot_name() {
+    async fn test_process_message_with_reaction() {
         let mut tg_client = MockTelegramInteractor::new();
-        let gtp_client = MockGtpInteractor::new();
         let mut public_gtp_client = MockGtpInteractor::new();
 
         public_gtp_client
             .expect_get_completion()
-            .with(eq("preamble Hello".to_string()))
             .times(1)
-            .returning(|_| Ok("Hello Sir".to_string().into()));
+            .returning(|_, _| Ok("How are you?".to_string().into()));
 
         tg_client
             .expect_send_message()
-            .with(eq(123), eq("Hello Sir"), eq(Some("MarkdownV2")))
+            .times(1)
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
+
+        tg_client
+            .expect_set_reaction()
+            .with(eq(0), eq(0), eq("👍"))
             .times(1)
             .returning(|_, _, _| Ok(()));
 
-        let bot = create_bot(tg_client, gtp_client, public_gtp_client);
-        let message =
-            create_public_message(Some("bot_name Hello".to_string()), None);
-        let result = bot.process_message(message).await;
+        let mut config = build_test_config();
+        config.reaction_emojis = vec!["👍".to_string()];
+
+        let bot = TgBot::new(
+            public_gtp_client,
+            MockGtpInteractor::new(),
+            tg_client,
+            None::<MockAuditLogStore>,
+            None::<MockPremiumStore>,
+            config,
+            || StepRng::new(0, 0),
+        );
+        let message = build_public_message().unwrap();
+        let result = bot.process_message(*message).await;
         assert!(result.is_ok());
     }
 
-    // Test when the message contains a text with a draw command
+    // Test pushing a notification to an allowed chat
     #[tokio::test]
-    async fn test_process_message_with_draw_command() {
+    async fn test_process_push() {
         let mut tg_client = MockTelegramInteractor::new();
-        let mut gtp_client = MockGtpInteractor::new();
-        let public_gtp_client = MockGtpInteractor::new();
-
-        gtp_client
-            .expect_get_image()
-            .with(eq(" cat"))
-            .times(1)
-            .returning(|_| Ok("url".to_string().into()));
 
         tg_client
-            .expect_send_image()
-            .with(eq(123), eq("url"))
+            .expect_send_message()
+            .with(eq(123), eq("Reminder"), eq(None), eq(None))
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
 
-        let bot = create_bot(tg_client, gtp_client, public_gtp_client);
-        let message =
-            create_private_message(Some("нарисуй cat".to_string()), None);
-        let result = bot.process_message(message).await;
-        assert!(result.is_ok());
+        let bot = create_bot(
+            tg_client,
+            MockGtpInteractor::new(),
+            MockGtpInteractor::new(),
+        );
+
+        let allowed =
+            build_push_request(r#"{"chat_id":123,"text":"Reminder"}"#);
+        assert!(bot.process_push(&allowed).await.is_ok());
+
+        let not_allowed =
+            build_push_request(r#"{"chat_id":124,"text":"Reminder"}"#);
+        assert!(bot.process_push(&not_allowed).await.is_err());
+    }
+
+    fn build_push_request(body: &str) -> Request {
+        build_json_request("/push", body)
+    }
+
+    fn build_json_request(uri: &str, body: &str) -> Request {
+        http::Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .unwrap()
     }
 
     // Test when the message contains a text without a bot name or draw command
@@ -724,15 +8685,20 @@ mod tests {
 
         public_gtp_client
             .expect_get_completion()
-            .with(eq("preamble Hello".to_string()))
+            .with(always(), eq("preamble Hello".to_string()))
             .times(1)
-            .returning(|_| Ok("Hello Sir".to_string().into()));
+            .returning(|_, _| Ok("Hello Sir".to_string().into()));
 
         tg_client
             .expect_send_message()
-            .with(eq(123), eq("Hello Sir"), eq(Some("MarkdownV2")))
+            .with(
+                eq(123),
+                eq("Hello Sir"),
+                eq(Some(ParseMode::MarkdownV2)),
+                eq(Some(1)),
+            )
             .times(1)
-            .returning(|_, _, _| Ok(()));
+            .returning(|_, _, _, _| Ok(SentMessage::default()));
 
         let bot = create_bot(tg_client, gtp_client, public_gtp_client);
         let message =
@@ -758,12 +8724,14 @@ mod tests {
                 last_name: None,
                 username: None,
                 chat_type: PRIVATE_CHAT.to_string(),
+                title: None,
             },
             date: Default::default(),
             text: Some("Hello".to_string()),
             caption: None,
             photo: None,
             reply_to_message: None,
+            ..Default::default()
         }))
     }
 
@@ -784,12 +8752,14 @@ mod tests {
                 last_name: None,
                 username: None,
                 chat_type: "PUBLIC".to_string(),
+                title: None,
             },
             date: Default::default(),
             text: Some("simple bot Hello".to_string()),
             caption: None,
             photo: None,
             reply_to_message: None,
+            ..Default::default()
         }))
     }
 
@@ -797,11 +8767,19 @@ mod tests {
         tg_client: MockTelegramInteractor,
         gtp_client: MockGtpInteractor,
         public_gtp_client: MockGtpInteractor,
-    ) -> TgBot<MockTelegramInteractor, MockGtpInteractor, StepRng> {
+    ) -> TgBot<
+        MockTelegramInteractor,
+        MockGtpInteractor,
+        MockAuditLogStore,
+        MockPremiumStore,
+        StepRng,
+    > {
         TgBot::new(
             public_gtp_client,
             gtp_client,
             tg_client,
+            None,
+            None,
             Config::new(
                 HashMap::default(),
                 "preamble".to_string(),
@@ -838,12 +8816,14 @@ mod tests {
                 last_name: None,
                 username: None,
                 chat_type: "public".to_string(),
+                title: None,
             },
             date: Utc::now().naive_utc(),
             text,
             caption: None,
             photo,
             reply_to_message: None,
+            ..Default::default()
         }
     }
 
@@ -867,12 +8847,14 @@ mod tests {
                 last_name: None,
                 username: None,
                 chat_type: PRIVATE_CHAT.to_string(),
+                title: None,
             },
             date: Utc::now().naive_utc(),
             text,
             caption: None,
             photo,
             reply_to_message: None,
+            ..Default::default()
         }
     }
 }
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+/// Placeholders a preamble template can use. A bare `{}` is `first_name`,
+/// the only placeholder older templates had.
+pub const PREAMBLE_VARIABLES: [&str; 5] =
+    ["first_name", "last_name", "username", "chat_title", "date"];
+
+/// Fills `{name}` placeholders of `template` from `context`. `{{` and `}}`
+/// are literal braces.
+pub fn format_preamble(
+    template: &str,
+    context: &HashMap<&str, &str>,
+) -> Result<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find(['{', '}']) {
+        result.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        let position = template.len() - tail.len();
+
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            result.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+
+        if tail.starts_with('}') {
+            bail!("Unmatched '}}' at position {position}");
+        }
+
+        let Some(end) = tail.find('}') else {
+            bail!("Unclosed '{{' at position {position}");
+        };
+        let name = match &tail[1..end] {
+            "" => "first_name",
+            name => name,
+        };
+        let Some(value) = context.get(name) else {
+            bail!("Unknown placeholder {{{name}}}");
+        };
+        result.push_str(value);
+        rest = &tail[end + 1..];
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Formats `template` with dummy values, so a broken template fails at
+/// startup instead of on the first group message.
+pub fn validate_preamble(template: &str) -> Result<()> {
+    let context = PREAMBLE_VARIABLES.map(|name| (name, name)).into();
+    format_preamble(template, &context).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::preamble::{format_preamble, validate_preamble};
+
+    #[test]
+    fn test_format_preamble() {
+        let context = HashMap::from([
+            ("first_name", "Sam"),
+            ("username", "sam"),
+            ("date", "2024-01-02"),
+        ]);
+
+        assert_eq!(
+            format_preamble("Hi {}, {{ok}} ", &context).unwrap(),
+            "Hi Sam, {ok} "
+        );
+        assert_eq!(
+            format_preamble("{first_name} (@{username}) {date}: ", &context)
+                .unwrap(),
+            "Sam (@sam) 2024-01-02: "
+        );
+        assert!(format_preamble("{last_name}", &context).is_err());
+        assert!(format_preamble("{first_name", &context).is_err());
+        assert!(format_preamble("first_name}", &context).is_err());
+    }
+
+    #[test]
+    fn test_validate_preamble() {
+        assert!(validate_preamble("{first_name} {chat_title}: ").is_ok());
+        assert!(validate_preamble("{}: ").is_ok());
+        assert!(validate_preamble("{name}: ").is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use anyhow::{anyhow, Result};
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{NaiveDateTime, Utc};
+#[cfg(test)]
+use mockall::automock;
+
+#[derive(Debug)]
+pub struct DynamoPremiumStore {
+    client: aws_sdk_dynamodb::Client,
+    table_name: String,
+}
+
+impl DynamoPremiumStore {
+    pub fn new(client: aws_sdk_dynamodb::Client, table_name: String) -> Self {
+        DynamoPremiumStore { client, table_name }
+    }
+}
+
+impl PremiumStore for DynamoPremiumStore {
+    async fn is_premium_user(&self, user_id: i64) -> Result<bool> {
+        let output = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key("user_id", AttributeValue::N(user_id.to_string()))
+            .send()
+            .await?;
+
+        let Some(item) = output.item() else {
+            return Ok(false);
+        };
+
+        let expires_at: i64 = item
+            .get("expires_at")
+            .and_then(|value| value.as_n().ok())
+            .ok_or_else(|| anyhow!("Premium session item has no expires_at"))?
+            .parse()?;
+
+        // DynamoDB removes expired items lazily, so check the ttl here too.
+        Ok(expires_at > Utc::now().timestamp())
+    }
+
+    async fn grant(&self, user_id: i64, until: NaiveDateTime) -> Result<()> {
+        let until = AttributeValue::N(until.and_utc().timestamp().to_string());
+
+        // Never shorten a session that already lasts longer.
+        let result = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .item("user_id", AttributeValue::N(user_id.to_string()))
+            .item("expires_at", until.clone())
+            .condition_expression(
+                "attribute_not_exists(expires_at) OR expires_at < :until",
+            )
+            .expression_attribute_values(":until", until)
+            .send()
+            .await;
+
+        match result {
+            Err(SdkError::ServiceError(error))
+                if error.err().is_conditional_check_failed_exception() =>
+            {
+                Ok(())
+            }
+            result => {
+                result?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg_attr(test, automock)]
+pub trait PremiumStore {
+    async fn is_premium_user(&self, user_id: i64) -> Result<bool>;
+    async fn grant(&self, user_id: i64, until: NaiveDateTime) -> Result<()>;
+}
@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+/// Allows at most `limit` requests per user in any `window`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: usize,
+    window: Duration,
+    requests: DashMap<i64, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        RateLimiter {
+            limit,
+            window,
+            requests: DashMap::new(),
+        }
+    }
+
+    /// Records a request of the user unless the limit is already reached.
+    pub fn try_acquire(&self, user_id: i64) -> bool {
+        let now = Instant::now();
+        let mut requests = self.requests.entry(user_id).or_default();
+
+        while requests
+            .front()
+            .is_some_and(|&at| now.duration_since(at) >= self.window)
+        {
+            requests.pop_front();
+        }
+
+        if requests.len() >= self.limit {
+            return false;
+        }
+
+        requests.push_back(now);
+        true
+    }
+
+    /// How long until the user's oldest request leaves the window, zero if
+    /// the user is under the limit.
+    pub fn retry_after(&self, user_id: i64) -> Duration {
+        let Some(requests) = self.requests.get(&user_id) else {
+            return Duration::ZERO;
+        };
+
+        match requests.front() {
+            Some(at) if requests.len() >= self.limit => {
+                self.window.saturating_sub(at.elapsed())
+            }
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::rate_limiter::RateLimiter;
+
+    #[test]
+    fn test_rate_limiter() {
+        let rate_limiter = RateLimiter::new(2, Duration::from_secs(60));
+
+        assert!(rate_limiter.try_acquire(1));
+        assert!(rate_limiter.try_acquire(1));
+        assert!(!rate_limiter.try_acquire(1));
+        assert!(rate_limiter.try_acquire(2));
+        assert!(rate_limiter.retry_after(1) > Duration::from_secs(59));
+        assert_eq!(rate_limiter.retry_after(2), Duration::ZERO);
+        assert_eq!(rate_limiter.retry_after(3), Duration::ZERO);
+
+        let rate_limiter = RateLimiter::new(1, Duration::ZERO);
+
+        assert!(rate_limiter.try_acquire(1));
+        assert!(rate_limiter.try_acquire(1));
+    }
+}
@@ -0,0 +1,123 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tracing::debug;
+
+#[derive(Debug)]
+struct CachedResponse {
+    user_id: i64,
+    history_version: u64,
+    prompt: String,
+    response: Arc<String>,
+    created_at: Instant,
+}
+
+/// Answers to repeated prompts of the same user within `ttl`, as long as
+/// the history is the same as before the first answer.
+#[derive(Debug)]
+pub struct ResponseCache {
+    ttl: Duration,
+    responses: DashMap<u64, CachedResponse>,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration) -> Self {
+        ResponseCache {
+            ttl,
+            responses: DashMap::new(),
+        }
+    }
+
+    pub fn get(
+        &self,
+        user_id: i64,
+        history_version: u64,
+        prompt: &str,
+    ) -> Option<Arc<String>> {
+        let key = cache_key(user_id, history_version, prompt);
+        let cached = self.responses.get(&key)?;
+
+        // The key is a hash, so it may belong to another prompt.
+        if cached.user_id != user_id
+            || cached.history_version != history_version
+            || cached.prompt != prompt
+        {
+            return None;
+        }
+
+        if cached.created_at.elapsed() >= self.ttl {
+            drop(cached);
+            self.responses.remove(&key);
+            return None;
+        }
+
+        debug!(prompt_hash = key, "Response cache hit");
+        Some(cached.response.clone())
+    }
+
+    pub fn insert(
+        &self,
+        user_id: i64,
+        history_version: u64,
+        prompt: &str,
+        response: Arc<String>,
+    ) {
+        self.responses
+            .retain(|_, cached| cached.created_at.elapsed() < self.ttl);
+
+        self.responses.insert(
+            cache_key(user_id, history_version, prompt),
+            CachedResponse {
+                user_id,
+                history_version,
+                prompt: prompt.to_string(),
+                response,
+                created_at: Instant::now(),
+            },
+        );
+    }
+
+    pub fn invalidate(&self, user_id: i64) {
+        self.responses.retain(|_, cached| cached.user_id != user_id);
+    }
+}
+
+fn cache_key(user_id: i64, history_version: u64, prompt: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    user_id.hash(&mut hasher);
+    history_version.hash(&mut hasher);
+    prompt.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::response_cache::ResponseCache;
+
+    #[test]
+    fn test_response_cache() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        let response = Arc::new("Hi".to_string());
+
+        cache.insert(1, 7, "Hello", response.clone());
+
+        assert_eq!(cache.get(1, 7, "Hello"), Some(response));
+        assert_eq!(cache.get(1, 7, "Hello!"), None);
+        assert_eq!(cache.get(1, 8, "Hello"), None);
+        assert_eq!(cache.get(2, 7, "Hello"), None);
+
+        cache.invalidate(1);
+
+        assert_eq!(cache.get(1, 7, "Hello"), None);
+
+        let cache = ResponseCache::new(Duration::ZERO);
+        cache.insert(1, 7, "Hello", Arc::new("Hi".to_string()));
+
+        assert_eq!(cache.get(1, 7, "Hello"), None);
+    }
+}
@@ -0,0 +1,203 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Response, StatusCode};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::event_handler::ProcessingError;
+
+/// A long `Retry-After` would outlive the Lambda invocation anyway.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Rate limited or failed API request, which may pass when repeated.
+#[derive(Debug, Error)]
+#[error("API error {status}: {body}")]
+pub struct RetryableError {
+    pub status: StatusCode,
+    pub retry_after: Option<Duration>,
+    pub body: String,
+}
+
+/// Turns a 429 or 5xx `response` into a [`RetryableError`].
+pub async fn retryable_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+        return Ok(response);
+    }
+
+    // Only the delay in seconds, HTTP dates are not worth parsing here.
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .filter(|_| status == StatusCode::TOO_MANY_REQUESTS);
+
+    Err(RetryableError {
+        status,
+        retry_after,
+        body: response.text().await?,
+    }
+    .into())
+}
+
+/// Error of a failed API request. A 4xx other than 429 fails the same way
+/// when Telegram delivers the update again, so it is permanent.
+pub fn status_error(status: StatusCode, body: String) -> anyhow::Error {
+    if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+        ProcessingError::Permanent(body).into()
+    } else {
+        anyhow::Error::msg(body)
+    }
+}
+
+/// Calls `f` up to `max_attempts` times while it fails with a
+/// [`RetryableError`]. The delay doubles from `base_delay` with each
+/// attempt unless the error has a `Retry-After`.
+pub async fn retry_with_backoff<F, Fut, T>(
+    mut f: F,
+    max_attempts: u32,
+    base_delay: Duration,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        let error = match f().await {
+            Ok(result) => return Ok(result),
+            Err(error) if attempt >= max_attempts => return Err(error),
+            Err(error) => error,
+        };
+
+        let Some(retryable) = error.downcast_ref::<RetryableError>() else {
+            return Err(error);
+        };
+
+        let delay = retryable
+            .retry_after
+            .unwrap_or_else(|| backoff_delay(base_delay, attempt))
+            .min(MAX_RETRY_DELAY);
+        warn!(
+            attempt,
+            status = retryable.status.as_u16(),
+            delay_ms = delay.as_millis() as u64,
+            "Retrying API request"
+        );
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// `base_delay` doubled for each attempt before `attempt`. The factor
+/// saturates, so any number of retries is safe.
+fn backoff_delay(base_delay: Duration, attempt: u32) -> Duration {
+    let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+    base_delay.saturating_mul(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use reqwest::StatusCode;
+
+    use crate::event_handler::is_retryable;
+    use crate::retry::{
+        backoff_delay, retry_with_backoff, status_error, RetryableError,
+        MAX_RETRY_DELAY,
+    };
+
+    fn retryable() -> anyhow::Error {
+        RetryableError {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            retry_after: Some(Duration::ZERO),
+            body: "Rate limit".to_string(),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn test_retry_with_backoff() {
+        let calls = AtomicU32::new(0);
+        let result = retry_with_backoff(
+            || async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(retryable()),
+                    _ => Ok("done"),
+                }
+            },
+            3,
+            Duration::from_millis(1),
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), "done");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let calls = AtomicU32::new(0);
+        let result: anyhow::Result<()> = retry_with_backoff(
+            || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(retryable())
+            },
+            2,
+            Duration::from_millis(1),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_with_backoff_permanent_error() {
+        let calls = AtomicU32::new(0);
+        let result: anyhow::Result<()> = retry_with_backoff(
+            || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(anyhow!("Bad request"))
+            },
+            3,
+            Duration::from_millis(1),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    // Test that the delay doubles and many retries don't overflow
+    #[test]
+    fn test_backoff_delay() {
+        let base_delay = Duration::from_millis(100);
+        assert_eq!(backoff_delay(base_delay, 1), base_delay);
+        assert_eq!(backoff_delay(base_delay, 3), base_delay * 4);
+        assert!(backoff_delay(base_delay, 33) >= MAX_RETRY_DELAY);
+        assert!(backoff_delay(base_delay, u32::MAX) >= MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_status_error() {
+        let body = || "Bad Request: can't parse entities".to_string();
+        assert!(!is_retryable(&status_error(
+            StatusCode::BAD_REQUEST,
+            body()
+        )));
+        assert!(!is_retryable(&status_error(StatusCode::FORBIDDEN, body())));
+        assert!(is_retryable(&status_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            body()
+        )));
+        assert!(is_retryable(&status_error(StatusCode::BAD_GATEWAY, body())));
+    }
+}
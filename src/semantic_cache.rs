@@ -0,0 +1,125 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use tracing::debug;
+
+/// Recent answers kept for each user.
+const RECENT_ANSWERS: usize = 20;
+
+#[derive(Debug)]
+struct CachedAnswer {
+    conversation: u64,
+    embedding: Vec<f32>,
+    response: Arc<String>,
+}
+
+/// Answers to prompts of the same user in the same conversation whose
+/// embeddings are closer than `threshold`, so a reworded question is not
+/// sent to GPT again.
+#[derive(Debug)]
+pub struct SemanticCache {
+    threshold: f32,
+    answers: DashMap<i64, VecDeque<CachedAnswer>>,
+}
+
+impl SemanticCache {
+    pub fn new(threshold: f32) -> Self {
+        SemanticCache {
+            threshold,
+            answers: DashMap::new(),
+        }
+    }
+
+    pub fn get(
+        &self,
+        user_id: i64,
+        conversation: u64,
+        embedding: &[f32],
+    ) -> Option<Arc<String>> {
+        let answers = self.answers.get(&user_id)?;
+        let (similarity, response) = answers
+            .iter()
+            .filter(|cached| cached.conversation == conversation)
+            .map(|cached| {
+                let similarity =
+                    cosine_similarity(&cached.embedding, embedding);
+                (similarity, &cached.response)
+            })
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))?;
+
+        if similarity < self.threshold {
+            return None;
+        }
+
+        debug!(similarity, "Semantic cache hit");
+        Some(response.clone())
+    }
+
+    pub fn insert(
+        &self,
+        user_id: i64,
+        conversation: u64,
+        embedding: Vec<f32>,
+        response: Arc<String>,
+    ) {
+        let mut answers = self.answers.entry(user_id).or_default();
+        if answers.len() == RECENT_ANSWERS {
+            answers.pop_front();
+        }
+        answers.push_back(CachedAnswer {
+            conversation,
+            embedding,
+            response,
+        });
+    }
+
+    pub fn invalidate(&self, user_id: i64) {
+        self.answers.remove(&user_id);
+    }
+}
+
+/// Cosine of the angle between `a` and `b`, 0 if either is zero.
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::semantic_cache::{cosine_similarity, SemanticCache};
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]), 1.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]), -1.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn test_semantic_cache() {
+        let cache = SemanticCache::new(0.95);
+        let response = Arc::new("Hi".to_string());
+
+        cache.insert(1, 7, vec![1.0, 0.1], response.clone());
+
+        assert_eq!(cache.get(1, 7, &[1.0, 0.11]), Some(response));
+        assert_eq!(cache.get(1, 7, &[0.1, 1.0]), None);
+        assert_eq!(cache.get(1, 8, &[1.0, 0.1]), None);
+        assert_eq!(cache.get(2, 7, &[1.0, 0.1]), None);
+
+        cache.invalidate(1);
+
+        assert_eq!(cache.get(1, 7, &[1.0, 0.1]), None);
+    }
+}
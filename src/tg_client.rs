@@ -1,21 +1,34 @@
 use std::time::Duration;
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use chrono::naive::serde::ts_seconds::deserialize as from_ts;
+use chrono::naive::serde::ts_seconds_option::deserialize as from_ts_option;
 use chrono::NaiveDateTime;
 use derive_more::Constructor;
 #[cfg(test)]
 use mockall::automock;
-use reqwest::multipart;
+use reqwest::{multipart, StatusCode, Url};
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
 use reqwest_retry::policies::ExponentialBackoff;
 use reqwest_retry::RetryTransientMiddleware;
 use serde::{Deserialize, Serialize};
 use tracing::error;
 
+use crate::chunk_splitter::MarkdownV2ChunkSplitter;
+use crate::event_handler::ErrorCode;
+use crate::gpt_client::ImageContent;
+use crate::retry::status_error;
+use crate::web_page::{
+    html_to_text, is_allowed_by_robots, resolve_public_host,
+};
+
 pub const PRIVATE_CHAT: &str = "private";
 
 const MAX_MSG_SIZE: usize = 4096;
+const PAGE_TIMEOUT: Duration = Duration::from_secs(10);
+// Anything past the first megabyte is unlikely to fit into the prompt.
+const MAX_PAGE_SIZE: usize = 1024 * 1024;
+const MAX_PAGE_REDIRECTS: usize = 5;
 
 static ESCAPE_UNARY_SYMBOLS: phf::Set<char> = phf::phf_set! {
     '_', '[', ']', '(', ')', '~', '>', '#', '+', '-', '=', '|','\\',
@@ -30,28 +43,232 @@ static ESCAPE_PAIR_SYMBOLS: phf::Set<char> = phf::phf_set! {
 pub struct Update {
     pub update_id: i64,
     pub message: Option<Message>,
+    pub edited_message: Option<Message>,
+    pub channel_post: Option<Message>,
+    pub pre_checkout_query: Option<PreCheckoutQuery>,
+    pub callback_query: Option<CallbackQuery>,
+    pub poll_answer: Option<PollAnswer>,
+    pub chat_boost: Option<ChatBoostUpdated>,
+    pub my_chat_member: Option<ChatMemberUpdated>,
+    pub chat_member: Option<ChatMemberUpdated>,
+}
+
+/// Update types the bot handles. Telegram only sends `chat_member` and
+/// `chat_boost` updates when they are asked for explicitly.
+pub const ALLOWED_UPDATES: [&str; 9] = [
+    "message",
+    "edited_message",
+    "channel_post",
+    "pre_checkout_query",
+    "callback_query",
+    "poll_answer",
+    "chat_boost",
+    "my_chat_member",
+    "chat_member",
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatMemberUpdated {
+    pub chat: Chat,
+    pub from: User,
+    pub old_chat_member: ChatMember,
+    pub new_chat_member: ChatMember,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatMember {
+    pub status: String,
+    pub user: User,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatBoostUpdated {
+    pub chat: Chat,
+    pub boost: ChatBoost,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatBoost {
+    pub boost_id: String,
+    pub source: ChatBoostSource,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum ChatBoostSource {
+    Premium { user: User },
+    GiftCode { user: User },
+    Giveaway { user: Option<User> },
+}
+
+impl ChatBoostSource {
+    pub fn user(&self) -> Option<&User> {
+        match self {
+            ChatBoostSource::Premium { user }
+            | ChatBoostSource::GiftCode { user } => Some(user),
+            ChatBoostSource::Giveaway { user } => user.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Poll {
+    pub id: String,
+    pub question: String,
+    pub options: Vec<PollOption>,
+    pub total_voter_count: u32,
+    pub is_closed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PollOption {
+    pub text: String,
+    pub voter_count: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PollAnswer {
+    pub poll_id: String,
+    pub user: Option<User>,
+    pub option_ids: Vec<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CallbackQuery {
+    pub id: String,
+    pub from: User,
+    pub message: Option<Message>,
+    pub data: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreCheckoutQuery {
+    pub id: String,
+    pub from: User,
+    pub currency: String,
+    pub total_amount: i32,
+    pub invoice_payload: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+pub struct SuccessfulPayment {
+    pub currency: String,
+    pub total_amount: i32,
+    pub invoice_payload: String,
+    pub telegram_payment_charge_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PhotoSize {
     pub file_id: String,
     pub file_size: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Message {
     pub message_id: i32,
+    // Missing in channel posts.
+    #[serde(default)]
     pub from: User,
     pub chat: Chat,
     #[serde(deserialize_with = "from_ts")]
     pub date: NaiveDateTime,
+    #[serde(default, deserialize_with = "from_ts_option")]
+    pub edit_date: Option<NaiveDateTime>,
     pub text: Option<String>,
     pub caption: Option<String>,
     pub photo: Option<Vec<PhotoSize>>,
+    pub media_group_id: Option<String>,
     pub reply_to_message: Option<Box<Message>>,
+    pub web_app_data: Option<WebAppData>,
+    pub successful_payment: Option<SuccessfulPayment>,
+    pub poll: Option<Poll>,
+    pub dice: Option<Dice>,
+    pub voice: Option<Voice>,
+    pub document: Option<Document>,
+    pub sticker: Option<Sticker>,
+    pub new_chat_members: Option<Vec<User>>,
+    pub left_chat_member: Option<User>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Voice {
+    pub file_id: String,
+    pub duration: i32,
+    pub mime_type: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Document {
+    pub file_id: String,
+    pub file_name: Option<String>,
+    pub mime_type: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sticker {
+    pub file_id: String,
+    pub emoji: Option<String>,
+    pub set_name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Dice {
+    pub emoji: String,
+    pub value: i32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+pub struct WebAppData {
+    pub data: String,
+    pub button_text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InlineQueryResult {
+    Article {
+        id: String,
+        title: String,
+        input_message_content: InputTextMessageContent,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Constructor, Serialize)]
+pub struct InputTextMessageContent {
+    message_text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BotCommand {
+    pub command: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum ReplyMarkup {
+    InlineKeyboardMarkup {
+        inline_keyboard: Vec<Vec<InlineKeyboardButton>>,
+    },
+    ReplyKeyboardMarkup {
+        keyboard: Vec<Vec<KeyboardButton>>,
+        one_time_keyboard: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Constructor, Serialize)]
+pub struct InlineKeyboardButton {
+    text: String,
+    callback_data: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Constructor, Serialize)]
+pub struct KeyboardButton {
+    text: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct User {
     pub id: i64,
     pub is_bot: bool,
@@ -63,7 +280,7 @@ pub struct User {
     pub language_code: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Chat {
     pub id: i64,
     pub first_name: Option<String>,
@@ -73,6 +290,8 @@ pub struct Chat {
     pub username: Option<String>,
     #[serde(rename = "type")]
     pub chat_type: String,
+    // Only groups, supergroups and channels have a title
+    pub title: Option<String>,
 }
 
 impl Chat {
@@ -85,11 +304,42 @@ impl Chat {
 pub struct TgClient {
     http_client: ClientWithMiddleware,
     send_message_url: String,
+    edit_message_url: String,
     send_image_url: String,
     send_voice_url: String,
+    send_document_url: String,
+    set_chat_photo_url: String,
     left_url: String,
+    copy_message_url: String,
+    answer_web_app_query_url: String,
+    set_reaction_url: String,
+    send_invoice_url: String,
+    answer_pre_checkout_query_url: String,
+    answer_callback_query_url: String,
+    get_my_commands_url: String,
+    set_my_commands_url: String,
+    get_chat_administrators_url: String,
+    send_dice_url: String,
+    send_poll_url: String,
+    pin_message_url: String,
+    send_chat_action_url: String,
     get_file_url: String,
     download_file_url: String,
+    set_webhook_url: String,
+    delete_webhook_url: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ParseMode {
+    MarkdownV2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatAction {
+    Typing,
+    UploadPhoto,
+    RecordVoice,
 }
 
 #[derive(Debug, Default, Constructor, Serialize)]
@@ -97,13 +347,155 @@ struct TgMessageRequest<'a> {
     chat_id: i64,
     text: &'a str,
     #[serde(skip_serializing_if = "Option::is_none")]
-    parse_mode: Option<&'static str>,
+    parse_mode: Option<ParseMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_markup: Option<ReplyMarkup>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_parameters: Option<ReplyParameters>,
+}
+
+/// A reply that is still sent if its message was deleted meanwhile.
+#[derive(Debug, Serialize)]
+struct ReplyParameters {
+    message_id: i32,
+    allow_sending_without_reply: bool,
+}
+
+impl ReplyParameters {
+    fn new(message_id: i32) -> Self {
+        ReplyParameters {
+            message_id,
+            allow_sending_without_reply: true,
+        }
+    }
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgEditMessageRequest<'a> {
+    chat_id: i64,
+    message_id: i32,
+    text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parse_mode: Option<ParseMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_markup: Option<ReplyMarkup>,
 }
 
 #[derive(Debug, Constructor, Serialize)]
 struct TgMessageImageRequest<'a> {
     chat_id: i64,
     photo: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_parameters: Option<ReplyParameters>,
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgSetMyCommandsRequest<'a> {
+    commands: &'a [BotCommand],
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgSetWebhookRequest<'a> {
+    url: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret_token: Option<&'a str>,
+    allowed_updates: &'a [&'a str],
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgChatActionRequest {
+    chat_id: i64,
+    action: ChatAction,
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgDiceRequest<'a> {
+    chat_id: i64,
+    emoji: &'a str,
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgPollOption<'a> {
+    text: &'a str,
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgPollRequest<'a> {
+    chat_id: i64,
+    question: &'a str,
+    options: Vec<TgPollOption<'a>>,
+    #[serde(rename = "type")]
+    poll_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correct_option_id: Option<u8>,
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgPinMessageRequest {
+    chat_id: i64,
+    message_id: i32,
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgCopyMessageRequest {
+    chat_id: i64,
+    from_chat_id: i64,
+    message_id: i32,
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgAnswerWebAppQueryRequest<'a> {
+    web_app_query_id: &'a str,
+    result: InlineQueryResult,
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgReactionRequest<'a> {
+    chat_id: i64,
+    message_id: i32,
+    reaction: [ReactionType<'a>; 1],
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ReactionType<'a> {
+    Emoji { emoji: &'a str },
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgInvoiceRequest<'a> {
+    chat_id: i64,
+    title: &'a str,
+    description: &'a str,
+    payload: &'a str,
+    provider_token: &'a str,
+    currency: &'a str,
+    prices: [LabeledPrice<'a>; 1],
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct LabeledPrice<'a> {
+    label: &'a str,
+    amount: i32,
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgAnswerCallbackQueryRequest<'a> {
+    callback_query_id: &'a str,
+}
+
+#[derive(Debug, Constructor, Serialize)]
+struct TgAnswerPreCheckoutQueryRequest<'a> {
+    pre_checkout_query_id: &'a str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_message: Option<&'a str>,
+}
+
+/// The part of a sent message the bot needs to refer to it later.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct SentMessage {
+    pub message_id: i32,
 }
 
 #[derive(Debug, Deserialize)]
@@ -132,13 +524,33 @@ impl TgClient {
         TgClient {
             http_client,
             send_message_url: format!("{url}/sendMessage"),
+            edit_message_url: format!("{url}/editMessageText"),
             send_image_url: format!("{url}/sendPhoto"),
             send_voice_url: format!("{url}/sendVoice"),
+            send_document_url: format!("{url}/sendDocument"),
+            set_chat_photo_url: format!("{url}/setChatPhoto"),
             left_url: format!("{url}/leaveChat"),
+            copy_message_url: format!("{url}/copyMessage"),
+            answer_web_app_query_url: format!("{url}/answerWebAppQuery"),
+            set_reaction_url: format!("{url}/setMessageReaction"),
+            send_invoice_url: format!("{url}/sendInvoice"),
+            answer_pre_checkout_query_url: format!(
+                "{url}/answerPreCheckoutQuery"
+            ),
+            answer_callback_query_url: format!("{url}/answerCallbackQuery"),
+            get_my_commands_url: format!("{url}/getMyCommands"),
+            set_my_commands_url: format!("{url}/setMyCommands"),
+            get_chat_administrators_url: format!("{url}/getChatAdministrators"),
+            send_dice_url: format!("{url}/sendDice"),
+            send_poll_url: format!("{url}/sendPoll"),
+            pin_message_url: format!("{url}/pinChatMessage"),
+            send_chat_action_url: format!("{url}/sendChatAction"),
             get_file_url: format!("{url}/getFile"),
             download_file_url: format!(
                 "https://api.telegram.org/file/bot{token}"
             ),
+            set_webhook_url: format!("{url}/setWebhook"),
+            delete_webhook_url: format!("{url}/deleteWebhook"),
         }
     }
 
@@ -148,22 +560,18 @@ impl TgClient {
             .get(&self.get_file_url)
             .query(&[("file_id", file_id)])
             .send()
-            .await?;
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
 
         if response.status().is_success() {
             let tg_response =
                 response.json::<TgResponse<FileMetadata>>().await?;
-            if tg_response.ok {
-                if let Some(file) = tg_response.result {
-                    Ok(file.file_path)
-                } else {
-                    bail!("Bad file id")
-                }
-            } else {
-                bail!("Bad file id")
+            match tg_response.result {
+                Some(file) if tg_response.ok => Ok(file.file_path),
+                _ => bail!(response_error(tg_response.error)),
             }
         } else {
-            bail!(response.text().await?)
+            bail!(api_error(response.status(), response.text().await?))
         }
     }
 
@@ -171,59 +579,123 @@ impl TgClient {
         &self,
         chat_id: i64,
         result_text: &str,
-        parse_mode: Option<&'static str>,
-    ) -> Result<()> {
-        let request_data =
-            TgMessageRequest::new(chat_id, result_text, parse_mode);
+        parse_mode: Option<ParseMode>,
+        reply_to_id: Option<i32>,
+    ) -> Result<SentMessage> {
+        let request_data = TgMessageRequest::new(
+            chat_id,
+            result_text,
+            parse_mode,
+            None,
+            reply_to_id.map(ReplyParameters::new),
+        );
 
         let response = self
             .http_client
             .post(&self.send_message_url)
             .json(&request_data)
             .send()
-            .await?;
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
 
-        if !response.status().is_success() {
+        let status = response.status();
+        if !status.is_success() {
             let tg_error = response.text().await?;
             error!(
                 "Telegram send error. Error: {}. Request {}",
                 tg_error, request_data.text
             );
-            let error = format!("Telegram send error. Error: {}", tg_error);
-            bail!(error);
+            bail!(api_error(
+                status,
+                format!("Telegram send error. Error: {}", tg_error)
+            ));
+        }
+
+        let tg_response = response.json::<TgResponse<SentMessage>>().await?;
+        match tg_response.result {
+            Some(result) if tg_response.ok => Ok(result),
+            _ => bail!(response_error(tg_response.error)),
         }
-        Ok(())
     }
 
     async fn send_message_by_chunks(
         &self,
         chat_id: i64,
-        parse_mode: Option<&'static str>,
+        parse_mode: Option<ParseMode>,
         result_text: &str,
-    ) -> Result<()> {
-        let mut i = 0;
-        let mut j = 0;
-        let len = result_text.len();
-
-        while i < len {
-            j += MAX_MSG_SIZE;
-            if j > len {
-                j = len;
-            };
-
-            let chunk = &result_text[i..j];
-            let res = self.send_text(chat_id, chunk, parse_mode).await;
-            if res.is_err() {
-                j -= 1;
-                let chunk = &result_text[i..j];
-                self.send_text(chat_id, chunk, parse_mode).await?;
-            }
-            i += MAX_MSG_SIZE;
+        mut reply_to_id: Option<i32>,
+    ) -> Result<SentMessage> {
+        let mut first_message = None;
+
+        // Only the first chunk is a reply, the rest follow it.
+        for chunk in
+            MarkdownV2ChunkSplitter::new(MAX_MSG_SIZE).split(result_text)
+        {
+            let sent = self
+                .send_text(chat_id, &chunk, parse_mode, reply_to_id.take())
+                .await?;
+            first_message.get_or_insert(sent);
         }
-        Ok(())
+
+        Ok(first_message.unwrap_or_default())
     }
 }
 
+/// Error of a failed Telegram request, coded for alerting.
+fn api_error(status: StatusCode, error: String) -> anyhow::Error {
+    status_error(status, error).context(ErrorCode::TelegramApiFailure)
+}
+
+/// Telegram accepted the request but answered `ok: false`.
+fn response_error(error: Option<String>) -> anyhow::Error {
+    anyhow!("Tg response error: {}", error.unwrap_or_default())
+        .context(ErrorCode::TelegramApiFailure)
+}
+
+/// Multipart requests take the reply parameters as a JSON field.
+fn with_reply_parameters(
+    form: multipart::Form,
+    reply_parameters: Option<ReplyParameters>,
+) -> Result<multipart::Form> {
+    Ok(match reply_parameters {
+        Some(reply_parameters) => form.text(
+            "reply_parameters",
+            serde_json::to_string(&reply_parameters)?,
+        ),
+        None => form,
+    })
+}
+
+/// A client for `url` that only connects to its checked public address,
+/// so DNS can't answer differently for the request itself. Redirects are
+/// followed by hand, each one is checked again.
+async fn page_client(url: &Url) -> Result<reqwest::Client> {
+    let addr = resolve_public_host(url).await?;
+    let host = url.host_str().context("Page URL has no host")?;
+
+    Ok(reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(host, addr)
+        .timeout(PAGE_TIMEOUT)
+        .build()?)
+}
+
+/// A missing or unreadable robots.txt allows everything.
+async fn is_allowed_page(client: &reqwest::Client, url: &Url) -> bool {
+    let Ok(robots_url) = url.join("/robots.txt") else {
+        return true;
+    };
+
+    let robots = match client.get(robots_url).send().await {
+        Ok(response) if response.status().is_success() => {
+            response.text().await.unwrap_or_default()
+        }
+        _ => return true,
+    };
+
+    is_allowed_by_robots(&robots, url.path())
+}
+
 impl TelegramInteractor for TgClient {
     async fn get_file_url(&self, file_id: &str) -> Result<String> {
         let file_path = self.get_file_path(file_id).await?;
@@ -234,134 +706,1138 @@ impl TelegramInteractor for TgClient {
         &self,
         chat_id: i64,
         text: &str,
-        parse_mode: Option<&'static str>,
-    ) -> Result<()> {
+        parse_mode: Option<ParseMode>,
+        reply_to_id: Option<i32>,
+    ) -> Result<SentMessage> {
         let result_text = escape_text(text);
 
         if result_text.chars().count() < MAX_MSG_SIZE {
-            self.send_text(chat_id, &result_text, parse_mode).await?;
-            return Ok(());
+            return self
+                .send_text(chat_id, &result_text, parse_mode, reply_to_id)
+                .await;
         }
 
-        self.send_message_by_chunks(chat_id, parse_mode, &result_text)
-            .await?;
+        self.send_message_by_chunks(
+            chat_id,
+            parse_mode,
+            &result_text,
+            reply_to_id,
+        )
+        .await
+    }
 
-        Ok(())
+    async fn send_message_with_reply_markup(
+        &self,
+        chat_id: i64,
+        text: &str,
+        parse_mode: Option<ParseMode>,
+        reply_markup: Option<ReplyMarkup>,
+        mut reply_to_id: Option<i32>,
+    ) -> Result<i32> {
+        let result_text = escape_text(text);
+        let mut chunks =
+            MarkdownV2ChunkSplitter::new(MAX_MSG_SIZE).split(&result_text);
+        // The keyboard is attached to the last chunk only, the reply is
+        // the first one like in `send_message`.
+        let last_chunk = chunks.pop().unwrap_or_default();
+        for chunk in chunks {
+            self.send_text(chat_id, &chunk, parse_mode, reply_to_id.take())
+                .await?;
+        }
+
+        let request_data = TgMessageRequest::new(
+            chat_id,
+            &last_chunk,
+            parse_mode,
+            reply_markup,
+            reply_to_id.map(ReplyParameters::new),
+        );
+
+        let response = self
+            .http_client
+            .post(&self.send_message_url)
+            .json(&request_data)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram send error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        let tg_response = response.json::<TgResponse<SentMessage>>().await?;
+        match tg_response.result {
+            Some(result) if tg_response.ok => Ok(result.message_id),
+            _ => bail!(response_error(tg_response.error)),
+        }
     }
 
-    async fn send_image(&self, chat_id: i64, url: &str) -> Result<()> {
-        let request_data = TgMessageImageRequest::new(chat_id, url);
+    async fn edit_message(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        text: &str,
+        parse_mode: Option<ParseMode>,
+        reply_markup: Option<ReplyMarkup>,
+    ) -> Result<()> {
+        let result_text = escape_text(text);
+        let mut chunks = MarkdownV2ChunkSplitter::new(MAX_MSG_SIZE)
+            .split(&result_text)
+            .into_iter();
+        // The edited message keeps the first chunk, the rest is sent anew.
+        let first_chunk = chunks.next().unwrap_or_default();
+
+        let request_data = TgEditMessageRequest::new(
+            chat_id,
+            message_id,
+            &first_chunk,
+            parse_mode,
+            reply_markup,
+        );
 
         let response = self
             .http_client
-            .post(&self.send_image_url)
+            .post(&self.edit_message_url)
             .json(&request_data)
             .send()
-            .await?;
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
 
-        if !response.status().is_success() {
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram edit message error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        for chunk in chunks {
+            self.send_text(chat_id, &chunk, parse_mode, None).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send_image(
+        &self,
+        chat_id: i64,
+        image: ImageContent,
+        reply_to_id: Option<i32>,
+    ) -> Result<()> {
+        let reply_parameters = reply_to_id.map(ReplyParameters::new);
+        let (response, request) = match image {
+            ImageContent::Url(url) => {
+                let request_data =
+                    TgMessageImageRequest::new(chat_id, &url, reply_parameters);
+
+                let response = self
+                    .http_client
+                    .post(&self.send_image_url)
+                    .json(&request_data)
+                    .send()
+                    .await
+                    .context(ErrorCode::TelegramApiFailure)?;
+
+                (response, url)
+            }
+            ImageContent::Bytes(bytes) => {
+                let part = multipart::Part::bytes(bytes)
+                    .file_name("image.png")
+                    .mime_str("image/png")?;
+                let form = with_reply_parameters(
+                    multipart::Form::new()
+                        .text("chat_id", chat_id.to_string())
+                        .part("photo", part),
+                    reply_parameters,
+                )?;
+
+                let response = reqwest::Client::new()
+                    .post(&self.send_image_url)
+                    .multipart(form)
+                    .send()
+                    .await
+                    .context(ErrorCode::TelegramApiFailure)?;
+
+                (response, "<image bytes>".to_string())
+            }
+        };
+
+        let status = response.status();
+        if !status.is_success() {
             let error = format!(
                 "Telegram send error. Error: {}. Request {}",
                 response.text().await?,
-                request_data.photo
+                request
             );
-            bail!(error);
+            bail!(api_error(status, error));
         }
 
         Ok(())
     }
 
-    async fn send_voice(&self, chat_id: i64, audio: Vec<u8>) -> Result<()> {
+    async fn send_voice(
+        &self,
+        chat_id: i64,
+        audio: Vec<u8>,
+        reply_to_id: Option<i32>,
+    ) -> Result<()> {
         let part = multipart::Part::bytes(audio)
             .file_name("voice.mp3")
             .mime_str("audio/mp3")?;
-        let form = multipart::Form::new()
-            .text("chat_id", chat_id.to_string())
-            .part("voice", part);
+        let form = with_reply_parameters(
+            multipart::Form::new()
+                .text("chat_id", chat_id.to_string())
+                .part("voice", part),
+            reply_to_id.map(ReplyParameters::new),
+        )?;
 
         let response = reqwest::Client::new()
             .post(&self.send_voice_url)
             .multipart(form)
             .send()
-            .await?;
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
 
-        if !response.status().is_success() {
+        let status = response.status();
+        if !status.is_success() {
             let error = format!(
                 "Telegram send voice error. Error: {}.",
                 response.text().await?
             );
-            bail!(error);
+            bail!(api_error(status, error));
         }
 
         let tg_response = response.json::<TgResponse<Message>>().await?;
         if !tg_response.ok {
-            bail!(
-                "Tg response error: {}",
-                tg_response.error.unwrap_or_default()
-            );
+            bail!(response_error(tg_response.error));
         }
 
         Ok(())
     }
 
-    async fn leave_chat(&self, chat_id: i64) -> Result<()> {
-        let response = self
-            .http_client
-            .get(&self.left_url)
-            .query(&[("chat_id", chat_id)])
+    async fn send_document(
+        &self,
+        chat_id: i64,
+        filename: &str,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let part = multipart::Part::bytes(data).file_name(filename.to_string());
+        let form = multipart::Form::new()
+            .text("chat_id", chat_id.to_string())
+            .part("document", part);
+
+        let response = reqwest::Client::new()
+            .post(&self.send_document_url)
+            .multipart(form)
             .send()
-            .await?;
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
 
-        if !response.status().is_success() {
+        let status = response.status();
+        if !status.is_success() {
             let error = format!(
-                "Telegram leave chat error. Error: {}.",
+                "Telegram send document error. Error: {}.",
                 response.text().await?
             );
-            bail!(error);
+            bail!(api_error(status, error));
+        }
+
+        let tg_response = response.json::<TgResponse<Message>>().await?;
+        if !tg_response.ok {
+            bail!(response_error(tg_response.error));
+        }
+
+        Ok(())
+    }
+
+    async fn set_chat_photo(&self, chat_id: i64, photo: Vec<u8>) -> Result<()> {
+        let part = multipart::Part::bytes(photo)
+            .file_name("photo.png")
+            .mime_str("image/png")?;
+        let form = multipart::Form::new()
+            .text("chat_id", chat_id.to_string())
+            .part("photo", part);
+
+        let response = reqwest::Client::new()
+            .post(&self.set_chat_photo_url)
+            .multipart(form)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram set chat photo error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        Ok(())
+    }
+
+    async fn download_file(&self, url: &str) -> Result<Vec<u8>> {
+        let response = self
+            .http_client
+            .get(url)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        if response.status().is_success() {
+            let file = response.bytes().await?;
+            Ok(Vec::from(file))
+        } else {
+            bail!(api_error(response.status(), response.text().await?))
+        }
+    }
+
+    async fn download_page(&self, url: &str) -> Result<Option<String>> {
+        let mut url = Url::parse(url)?;
+        let mut redirects = 0;
+
+        let mut response = loop {
+            let client = page_client(&url).await?;
+            if !is_allowed_page(&client, &url).await {
+                return Ok(None);
+            }
+
+            let response = client.get(url.clone()).send().await?;
+            if !response.status().is_redirection() {
+                break response;
+            }
+
+            redirects += 1;
+            if redirects > MAX_PAGE_REDIRECTS {
+                bail!("Too many redirects");
+            }
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .context("Redirect has no location")?;
+            url = url.join(location)?;
+        };
+
+        if !response.status().is_success() {
+            bail!("Page request failed with {}", response.status())
+        }
+
+        let mut html = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            html.extend_from_slice(&chunk);
+            if html.len() >= MAX_PAGE_SIZE {
+                html.truncate(MAX_PAGE_SIZE);
+                break;
+            }
+        }
+
+        Ok(Some(html_to_text(&html)?))
+    }
+
+    async fn copy_message(
+        &self,
+        to_chat_id: i64,
+        from_chat_id: i64,
+        message_id: i32,
+    ) -> Result<i32> {
+        let request_data =
+            TgCopyMessageRequest::new(to_chat_id, from_chat_id, message_id);
+
+        let response = self
+            .http_client
+            .post(&self.copy_message_url)
+            .json(&request_data)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram copy message error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        let tg_response = response.json::<TgResponse<SentMessage>>().await?;
+        match tg_response.result {
+            Some(result) if tg_response.ok => Ok(result.message_id),
+            _ => bail!(response_error(tg_response.error)),
+        }
+    }
+
+    async fn answer_web_app_query(
+        &self,
+        web_app_query_id: &str,
+        result: InlineQueryResult,
+    ) -> Result<()> {
+        let request_data =
+            TgAnswerWebAppQueryRequest::new(web_app_query_id, result);
+
+        let response = self
+            .http_client
+            .post(&self.answer_web_app_query_url)
+            .json(&request_data)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram answer web app query error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        Ok(())
+    }
+
+    async fn set_reaction(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        emoji: &str,
+    ) -> Result<()> {
+        let request_data = TgReactionRequest::new(
+            chat_id,
+            message_id,
+            [ReactionType::Emoji { emoji }],
+        );
+
+        let response = self
+            .http_client
+            .post(&self.set_reaction_url)
+            .json(&request_data)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram set reaction error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        Ok(())
+    }
+
+    async fn create_invoice(
+        &self,
+        chat_id: i64,
+        title: &str,
+        description: &str,
+        provider_token: &str,
+        currency: &str,
+        price: i32,
+    ) -> Result<i32> {
+        // The bot sells a single product, so the title doubles as payload.
+        let request_data = TgInvoiceRequest::new(
+            chat_id,
+            title,
+            description,
+            title,
+            provider_token,
+            currency,
+            [LabeledPrice::new(title, price)],
+        );
+
+        let response = self
+            .http_client
+            .post(&self.send_invoice_url)
+            .json(&request_data)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram send invoice error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        let tg_response = response.json::<TgResponse<SentMessage>>().await?;
+        match tg_response.result {
+            Some(result) if tg_response.ok => Ok(result.message_id),
+            _ => bail!(response_error(tg_response.error)),
+        }
+    }
+
+    async fn answer_callback_query(
+        &self,
+        callback_query_id: &str,
+    ) -> Result<()> {
+        let request_data = TgAnswerCallbackQueryRequest::new(callback_query_id);
+
+        let response = self
+            .http_client
+            .post(&self.answer_callback_query_url)
+            .json(&request_data)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram answer callback query error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        Ok(())
+    }
+
+    async fn answer_pre_checkout_query<'a>(
+        &self,
+        pre_checkout_query_id: &'a str,
+        error_message: Option<&'a str>,
+    ) -> Result<()> {
+        let request_data = TgAnswerPreCheckoutQueryRequest::new(
+            pre_checkout_query_id,
+            error_message.is_none(),
+            error_message,
+        );
+
+        let response = self
+            .http_client
+            .post(&self.answer_pre_checkout_query_url)
+            .json(&request_data)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram answer pre-checkout query error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        Ok(())
+    }
+
+    async fn send_dice(&self, chat_id: i64, emoji: &str) -> Result<Dice> {
+        let request_data = TgDiceRequest::new(chat_id, emoji);
+
+        let response = self
+            .http_client
+            .post(&self.send_dice_url)
+            .json(&request_data)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram send dice error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        let tg_response = response.json::<TgResponse<Message>>().await?;
+        match tg_response.result.and_then(|message| message.dice) {
+            Some(dice) if tg_response.ok => Ok(dice),
+            _ => bail!(response_error(tg_response.error)),
+        }
+    }
+
+    async fn send_poll<'a>(
+        &self,
+        chat_id: i64,
+        question: &'a str,
+        options: &[&'a str],
+        is_quiz: bool,
+        correct_option_id: Option<u8>,
+    ) -> Result<()> {
+        let request_data = TgPollRequest::new(
+            chat_id,
+            question,
+            options
+                .iter()
+                .map(|&text| TgPollOption::new(text))
+                .collect(),
+            if is_quiz { "quiz" } else { "regular" },
+            correct_option_id,
+        );
+
+        let response = self
+            .http_client
+            .post(&self.send_poll_url)
+            .json(&request_data)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram send poll error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        Ok(())
+    }
+
+    async fn pin_message(&self, chat_id: i64, message_id: i32) -> Result<()> {
+        let request_data = TgPinMessageRequest::new(chat_id, message_id);
+
+        let response = self
+            .http_client
+            .post(&self.pin_message_url)
+            .json(&request_data)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram pin message error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        Ok(())
+    }
+
+    async fn get_my_commands(&self) -> Result<Vec<BotCommand>> {
+        let response = self
+            .http_client
+            .get(&self.get_my_commands_url)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram get my commands error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        let tg_response =
+            response.json::<TgResponse<Vec<BotCommand>>>().await?;
+        match tg_response.result {
+            Some(result) if tg_response.ok => Ok(result),
+            _ => bail!(response_error(tg_response.error)),
+        }
+    }
+
+    async fn set_my_commands(&self, commands: &[BotCommand]) -> Result<()> {
+        let request_data = TgSetMyCommandsRequest::new(commands);
+
+        let response = self
+            .http_client
+            .post(&self.set_my_commands_url)
+            .json(&request_data)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram set my commands error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        Ok(())
+    }
+
+    async fn get_chat_administrators(
+        &self,
+        chat_id: i64,
+    ) -> Result<Vec<ChatMember>> {
+        let response = self
+            .http_client
+            .get(&self.get_chat_administrators_url)
+            .query(&[("chat_id", chat_id)])
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram get chat administrators error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        let tg_response =
+            response.json::<TgResponse<Vec<ChatMember>>>().await?;
+        match tg_response.result {
+            Some(result) if tg_response.ok => Ok(result),
+            _ => bail!(response_error(tg_response.error)),
+        }
+    }
+
+    async fn send_chat_action(
+        &self,
+        chat_id: i64,
+        action: ChatAction,
+    ) -> Result<()> {
+        let request_data = TgChatActionRequest::new(chat_id, action);
+
+        let response = self
+            .http_client
+            .post(&self.send_chat_action_url)
+            .json(&request_data)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram send chat action error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        Ok(())
+    }
+
+    async fn leave_chat(&self, chat_id: i64) -> Result<()> {
+        let response = self
+            .http_client
+            .get(&self.left_url)
+            .query(&[("chat_id", chat_id)])
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram leave chat error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        Ok(())
+    }
+
+    async fn setup_webhook<'a>(
+        &self,
+        url: &str,
+        secret: Option<&'a str>,
+        allowed_updates: &[&'a str],
+    ) -> Result<()> {
+        let request_data =
+            TgSetWebhookRequest::new(url, secret, allowed_updates);
+
+        let response = self
+            .http_client
+            .post(&self.set_webhook_url)
+            .json(&request_data)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram set webhook error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
+        }
+
+        Ok(())
+    }
+
+    async fn teardown_webhook(&self) -> Result<()> {
+        let response = self
+            .http_client
+            .post(&self.delete_webhook_url)
+            .send()
+            .await
+            .context(ErrorCode::TelegramApiFailure)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = format!(
+                "Telegram delete webhook error. Error: {}.",
+                response.text().await?
+            );
+            bail!(api_error(status, error));
         }
 
         Ok(())
     }
 }
 
+/// Escapes MarkdownV2 special characters, keeping `**bold**` and code spans
+/// that have a closing backtick. Other stars and backticks are escaped, so
+/// the result always parses.
 fn escape_text(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
     let mut result_text = String::with_capacity(text.len());
+    // Start and length of the closing delimiter of the open code span.
+    let mut code_end = None;
+    let mut index = 0;
 
-    let mut peekable = text.chars().peekable();
-    let mut prev = '\0';
+    while index < chars.len() {
+        let ch = chars[index];
 
-    while let Some(ch) = peekable.next() {
-        if ESCAPE_UNARY_SYMBOLS.contains(&ch)
-            || (ESCAPE_PAIR_SYMBOLS.contains(&ch)
-                && (prev != ch
-                    && peekable.peek().is_some_and(|n_ch| *n_ch != ch)))
-        {
+        let delimiter = match code_end {
+            Some((end, len)) if index == end => {
+                code_end = None;
+                Some(len)
+            }
+            None if ch == '`' => code_span(&chars, index).map(|(end, len)| {
+                code_end = Some((end, len));
+                len
+            }),
+            _ => None,
+        };
+        if let Some(len) = delimiter {
+            result_text.extend(&chars[index..index + len]);
+            index += len;
+            continue;
+        }
+
+        if ESCAPE_PAIR_SYMBOLS.contains(&ch) {
+            // An odd star would leave a bold span open.
+            let run = chars[index..].iter().take_while(|&&c| c == ch).count();
+            if run % 2 == 1 {
+                result_text.push('\\');
+            }
+            result_text.extend(&chars[index..index + run]);
+            index += run;
+            continue;
+        }
+
+        if ESCAPE_UNARY_SYMBOLS.contains(&ch) || ch == '`' {
             result_text.push('\\');
         }
 
         result_text.push(ch);
-        prev = ch
+        index += 1;
     }
     result_text
 }
 
+/// Start and length of the closing delimiter of a non-empty code span that
+/// opens at `start`, a ``` block or else an inline one.
+fn code_span(chars: &[char], start: usize) -> Option<(usize, usize)> {
+    let is_delimiter = |index: usize, len: usize| {
+        chars
+            .get(index..index + len)
+            .is_some_and(|delimiter| delimiter.iter().all(|&c| c == '`'))
+    };
+
+    if is_delimiter(start, 3) {
+        let end =
+            (start + 4..chars.len()).find(|&index| is_delimiter(index, 3));
+        if let Some(end) = end {
+            return Some((end, 3));
+        }
+    }
+
+    let end = (start + 2..chars.len()).find(|&index| chars[index] == '`')?;
+    (chars[start + 1] != '`').then_some((end, 1))
+}
+
 #[cfg_attr(test, automock)]
 pub trait TelegramInteractor: Send + Sync {
     async fn get_file_url(&self, file_id: &str) -> Result<String>;
+    /// `reply_to_id` threads the message as a reply in the chat. A long
+    /// text is sent in chunks, the first one is returned.
     async fn send_message(
         &self,
         chat_id: i64,
         text: &str,
-        parse_mode: Option<&'static str>,
+        parse_mode: Option<ParseMode>,
+        reply_to_id: Option<i32>,
+    ) -> Result<SentMessage>;
+    async fn send_message_with_reply_markup(
+        &self,
+        chat_id: i64,
+        text: &str,
+        parse_mode: Option<ParseMode>,
+        reply_markup: Option<ReplyMarkup>,
+        reply_to_id: Option<i32>,
+    ) -> Result<i32>;
+    /// Replaces the text of a sent message, the overflow is sent anew.
+    /// A keyboard the message had is dropped unless `reply_markup` has it.
+    async fn edit_message(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        text: &str,
+        parse_mode: Option<ParseMode>,
+        reply_markup: Option<ReplyMarkup>,
+    ) -> Result<()>;
+    async fn send_image(
+        &self,
+        chat_id: i64,
+        image: ImageContent,
+        reply_to_id: Option<i32>,
+    ) -> Result<()>;
+    async fn send_voice(
+        &self,
+        chat_id: i64,
+        audio: Vec<u8>,
+        reply_to_id: Option<i32>,
+    ) -> Result<()>;
+    async fn send_document(
+        &self,
+        chat_id: i64,
+        filename: &str,
+        data: Vec<u8>,
+    ) -> Result<()>;
+    async fn set_chat_photo(&self, chat_id: i64, photo: Vec<u8>) -> Result<()>;
+    async fn download_file(&self, url: &str) -> Result<Vec<u8>>;
+    /// Text of the web page, or `None` if robots.txt disallows it.
+    async fn download_page(&self, url: &str) -> Result<Option<String>>;
+    async fn copy_message(
+        &self,
+        to_chat_id: i64,
+        from_chat_id: i64,
+        message_id: i32,
+    ) -> Result<i32>;
+    async fn answer_web_app_query(
+        &self,
+        web_app_query_id: &str,
+        result: InlineQueryResult,
+    ) -> Result<()>;
+    async fn set_reaction(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        emoji: &str,
+    ) -> Result<()>;
+    async fn create_invoice(
+        &self,
+        chat_id: i64,
+        title: &str,
+        description: &str,
+        provider_token: &str,
+        currency: &str,
+        price: i32,
+    ) -> Result<i32>;
+    async fn answer_callback_query(
+        &self,
+        callback_query_id: &str,
+    ) -> Result<()>;
+    /// Declines the checkout with `error_message`, if there is one.
+    async fn answer_pre_checkout_query<'a>(
+        &self,
+        pre_checkout_query_id: &'a str,
+        error_message: Option<&'a str>,
+    ) -> Result<()>;
+    async fn send_dice(&self, chat_id: i64, emoji: &str) -> Result<Dice>;
+    /// A quiz has to have `correct_option_id`.
+    async fn send_poll<'a>(
+        &self,
+        chat_id: i64,
+        question: &'a str,
+        options: &[&'a str],
+        is_quiz: bool,
+        correct_option_id: Option<u8>,
+    ) -> Result<()>;
+    /// Groups need the bot to be an admin allowed to pin messages.
+    async fn pin_message(&self, chat_id: i64, message_id: i32) -> Result<()>;
+    async fn get_my_commands(&self) -> Result<Vec<BotCommand>>;
+    /// Replaces the command menu Telegram shows to users.
+    async fn set_my_commands(&self, commands: &[BotCommand]) -> Result<()>;
+    async fn get_chat_administrators(
+        &self,
+        chat_id: i64,
+    ) -> Result<Vec<ChatMember>>;
+    async fn send_chat_action(
+        &self,
+        chat_id: i64,
+        action: ChatAction,
     ) -> Result<()>;
-    async fn send_image(&self, chat_id: i64, url: &str) -> Result<()>;
-    async fn send_voice(&self, chat_id: i64, audio: Vec<u8>) -> Result<()>;
     async fn leave_chat(&self, chat_id: i64) -> Result<()>;
+    /// Points Telegram to `url` with the `setWebhook` API.
+    async fn setup_webhook<'a>(
+        &self,
+        url: &str,
+        secret: Option<&'a str>,
+        allowed_updates: &[&'a str],
+    ) -> Result<()>;
+    async fn teardown_webhook(&self) -> Result<()>;
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::tg_client::escape_text;
+    use anyhow::bail;
+    use proptest::prelude::*;
+
+    use reqwest::StatusCode;
+
+    use crate::chunk_splitter::MarkdownV2ChunkSplitter;
+    use crate::event_handler::{error_code, is_retryable, ErrorCode};
+    use crate::tg_client::{
+        api_error, escape_text, response_error, ParseMode, ReplyParameters,
+        TgMessageRequest, TgSetWebhookRequest, ESCAPE_UNARY_SYMBOLS,
+        MAX_MSG_SIZE,
+    };
+
+    /// Parses MarkdownV2 the way Telegram does, as far as the bot uses it,
+    /// and returns the visible text.
+    fn parse_markdown_v2(text: &str) -> anyhow::Result<String> {
+        let mut visible = String::new();
+        let mut rest = text;
+        let mut code: Option<(&str, usize)> = None;
+        let mut bold = false;
+
+        while let Some(ch) = rest.chars().next() {
+            let position = text.len() - rest.len();
+
+            if let Some(escaped) = rest.strip_prefix('\\') {
+                match escaped.chars().next() {
+                    Some(ch @ '\u{1}'..='\u{7e}') => visible.push(ch),
+                    _ => bail!("Bad escape at {position}"),
+                }
+                rest = &escaped[1..];
+                continue;
+            }
+
+            if let Some((delimiter, start)) = code {
+                if let Some(after) = rest.strip_prefix(delimiter) {
+                    if visible.len() == start {
+                        bail!("Empty code at {position}");
+                    }
+                    code = None;
+                    rest = after;
+                    continue;
+                }
+                if ch == '`' {
+                    bail!("Unescaped ` in code at {position}");
+                }
+                visible.push(ch);
+            } else if let Some(after) = rest.strip_prefix("```") {
+                code = Some(("```", visible.len()));
+                rest = after;
+                continue;
+            } else if ch == '`' {
+                code = Some(("`", visible.len()));
+            } else if ch == '*' {
+                bold = !bold;
+            } else if ESCAPE_UNARY_SYMBOLS.contains(&ch) {
+                bail!("Unescaped {ch} at {position}");
+            } else {
+                visible.push(ch);
+            }
+
+            rest = &rest[ch.len_utf8()..];
+        }
+
+        if code.is_some() {
+            bail!("Unclosed code");
+        }
+        if bold {
+            bail!("Unclosed bold");
+        }
+
+        Ok(visible)
+    }
+
+    fn split_into_chunks(text: &str) -> Vec<String> {
+        MarkdownV2ChunkSplitter::new(MAX_MSG_SIZE).split(text)
+    }
+
+    // Test that failed Telegram requests carry the alerting code
+    #[test]
+    fn test_api_error_code() {
+        let error = api_error(StatusCode::BAD_REQUEST, "Bad".to_string());
+        assert_eq!(error_code(&error), Some(ErrorCode::TelegramApiFailure));
+        assert!(!is_retryable(&error));
+
+        let error = api_error(StatusCode::BAD_GATEWAY, "Bad".to_string());
+        assert_eq!(error_code(&error), Some(ErrorCode::TelegramApiFailure));
+        assert!(is_retryable(&error));
+
+        let error = response_error(Some("Bad".to_string()));
+        assert_eq!(error_code(&error), Some(ErrorCode::TelegramApiFailure));
+    }
+
+    #[test]
+    fn test_parse_mode_serialization() {
+        let request = TgMessageRequest::new(
+            1,
+            "text",
+            Some(ParseMode::MarkdownV2),
+            None,
+            None,
+        );
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"chat_id":1,"text":"text","parse_mode":"MarkdownV2"}"#
+        );
+
+        let request = TgMessageRequest::new(
+            1,
+            "text",
+            None,
+            None,
+            Some(ReplyParameters::new(7)),
+        );
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"chat_id":1,"text":"text","reply_parameters":{"message_id":7,"allow_sending_without_reply":true}}"#
+        );
+    }
+
+    #[test]
+    fn test_set_webhook_serialization() {
+        let request = TgSetWebhookRequest::new(
+            "https://example.com",
+            None,
+            &["message", "chat_member"],
+        );
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"url":"https://example.com","allowed_updates":["message","chat_member"]}"#
+        );
+
+        let request = TgSetWebhookRequest::new(
+            "https://example.com",
+            Some("secret"),
+            &[],
+        );
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"url":"https://example.com","secret_token":"secret","allowed_updates":[]}"#
+        );
+    }
 
     #[tokio::test]
     async fn test_escape_text() {
@@ -383,4 +1859,104 @@ mod tests {
         let escaped_text = escape_text(text);
         assert_eq!(escaped_text, "Hello **world**\\!");
     }
+
+    #[test]
+    fn test_escape_text_regressions() {
+        let cases = [
+            ("C:\\Users\\bot", "C:\\\\Users\\\\bot"),
+            ("你好，世界。", "你好，世界。"),
+            ("Готово 👍!", "Готово 👍\\!"),
+            (
+                "```rust\nlet a = b.c;\n```",
+                "```rust\nlet a \\= b\\.c;\n```",
+            ),
+            ("```\nlet s = `x`;\n```", "```\nlet s \\= \\`x\\`;\n```"),
+            ("Run `ls` or ` alone", "Run `ls` or \\` alone"),
+            ("``` unclosed", "\\`\\`\\` unclosed"),
+            ("``", "\\`\\`"),
+            ("**bold*italic**", "**bold\\*italic**"),
+            ("***bold***", "\\***bold\\***"),
+            ("2 * 3 = 6*", "2 \\* 3 \\= 6\\*"),
+        ];
+
+        for (text, expected) in cases {
+            let escaped_text = escape_text(text);
+            assert_eq!(escaped_text, expected);
+            assert!(parse_markdown_v2(&escaped_text).is_ok(), "{text}");
+        }
+
+        assert_eq!(
+            parse_markdown_v2(&escape_text("C:\\Users\\bot")).unwrap(),
+            "C:\\Users\\bot"
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_escape_text_parses(text in any::<String>()) {
+            let escaped_text = escape_text(&text);
+            prop_assert!(parse_markdown_v2(&escaped_text).is_ok());
+        }
+
+        #[test]
+        fn test_escape_text_parses_special(text in "[*`\\\\_.!a ж😀\n]*") {
+            let escaped_text = escape_text(&text);
+            prop_assert!(parse_markdown_v2(&escaped_text).is_ok());
+        }
+
+        // Without stars and backticks there is no formatting to lose.
+        #[test]
+        fn test_escape_text_round_trip(text in "[^*`]*") {
+            let escaped_text = escape_text(&text);
+            prop_assert_eq!(parse_markdown_v2(&escaped_text).unwrap(), text);
+        }
+    }
+
+    fn assert_chunks(text: &str) {
+        let chunks = split_into_chunks(text);
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(!chunk.is_empty());
+            assert!(chunk.len() <= MAX_MSG_SIZE);
+            assert!(std::str::from_utf8(chunk.as_bytes()).is_ok());
+        }
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn test_split_into_chunks_cyrillic() {
+        assert_chunks(&"ж".repeat(4097));
+    }
+
+    #[test]
+    fn test_split_into_chunks_japanese() {
+        assert_chunks(&"日本語".repeat(2000));
+    }
+
+    #[test]
+    fn test_split_into_chunks_emoji() {
+        assert_chunks(&"👍🏽🎉".repeat(1500));
+    }
+
+    #[test]
+    fn test_split_into_chunks_mixed() {
+        assert_chunks(&"Hello, мир! 日本 🎉 ".repeat(500));
+    }
+
+    #[test]
+    fn test_split_into_chunks_keeps_escape_sequence() {
+        let text = format!("{}\\!tail", "a".repeat(MAX_MSG_SIZE - 1));
+        let chunks = split_into_chunks(&text);
+
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[1].starts_with("\\!"));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn test_split_into_chunks_short_text() {
+        assert_eq!(split_into_chunks("Hello"), vec!["Hello"]);
+        assert!(split_into_chunks("").is_empty());
+    }
 }
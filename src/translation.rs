@@ -0,0 +1,97 @@
+use anyhow::Result;
+use whichlang::Lang;
+
+use crate::gpt_client::GtpInteractor;
+
+/// Fewer letters than this are too little to tell the language by.
+const MIN_DETECTION_LETTERS: usize = 10;
+
+pub trait TranslationClient {
+    async fn translate(&self, text: &str, target_lang: &str) -> Result<String>;
+}
+
+impl<T: GtpInteractor> TranslationClient for T {
+    async fn translate(&self, text: &str, target_lang: &str) -> Result<String> {
+        let prompt = format!(
+            "Translate to the language with code '{target_lang}'. \
+             Reply with the translation only: {text}"
+        );
+        let translation = self.get_stateless_completion(prompt).await?;
+
+        Ok(translation.to_string())
+    }
+}
+
+/// English text is left as is when all its letters are ASCII.
+pub fn needs_translation(text: &str, target_lang: &str) -> bool {
+    target_lang != "en"
+        || text
+            .chars()
+            .any(|ch| ch.is_alphabetic() && !ch.is_ascii_alphabetic())
+}
+
+/// ISO 639-1 code of the language `text` is most likely written in. Only
+/// the languages `whichlang` knows are detected, Ukrainian is not one of
+/// them. Only plain words count, so short replies, links and code give
+/// `None` instead of a guess.
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    if word_letters(text) < MIN_DETECTION_LETTERS {
+        return None;
+    }
+
+    let lang = match whichlang::detect_language(text) {
+        Lang::Ara => "ar",
+        Lang::Cmn => "zh",
+        Lang::Deu => "de",
+        Lang::Eng => "en",
+        Lang::Fra => "fr",
+        Lang::Hin => "hi",
+        Lang::Ita => "it",
+        Lang::Jpn => "ja",
+        Lang::Kor => "ko",
+        Lang::Nld => "nl",
+        Lang::Por => "pt",
+        Lang::Rus => "ru",
+        Lang::Spa => "es",
+        Lang::Swe => "sv",
+        Lang::Tur => "tr",
+        Lang::Vie => "vi",
+    };
+    Some(lang)
+}
+
+fn word_letters(text: &str) -> usize {
+    text.split_whitespace()
+        .map(|word| word.trim_matches(|ch: char| !ch.is_alphanumeric()))
+        .filter(|word| {
+            word.chars()
+                .all(|ch| ch.is_alphabetic() || ch == '-' || ch == '\'')
+        })
+        .map(|word| word.chars().filter(|ch| ch.is_alphabetic()).count())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::translation::{detect_language, needs_translation};
+
+    #[test]
+    fn test_needs_translation() {
+        assert!(!needs_translation("Hello, world!", "en"));
+        assert!(needs_translation("Привет, world!", "en"));
+        assert!(needs_translation("Hello", "de"));
+    }
+
+    #[test]
+    fn test_detect_language() {
+        assert_eq!(
+            detect_language("What is the weather like today?"),
+            Some("en")
+        );
+        assert_eq!(detect_language("Какая сегодня погода?"), Some("ru"));
+        assert_eq!(detect_language("Wie ist das Wetter heute?"), Some("de"));
+        assert_eq!(detect_language("ok"), None);
+        assert_eq!(detect_language("https://example.com/some/page"), None);
+        assert_eq!(detect_language("let x = vec![1, 2, 3];"), None);
+    }
+}
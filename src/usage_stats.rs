@@ -0,0 +1,222 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{NaiveDate, Utc};
+use dashmap::DashMap;
+
+// Tokens are recorded right after the answer, the asker isn't needed longer.
+const CONVERSATION_USER_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// GPT usage counters since the Lambda instance started.
+#[derive(Debug, Default)]
+pub struct UsageStats {
+    requests: AtomicU64,
+    completions: AtomicU64,
+    images: AtomicU64,
+    errors: AtomicU64,
+    tokens: AtomicU64,
+    users: UsageTracker,
+    /// The user who asked last in each shared conversation.
+    conversation_users: DashMap<i64, (i64, Instant)>,
+}
+
+impl UsageStats {
+    pub fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_completion(&self, tokens: u64) {
+        self.completions.fetch_add(1, Ordering::Relaxed);
+        self.tokens.fetch_add(tokens, Ordering::Relaxed);
+    }
+
+    /// `user_id` may be the id of a shared conversation, whose tokens are
+    /// counted for the user who asked.
+    pub fn record_user_tokens(
+        &self,
+        user_id: i64,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) {
+        let user_id = self
+            .conversation_users
+            .get(&user_id)
+            .map_or(user_id, |user| user.0);
+        self.users.record(user_id, prompt_tokens, completion_tokens);
+    }
+
+    pub fn set_conversation_user(&self, conversation_id: i64, user_id: i64) {
+        let now = Instant::now();
+        self.conversation_users.retain(|_, (_, asked_at)| {
+            now.duration_since(*asked_at) < CONVERSATION_USER_TTL
+        });
+
+        self.conversation_users
+            .insert(conversation_id, (user_id, now));
+    }
+
+    pub fn users(&self) -> &UsageTracker {
+        &self.users
+    }
+
+    pub fn record_image(&self) {
+        self.images.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report(&self, uptime: Duration) -> String {
+        let uptime = uptime.as_secs();
+        format!(
+            "Аптайм: {}ч {}м {}с\nЗапросы: {}\nОтветы: {}\nКартинки: {}\n\
+             Ошибки: {}\nТокены: {}",
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60,
+            self.requests.load(Ordering::Relaxed),
+            self.completions.load(Ordering::Relaxed),
+            self.images.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            self.tokens.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Prompt and completion tokens of each user today, the counters start
+/// over at midnight UTC.
+#[derive(Debug)]
+pub struct UsageTracker {
+    day: Mutex<NaiveDate>,
+    users: DashMap<i64, (u64, u64)>,
+}
+
+impl Default for UsageTracker {
+    fn default() -> Self {
+        UsageTracker {
+            day: Mutex::new(Utc::now().date_naive()),
+            users: DashMap::new(),
+        }
+    }
+}
+
+impl UsageTracker {
+    pub fn record(
+        &self,
+        user_id: i64,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) {
+        self.reset_if_new_day(Utc::now().date_naive());
+
+        let mut tokens = self.users.entry(user_id).or_default();
+        tokens.0 += prompt_tokens;
+        tokens.1 += completion_tokens;
+    }
+
+    /// Tokens per user and the estimated cost in USD, the most expensive
+    /// users first.
+    pub fn report(
+        &self,
+        input_cost_per_1k: f64,
+        output_cost_per_1k: f64,
+    ) -> String {
+        let day = self.reset_if_new_day(Utc::now().date_naive());
+        let cost = |(prompt_tokens, completion_tokens): (u64, u64)| {
+            (prompt_tokens as f64 * input_cost_per_1k
+                + completion_tokens as f64 * output_cost_per_1k)
+                / 1000.0
+        };
+
+        let mut users = self
+            .users
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| {
+            cost(b.1).total_cmp(&cost(a.1)).then(a.0.cmp(&b.0))
+        });
+
+        let mut total = (0, 0);
+        let mut text = format!("Токены за {day}:");
+        for (user_id, tokens) in users {
+            total.0 += tokens.0;
+            total.1 += tokens.1;
+            text.push_str(&format!(
+                "\n{user_id}: {} + {}, ${:.4}",
+                tokens.0,
+                tokens.1,
+                cost(tokens)
+            ));
+        }
+        text.push_str(&format!(
+            "\nВсего: {} + {}, ${:.4}",
+            total.0,
+            total.1,
+            cost(total)
+        ));
+
+        text
+    }
+
+    /// Drops the counters of a past day and returns the current one.
+    fn reset_if_new_day(&self, today: NaiveDate) -> NaiveDate {
+        let mut day = self.day.lock().unwrap();
+        if *day != today {
+            self.users.clear();
+            *day = today;
+        }
+        *day
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{Days, Utc};
+
+    use crate::usage_stats::{UsageStats, UsageTracker};
+
+    #[test]
+    fn test_usage_stats_report() {
+        let stats = UsageStats::default();
+
+        stats.record_request();
+        stats.record_completion(42);
+        stats.record_request();
+        stats.record_image();
+        stats.record_request();
+        stats.record_error();
+
+        assert_eq!(
+            stats.report(Duration::from_secs(3725)),
+            "Аптайм: 1ч 2м 5с\nЗапросы: 3\nОтветы: 1\nКартинки: 1\n\
+             Ошибки: 1\nТокены: 42"
+        );
+    }
+
+    #[test]
+    fn test_usage_tracker_report() {
+        let tracker = UsageTracker::default();
+
+        tracker.record(1, 1000, 500);
+        tracker.record(2, 4000, 1000);
+        tracker.record(1, 1000, 500);
+
+        let today = Utc::now().date_naive();
+        assert_eq!(
+            tracker.report(0.5, 1.5),
+            format!(
+                "Токены за {today}:\n2: 4000 + 1000, $3.5000\n\
+                 1: 2000 + 1000, $2.5000\nВсего: 6000 + 2000, $6.0000"
+            )
+        );
+
+        tracker.reset_if_new_day(today + Days::new(1));
+
+        assert!(tracker.users.is_empty());
+    }
+}
@@ -0,0 +1,49 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+pub const TONES: &str = "formal, casual, technical, creative";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tone {
+    Formal,
+    Casual,
+    Technical,
+    Creative,
+}
+
+impl Tone {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Tone::Formal => "formal",
+            Tone::Casual => "casual",
+            Tone::Technical => "technical",
+            Tone::Creative => "creative",
+        }
+    }
+
+    pub fn instruction(&self) -> String {
+        format!("Respond in a {} tone. ", self.as_str())
+    }
+}
+
+impl FromStr for Tone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "formal" => Ok(Tone::Formal),
+            "casual" => Ok(Tone::Casual),
+            "technical" => Ok(Tone::Technical),
+            "creative" => Ok(Tone::Creative),
+            _ => Err(anyhow!("Unknown tone: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct UserPrefs {
+    pub tone: Option<Tone>,
+}
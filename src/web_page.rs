@@ -0,0 +1,195 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use anyhow::{bail, Context, Result};
+use reqwest::Url;
+
+// Wide enough that html2text doesn't wrap the lines of the page.
+const TEXT_WIDTH: usize = 1000;
+
+/// Readable text of an HTML page without the markup.
+pub fn html_to_text(html: &[u8]) -> Result<String> {
+    let text = html2text::config::plain()
+        .no_link_wrapping()
+        .string_from_read(html, TEXT_WIDTH)?;
+
+    Ok(text.trim().to_string())
+}
+
+/// Checks `path` against the `User-agent: *` rules of a robots.txt. The
+/// longest matching rule wins and `Allow` wins a tie.
+pub fn is_allowed_by_robots(robots: &str, path: &str) -> bool {
+    let mut applies = false;
+    let mut in_agents = false;
+    let mut best: Option<(usize, bool)> = None;
+
+    for line in robots.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+
+        match key.trim().to_lowercase().as_str() {
+            "user-agent" => {
+                // Consecutive agent lines share one group of rules.
+                if !in_agents {
+                    applies = false;
+                }
+                in_agents = true;
+                applies |= value == "*";
+            }
+            rule @ ("allow" | "disallow") => {
+                in_agents = false;
+                if !applies || value.is_empty() || !path.starts_with(value) {
+                    continue;
+                }
+
+                let allow = rule == "allow";
+                let is_better = best.is_none_or(|(len, best_allow)| {
+                    value.len() > len
+                        || (value.len() == len && allow && !best_allow)
+                });
+                if is_better {
+                    best = Some((value.len(), allow));
+                }
+            }
+            _ => in_agents = false,
+        }
+    }
+
+    best.is_none_or(|(_, allow)| allow)
+}
+
+/// Resolves the host of an https `url` and refuses addresses inside the
+/// Lambda or the VPC, like the runtime API on 127.0.0.1 or the metadata
+/// on 169.254.169.254. GPT picks the URLs, so anyone can steer it there.
+pub async fn resolve_public_host(url: &Url) -> Result<SocketAddr> {
+    if url.scheme() != "https" {
+        bail!("Only https pages can be read");
+    }
+
+    let host = url.host_str().context("Page URL has no host")?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    // IPv6 literals come in brackets, which the resolver doesn't take.
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<_> = tokio::net::lookup_host((host, port)).await?.collect();
+
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public_ip(addr.ip())) {
+        bail!("Page host is not public");
+    }
+
+    Ok(addrs[0])
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ipv4(ip);
+            }
+
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local fc00::/7 and link-local fe80::/10.
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        // Carrier-grade NAT 100.64.0.0/10 and benchmarking 198.18.0.0/15.
+        || (a == 100 && b & 0xc0 == 64)
+        || (a == 198 && b & 0xfe == 18)
+        || a >= 240)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use reqwest::Url;
+
+    use crate::web_page::{
+        html_to_text, is_allowed_by_robots, is_public_ip, resolve_public_host,
+    };
+
+    #[test]
+    fn test_html_to_text() {
+        let html = b"<html><head><script>var a;</script></head>\
+            <body><h1>Title</h1><p>Some text</p></body></html>";
+
+        let text = html_to_text(html).unwrap();
+
+        assert!(text.contains("Title"));
+        assert!(text.contains("Some text"));
+        assert!(!text.contains("<p>"));
+    }
+
+    #[test]
+    fn test_is_public_ip() {
+        let is_public = |ip: &str| is_public_ip(ip.parse::<IpAddr>().unwrap());
+
+        assert!(is_public("93.184.216.34"));
+        assert!(is_public("2606:2800:220:1::"));
+        assert!(!is_public("127.0.0.1"));
+        assert!(!is_public("10.0.0.5"));
+        assert!(!is_public("172.16.1.1"));
+        assert!(!is_public("192.168.1.1"));
+        assert!(!is_public("169.254.169.254"));
+        assert!(!is_public("100.64.0.1"));
+        assert!(!is_public("0.0.0.0"));
+        assert!(!is_public("::1"));
+        assert!(!is_public("fd00::1"));
+        assert!(!is_public("fe80::1"));
+        assert!(!is_public("::ffff:127.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_public_host() {
+        for url in [
+            "http://93.184.216.34/",
+            "https://127.0.0.1:9001/2018-06-01/runtime/invocation/next",
+            "https://169.254.169.254/latest/meta-data/",
+            "https://[::1]/",
+            "file:///etc/passwd",
+        ] {
+            let url = Url::parse(url).unwrap();
+            assert!(resolve_public_host(&url).await.is_err(), "{url}");
+        }
+
+        let url = Url::parse("https://93.184.216.34/page").unwrap();
+        assert_eq!(
+            resolve_public_host(&url).await.unwrap(),
+            "93.184.216.34:443".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_is_allowed_by_robots() {
+        let robots = "User-agent: Googlebot\n\
+                      Disallow: /\n\
+                      \n\
+                      User-agent: *\n\
+                      Disallow: /private # secret\n\
+                      Allow: /private/public\n";
+
+        assert!(is_allowed_by_robots(robots, "/news"));
+        assert!(!is_allowed_by_robots(robots, "/private/page"));
+        assert!(is_allowed_by_robots(robots, "/private/public/page"));
+        assert!(is_allowed_by_robots("", "/private"));
+        assert!(!is_allowed_by_robots("User-agent: *\nDisallow: /", "/a"));
+    }
+}